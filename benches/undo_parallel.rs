@@ -0,0 +1,58 @@
+//! Undoing 10k file deletes one after the other and with `Stack::undo_parallel`
+//!
+//! Run with `cargo bench --features rayon --bench undo_parallel`
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use rewind::stack::Stack;
+
+const FILES: usize = 10_000;
+
+/// A stack of atoms each removing one of the files in `dir` on undo
+fn stack_of_files(dir: &Path, independent: bool) -> Stack {
+    let mut stack = Stack::new();
+    for i in 0..FILES {
+        let path = dir.join(format!("{i}.tmp"));
+        fs::write(&path, b"rewind").unwrap();
+        let cleanup = rewind::simple(path, |p: PathBuf| fs::remove_file(p).is_ok());
+        if independent {
+            stack.push_independent(cleanup);
+        } else {
+            stack.push(cleanup);
+        }
+    }
+    stack
+}
+
+fn time_undo(dir: &Path, independent: bool) -> Duration {
+    let mut stack = stack_of_files(dir, independent);
+    let start = Instant::now();
+    let undone = if independent {
+        stack.undo_parallel()
+    } else {
+        stack.undo_all()
+    };
+    let elapsed = start.elapsed();
+    assert!(undone
+        .iter()
+        .all(|r| r.downcast_ref::<bool>() == Some(&true)));
+    elapsed
+}
+
+fn main() {
+    let dir = std::env::temp_dir().join(format!("rewind-bench-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let serial = time_undo(&dir, false);
+    let parallel = time_undo(&dir, true);
+    fs::remove_dir(&dir).unwrap();
+    println!("undo_all:      {serial:?} for {FILES} files");
+    println!("undo_parallel: {parallel:?} for {FILES} files");
+    println!(
+        "speedup:       {:.1}x",
+        serial.as_secs_f64() / parallel.as_secs_f64()
+    );
+}
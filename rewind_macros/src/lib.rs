@@ -1,114 +1,1652 @@
 use proc_macro2::{Ident, Span, TokenStream};
 use quote::{quote, ToTokens};
 use syn::{
-    parse::Parse, parse_macro_input, parse_quote, token::Token, visit::Visit, visit_mut::VisitMut,
-    Block,
+    parse::Parse, parse_macro_input, parse_quote, punctuated::Punctuated, visit_mut::VisitMut,
 };
 
 struct IsoToArg {
-    eq: syn::Token![=],
-    right: syn::Path,
+    _eq: syn::Token![=],
+    right: UndoTarget,
+}
+enum UndoTarget {
+    Method {
+        path: syn::Path,
+        /// Arguments to call `path` with, evaluated before the operation runs
+        call_args: Option<Punctuated<syn::Expr, syn::Token![,]>>,
+    },
+    /// Used as the undo directly, taking the state and the value
+    Closure(syn::ExprClosure),
+    /// From `undo = ...`, evaluated as the undo with the state and the value bound to these
+    Expr {
+        expr: syn::Expr,
+        state: Ident,
+        value: Ident,
+    },
+}
+impl Parse for UndoTarget {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        match input.parse()? {
+            syn::Expr::Path(p) if p.attrs.is_empty() && p.qself.is_none() => Ok(Self::Method {
+                path: p.path,
+                call_args: None,
+            }),
+            syn::Expr::Call(syn::ExprCall { func, args, .. }) => match *func {
+                syn::Expr::Path(p) if p.qself.is_none() => Ok(Self::Method {
+                    path: p.path,
+                    call_args: Some(args),
+                }),
+                f => Err(syn::Error::new_spanned(f, "expected a method name")),
+            },
+            syn::Expr::Closure(c) if c.inputs.len() == 2 => Ok(Self::Closure(c)),
+            syn::Expr::Closure(c) => Err(syn::Error::new_spanned(
+                c,
+                "undo closure must take the state and the value, e.g. `|s, v| ...`",
+            )),
+            e => Err(syn::Error::new_spanned(
+                e,
+                "expected a method, method call or closure",
+            )),
+        }
+    }
 }
 impl Parse for IsoToArg {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
-        syn::Ident::parse(&input).and_then(|t| {
-            if &t.to_string() != "to" {
-                Err(input.error("expecting to = ..."))
-            } else {
-                Ok(t)
+        if input.is_empty() {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "expected the undo, e.g. `#[isomorphic(to = pop)]`",
+            ));
+        }
+        let expected = "expected `to = ...` or `undo = ...`";
+        let key = syn::Ident::parse(input).map_err(|e| syn::Error::new(e.span(), expected))?;
+        if key != "to" && key != "undo" {
+            return Err(syn::Error::new_spanned(key, expected));
+        }
+        let _eq = input
+            .parse()
+            .map_err(|e| syn::Error::new(e.span(), format!("expected `=` after `{key}`")))?;
+        if input.is_empty() || input.peek(syn::Token![,]) {
+            return Err(input.error(format!("expected the undo after `{key} =`")));
+        }
+        let right = if key == "to" {
+            input.parse()?
+        } else {
+            // Spanned to the user's `undo` so their expression can refer to them
+            UndoTarget::Expr {
+                expr: input.parse()?,
+                state: Ident::new("state", key.span()),
+                value: Ident::new("value", key.span()),
             }
-        })?;
-
-        Ok(Self {
-            eq: input.parse()?,
-            right: input.parse()?,
-        })
+        };
+        Ok(Self { _eq, right })
     }
 }
 struct IsoArgs {
     to: IsoToArg,
+    /// Call the undo target without the value returned by the operation
+    no_args: bool,
+    /// Keep the original function and emit the wrapper with this suffix, spanned to the option
+    /// so a clashing name is reported there
+    keep_original: Option<(String, Span)>,
+    /// `Result<_, E>` returned by the function, so only successes get a side effect
+    fail_type: Option<syn::Type>,
+    /// Called with the state and the error instead of the undo when the function fails
+    on_fail: Option<syn::Path>,
+    /// Runs the operation again after it was undone
+    redo: Option<RedoTarget>,
+}
+enum RedoTarget {
+    /// Called on the state with clones of the arguments
+    Method(syn::Path),
+    /// Used as the redo directly, taking the state
+    Closure(syn::ExprClosure),
+}
+impl Parse for RedoTarget {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        match input.parse()? {
+            syn::Expr::Path(p) if p.attrs.is_empty() && p.qself.is_none() => {
+                Ok(Self::Method(p.path))
+            }
+            syn::Expr::Closure(c) if c.inputs.len() == 1 => Ok(Self::Closure(c)),
+            syn::Expr::Closure(c) => Err(syn::Error::new_spanned(
+                c,
+                "redo closure must take only the state, e.g. `|s| ...`",
+            )),
+            e => Err(syn::Error::new_spanned(e, "expected a method or closure")),
+        }
+    }
 }
 type IsoBody = syn::ItemFn;
 
 impl Parse for IsoArgs {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
-        Ok(Self { to: input.parse()? })
+        let to = input.parse()?;
+        Self::parse_options(to, input)
+    }
+}
+impl IsoArgs {
+    /// Parse the options following the undo target
+    fn parse_options(to: IsoToArg, input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let mut no_args = false;
+        let mut keep_original = None;
+        let mut fail_type = None;
+        let mut on_fail = None::<syn::Path>;
+        let mut redo = None::<(Ident, RedoTarget)>;
+        while input.parse::<Option<syn::Token![,]>>()?.is_some() {
+            if input.is_empty() {
+                break;
+            }
+            let flag = syn::Ident::parse(input)?;
+            match flag.to_string().as_str() {
+                "no_args" => no_args = true,
+                "keep_original" => {
+                    keep_original.get_or_insert(("_iso".to_owned(), flag.span()));
+                }
+                "suffix" => {
+                    input.parse::<syn::Token![=]>()?;
+                    let suffix: syn::LitStr = input.parse()?;
+                    keep_original = Some((suffix.value(), suffix.span()));
+                }
+                "fail_type" => {
+                    input.parse::<syn::Token![=]>()?;
+                    fail_type = Some(input.parse()?);
+                }
+                "skip_on_err" => {
+                    fail_type.get_or_insert(parse_quote! { Result<_, _> });
+                }
+                "on_fail" => {
+                    input.parse::<syn::Token![=]>()?;
+                    on_fail = Some(input.parse()?);
+                }
+                "redo" => {
+                    input.parse::<syn::Token![=]>()?;
+                    redo = Some((flag, input.parse()?));
+                }
+                "to" | "undo" => {
+                    return Err(syn::Error::new_spanned(
+                        flag,
+                        "only one of `to` and `undo` can be given",
+                    ))
+                }
+                _ => return Err(syn::Error::new_spanned(flag, "unknown option")),
+            }
+        }
+        if let (Some(on_fail), None) = (&on_fail, &fail_type) {
+            return Err(syn::Error::new_spanned(
+                on_fail,
+                "`on_fail` needs `fail_type = Result<_, E>` too",
+            ));
+        }
+        if let (Some((redo, _)), Some(_)) = (&redo, &fail_type) {
+            return Err(syn::Error::new_spanned(
+                redo,
+                "`redo` can't be used with `fail_type`",
+            ));
+        }
+        Ok(Self {
+            to,
+            no_args,
+            keep_original,
+            fail_type,
+            on_fail,
+            redo: redo.map(|(_, r)| r),
+        })
     }
 }
 
+/// Replaces uses of the `self` value, keeping `self::` paths and `Self` as they are
 struct SelfRenameVisitor {
     rename: Ident,
 }
 impl VisitMut for SelfRenameVisitor {
-    fn visit_path_segment_mut(&mut self, i: &mut syn::PathSegment) {
-        let rename = &self.rename;
-        if i.ident == Ident::new("self", Span::call_site()) {
-            *i = parse_quote! { #rename };
+    fn visit_expr_path_mut(&mut self, i: &mut syn::ExprPath) {
+        if i.qself.is_none() && i.path.is_ident("self") {
+            let rename = &self.rename;
+            i.path = parse_quote! { #rename };
+        } else {
+            syn::visit_mut::visit_expr_path_mut(self, i);
+        }
+    }
+    /// Macro arguments are not parsed, so the tokens are renamed instead, which works for
+    /// expression-like macros such as `format!` and `matches!`
+    fn visit_macro_mut(&mut self, i: &mut syn::Macro) {
+        i.tokens = self.rename_tokens(std::mem::take(&mut i.tokens));
+    }
+    /// `self` in nested items refers to their own receivers
+    fn visit_item_mut(&mut self, _: &mut syn::Item) {}
+}
+impl SelfRenameVisitor {
+    fn rename_tokens(&self, tokens: TokenStream) -> TokenStream {
+        use proc_macro2::TokenTree;
+        let mut tokens = tokens.into_iter().peekable();
+        let mut renamed = Vec::new();
+        while let Some(t) = tokens.next() {
+            renamed.push(match t {
+                TokenTree::Ident(i)
+                    if i == "self"
+                        && !matches!(tokens.peek(), Some(TokenTree::Punct(p)) if p.as_char() == ':') =>
+                {
+                    TokenTree::Ident(self.rename.clone())
+                }
+                TokenTree::Group(g) => {
+                    let mut group =
+                        proc_macro2::Group::new(g.delimiter(), self.rename_tokens(g.stream()));
+                    group.set_span(g.span());
+                    TokenTree::Group(group)
+                }
+                t => t,
+            });
         }
+        renamed.into_iter().collect()
     }
 }
 
-fn isomorphic_main(args: IsoArgs, body: IsoBody) -> TokenStream {
+/// Replaces `Self` with the implementing type, for code moved out of its impl block
+struct SelfTypeVisitor {
+    ty: syn::Type,
+}
+impl VisitMut for SelfTypeVisitor {
+    fn visit_type_mut(&mut self, i: &mut syn::Type) {
+        match i {
+            syn::Type::Path(p) if p.qself.is_none() && p.path.is_ident("Self") => {
+                *i = self.ty.clone();
+            }
+            _ => syn::visit_mut::visit_type_mut(self, i),
+        }
+    }
+    fn visit_type_path_mut(&mut self, i: &mut syn::TypePath) {
+        self.qualify(&mut i.qself, &mut i.path);
+        syn::visit_mut::visit_type_path_mut(self, i);
+    }
+    fn visit_expr_path_mut(&mut self, i: &mut syn::ExprPath) {
+        self.qualify(&mut i.qself, &mut i.path);
+        syn::visit_mut::visit_expr_path_mut(self, i);
+    }
+}
+impl SelfTypeVisitor {
+    /// `Self::Item` to `<Type>::Item`
+    fn qualify(&self, qself: &mut Option<syn::QSelf>, path: &mut syn::Path) {
+        if qself.is_some() || path.segments.len() < 2 || path.segments[0].ident != "Self" {
+            return;
+        }
+        *qself = Some(syn::QSelf {
+            lt_token: Default::default(),
+            ty: Box::new(self.ty.clone()),
+            position: 0,
+            as_token: None,
+            gt_token: Default::default(),
+        });
+        path.leading_colon = Some(Default::default());
+        path.segments = path.segments.iter().skip(1).cloned().collect();
+    }
+}
+
+/// Adds `bound` to every `impl Trait`
+struct CaptureVisitor {
+    bound: syn::TypeParamBound,
+}
+impl VisitMut for CaptureVisitor {
+    fn visit_type_impl_trait_mut(&mut self, i: &mut syn::TypeImplTrait) {
+        i.bounds.push(self.bound.clone());
+        syn::visit_mut::visit_type_impl_trait_mut(self, i);
+    }
+}
+
+/// Name of the argument taking the encased state in generated wrappers
+fn encased_ident() -> Ident {
+    Ident::new("__rewind_iso_encased", Span::mixed_site())
+}
+
+/// Call the undo target on the state, as a method if the operation was a method
+fn undo_call(is_method: bool, target: &syn::Path, args: TokenStream) -> TokenStream {
+    if is_method {
+        quote! { __rewind_iso_self. #target (#args); }
+    } else {
+        quote! { #target (__rewind_iso_self, #args); }
+    }
+}
+
+/// The wrapper for `body`, taking `Encased` state in place of its first argument
+fn isomorphic_wrapper(args: &IsoArgs, body: &IsoBody) -> syn::Result<syn::ItemFn> {
     let mut wrap = body.clone();
+    let self_rename = Ident::new("__rewind_iso_self", Span::mixed_site());
+    // `self: &mut Encased<Self>` is not a valid receiver on stable, so the encased state is taken
+    // as a normal argument instead, making the wrapper an associated function
+    let encased_name = encased_ident();
+    let Some(first) = wrap.sig.inputs.first_mut() else {
+        return Err(syn::Error::new_spanned(
+            &body.sig,
+            "the state must be the first argument, e.g. `&mut self` or `s: &mut S`",
+        ));
+    };
+    // Shared receivers only need a shared borrow of the state for the action, the undo still
+    // gets it mutably
+    let (state_ty, state_pat, is_method, shared): (syn::Type, syn::Pat, _, _) = match first {
+        syn::FnArg::Receiver(r) => {
+            let rf = r
+                .reference
+                .as_ref()
+                .map(|(and, lifetime)| quote! { #and #lifetime });
+            let mutab = &r.mutability;
+            let shared = r.reference.is_some() && mutab.is_none();
+            *first = parse_quote! { #encased_name: #rf #mutab ::rewind::atom::Encased<Self> };
+            (
+                parse_quote! { Self },
+                parse_quote! { #self_rename },
+                true,
+                shared,
+            )
+        }
+        syn::FnArg::Typed(t) => match &*t.ty {
+            syn::Type::Reference(r) => {
+                let state_ty = (*r.elem).clone();
+                let and = r.and_token;
+                let lifetime = r.lifetime.clone();
+                let mutab = r.mutability;
+                let pat = (*t.pat).clone();
+                let attrs = t.attrs.clone();
+                *first = parse_quote! { #(#attrs)* #encased_name: #and #lifetime #mutab ::rewind::atom::Encased<#state_ty> };
+                (state_ty, pat, false, mutab.is_none())
+            }
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &t.ty,
+                    "the first argument must be `self` or a reference to the state",
+                ))
+            }
+        },
+    };
+    if shared && body.sig.asyncness.is_some() {
+        return Err(syn::Error::new_spanned(
+            &body.sig.inputs[0],
+            "async isomorphic functions must take the state mutably",
+        ));
+    }
+    let (ret_ty, returns_unit): (syn::Type, _) = match &body.sig.output {
+        syn::ReturnType::Default => (parse_quote! { () }, true),
+        syn::ReturnType::Type(_, t) => (
+            (**t).clone(),
+            matches!(&**t, syn::Type::Tuple(t) if t.elems.is_empty()),
+        ),
+    };
+    let fail = match &args.fail_type {
+        Some(fail_type) => Some(result_types(fail_type, &ret_ty)?),
+        None => None,
+    };
+    if let (Some(on_fail), true) = (&args.on_fail, shared) {
+        return Err(syn::Error::new_spanned(
+            on_fail,
+            "`on_fail` needs the state mutably",
+        ));
+    }
+    let (ret_ty, returns_unit) = match &fail {
+        Some((ok, _)) => (
+            ok.clone(),
+            matches!(ok, syn::Type::Tuple(t) if t.elems.is_empty()),
+        ),
+        None => (ret_ty, returns_unit),
+    };
+    if let (Some(_), Some(asyncness)) = (&args.redo, &body.sig.asyncness) {
+        return Err(syn::Error::new_spanned(
+            asyncness,
+            "`redo` can't be used with async functions",
+        ));
+    }
+    let effect_ty = if args.redo.is_some() {
+        quote! {
+            ::rewind::atom::Redoable<
+                #ret_ty,
+                #state_ty,
+                impl FnOnce(&mut #state_ty, #ret_ty) + Clone,
+                impl FnOnce(&mut #state_ty) -> #ret_ty + Clone,
+            >
+        }
+    } else {
+        quote! { ::rewind::atom::SideEffect<#ret_ty, (), #state_ty, impl FnOnce(&mut #state_ty, #ret_ty)> }
+    };
+    wrap.sig.output = match &fail {
+        Some((_, err)) => parse_quote! { -> ::core::result::Result<#effect_ty, #err> },
+        None => parse_quote! { -> #effect_ty },
+    };
+    let mut orig_body = body.block.clone();
+    SelfRenameVisitor {
+        rename: self_rename.clone(),
+    }
+    .visit_block_mut(&mut orig_body);
+    let mut captures = None;
+    let undo = match &args.to.right {
+        UndoTarget::Closure(c) => c.to_token_stream(),
+        UndoTarget::Expr { expr, state, value } => {
+            let mut expr = expr.clone();
+            SelfRenameVisitor {
+                rename: state.clone(),
+            }
+            .visit_expr_mut(&mut expr);
+            // Only bound if used, to avoid unused variable warnings on the user's behalf
+            let mentions = |name: &Ident| {
+                fn find(tokens: TokenStream, name: &Ident) -> bool {
+                    tokens.into_iter().any(|t| match t {
+                        proc_macro2::TokenTree::Ident(i) => i == *name,
+                        proc_macro2::TokenTree::Group(g) => find(g.stream(), name),
+                        _ => false,
+                    })
+                }
+                if find(expr.to_token_stream(), name) {
+                    name.to_token_stream()
+                } else {
+                    quote! { _ }
+                }
+            };
+            let (state, value) = (mentions(state), mentions(value));
+            quote! { move |#state, #value| { #expr; } }
+        }
+        UndoTarget::Method {
+            path: to_target,
+            call_args: Some(call_args),
+        } => {
+            let mut call_args = call_args.clone();
+            let mut renamer = SelfRenameVisitor {
+                rename: self_rename.clone(),
+            };
+            for a in call_args.iter_mut() {
+                renamer.visit_expr_mut(a);
+            }
+            let names: Vec<_> = (0..call_args.len())
+                .map(|i| Ident::new(&format!("__rewind_iso_arg{i}"), Span::mixed_site()))
+                .collect();
+            let exprs = call_args.iter();
+            captures = Some(quote! {
+                let (#(#names,)*) = (#encased_name).read(|#state_pat| (#(#exprs,)*));
+            });
+            let call = undo_call(is_method, to_target, quote! { #(#names),* });
+            quote! { move |__rewind_iso_self, _| { #call } }
+        }
+        UndoTarget::Method {
+            path: to_target, ..
+        } if args.no_args || returns_unit => {
+            let call = undo_call(is_method, to_target, quote! {});
+            quote! { move |__rewind_iso_self, _| { #call } }
+        }
+        UndoTarget::Method {
+            path: to_target, ..
+        } => {
+            let call = undo_call(is_method, to_target, quote! { __rewind_iso_value });
+            quote! { move |__rewind_iso_self, __rewind_iso_value| { #call } }
+        }
+    };
+    // A failed operation gets no undo, so it is run first and only given one if it succeeds
+    let op_undo = match &fail {
+        Some(_) => quote! { |_, _| () },
+        None => undo.clone(),
+    };
+    let op = if body.sig.asyncness.is_some() {
+        quote! { (#encased_name).peel_mut_async(async move |#state_pat| #orig_body, #op_undo).await }
+    } else if shared {
+        quote! { (#encased_name).peel(move |#state_pat| { #orig_body }, #op_undo) }
+    } else {
+        quote! { (#encased_name).peel_mut(move |#state_pat| { #orig_body }, #op_undo) }
+    };
+    let redo = match &args.redo {
+        Some(redo) => Some(redo_closure(redo, body, is_method, &state_ty, &ret_ty)?),
+        None => None,
+    };
+    wrap.block = match (&fail, redo) {
+        (None, Some((redo_captures, redo))) => parse_quote! {{
+            #captures
+            #redo_captures
+            #op.with_redo(#redo)
+        }},
+        (None, None) => parse_quote! { { #captures #op } },
+        (Some(_), _) => {
+            let on_fail = args.on_fail.as_ref().map(|on_fail| {
+                quote! { (#encased_name).modify(|__rewind_iso_self| #on_fail(__rewind_iso_self, &__rewind_iso_err)); }
+            });
+            let op = if body.sig.asyncness.is_none() && !shared {
+                quote! { (#encased_name).peel_mut_fallible(move |#state_pat| { #orig_body }, #undo) }
+            } else {
+                quote! {
+                    #op.decay().map(|__rewind_iso_value| {
+                        (#encased_name).peel(move |_| __rewind_iso_value, #undo)
+                    })
+                }
+            };
+            parse_quote! {{
+                #captures
+                match #op {
+                    ::core::result::Result::Ok(__rewind_iso_effect) => {
+                        ::core::result::Result::Ok(__rewind_iso_effect)
+                    }
+                    ::core::result::Result::Err(__rewind_iso_err) => {
+                        #on_fail
+                        ::core::result::Result::Err(__rewind_iso_err)
+                    }
+                }
+            }}
+        }
+    };
+    Ok(wrap)
+}
 
-    let first_arg_ty = match body
+/// The redo for `body`, along with the statements to run before the operation since it takes the
+/// arguments the redo needs clones of
+fn redo_closure(
+    redo: &RedoTarget,
+    body: &IsoBody,
+    is_method: bool,
+    state_ty: &syn::Type,
+    ret_ty: &syn::Type,
+) -> syn::Result<(TokenStream, TokenStream)> {
+    let path = match redo {
+        RedoTarget::Closure(c) => return Ok((quote! {}, c.to_token_stream())),
+        RedoTarget::Method(path) => path,
+    };
+    let names = body
         .sig
         .inputs
         .iter()
-        .nth(0)
-        .map(|f| match f {
-            syn::FnArg::Receiver(r) => parse_quote! { Self },
-            syn::FnArg::Typed(t) => t.ty.clone(),
+        .skip(1)
+        .map(|arg| match arg {
+            syn::FnArg::Typed(syn::PatType { pat, .. }) => match &**pat {
+                syn::Pat::Ident(p) if p.subpat.is_none() => Ok(p.ident.clone()),
+                _ => Err(syn::Error::new_spanned(
+                    pat,
+                    "`redo` needs the arguments to be plain names",
+                )),
+            },
+            syn::FnArg::Receiver(r) => Err(syn::Error::new_spanned(r, "unexpected receiver")),
         })
-        .ok_or_else(|| syn::Error::new_spanned(&body.sig.inputs, "must have at least 1 argument"))
-    {
-        Ok(v) => v,
+        .collect::<syn::Result<Vec<_>>>()?;
+    // Spanned to each argument so one that isn't `Clone` is reported there
+    let clones = names.iter().map(|name| {
+        quote::quote_spanned! {name.span()=>
+            let #name = ::core::clone::Clone::clone(&#name);
+        }
+    });
+    let call = if is_method {
+        quote! { __rewind_iso_self. #path (#(#names),*) }
+    } else {
+        quote! { #path (__rewind_iso_self, #(#names),*) }
+    };
+    let redo = Ident::new("__rewind_iso_redo", Span::mixed_site());
+    Ok((
+        quote! {
+            let #redo = {
+                #(#clones)*
+                move |__rewind_iso_self: &mut #state_ty| -> #ret_ty { #call }
+            };
+        },
+        redo.to_token_stream(),
+    ))
+}
+
+/// The `Ok` and `Err` types from `fail_type`, taking them from the return type where given as `_`
+fn result_types(fail_type: &syn::Type, ret_ty: &syn::Type) -> syn::Result<(syn::Type, syn::Type)> {
+    fn type_args(ty: &syn::Type) -> Vec<syn::Type> {
+        let syn::Type::Path(p) = ty else {
+            return Vec::new();
+        };
+        match p.path.segments.last().map(|s| &s.arguments) {
+            Some(syn::PathArguments::AngleBracketed(args)) => args
+                .args
+                .iter()
+                .filter_map(|a| match a {
+                    syn::GenericArgument::Type(t) => Some(t.clone()),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+    let [ok, err] = <[_; 2]>::try_from(type_args(fail_type)).map_err(|_| {
+        syn::Error::new_spanned(fail_type, "expected `Result<_, E>` with the error type")
+    })?;
+    let from_ret = |ty: syn::Type, i: usize, which: &str| match ty {
+        syn::Type::Infer(_) => type_args(ret_ty).into_iter().nth(i).ok_or_else(|| {
+            syn::Error::new_spanned(
+                ret_ty,
+                format!(
+                    "can't tell the `{which}` type of this, give it in `fail_type = Result<T, E>`"
+                ),
+            )
+        }),
+        ty => Ok(ty),
+    };
+    Ok((from_ret(ok, 0, "Ok")?, from_ret(err, 1, "Err")?))
+}
+
+fn isomorphic_main(args: IsoArgs, body: IsoBody) -> TokenStream {
+    let mut wrap = match isomorphic_wrapper(&args, &body) {
+        Ok(wrap) => wrap,
         Err(e) => return e.to_compile_error(),
     };
-    let second_arg_ty = body
-        .sig
-        .inputs
-        .iter()
-        .nth(1)
-        .map(|f| match f {
-            syn::FnArg::Receiver(_) => unreachable!(),
-            syn::FnArg::Typed(t) => t.ty.to_owned(),
+    match &args.keep_original {
+        Some((suffix, span)) => {
+            wrap.sig.ident = Ident::new(&format!("{}{suffix}", body.sig.ident), *span);
+            quote! { #body #wrap }
+        }
+        None => wrap.to_token_stream(),
+    }
+}
+
+/// Arguments of `#[undo(...)]` inside an `#[isomorphic_impl]` block, the same as for
+/// [`isomorphic`] but the `to =` is optional
+struct UndoAttrArgs(IsoArgs);
+impl Parse for UndoAttrArgs {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let to = if input.peek(syn::Ident) && input.peek2(syn::Token![=]) {
+            input.parse()?
+        } else {
+            IsoToArg {
+                _eq: Default::default(),
+                right: input.parse()?,
+            }
+        };
+        IsoArgs::parse_options(to, input).map(Self)
+    }
+}
+
+struct IsoImplArgs {
+    /// Name of the generated trait
+    name: Option<Ident>,
+    /// Visibility of the generated trait
+    vis: Option<syn::Visibility>,
+    /// Add the methods to the newtype from `derive(Encase)` instead of generating a trait, with
+    /// its name if it isn't the default
+    newtype: Option<Option<Ident>>,
+}
+impl Parse for IsoImplArgs {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let mut args = Self {
+            name: None,
+            vis: None,
+            newtype: None,
+        };
+        while !input.is_empty() {
+            let key = syn::Ident::parse(input)?;
+            if key == "newtype" && !input.peek(syn::Token![=]) {
+                args.newtype = Some(None);
+            } else {
+                input.parse::<syn::Token![=]>()?;
+                match key.to_string().as_str() {
+                    "name" => args.name = Some(input.parse()?),
+                    "vis" => args.vis = Some(input.parse()?),
+                    "newtype" => args.newtype = Some(Some(input.parse()?)),
+                    _ => return Err(syn::Error::new_spanned(key, "unknown option")),
+                }
+            }
+            if input.parse::<Option<syn::Token![,]>>()?.is_none() {
+                break;
+            }
+        }
+        if !input.is_empty() {
+            return Err(input.error("expected `,`"));
+        }
+        if let (Some(_), Some(name)) = (&args.newtype, &args.name) {
+            return Err(syn::Error::new_spanned(
+                name,
+                "`name` is for the trait, which isn't generated with `newtype`",
+            ));
+        }
+        Ok(args)
+    }
+}
+
+/// Arguments of [`isomorphic_pair`], the two methods followed by those of [`isomorphic_impl`]
+struct IsoPairArgs {
+    pair: [Ident; 2],
+    rest: IsoImplArgs,
+}
+impl Parse for IsoPairArgs {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let expected = "expected the two methods, e.g. `#[isomorphic_pair(push, pop)]`";
+        let first = Ident::parse(input).map_err(|e| syn::Error::new(e.span(), expected))?;
+        input
+            .parse::<syn::Token![,]>()
+            .map_err(|e| syn::Error::new(e.span(), expected))?;
+        let second = Ident::parse(input).map_err(|e| syn::Error::new(e.span(), expected))?;
+        if first == second {
+            return Err(syn::Error::new_spanned(
+                second,
+                "a method can't be its own inverse here, use `#[undo]` instead",
+            ));
+        }
+        let rest = if input.parse::<Option<syn::Token![,]>>()?.is_some() {
+            input.parse()?
+        } else if input.is_empty() {
+            IsoImplArgs::parse(input)?
+        } else {
+            return Err(input.error("expected `,`"));
+        };
+        Ok(Self {
+            pair: [first, second],
+            rest,
+        })
+    }
+}
+
+/// [`isomorphic_impl`] with the pair of methods marked as undoing each other
+fn isomorphic_pair_main(args: IsoPairArgs, mut item: syn::ItemImpl) -> syn::Result<TokenStream> {
+    let [first, second] = &args.pair;
+    for (name, inverse) in [(first, second), (second, first)] {
+        let method = item
+            .items
+            .iter_mut()
+            .find_map(|i| match i {
+                syn::ImplItem::Fn(m) if m.sig.ident == *name => Some(m),
+                _ => None,
+            })
+            .ok_or_else(|| syn::Error::new_spanned(name, "no method with this name in the impl"))?;
+        if let Some(undo) = method.attrs.iter().find(|a| a.path().is_ident("undo")) {
+            return Err(syn::Error::new_spanned(
+                undo,
+                "methods of the pair already undo each other",
+            ));
+        }
+        method.attrs.push(parse_quote! { #[undo(#inverse)] });
+    }
+    Ok(isomorphic_impl_main(args.rest, item))
+}
+
+/// The declaration and implementation of the extension trait method for `method`, which calls the
+/// original method as the operation
+fn impl_wrapper(
+    self_ty: &syn::Type,
+    trait_: Option<&syn::Path>,
+    generics: &syn::Generics,
+    args: IsoArgs,
+    method: &syn::ImplItemFn,
+    newtype: bool,
+) -> syn::Result<(syn::TraitItemFn, syn::ImplItemFn)> {
+    if let Some((_, span)) = args.keep_original {
+        return Err(syn::Error::new(
+            span,
+            "isomorphic_impl always keeps the original methods",
+        ));
+    }
+    let receiver = match method.sig.inputs.first() {
+        Some(syn::FnArg::Receiver(r)) if r.reference.is_some() => r.clone(),
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &method.sig,
+                "methods marked with `#[undo]` must take `&self` or `&mut self`",
+            ))
+        }
+    };
+    let mut sig = method.sig.clone();
+    let mut call_args = Vec::new();
+    for t in sig.inputs.iter_mut().skip(1).filter_map(|a| match a {
+        syn::FnArg::Typed(t) => Some(t),
+        syn::FnArg::Receiver(_) => None,
+    }) {
+        match &mut *t.pat {
+            syn::Pat::Ident(p) if p.by_ref.is_none() && p.subpat.is_none() => {
+                p.mutability = None;
+                call_args.push(p.ident.clone());
+            }
+            p => {
+                return Err(syn::Error::new_spanned(
+                    p,
+                    "arguments of methods marked with `#[undo]` must be plain names",
+                ))
+            }
+        }
+    }
+    let name = &sig.ident;
+    let await_ = sig.asyncness.map(|_| quote! { .await });
+    let as_trait = trait_.map(|t| quote! { as #t });
+    let delegate = syn::ItemFn {
+        attrs: Vec::new(),
+        vis: syn::Visibility::Inherited,
+        block: parse_quote! { { <#self_ty #as_trait>::#name(self, #(#call_args),*) #await_ } },
+        sig,
+    };
+    let mut wrap = isomorphic_wrapper(&args, &delegate)?;
+    let encased = if !newtype {
+        quote! { self }
+    } else if receiver.mutability.is_some() {
+        quote! { &mut self.0 }
+    } else {
+        quote! { &self.0 }
+    };
+    wrap.sig.inputs[0] = syn::FnArg::Receiver(receiver);
+    let block = &wrap.block;
+    let encased_name = encased_ident();
+    wrap.block = parse_quote! { { let #encased_name = #encased; #block } };
+    let mut self_ty = SelfTypeVisitor {
+        ty: self_ty.clone(),
+    };
+    for arg in wrap.sig.inputs.iter_mut().skip(1) {
+        self_ty.visit_fn_arg_mut(arg);
+    }
+    self_ty.visit_return_type_mut(&mut wrap.sig.output);
+    self_ty.visit_block_mut(&mut wrap.block);
+    // Trait methods returning `impl Trait` capture every lifetime by default, which would keep
+    // the encased state borrowed for as long as the side effect lives
+    let params = generics.params.iter().chain(&wrap.sig.generics.params);
+    let captured: Vec<_> = params
+        .map(|p| match p {
+            syn::GenericParam::Lifetime(l) => l.lifetime.to_token_stream(),
+            syn::GenericParam::Type(t) => t.ident.to_token_stream(),
+            syn::GenericParam::Const(c) => c.ident.to_token_stream(),
         })
-        .unwrap_or(Box::new(parse_quote!(())));
-    wrap.sig.output = parse_quote! { -> ::rewind::atom::SideEffect<(), (), #first_arg_ty, impl FnOnce(&mut #first_arg_ty, ())> };
-    if let Some(syn::FnArg::Receiver(r)) = wrap.sig.inputs.first_mut() {
-        let rf = r
-            .reference
+        .collect();
+    let mut decl_sig = wrap.sig.clone();
+    CaptureVisitor {
+        bound: parse_quote! { use<Self, #(#captured),*> },
+    }
+    .visit_return_type_mut(&mut decl_sig.output);
+    CaptureVisitor {
+        bound: parse_quote! { use<#(#captured),*> },
+    }
+    .visit_return_type_mut(&mut wrap.sig.output);
+
+    let (docs, attrs): (Vec<_>, Vec<_>) = method
+        .attrs
+        .iter()
+        .cloned()
+        .partition(|a| a.path().is_ident("doc"));
+    let cfgs = attrs.iter().filter(|a| a.path().is_ident("cfg"));
+    if decl_sig.asyncness.take().is_some() {
+        let syn::ReturnType::Type(_, out) = &decl_sig.output else {
+            unreachable!("the wrapper always returns a side effect")
+        };
+        decl_sig.output = parse_quote! { -> impl ::core::future::Future<Output = #out> };
+    }
+    let decl = parse_quote! { #(#docs)* #(#cfgs)* #decl_sig; };
+    let imp = syn::ImplItemFn {
+        attrs,
+        vis: syn::Visibility::Inherited,
+        defaultness: None,
+        sig: wrap.sig,
+        block: *wrap.block,
+    };
+    Ok((decl, imp))
+}
+
+fn isomorphic_impl_main(args: IsoImplArgs, mut item: syn::ItemImpl) -> TokenStream {
+    let trait_ = match &item.trait_ {
+        Some((Some(not), _, _)) => {
+            return syn::Error::new_spanned(not, "negative impls have no methods to wrap")
+                .to_compile_error()
+        }
+        Some((None, path, _)) => Some(path.clone()),
+        None => None,
+    };
+    let self_ty = (*item.self_ty).clone();
+    if let Some(newtype) = args.newtype {
+        return isomorphic_impl_newtype(newtype, args.vis, item, trait_, self_ty)
+            .unwrap_or_else(|e| e.to_compile_error());
+    }
+    let name = match (args.name, &self_ty) {
+        (Some(name), _) => name,
+        (None, syn::Type::Path(p)) if p.qself.is_none() => {
+            let last = &p.path.segments.last().expect("paths are never empty").ident;
+            // Keeps the traits for an inherent impl and trait impls of the same type apart
+            let of_trait = trait_.as_ref().map_or(String::new(), |t| {
+                t.segments
+                    .last()
+                    .expect("paths are never empty")
+                    .ident
+                    .to_string()
+            });
+            Ident::new(&format!("Encased{last}{of_trait}Ext"), last.span())
+        }
+        (None, ty) => {
+            return syn::Error::new_spanned(ty, "the trait needs a name, e.g. `name = MyExt`")
+                .to_compile_error()
+        }
+    };
+    let mut errors = None::<syn::Error>;
+    let mut vis = None;
+    let mut decls = Vec::new();
+    let mut impls = Vec::new();
+    for impl_item in &mut item.items {
+        let syn::ImplItem::Fn(method) = impl_item else {
+            continue;
+        };
+        let Some(at) = method.attrs.iter().position(|a| a.path().is_ident("undo")) else {
+            continue;
+        };
+        let attr = method.attrs.remove(at);
+        let wrapped = attr.parse_args::<UndoAttrArgs>().and_then(|args| {
+            impl_wrapper(
+                &self_ty,
+                trait_.as_ref(),
+                &item.generics,
+                args.0,
+                method,
+                false,
+            )
+        });
+        match wrapped {
+            Ok((decl, imp)) => {
+                decls.push(decl);
+                impls.push(imp);
+            }
+            Err(e) => match &mut errors {
+                Some(errors) => errors.combine(e),
+                None => errors = Some(e),
+            },
+        }
+        let openness = |v: &syn::Visibility| match v {
+            syn::Visibility::Public(_) => 2,
+            syn::Visibility::Restricted(_) => 1,
+            syn::Visibility::Inherited => 0,
+        };
+        if vis
             .as_ref()
-            .map(|(and, lifetime)| quote! { #and #lifetime });
-        let mutab = &r.mutability;
-        r.ty = parse_quote! { #rf #mutab ::rewind::atom::Encased<Self> };
-        r.mutability.take();
-        r.reference.take();
+            .is_none_or(|v| openness(&method.vis) > openness(v))
+        {
+            vis = Some(method.vis.clone());
+        }
     }
-    let first_arg_name = match body.sig.inputs.first().unwrap() {
-        syn::FnArg::Receiver(_) => parse_quote! { self },
-        syn::FnArg::Typed(t) => t.pat.to_owned(),
+    let errors = errors.map(|e| e.to_compile_error());
+    let Some(vis) = vis.map(|v| args.vis.unwrap_or(v)) else {
+        return syn::Error::new_spanned(&self_ty, "no methods are marked with `#[undo(...)]`")
+            .to_compile_error();
     };
-    let mut orig_body = body.block.clone();
-    let to_target = &args.to.right;
-    let self_rename = Ident::new("__rewind_iso_self", Span::mixed_site());
-    SelfRenameVisitor {
-        rename: self_rename.clone(),
+    let cfgs: Vec<_> = item
+        .attrs
+        .iter()
+        .filter(|a| a.path().is_ident("cfg"))
+        .collect();
+    let generics = &item.generics;
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let doc = format!(
+        " The methods of `{}` marked with `#[undo]`, called on its `Encased` state",
+        self_ty.to_token_stream()
+    );
+    quote! {
+        #item
+        #errors
+        #(#cfgs)*
+        #[doc = #doc]
+        #vis trait #name #generics #where_clause {
+            #(#decls)*
+        }
+        #(#cfgs)*
+        impl #impl_generics #name #ty_generics for ::rewind::atom::Encased<#self_ty> #where_clause {
+            #(#impls)*
+        }
     }
-    .visit_block_mut(&mut orig_body);
-    wrap.block = parse_quote! { { (#first_arg_name).peel_mut(move |#self_rename| { #orig_body }, move |__rewind_iso_self, _| { __rewind_iso_self. #to_target (); }) } };
-    wrap.to_token_stream()
 }
 
+/// [`isomorphic_impl`] adding the methods to the newtype from [`derive@Encase`] rather than a trait
+fn isomorphic_impl_newtype(
+    name: Option<Ident>,
+    vis: Option<syn::Visibility>,
+    mut item: syn::ItemImpl,
+    trait_: Option<syn::Path>,
+    self_ty: syn::Type,
+) -> syn::Result<TokenStream> {
+    let newtype = match &self_ty {
+        syn::Type::Path(p) if p.qself.is_none() => {
+            let mut newtype = p.clone();
+            let last = newtype
+                .path
+                .segments
+                .last_mut()
+                .expect("paths are never empty");
+            last.ident = name.unwrap_or_else(|| encased_newtype_ident(&last.ident));
+            newtype
+        }
+        ty => {
+            return Err(syn::Error::new_spanned(
+                ty,
+                "`newtype` needs a named type, as made by `derive(Encase)`",
+            ))
+        }
+    };
+    let mut errors = None::<syn::Error>;
+    let mut methods = Vec::new();
+    for impl_item in &mut item.items {
+        let syn::ImplItem::Fn(method) = impl_item else {
+            continue;
+        };
+        let method_vis = vis.clone().unwrap_or_else(|| method.vis.clone());
+        let Some(at) = method.attrs.iter().position(|a| a.path().is_ident("undo")) else {
+            // Private methods of inherent impls are left out, like they would be from the trait
+            if trait_.is_none() && matches!(method.vis, syn::Visibility::Inherited) {
+                continue;
+            }
+            methods.extend(newtype_delegate(
+                &self_ty,
+                trait_.as_ref(),
+                method,
+                method_vis,
+            ));
+            continue;
+        };
+        let attr = method.attrs.remove(at);
+        let wrapped = attr.parse_args::<UndoAttrArgs>().and_then(|args| {
+            impl_wrapper(
+                &self_ty,
+                trait_.as_ref(),
+                &item.generics,
+                args.0,
+                method,
+                true,
+            )
+        });
+        match wrapped {
+            Ok((_, mut imp)) => {
+                imp.attrs = method.attrs.clone();
+                imp.vis = method_vis;
+                methods.push(imp);
+            }
+            Err(e) => match &mut errors {
+                Some(errors) => errors.combine(e),
+                None => errors = Some(e),
+            },
+        }
+    }
+    let errors = errors.map(|e| e.to_compile_error());
+    let cfgs = item.attrs.iter().filter(|a| a.path().is_ident("cfg"));
+    let (impl_generics, _, where_clause) = item.generics.split_for_impl();
+    Ok(quote! {
+        #item
+        #errors
+        #(#cfgs)*
+        impl #impl_generics #newtype #where_clause {
+            #(#methods)*
+        }
+    })
+}
+
+/// A method of the newtype calling `method` on the state through a borrow of it, or `None` if
+/// it can't be called that way
+fn newtype_delegate(
+    self_ty: &syn::Type,
+    trait_: Option<&syn::Path>,
+    method: &syn::ImplItemFn,
+    vis: syn::Visibility,
+) -> Option<syn::ImplItemFn> {
+    /// Whether a type borrows from something, which a result can't do from behind the borrow
+    struct Borrows(bool);
+    impl<'ast> syn::visit::Visit<'ast> for Borrows {
+        fn visit_lifetime(&mut self, i: &'ast syn::Lifetime) {
+            self.0 |= i.ident != "static";
+        }
+        fn visit_type_reference(&mut self, i: &'ast syn::TypeReference) {
+            self.0 |= i.lifetime.is_none();
+            syn::visit::visit_type_reference(self, i);
+        }
+    }
+    let mut sig = method.sig.clone();
+    let mutable = match sig.inputs.first() {
+        Some(syn::FnArg::Receiver(r)) if r.reference.is_some() => r.mutability.is_some(),
+        _ => return None,
+    };
+    let mut borrows = Borrows(false);
+    syn::visit::Visit::visit_return_type(&mut borrows, &sig.output);
+    if borrows.0 || sig.asyncness.is_some() || sig.unsafety.is_some() {
+        return None;
+    }
+    sig.constness = None;
+    let mut self_ty_visitor = SelfTypeVisitor {
+        ty: self_ty.clone(),
+    };
+    let mut call_args = Vec::new();
+    for (i, arg) in sig.inputs.iter_mut().skip(1).enumerate() {
+        let syn::FnArg::Typed(t) = arg else {
+            return None;
+        };
+        let name = match &*t.pat {
+            syn::Pat::Ident(p) if p.by_ref.is_none() && p.subpat.is_none() => p.ident.clone(),
+            _ => Ident::new(&format!("__rewind_arg{i}"), Span::mixed_site()),
+        };
+        *t.pat = parse_quote! { #name };
+        self_ty_visitor.visit_type_mut(&mut t.ty);
+        call_args.push(name);
+    }
+    self_ty_visitor.visit_return_type_mut(&mut sig.output);
+    let name = &sig.ident;
+    let as_trait = trait_.map(|t| quote! { as #t });
+    let state = Ident::new("__rewind_state", Span::mixed_site());
+    let call = quote! { <#self_ty #as_trait>::#name(#state, #(#call_args),*) };
+    let block = if mutable {
+        parse_quote! {{ self.0.modify(|#state| #call) }}
+    } else {
+        parse_quote! {{ self.0.read(|#state| #call) }}
+    };
+    Some(syn::ImplItemFn {
+        attrs: method.attrs.clone(),
+        vis,
+        defaultness: None,
+        sig,
+        block,
+    })
+}
+
+/// `Encased<Name>`, the default name of the newtype for `Name`
+fn encased_newtype_ident(name: &Ident) -> Ident {
+    Ident::new(&format!("Encased{name}"), name.span())
+}
+
+fn derive_encase_main(item: syn::DeriveInput) -> syn::Result<TokenStream> {
+    if let syn::Data::Union(u) = &item.data {
+        return Err(syn::Error::new_spanned(
+            u.union_token,
+            "Encase can't be derived for unions",
+        ));
+    }
+    let name = &item.ident;
+    let vis = &item.vis;
+    let newtype = encased_newtype_ident(name);
+    let generics = &item.generics;
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let encased = quote! { ::rewind::atom::Encased<#name #ty_generics> };
+    let doc = format!(
+        " `{name}` in an `Encased`, with the methods from `#[isomorphic_impl(newtype)]` impls"
+    );
+    Ok(quote! {
+        #[doc = #doc]
+        #vis struct #newtype #generics (#encased) #where_clause;
+
+        impl #impl_generics #newtype #ty_generics #where_clause {
+            #vis fn new(state: #name #ty_generics) -> Self {
+                Self(::rewind::encase(state))
+            }
+            /// Read the state, see `Encased::read`
+            #vis fn read<R>(&self, f: impl ::core::ops::FnOnce(&#name #ty_generics) -> R) -> R {
+                self.0.read(f)
+            }
+            /// Mutate the state without creating an undo, see `Encased::modify`
+            #vis fn modify<R>(&mut self, f: impl ::core::ops::FnOnce(&mut #name #ty_generics) -> R) -> R {
+                self.0.modify(f)
+            }
+            /// The `Encased` state, for creating side effects directly
+            #vis fn encased(&mut self) -> &mut #encased {
+                &mut self.0
+            }
+            #vis fn into_encased(self) -> #encased {
+                self.0
+            }
+        }
+
+        impl #impl_generics ::core::convert::From<#name #ty_generics> for #newtype #ty_generics #where_clause {
+            fn from(state: #name #ty_generics) -> Self {
+                Self::new(state)
+            }
+        }
+
+        impl #impl_generics ::core::clone::Clone for #newtype #ty_generics #where_clause {
+            /// Shares the state, like cloning the `Encased` does
+            fn clone(&self) -> Self {
+                Self(::core::clone::Clone::clone(&self.0))
+            }
+        }
+    })
+}
+
+/// Turn a method into one which returns a `rewind::atom::SideEffect` on an
+/// `Encased<Self>`, undone by calling `to`
+///
+/// To adopt it gradually, `keep_original` leaves the method as it is and adds the wrapper as
+/// `<name>_iso`, or `suffix = "_undoable"` to pick the suffix.
+///
+/// The attributes, docs and visibility of the method are kept on the generated one, and since
+/// the body is moved into a closure in it lint attributes such as `#[allow]` still apply to it.
+///
+/// Free functions taking `&mut S` as their first argument work the same way, taking
+/// `&mut Encased<S>` instead and calling `to` as a free function with the state first.
+///
+/// `&self` (or `&S`) only borrows the state immutably for the operation itself, which is useful
+/// for operations that don't change the state but still need an undo registered. The undo is
+/// always given the state mutably.
+///
+/// The value returned by the method is kept in the side effect and passed to `to` when undoing,
+/// unless the method returns `()` or `no_args` is given (`#[isomorphic(to = clear, no_args)]`).
+///
+/// `to` can also be given its own arguments, e.g. `#[isomorphic(to = truncate(self.len()))]`.
+/// These are evaluated before the method runs and `self` in them refers to the state then.
+///
+/// For anything else, `to` can be a closure taking the state and the value:
+/// `#[isomorphic(to = |s, v| s.extend(v))]`.
+///
+/// Or `undo` can be any expression, run as the undo with `state` (the state, also available as
+/// `self`) and `value` in scope: `#[isomorphic(undo = self.journal.revert(value))]`. Only one
+/// of `to` and `undo` can be given.
+///
+/// For fallible operations, `fail_type = Result<_, E>` makes the function return
+/// `Result<SideEffect<..>, E>`, with the `Ok` value in the side effect, so only successful calls
+/// get undone. Failed calls are assumed to leave the state as it was, unless `on_fail = handler`
+/// is given, which is called right away with the state and `&E` to clean up after them. The
+/// `_` stands for the `Ok` type of the return type and can be given explicitly when that is an
+/// alias such as `io::Result<T>`. `skip_on_err` is the same as `fail_type = Result<_, _>`, taking
+/// both types from the return type.
+///
+/// `redo = method` makes the function return a `Redoable` instead, whose undo gives back an atom
+/// that runs the operation again. The method is called like the undo, with clones of the
+/// arguments, so it is usually the original function kept with `keep_original`. A closure taking
+/// only the state can be given instead. The arguments have to be `Clone`, and `redo` can't be
+/// used on async functions or with `fail_type`.
+///
+/// Methods of trait impls can't have their signature changed, use [`isomorphic_impl`] on the
+/// impl block for those instead.
 #[proc_macro_attribute]
 pub fn isomorphic(
     args: proc_macro::TokenStream,
     raw: proc_macro::TokenStream,
 ) -> proc_macro::TokenStream {
-    isomorphic_main(
-        parse_macro_input!(args as IsoArgs),
-        parse_macro_input!(raw as IsoBody),
+    let args = parse_macro_input!(args as IsoArgs);
+    match syn::parse::<syn::Item>(raw.clone()) {
+        Ok(syn::Item::Fn(_)) | Err(_) => (),
+        Ok(item) => {
+            let start = item.to_token_stream().into_iter().next().map(|t| t.span());
+            return syn::Error::new(
+                start.unwrap_or_else(Span::call_site),
+                "isomorphic can only be used on functions",
+            )
+            .to_compile_error()
+            .into();
+        }
+    }
+    isomorphic_main(args, parse_macro_input!(raw as IsoBody)).into()
+}
+
+/// [`isomorphic`] for every method in an impl block marked with `#[undo(...)]`
+///
+/// Rather than replacing the methods, this generates an extension trait implemented for the
+/// `Encased` state, `Encased<Name>Ext` unless given as `#[isomorphic_impl(name = MyExt)]`, with
+/// an undoable version of each marked method of the same name. With the trait in scope they can
+/// then be called directly on the encased state, e.g. `items.push(1)`. Without it the call goes
+/// through to the original method on the state and is not undone.
+///
+/// `#[undo(...)]` takes the same arguments as [`isomorphic`] except `keep_original`, and the
+/// `to =` can be left out: `#[undo(pop)]`. Marked methods must take `&self` or `&mut self` and
+/// name their other arguments plainly.
+///
+/// The trait has the visibility of the most public marked method, or the one given with
+/// `vis = pub(crate)`.
+///
+/// With `newtype` the methods are added to the newtype from [`derive@Encase`] instead of a trait,
+/// along with the public methods which aren't marked, see there.
+///
+/// This also works on trait impls, where `#[isomorphic]` can't be used since it changes the
+/// signature of the method. The original methods are again left as they are, and the generated
+/// trait is named `Encased<Name><Trait>Ext` by default. Methods in trait impls have no
+/// visibility, so the trait is private unless given `vis`.
+#[proc_macro_attribute]
+pub fn isomorphic_impl(
+    args: proc_macro::TokenStream,
+    raw: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    isomorphic_impl_main(
+        parse_macro_input!(args as IsoImplArgs),
+        parse_macro_input!(raw as syn::ItemImpl),
+    )
+    .into()
+}
+
+/// [`isomorphic_impl`] where two methods undo each other, e.g. `#[isomorphic_pair(push, pop)]`
+///
+/// This is the same as marking the first method with `#[undo(pop)]` and the second with
+/// `#[undo(push)]`, so each is undone by calling the other the way `to =` would: with the value
+/// the operation returned, or with no arguments if it returned `()`. Other methods in the block
+/// can still be marked with `#[undo(...)]`, and the options of [`isomorphic_impl`] can follow
+/// the pair.
+///
+/// ```ignore
+/// #[rewind::isomorphic_pair(push, pop)]
+/// impl Stack {
+///     pub fn push(&mut self, v: i32) {
+///         self.els.push(v);
+///     }
+///     pub fn pop(&mut self) -> i32 {
+///         self.els.pop().unwrap()
+///     }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn isomorphic_pair(
+    args: proc_macro::TokenStream,
+    raw: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    isomorphic_pair_main(
+        parse_macro_input!(args as IsoPairArgs),
+        parse_macro_input!(raw as syn::ItemImpl),
     )
+    .unwrap_or_else(|e| e.to_compile_error())
     .into()
 }
+
+fn transaction_main(handle: Ident, mut body: syn::ItemFn) -> TokenStream {
+    let syn::ReturnType::Type(_, ret_ty) = &body.sig.output else {
+        return syn::Error::new_spanned(&body.sig, "transactions must return a `Result`")
+            .to_compile_error();
+    };
+    let stack = Ident::new("__rewind_tx", Span::mixed_site());
+    let result = Ident::new("__rewind_tx_result", Span::mixed_site());
+    let block = &body.block;
+    // Running the body separately keeps `?` and `return` from skipping the resolution below
+    let run = if body.sig.asyncness.is_some() {
+        quote! { async move #block.await }
+    } else {
+        quote! { (move || -> #ret_ty #block)() }
+    };
+    body.block = parse_quote! {{
+        let mut #stack = ::rewind::stack::Stack::new();
+        let #handle = &mut #stack;
+        let #result = #run;
+        match &#result {
+            ::core::result::Result::Ok(_) => {
+                #stack.decay_all();
+            }
+            ::core::result::Result::Err(_) => {
+                #stack.undo_all();
+            }
+        }
+        #result
+    }};
+    body.to_token_stream()
+}
+
+struct AtomicInput {
+    states: Punctuated<Ident, syn::Token![,]>,
+    body: syn::Block,
+}
+impl Parse for AtomicInput {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let states = Punctuated::parse_separated_nonempty(input)?;
+        input
+            .parse::<syn::Token![=>]>()
+            .map_err(|e| syn::Error::new(e.span(), "expected `=>` after the states"))?;
+        let body = input.parse()?;
+        Ok(Self { states, body })
+    }
+}
+
+fn atomic_main(input: AtomicInput) -> TokenStream {
+    let stack = Ident::new("__rewind_atomic", Span::mixed_site());
+    let result = Ident::new("__rewind_atomic_result", Span::mixed_site());
+    let states = input.states.iter();
+    let body = &input.body;
+    quote! {{
+        let #stack = ::core::cell::RefCell::new(::rewind::stack::Stack::new());
+        let #result = {
+            #(
+                #[allow(unused_mut)]
+                let mut #states = ::rewind::stack::EncasedScope::new(&#states, &#stack);
+            )*
+            // Running the body separately keeps `?` and `return` from skipping the resolution below
+            (|| #body)()
+        };
+        if ::core::result::Result::is_ok(&#result) {
+            #stack.into_inner().decay_all();
+        }
+        // Otherwise the stack undoes everything when dropped, as it does on a panic
+        #result
+    }}
+}
+
+/// Undo the side effects made in a block if it fails
+///
+/// Inside the block each of the named `Encased` states is shadowed by a
+/// `rewind::stack::EncasedScope`, whose `peel_mut` and `peel` push the side effects onto a shared
+/// stack as they are made. They are decayed if the block evaluates to `Ok`, and undone newest
+/// first if it evaluates to `Err`, returns one early, including through `?`, or panics.
+///
+/// ```ignore
+/// let items = rewind::encase(vec![1]);
+/// let log = rewind::encase(Vec::new());
+/// let result: Result<(), &str> = rewind::atomic!(items, log => {
+///     let popped = items.peel_mut(|i| i.pop(), |i, v| i.extend(v));
+///     log.peel_mut(|l| l.extend(popped), |l, _| { l.pop(); });
+///     Err("nope")
+/// });
+/// assert_eq!(*items.borrow_state(), vec![1]);
+/// assert!(log.borrow_state().is_empty());
+/// ```
+///
+/// The result of the block usually needs its type given, since there is nothing else to say
+/// what `?` converts errors into.
+#[proc_macro]
+pub fn atomic(raw: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    atomic_main(parse_macro_input!(raw as AtomicInput)).into()
+}
+
+/// Undo everything a function did if it fails
+///
+/// The body is given a `tx: &mut rewind::stack::Stack` to push its atoms and side effects onto.
+/// They are decayed if the function returns `Ok`, and undone newest first if it returns `Err`,
+/// including through `?`, or panics. The handle can be renamed with `#[transaction(name)]`.
+///
+/// ```ignore
+/// #[rewind::transaction]
+/// fn import(items: &mut Encased<Vec<Item>>, raw: &[&str]) -> Result<(), ParseError> {
+///     for r in raw {
+///         let item = r.parse()?;
+///         tx.push(items.peel_mut(|i| i.push(item), |i, _| i.pop()));
+///     }
+///     Ok(())
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn transaction(
+    args: proc_macro::TokenStream,
+    raw: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let handle = if args.is_empty() {
+        Ident::new("tx", Span::call_site())
+    } else {
+        parse_macro_input!(args as Ident)
+    };
+    transaction_main(handle, parse_macro_input!(raw as syn::ItemFn)).into()
+}
+
+/// How a field is handled by `#[derive(Rewind)]`
+enum SnapshotField {
+    Skip,
+    Clone,
+    /// Snapshotted with its own `Rewind` impl
+    Nested,
+}
+impl SnapshotField {
+    fn of(field: &syn::Field) -> syn::Result<Self> {
+        let mut kind = Self::Clone;
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("rewind")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("skip") {
+                    kind = Self::Skip;
+                } else if meta.path.is_ident("nested") {
+                    kind = Self::Nested;
+                } else {
+                    return Err(meta.error("expected `skip` or `nested`"));
+                }
+                Ok(())
+            })?;
+        }
+        Ok(kind)
+    }
+}
+
+fn derive_rewind_main(item: syn::DeriveInput) -> syn::Result<TokenStream> {
+    let syn::Data::Struct(data) = &item.data else {
+        return Err(syn::Error::new_spanned(
+            &item.ident,
+            "Rewind can only be derived for structs",
+        ));
+    };
+    let name = &item.ident;
+    let vis = &item.vis;
+    let snapshot = Ident::new(&format!("{name}Snapshot"), name.span());
+    let (impl_generics, ty_generics, where_clause) = item.generics.split_for_impl();
+    let mut bounds = item
+        .generics
+        .where_clause
+        .clone()
+        .unwrap_or_else(|| syn::WhereClause {
+            where_token: Default::default(),
+            predicates: Punctuated::new(),
+        });
+    let mut snapshot_bounds = bounds.clone();
+    let mut fields = Vec::new();
+    let mut takes = Vec::new();
+    let mut restores = Vec::new();
+    for (i, field) in data.fields.iter().enumerate() {
+        let member = match &field.ident {
+            Some(ident) => syn::Member::Named(ident.clone()),
+            None => syn::Member::Unnamed(i.into()),
+        };
+        // Tuple fields are renumbered in the snapshot since skipped ones are left out
+        let snap_member = match &field.ident {
+            Some(ident) => syn::Member::Named(ident.clone()),
+            None => syn::Member::Unnamed(fields.len().into()),
+        };
+        let ty = &field.ty;
+        let (snap_ty, take, restore): (syn::Type, _, _) = match SnapshotField::of(field)? {
+            SnapshotField::Skip => continue,
+            SnapshotField::Clone => {
+                bounds
+                    .predicates
+                    .push(parse_quote! { #ty: ::core::clone::Clone });
+                (
+                    ty.clone(),
+                    quote! { ::core::clone::Clone::clone(&self.#member) },
+                    quote! { self.#member = snapshot.#snap_member; },
+                )
+            }
+            SnapshotField::Nested => {
+                let rewind = quote! { ::rewind::atom::Rewind };
+                bounds.predicates.push(parse_quote! { #ty: #rewind });
+                snapshot_bounds
+                    .predicates
+                    .push(parse_quote! { #ty: #rewind });
+                (
+                    parse_quote! { <#ty as #rewind>::Snapshot },
+                    quote! { #rewind::rewind_snapshot(&self.#member) },
+                    quote! { #rewind::rewind_restore(&mut self.#member, snapshot.#snap_member); },
+                )
+            }
+        };
+        let field_vis = &field.vis;
+        fields.push(match &field.ident {
+            Some(ident) => quote! { #field_vis #ident: #snap_ty },
+            None => quote! { #field_vis #snap_ty },
+        });
+        takes.push(match &field.ident {
+            Some(ident) => quote! { #ident: #take },
+            None => take,
+        });
+        restores.push(restore);
+    }
+    // Generic parameters may only be used by skipped fields
+    let phantom = Ident::new("__rewind_phantom", Span::mixed_site());
+    if !item.generics.params.is_empty() {
+        let phantom_ty = quote! { ::core::marker::PhantomData<fn() -> #name #ty_generics> };
+        fields.push(match &data.fields {
+            syn::Fields::Named(_) => quote! { #phantom: #phantom_ty },
+            _ => phantom_ty,
+        });
+        takes.push(match &data.fields {
+            syn::Fields::Named(_) => quote! { #phantom: ::core::marker::PhantomData },
+            _ => quote! { ::core::marker::PhantomData },
+        });
+    }
+    let generics = &item.generics;
+    let (def, take) = match &data.fields {
+        syn::Fields::Named(_) => (
+            quote! { #generics #snapshot_bounds { #(#fields),* } },
+            quote! { #snapshot { #(#takes),* } },
+        ),
+        _ => (
+            quote! { #generics (#(#fields),*) #snapshot_bounds; },
+            quote! { #snapshot (#(#takes),*) },
+        ),
+    };
+    let doc = format!(" The fields of [`{name}`] saved by `Rewind::rewind_snapshot`");
+    Ok(quote! {
+        #[doc = #doc]
+        #vis struct #snapshot #def
+
+        impl #impl_generics ::rewind::atom::Rewind for #name #ty_generics #bounds {
+            type Snapshot = #snapshot #ty_generics;
+            fn rewind_snapshot(&self) -> Self::Snapshot {
+                #take
+            }
+            fn rewind_restore(&mut self, snapshot: Self::Snapshot) {
+                #(#restores)*
+            }
+        }
+
+        impl #impl_generics #name #ty_generics #where_clause {
+            /// Restore the current state of `self` when the returned guard is dropped, unless it
+            /// is disarmed
+            #vis fn guard(&mut self) -> ::rewind::atom::Guard<'_, Self, impl ::core::ops::FnOnce(&mut Self)>
+            where
+                Self: ::rewind::atom::Rewind,
+            {
+                let snapshot = ::rewind::atom::Rewind::rewind_snapshot(self);
+                ::rewind::guard(self, move |s| ::rewind::atom::Rewind::rewind_restore(s, snapshot))
+            }
+        }
+    })
+}
+
+/// Generate `Encased<Name>`, a newtype around `rewind::atom::Encased<Name>`
+///
+/// On its own this just has `new`, `read` and `modify`. Adding `newtype` to
+/// [`isomorphic_impl`] on the impl blocks of `Name` gives it a method for each public method,
+/// going through a borrow of the state, except for those marked with `#[undo(...)]` which
+/// return side effects:
+///
+/// ```ignore
+/// #[derive(Default, rewind::Encase)]
+/// struct Tags {
+///     names: Vec<String>,
+/// }
+///
+/// #[rewind::isomorphic_impl(newtype)]
+/// impl Tags {
+///     #[undo(pop, no_args)]
+///     pub fn add(&mut self, name: String) {
+///         self.names.push(name);
+///     }
+///     pub fn pop(&mut self) -> Option<String> {
+///         self.names.pop()
+///     }
+///     pub fn len(&self) -> usize {
+///         self.names.len()
+///     }
+/// }
+///
+/// let mut tags = EncasedTags::new(Tags::default());
+/// let added = tags.add("a".to_owned());
+/// assert_eq!(tags.len(), 1);
+/// ```
+///
+/// Methods which can't be called through a borrow are left out, which are those returning
+/// borrows, async methods and those not taking `&self` or `&mut self`.
+#[proc_macro_derive(Encase)]
+pub fn derive_encase(raw: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    derive_encase_main(parse_macro_input!(raw as syn::DeriveInput))
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// Snapshot and restore a struct, for when only a few fields need to be rolled back or when the
+/// state is not behind an `Encased`
+///
+/// This implements `rewind::Rewind` with a `<Name>Snapshot` struct of the cloned fields, and
+/// adds a `guard` method which restores the fields when dropped:
+///
+/// ```ignore
+/// #[derive(rewind::Rewind)]
+/// struct Config {
+///     retries: u8,
+///     #[rewind(skip)]
+///     cache: Vec<u8>,
+///     #[rewind(nested)]
+///     limits: Limits,
+/// }
+/// ```
+///
+/// Fields are cloned unless they are marked `skip`, which leaves them out of the snapshot, or
+/// `nested`, which uses their own `Rewind` impl instead.
+#[proc_macro_derive(Rewind, attributes(rewind))]
+pub fn derive_rewind(raw: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    derive_rewind_main(parse_macro_input!(raw as syn::DeriveInput))
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
@@ -1,31 +1,63 @@
 use std::{
-    cell::RefCell,
+    any::Any,
+    cell::{Cell, OnceCell, Ref, RefCell, RefMut},
+    collections::{HashSet, VecDeque},
     fmt::Debug,
-    mem::ManuallyDrop,
-    ops::{Deref, DerefMut},
-    rc::Rc,
+    future::Future,
+    hash::Hash,
+    marker::PhantomData,
+    ops::{Deref, DerefMut, Range},
+    rc::{Rc, Weak},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
 };
 
+use crate::stack::{AtomHandle, Histogram, Stack, Transaction};
+
 /// Carries a value with an undo action
+///
+/// `Simple` is [`Send`] and [`Sync`] whenever `T` and `Undo` are. `Option` forwards both auto
+/// traits, so no manual impls are needed and `R` has no bearing on them since it is never stored.
 pub struct Simple<T, R, Undo: FnOnce(T) -> R> {
-    val: ManuallyDrop<T>,
-    undo: Option<ManuallyDrop<Undo>>,
+    val: Option<T>,
+    undo: Option<Undo>,
 }
 
 impl<T, R, Undo: FnOnce(T) -> R> Simple<T, R, Undo> {
     pub(crate) fn new(val: T, undo: Undo) -> Self {
         Self {
-            val: ManuallyDrop::new(val),
-            undo: Some(ManuallyDrop::new(undo)),
+            val: Some(val),
+            undo: Some(undo),
         }
     }
-    fn undo_mut(&mut self) -> Option<R> {
-        if let Some(mut undo) = self.undo.take() {
-            Some(unsafe { ManuallyDrop::take(&mut undo)(ManuallyDrop::take(&mut self.val)) })
-        } else {
-            None
+    fn value(&self) -> &T {
+        self.val.as_ref().expect("only taken when consumed")
+    }
+    /// Convert the value given back by [`decay`](Atom::decay) with `f`, the undo still gets the
+    /// original value
+    ///
+    /// `f` is only called when decaying.
+    ///
+    /// ```
+    /// # use rewind::Atom;
+    /// let id = rewind::simple(7, |id| format!("removed {id}")).map_value(|id| id.to_string());
+    /// assert_eq!(id.decay(), "7");
+    /// let id = rewind::simple(7, |id| format!("removed {id}")).map_value(|id| id.to_string());
+    /// assert_eq!(id.undo(), "removed 7");
+    /// ```
+    pub fn map_value<U, F: FnOnce(T) -> U>(self, f: F) -> MappedSimple<T, U, R, Undo, F> {
+        MappedSimple {
+            inner: Some(self),
+            map: Some(f),
         }
     }
+    fn undo_mut(&mut self) -> Option<R> {
+        let (undo, val) = (self.undo.take()?, self.val.take()?);
+        Some(undo(val))
+    }
 }
 
 impl<T, R, Undo: FnOnce(T) -> R> Atom for Simple<T, R, Undo> {
@@ -49,8 +81,8 @@ impl<T, R, Undo: FnOnce(T) -> R> Atom for Simple<T, R, Undo> {
     /// assert_eq!(v.decay(), 4);
     /// ```
     fn decay(mut self) -> Self::Decay {
-        self.undo.take().map(|u| ManuallyDrop::into_inner(u));
-        unsafe { ManuallyDrop::take(&mut self.val) }
+        self.undo.take();
+        self.val.take().expect("only taken when consumed")
     }
 }
 
@@ -65,259 +97,3868 @@ impl<T: Debug, R, Undo: FnOnce(T) -> R> Debug for Simple<T, R, Undo> {
     }
 }
 
-/// Caries an undo operation + an owned mutable value
-///
-/// See [`own`](rewind::own) for examples
-pub struct Owning<T, Undo: FnOnce(T) -> T> {
-    val: Option<ManuallyDrop<Simple<T, T, Undo>>>,
-    stored: ManuallyDrop<T>,
-}
-impl<T, Undo: FnOnce(T) -> T> Owning<T, Undo> {
-    pub(crate) fn new(val: T, undo: Undo) -> Self
-    where
-        T: Clone,
-    {
-        Self {
-            val: Some(ManuallyDrop::new(Simple::new(val.clone(), undo))),
-            stored: ManuallyDrop::new(val),
-        }
-    }
-    pub fn get(&self) -> &T {
-        &self.stored
+/// Compares the values only, see the caveats on the [`Ord`] impl
+impl<T: PartialEq, R, Undo: FnOnce(T) -> R> PartialEq for Simple<T, R, Undo> {
+    fn eq(&self, other: &Self) -> bool {
+        self.value() == other.value()
     }
-    pub fn get_mut(&mut self) -> &mut T {
-        &mut self.stored
-    }
-    fn undo_mut(&mut self) -> Option<T> {
-        self.val
-            .take()
-            .map(|mut val| unsafe { ManuallyDrop::take(&mut val) }.undo())
+}
+impl<T: Eq, R, Undo: FnOnce(T) -> R> Eq for Simple<T, R, Undo> {}
+impl<T: PartialOrd, R, Undo: FnOnce(T) -> R> PartialOrd for Simple<T, R, Undo> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        self.value().partial_cmp(other.value())
     }
 }
-
-impl<T, Undo: FnOnce(T) -> T> Drop for Owning<T, Undo> {
-    fn drop(&mut self) {
-        self.undo_mut();
+/// Orders atoms by their values, e.g. to keep them in a [`BinaryHeap`](std::collections::BinaryHeap)
+///
+/// The undo is not part of the comparison, it can't be. Two atoms which compare equal may still
+/// do completely different things when undone, so a [`BTreeSet`](std::collections::BTreeSet) or
+/// a map key lookup will treat them as the same even though they aren't interchangeable. Keep
+/// that in mind before deduplicating atoms this way: whichever one is dropped gets undone.
+///
+/// ```
+/// # use rewind::Atom;
+/// let mut heap = std::collections::BinaryHeap::new();
+/// for v in [3, 9, 4] {
+///     heap.push(rewind::simple(v, |v| v * 10));
+/// }
+/// assert_eq!(heap.pop().unwrap().undo(), 90);
+/// ```
+impl<T: Ord, R, Undo: FnOnce(T) -> R> Ord for Simple<T, R, Undo> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.value().cmp(other.value())
     }
 }
 
-impl<T, Undo: FnOnce(T) -> T> Deref for Owning<T, Undo> {
-    type Target = T;
+/// A [`Simple`] whose decayed value is converted, see [`Simple::map_value`]
+///
+/// The mapped value only exists once decayed, so unlike its result this can only give access to
+/// the original value, with [`original`](Self::original).
+pub struct MappedSimple<T, U, R, Undo: FnOnce(T) -> R, F: FnOnce(T) -> U> {
+    inner: Option<Simple<T, R, Undo>>,
+    map: Option<F>,
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.stored
+impl<T, U, R, Undo: FnOnce(T) -> R, F: FnOnce(T) -> U> MappedSimple<T, U, R, Undo, F> {
+    pub fn original(&self) -> &T {
+        self.inner
+            .as_ref()
+            .expect("only taken when consumed")
+            .value()
     }
 }
 
-impl<T, Undo: FnOnce(T) -> T> DerefMut for Owning<T, Undo> {
-    fn deref_mut(&mut self) -> &mut T {
-        &mut self.stored
+impl<T, U, R, Undo: FnOnce(T) -> R, F: FnOnce(T) -> U> Drop for MappedSimple<T, U, R, Undo, F> {
+    // Dropping `inner` runs the undo
+    fn drop(&mut self) {}
+}
+
+impl<T, U, R, Undo: FnOnce(T) -> R, F: FnOnce(T) -> U> Atom for MappedSimple<T, U, R, Undo, F> {
+    type Undo = R;
+    type Decay = U;
+    fn undo(mut self) -> Self::Undo {
+        self.inner.take().expect("only taken when consumed").undo()
+    }
+    fn decay(mut self) -> Self::Decay {
+        let value = self.inner.take().expect("only taken when consumed").decay();
+        (self.map.take().expect("only taken when consumed"))(value)
     }
 }
-impl<T: Debug, Undo: FnOnce(T) -> T> Debug for Owning<T, Undo> {
+
+impl<T: Debug, U, R, Undo: FnOnce(T) -> R, F: FnOnce(T) -> U> Debug
+    for MappedSimple<T, U, R, Undo, F>
+{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Owning")
-            .field("inner", &self.val)
-            .field("mutable", &self.stored)
+        f.debug_struct("MappedSimple")
+            .field("original", self.original())
             .finish()
     }
 }
 
-impl<T, Undo: FnOnce(T) -> T> Atom for Owning<T, Undo> {
-    type Undo = T;
+/// A [`Simple`] whose undo also gets a context, e.g. a logger or a connection
+///
+/// The context is kept apart from the value, so the value given back by
+/// [`decay`](Atom::decay) is the same as with [`Simple`] and the context is just dropped.
+pub struct ContextualAtom<C: Clone, T, R, Undo: FnOnce(C, T) -> R> {
+    context: Option<C>,
+    val: Option<T>,
+    undo: Option<Undo>,
+}
+
+impl<C: Clone, T, R, Undo: FnOnce(C, T) -> R> ContextualAtom<C, T, R, Undo> {
+    pub(crate) fn new(context: C, val: T, undo: Undo) -> Self {
+        Self {
+            context: Some(context),
+            val: Some(val),
+            undo: Some(undo),
+        }
+    }
+    /// A copy of the context, which can be kept after the atom is resolved
+    pub fn context(&self) -> C {
+        self.context.clone().expect("only taken when consumed")
+    }
+    pub fn value(&self) -> &T {
+        self.val.as_ref().expect("only taken when consumed")
+    }
+    fn undo_mut(&mut self) -> Option<R> {
+        let undo = self.undo.take()?;
+        Some(undo(self.context.take()?, self.val.take()?))
+    }
+}
+
+impl<C: Clone, T, R, Undo: FnOnce(C, T) -> R> Atom for ContextualAtom<C, T, R, Undo> {
+    type Undo = R;
     type Decay = T;
+    /// Returns the result of the undo function
+    ///
+    /// ```
+    /// # use rewind::Atom;
+    /// let v = rewind::effect_with_context(10, 4, |scale, v| v * scale);
+    /// assert_eq!(v.undo(), 40);
+    /// ```
     fn undo(mut self) -> Self::Undo {
         self.undo_mut().unwrap()
     }
-
-    /// Returns the modified part
+    /// Returns the original value, dropping the context
     ///
     /// ```
     /// # use rewind::Atom;
-    /// let mut items = rewind::own_id(vec!["hello", "world"]);
-    /// items.push("wow");
-    /// let items = items.decay();
-    /// assert_eq!(items.get(2), Some(&"wow"));
+    /// let v = rewind::effect_with_context(10, 4, |scale, v| v * scale);
+    /// assert_eq!(v.decay(), 4);
     /// ```
     fn decay(mut self) -> Self::Decay {
-        unsafe { ManuallyDrop::take(&mut self.val.take().unwrap()) }.decay();
+        self.undo.take();
+        self.context.take();
+        self.val.take().expect("only taken when consumed")
+    }
+}
+
+impl<C: Clone, T, R, Undo: FnOnce(C, T) -> R> Drop for ContextualAtom<C, T, R, Undo> {
+    fn drop(&mut self) {
+        self.undo_mut();
+    }
+}
 
-        unsafe { ManuallyDrop::take(&mut self.stored) }
+impl<C: Clone + Debug, T: Debug, R, Undo: FnOnce(C, T) -> R> Debug
+    for ContextualAtom<C, T, R, Undo>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ContextualAtom")
+            .field("context", &self.context)
+            .field("value", &self.val)
+            .finish()
     }
 }
 
+/// Builds a [`Simple`] whose undo is only known after some setup, which may fail
 ///
+/// Nothing is undone if the builder is dropped, or if the undo fails to be made, the value is
+/// just dropped.
 ///
-/// See [`encase`](rewind::encase) for usage details
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Default)]
-#[repr(transparent)]
-pub struct Encased<S>(Rc<RefCell<S>>);
+/// ```
+/// # use rewind::Atom;
+/// let created = rewind::AtomBuilder::new("report.txt");
+/// let undo: Result<_, &str> = Ok(|name| format!("deleted {name}"));
+/// let created = created.try_build(undo).unwrap();
+/// assert_eq!(created.undo(), "deleted report.txt");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AtomBuilder<T> {
+    value: T,
+}
 
-impl<S> Clone for Encased<S> {
-    fn clone(&self) -> Self {
-        Self(self.0.clone())
+impl<T> AtomBuilder<T> {
+    pub fn new(value: T) -> Self {
+        Self { value }
+    }
+    /// The value the atom will carry, e.g. to decide on the undo
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+    pub fn with_undo<R, Undo: FnOnce(T) -> R>(self, undo: Undo) -> Simple<T, R, Undo> {
+        Simple::new(self.value, undo)
+    }
+    /// Finish with an undo which may have failed to be made, dropping the value if it did
+    pub fn try_build<R, E, Undo: FnOnce(T) -> R>(
+        self,
+        undo: Result<Undo, E>,
+    ) -> Result<Simple<T, R, Undo>, E> {
+        undo.map(|undo| self.with_undo(undo))
+    }
+    /// Give up on the atom, returning the value
+    pub fn into_inner(self) -> T {
+        self.value
     }
 }
 
-/// An operation that has side effects on a shared state
+/// Holds an atom across `.await`s, undoing it if the task is cancelled, see
+/// [`with_rollback`](rewind::with_rollback)
 ///
-/// The shared state `S` is managed via [`Encased<S>`]
-pub struct SideEffect<T, R, S, Undo: FnOnce(&mut S, T) -> R> {
-    undo: Option<ManuallyDrop<Undo>>,
-    value: ManuallyDrop<T>,
-    parent: Encased<S>,
+/// This is only a named place for the atom to live in the future's state: dropping a future drops
+/// everything it holds, so the atom is undone on drop like any other. Call
+/// [`complete`](Self::complete) once the work it guards is done.
+pub struct AbortRollback<A: Atom> {
+    atom: Option<A>,
 }
-impl<S> Encased<S> {
-    pub fn peel_mut<R, Ru, U: FnOnce(&mut S, R) -> Ru>(
-        &mut self,
-        act: impl FnOnce(&mut S) -> R,
-        undo: U,
-    ) -> SideEffect<R, Ru, S, U> {
-        let stored = act(&mut (*self.0).borrow_mut());
-        SideEffect::with_parent(stored, undo, Encased(self.0.clone()))
+
+impl<A: Atom> AbortRollback<A> {
+    pub fn new(atom: A) -> Self {
+        Self { atom: Some(atom) }
     }
-    pub(crate) fn new(s: S) -> Self {
-        Self(Rc::new(RefCell::new(s)))
+    /// The work finished, so decay the atom
+    pub fn complete(self) -> A::Decay {
+        self.decay()
     }
 }
-impl<S> Deref for Encased<S> {
-    type Target = S;
+
+impl<A: Atom> Drop for AbortRollback<A> {
+    fn drop(&mut self) {
+        if let Some(atom) = self.atom.take() {
+            atom.undo();
+        }
+    }
+}
+
+impl<A: Atom> Deref for AbortRollback<A> {
+    type Target = A;
 
     fn deref(&self) -> &Self::Target {
-        unsafe { &*(*self.0).as_ptr() }
+        self.atom.as_ref().expect("only taken when consumed")
     }
 }
-impl<S> DerefMut for Encased<S> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut *(*self.0).as_ptr() }
+
+impl<A: Atom + Debug> Debug for AbortRollback<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("AbortRollback").field(&self.atom).finish()
     }
 }
 
-impl<T, R, S, Undo: FnOnce(&mut S, T) -> R> SideEffect<T, R, S, Undo> {
-    fn with_parent(value: T, undo: Undo, parent: Encased<S>) -> Self {
-        Self {
-            undo: Some(ManuallyDrop::new(undo)),
-            value: ManuallyDrop::new(value),
-            parent,
-        }
+impl<A: Atom> Atom for AbortRollback<A> {
+    type Undo = A::Undo;
+    type Decay = A::Decay;
+    fn undo(mut self) -> Self::Undo {
+        self.atom.take().expect("only taken when consumed").undo()
     }
-    pub fn peel_mut<Rv, Ru, U: FnOnce(&mut S, Rv) -> Ru>(
-        &mut self,
-        act: impl FnOnce(&mut S) -> Rv,
-        undo: U,
-    ) -> SideEffect<Rv, Ru, S, U> {
-        self.parent.peel_mut(act, undo)
+    fn decay(mut self) -> Self::Decay {
+        self.atom.take().expect("only taken when consumed").decay()
     }
 }
-impl<T, R, S, Undo: FnOnce(&mut S, T) -> R> Deref for SideEffect<T, R, S, Undo> {
-    type Target = T;
 
-    fn deref(&self) -> &Self::Target {
-        &self.value
-    }
+/// Records how long undoing `A` takes in a [`Histogram`], see [`timed`](rewind::timed)
+///
+/// Dropping it counts as undoing it, decaying records nothing.
+pub struct Timed<A: Atom> {
+    atom: Option<A>,
+    name: &'static str,
+    histogram: Rc<RefCell<Histogram>>,
 }
-impl<T, R, S, Undo: FnOnce(&mut S, T) -> R> DerefMut for SideEffect<T, R, S, Undo> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.value
+
+impl<A: Atom> Timed<A> {
+    pub(crate) fn new(name: &'static str, atom: A, histogram: &Rc<RefCell<Histogram>>) -> Self {
+        Self {
+            atom: Some(atom),
+            name,
+            histogram: histogram.clone(),
+        }
+    }
+    fn undo_mut(&mut self) -> Option<A::Undo> {
+        let atom = self.atom.take()?;
+        let start = Instant::now();
+        let r = atom.undo();
+        self.histogram
+            .borrow_mut()
+            .record(self.name, start.elapsed());
+        Some(r)
     }
 }
-impl<T, R, S, Undo: FnOnce(&mut S, T) -> R> Drop for SideEffect<T, R, S, Undo> {
+
+impl<A: Atom> Drop for Timed<A> {
     fn drop(&mut self) {
-        if let Some(undo) = &mut self.undo {
-            let value = unsafe { ManuallyDrop::take(&mut self.value) };
-            let undo = unsafe { ManuallyDrop::take(undo) };
-            undo(&mut self.parent, value);
-        }
+        self.undo_mut();
     }
 }
-impl<T, S, R, Undo: FnOnce(&mut S, T) -> R> Atom for SideEffect<T, R, S, Undo> {
-    type Undo = R;
-    type Decay = T;
 
-    fn undo(mut self) -> Self::Undo {
-        let value = unsafe { ManuallyDrop::take(&mut self.value) };
-        ManuallyDrop::into_inner(self.undo.take().unwrap())(&mut self.parent, value)
-    }
+impl<A: Atom> Deref for Timed<A> {
+    type Target = A;
 
-    fn decay(mut self) -> Self::Decay {
-        self.undo.take();
-        unsafe { ManuallyDrop::take(&mut self.value) }
+    fn deref(&self) -> &Self::Target {
+        self.atom.as_ref().expect("only taken when consumed")
     }
 }
-impl<T: Debug, S: Debug, R, Undo: FnOnce(&mut S, T) -> R> Debug for SideEffect<T, R, S, Undo> {
+
+impl<A: Atom + Debug> Debug for Timed<A> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("SideEffect")
-            .field("value", &self.value)
-            .field("parent", &self.parent)
+        f.debug_struct("Timed")
+            .field("name", &self.name)
+            .field("atom", &self.atom)
             .finish()
     }
 }
 
-/// An undo action that can be cancelled
+impl<A: Atom> Atom for Timed<A> {
+    type Undo = A::Undo;
+    type Decay = A::Decay;
+    fn undo(mut self) -> Self::Undo {
+        self.undo_mut().expect("only taken when consumed")
+    }
+    fn decay(mut self) -> Self::Decay {
+        self.atom.take().expect("only taken when consumed").decay()
+    }
+}
+
+/// Two atoms stored together which can still be resolved on their own, see
+/// [`pair`](rewind::pair)
 ///
-/// Implementors should implement [`Drop`] as `self.undo();`, although unfortunately since [`Drop`] cannot
-/// be implemented on a generic this cannot be enforced
-#[allow(drop_bounds)]
-pub trait Atom: Drop {
-    type Undo;
-    type Decay;
-    /// Undo the operation
-    ///
-    /// ```
-    /// # use rewind::Atom;
-    /// let mut items = rewind::own_id(vec!["hello", "world"]);
-    /// items.push("wow");
-    /// let items = items.undo();
-    /// assert_eq!(items.len(), 2);
-    /// ```
-    fn undo(self) -> Self::Undo;
-    /// Forget about how to undo
+/// Undoing or dropping it undoes `B` and then `A`, as if they had been dropped separately in the
+/// order they were created.
+pub struct PairedAtom<A: Atom, B: Atom> {
+    first: Option<A>,
+    second: Option<B>,
+}
+
+impl<A: Atom, B: Atom> PairedAtom<A, B> {
+    pub(crate) fn new(first: A, second: B) -> Self {
+        Self {
+            first: Some(first),
+            second: Some(second),
+        }
+    }
+    pub fn first(&self) -> &A {
+        self.first.as_ref().expect("only taken when consumed")
+    }
+    pub fn second(&self) -> &B {
+        self.second.as_ref().expect("only taken when consumed")
+    }
+    /// Undo only `A`, giving back `B` unresolved
     ///
     /// ```
     /// # use rewind::Atom;
-    /// let mut items = rewind::own_id(vec!["hello", "world"]);
-    /// items.push("wow");
-    /// let items = items.decay();
-    /// assert_eq!(items.len(), 3);
+    /// let (undone, rest) = rewind::pair(rewind::simple(1, |v| v), rewind::simple(2, |v| v))
+    ///     .undo_first();
+    /// assert_eq!((undone, rest.decay()), (1, 2));
     /// ```
-    fn decay(self) -> Self::Decay;
+    pub fn undo_first(self) -> (A::Undo, B) {
+        let (first, second) = self.split();
+        (first.undo(), second)
+    }
+    /// Undo only `B`, giving back `A` unresolved
+    pub fn undo_second(self) -> (A, B::Undo) {
+        let (first, second) = self.split();
+        (first, second.undo())
+    }
+    /// Take both atoms out without resolving either
+    pub fn split(mut self) -> (A, B) {
+        (
+            self.first.take().expect("only taken when consumed"),
+            self.second.take().expect("only taken when consumed"),
+        )
+    }
 }
 
-#[cfg(test)]
-mod tests {
+impl<A: Atom, B: Atom> Drop for PairedAtom<A, B> {
+    fn drop(&mut self) {
+        self.second.take();
+        self.first.take();
+    }
+}
 
-    use super::*;
+impl<A: Atom + Debug, B: Atom + Debug> Debug for PairedAtom<A, B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PairedAtom")
+            .field("first", &self.first)
+            .field("second", &self.second)
+            .finish()
+    }
+}
 
-    #[test]
-    fn simple_undo_does_not_cause_ub_on_drop() {
-        Simple::new(vec!["test"], |c| c).undo();
+impl<A: Atom, B: Atom> Atom for PairedAtom<A, B> {
+    type Undo = (A::Undo, B::Undo);
+    type Decay = (A::Decay, B::Decay);
+    fn undo(self) -> Self::Undo {
+        let (first, second) = self.split();
+        let second = second.undo();
+        (first.undo(), second)
     }
-    #[test]
-    fn owned_undo_does_not_cause_ub_on_drop() {
-        Owning::new(vec!["test"], |c| c).undo();
+    fn decay(self) -> Self::Decay {
+        let (first, second) = self.split();
+        (first.decay(), second.decay())
     }
+}
 
-    #[test]
-    fn atom_runs_on_drop_if_uncancelled() {
-        let mut scoped = 12;
-        {
-            let atom = Simple::new(&mut scoped, |v| {
-                *v = 0;
-            });
-            drop(atom);
+/// A [`Simple`] which logs its value when it is undone or decayed, see
+/// [`debug_atom`](rewind::debug_atom)
+///
+/// Messages are `undo: <value>` and `decay: <value>`, at debug level with the `rewind` target
+/// unless changed. Dropping it counts as undoing it.
+#[cfg(feature = "log")]
+pub struct DebugAtom<T: Debug, R, Undo: FnOnce(T) -> R> {
+    inner: Option<Simple<T, R, Undo>>,
+    prefix: Option<String>,
+    target: &'static str,
+    level: log::Level,
+}
+
+#[cfg(feature = "log")]
+impl<T: Debug, R, Undo: FnOnce(T) -> R> DebugAtom<T, R, Undo> {
+    pub(crate) fn new(val: T, undo: Undo) -> Self {
+        Self {
+            inner: Some(Simple::new(val, undo)),
+            prefix: None,
+            target: "rewind",
+            level: log::Level::Debug,
         }
-        assert_eq!(scoped, 0);
     }
+    /// Start messages with `label`, e.g. `label: undo: <value>`
+    pub fn with_prefix(mut self, label: &str) -> Self {
+        self.prefix = Some(label.to_owned());
+        self
+    }
+    pub fn with_target(mut self, target: &'static str) -> Self {
+        self.target = target;
+        self
+    }
+    pub fn with_level(mut self, level: log::Level) -> Self {
+        self.level = level;
+        self
+    }
+    fn take_logged(&mut self, action: &str) -> Option<Simple<T, R, Undo>> {
+        let inner = self.inner.take()?;
+        let prefix = self
+            .prefix
+            .as_deref()
+            .map_or(String::new(), |p| format!("{p}: "));
+        log::log!(target: self.target, self.level, "{prefix}{action}: {:?}", inner.value());
+        Some(inner)
+    }
+}
 
-    #[test]
-    fn cancelling_atom_stops_it_running_on_drop() {
-        let mut scoped = 12;
-        {
-            let atom = Simple::new(&mut scoped, |s| {
-                *s = 13;
-            });
-            atom.decay();
+#[cfg(feature = "log")]
+impl<T: Debug, R, Undo: FnOnce(T) -> R> Drop for DebugAtom<T, R, Undo> {
+    fn drop(&mut self) {
+        self.take_logged("undo");
+    }
+}
+
+#[cfg(feature = "log")]
+impl<T: Debug, R, Undo: FnOnce(T) -> R> Debug for DebugAtom<T, R, Undo> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DebugAtom")
+            .field("inner", &self.inner)
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+#[cfg(feature = "log")]
+impl<T: Debug, R, Undo: FnOnce(T) -> R> Atom for DebugAtom<T, R, Undo> {
+    type Undo = R;
+    type Decay = T;
+    fn undo(mut self) -> Self::Undo {
+        self.take_logged("undo")
+            .expect("only taken when consumed")
+            .undo()
+    }
+    fn decay(mut self) -> Self::Decay {
+        self.take_logged("decay")
+            .expect("only taken when consumed")
+            .decay()
+    }
+}
+
+/// Caries an undo operation + an owned mutable value
+///
+/// See [`own`](rewind::own) for examples
+///
+/// Like [`Simple`], this is [`Send`] and [`Sync`] whenever `T` and `Undo` are.
+pub struct Owning<T, Undo: FnOnce(T) -> T> {
+    val: Option<Simple<T, T, Undo>>,
+    stored: Option<T>,
+}
+impl<T, Undo: FnOnce(T) -> T> Owning<T, Undo> {
+    pub(crate) fn new(val: T, undo: Undo) -> Self
+    where
+        T: Clone,
+    {
+        Self {
+            val: Some(Simple::new(val.clone(), undo)),
+            stored: Some(val),
+        }
+    }
+    pub fn get(&self) -> &T {
+        self.stored.as_ref().expect("only taken when consumed")
+    }
+    pub fn get_mut(&mut self) -> &mut T {
+        self.stored.as_mut().expect("only taken when consumed")
+    }
+    /// Swap out the modified value, returning the previous one
+    ///
+    /// This does not change what [`undo`](Atom::undo) goes back to, that is always the original
+    /// value
+    ///
+    /// ```
+    /// # use rewind::Atom;
+    /// let mut v = rewind::own_id(1);
+    /// assert_eq!(v.replace(2), 1);
+    /// assert_eq!(v.replace(3), 2);
+    /// assert_eq!(v.undo(), 1);
+    /// ```
+    pub fn replace(&mut self, new_value: T) -> T {
+        std::mem::replace(self.get_mut(), new_value)
+    }
+    /// Take the atom apart without undoing, giving the modified value, the original value and
+    /// the undo
+    ///
+    /// ```
+    /// let mut items = rewind::own(vec![1], |v| v);
+    /// items.push(2);
+    /// let (modified, original, undo) = items.into_parts();
+    /// assert_eq!((modified, undo(original)), (vec![1, 2], vec![1]));
+    /// ```
+    pub fn into_parts(mut self) -> (T, T, Undo) {
+        let mut original = self.val.take().expect("only taken when consumed");
+        let stored = self.stored.take().expect("only taken when consumed");
+        let undo = original.undo.take().expect("only taken when consumed");
+        let value = original.val.take().expect("only taken when consumed");
+        (stored, value, undo)
+    }
+    fn undo_mut(&mut self) -> Option<T> {
+        self.stored.take();
+        self.val.take().map(Atom::undo)
+    }
+}
+
+impl<T, Undo: FnOnce(T) -> T> Drop for Owning<T, Undo> {
+    fn drop(&mut self) {
+        self.undo_mut();
+    }
+}
+
+impl<T, Undo: FnOnce(T) -> T> Deref for Owning<T, Undo> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.get()
+    }
+}
+
+impl<T, Undo: FnOnce(T) -> T> DerefMut for Owning<T, Undo> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.get_mut()
+    }
+}
+/// Advances the modified iterator
+///
+/// Undoing an `Owning` iterator after partial iteration restores the pre-operation iterator
+/// state, not the partially-consumed state.
+///
+/// ```
+/// # use rewind::Atom;
+/// let mut it = rewind::own_id(vec![1, 2, 3].into_iter());
+/// assert_eq!(it.next(), Some(1));
+/// assert_eq!(it.undo().collect::<Vec<_>>(), vec![1, 2, 3]);
+/// ```
+impl<T: Iterator, Undo: FnOnce(T) -> T> Iterator for Owning<T, Undo> {
+    type Item = T::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.get_mut().next()
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.get().size_hint()
+    }
+}
+
+impl<T: ExactSizeIterator, Undo: FnOnce(T) -> T> ExactSizeIterator for Owning<T, Undo> {}
+
+impl<T: Debug, Undo: FnOnce(T) -> T> Debug for Owning<T, Undo> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Owning")
+            .field("inner", &self.val)
+            .field("mutable", &self.stored)
+            .finish()
+    }
+}
+
+/// Compares the current values only, see the caveats on the [`Ord`] impl
+impl<T: PartialEq, Undo: FnOnce(T) -> T> PartialEq for Owning<T, Undo> {
+    fn eq(&self, other: &Self) -> bool {
+        self.get() == other.get()
+    }
+}
+impl<T: Eq, Undo: FnOnce(T) -> T> Eq for Owning<T, Undo> {}
+impl<T: PartialOrd, Undo: FnOnce(T) -> T> PartialOrd for Owning<T, Undo> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        self.get().partial_cmp(other.get())
+    }
+}
+/// Orders atoms by their current, possibly modified, values
+///
+/// Neither the original value nor the undo is compared, so like with [`Simple`], atoms which
+/// compare equal can undo to entirely different values. Modifying an atom while it is a key of a
+/// map or in a heap changes its place in the order, which those collections don't expect, in the
+/// same way as with interior mutability.
+impl<T: Ord, Undo: FnOnce(T) -> T> Ord for Owning<T, Undo> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.get().cmp(other.get())
+    }
+}
+
+impl<T, Undo: FnOnce(T) -> T> Atom for Owning<T, Undo> {
+    type Undo = T;
+    type Decay = T;
+    fn undo(mut self) -> Self::Undo {
+        self.undo_mut().unwrap()
+    }
+
+    /// Returns the modified part
+    ///
+    /// ```
+    /// # use rewind::Atom;
+    /// let mut items = rewind::own_id(vec!["hello", "world"]);
+    /// items.push("wow");
+    /// let items = items.decay();
+    /// assert_eq!(items.get(2), Some(&"wow"));
+    /// ```
+    fn decay(mut self) -> Self::Decay {
+        self.val.take().expect("only taken when consumed").decay();
+        self.stored.take().expect("only taken when consumed")
+    }
+}
+
+type Observers<T> = Vec<Box<dyn Fn(&T)>>;
+
+/// An [`Owning`] which tells observers about every change, see
+/// [`observable_own`](rewind::observable_own)
+///
+/// Since [`DerefMut`] has to give out a plain reference, changes go through
+/// [`get_mut`](Self::get_mut) instead, whose guard notifies the observers once it is dropped.
+/// Undoing, including by dropping, notifies them with the restored value; decaying doesn't.
+pub struct ObservableOwning<T, Undo: FnOnce(T) -> T> {
+    inner: Option<Owning<T, Undo>>,
+    observers: Observers<T>,
+}
+
+impl<T, Undo: FnOnce(T) -> T> ObservableOwning<T, Undo> {
+    pub(crate) fn new(val: T, undo: Undo) -> Self
+    where
+        T: Clone,
+    {
+        Self {
+            inner: Some(Owning::new(val, undo)),
+            observers: Vec::new(),
+        }
+    }
+    /// Call `f` with the value after every change
+    pub fn observe(&mut self, f: impl Fn(&T) + 'static) -> &mut Self {
+        self.observers.push(Box::new(f));
+        self
+    }
+    /// Access the value mutably, the observers are notified when the guard is dropped
+    ///
+    /// ```
+    /// # use rewind::Atom;
+    /// # use std::{cell::Cell, rc::Rc};
+    /// let seen = Rc::new(Cell::new(0));
+    /// let mut items = rewind::observable_own(vec![1], |v| v);
+    /// let s = seen.clone();
+    /// items.observe(move |v| s.set(v.len()));
+    /// items.get_mut().push(2);
+    /// assert_eq!(seen.get(), 2);
+    /// items.undo();
+    /// assert_eq!(seen.get(), 1);
+    /// ```
+    pub fn get_mut(&mut self) -> ObservedMut<'_, T, Undo> {
+        ObservedMut { owner: self }
+    }
+    fn owning(&self) -> &Owning<T, Undo> {
+        self.inner.as_ref().expect("only taken when consumed")
+    }
+    fn notify(&self, value: &T) {
+        for observer in &self.observers {
+            observer(value);
+        }
+    }
+    fn undo_mut(&mut self) -> Option<T> {
+        let value = self.inner.take()?.undo();
+        self.notify(&value);
+        Some(value)
+    }
+}
+
+impl<T, Undo: FnOnce(T) -> T> Drop for ObservableOwning<T, Undo> {
+    fn drop(&mut self) {
+        self.undo_mut();
+    }
+}
+
+impl<T, Undo: FnOnce(T) -> T> Deref for ObservableOwning<T, Undo> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.owning()
+    }
+}
+
+impl<T: Debug, Undo: FnOnce(T) -> T> Debug for ObservableOwning<T, Undo> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ObservableOwning")
+            .field("inner", &self.inner)
+            .field("observers", &self.observers.len())
+            .finish()
+    }
+}
+
+impl<T, Undo: FnOnce(T) -> T> Atom for ObservableOwning<T, Undo> {
+    type Undo = T;
+    type Decay = T;
+    fn undo(mut self) -> Self::Undo {
+        self.undo_mut().expect("only taken when consumed")
+    }
+    fn decay(mut self) -> Self::Decay {
+        self.inner.take().expect("only taken when consumed").decay()
+    }
+}
+
+/// Mutable access to an [`ObservableOwning`], notifying its observers when dropped
+pub struct ObservedMut<'a, T, Undo: FnOnce(T) -> T> {
+    owner: &'a mut ObservableOwning<T, Undo>,
+}
+
+impl<T, Undo: FnOnce(T) -> T> Deref for ObservedMut<'_, T, Undo> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.owner.owning()
+    }
+}
+
+impl<T, Undo: FnOnce(T) -> T> DerefMut for ObservedMut<'_, T, Undo> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.owner
+            .inner
+            .as_mut()
+            .expect("only taken when consumed")
+            .get_mut()
+    }
+}
+
+impl<T, Undo: FnOnce(T) -> T> Drop for ObservedMut<'_, T, Undo> {
+    fn drop(&mut self) {
+        self.owner.notify(self.owner.owning());
+    }
+}
+
+impl<T: Debug, Undo: FnOnce(T) -> T> Debug for ObservedMut<'_, T, Undo> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ObservedMut").field(&**self).finish()
+    }
+}
+
+/// Like [`Owning`] but only keeps part of the original value around for the undo
+///
+/// See [`own_with`](rewind::own_with) for examples
+pub struct OwningWith<T, U, Undo: FnOnce(T, U) -> T> {
+    stored: Option<T>,
+    undo: Option<(U, Undo)>,
+}
+
+impl<T, U, Undo: FnOnce(T, U) -> T> OwningWith<T, U, Undo> {
+    pub(crate) fn new(val: T, extract: impl FnOnce(&T) -> U, undo: Undo) -> Self {
+        Self {
+            undo: Some((extract(&val), undo)),
+            stored: Some(val),
+        }
+    }
+    pub fn get(&self) -> &T {
+        self
+    }
+    pub fn get_mut(&mut self) -> &mut T {
+        self
+    }
+    fn undo_mut(&mut self) -> Option<T> {
+        let (snapshot, undo) = self.undo.take()?;
+        Some(undo(self.stored.take()?, snapshot))
+    }
+}
+
+impl<T, U, Undo: FnOnce(T, U) -> T> Drop for OwningWith<T, U, Undo> {
+    fn drop(&mut self) {
+        self.undo_mut();
+    }
+}
+
+impl<T, U, Undo: FnOnce(T, U) -> T> Deref for OwningWith<T, U, Undo> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.stored.as_ref().unwrap()
+    }
+}
+
+impl<T, U, Undo: FnOnce(T, U) -> T> DerefMut for OwningWith<T, U, Undo> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.stored.as_mut().unwrap()
+    }
+}
+
+impl<T: Debug, U: Debug, Undo: FnOnce(T, U) -> T> Debug for OwningWith<T, U, Undo> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OwningWith")
+            .field("snapshot", &self.undo.as_ref().map(|(s, _)| s))
+            .field("mutable", &self.stored)
+            .finish()
+    }
+}
+
+impl<T, U, Undo: FnOnce(T, U) -> T> Atom for OwningWith<T, U, Undo> {
+    type Undo = T;
+    type Decay = T;
+    /// Returns the value as restored by the undo function
+    fn undo(mut self) -> Self::Undo {
+        self.undo_mut().unwrap()
+    }
+    /// Returns the modified value
+    fn decay(mut self) -> Self::Decay {
+        self.undo.take();
+        self.stored.take().unwrap()
+    }
+}
+
+/// A string with text editing operations which can be undone
+///
+/// Each edit returns a [`SideEffect`] on the shared string, so edits can be collected on a
+/// [`Stack`](crate::stack::Stack) and undone newest first. Positions are byte offsets and must
+/// lie on `char` boundaries.
+///
+/// ```
+/// # use rewind::Atom;
+/// let mut text = rewind::AtomStr::new("hello world");
+/// let replaced = text.replace(6..11, "rust").unwrap();
+/// let inserted = text.insert(0, "oh ").unwrap();
+/// assert_eq!(&*text.borrow_state(), "oh hello rust");
+/// inserted.undo();
+/// replaced.undo();
+/// assert_eq!(&*text.borrow_state(), "hello world");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AtomStr(Encased<String>);
+
+/// Errors from editing an [`AtomStr`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StrError {
+    /// The position is past the end of the string
+    OutOfBounds,
+    /// The position is inside a multi-byte `char`
+    NotCharBoundary,
+}
+
+impl std::fmt::Display for StrError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StrError::OutOfBounds => f.write_str("position is out of bounds"),
+            StrError::NotCharBoundary => f.write_str("position is not on a char boundary"),
+        }
+    }
+}
+impl std::error::Error for StrError {}
+
+// The edits return their side effects directly so they can be pushed onto a stack as is
+#[allow(clippy::type_complexity)]
+impl AtomStr {
+    pub fn new(s: impl Into<String>) -> Self {
+        Self(Encased::new(s.into()))
+    }
+    /// Insert `s` at `pos`, the value is the range the text was inserted at
+    pub fn insert(
+        &mut self,
+        pos: usize,
+        s: &str,
+    ) -> Result<
+        SideEffect<Range<usize>, (), String, impl FnOnce(&mut String, Range<usize>)>,
+        StrError,
+    > {
+        self.check(pos..pos)?;
+        Ok(self.0.peel_mut(
+            |text| {
+                text.insert_str(pos, s);
+                pos..pos + s.len()
+            },
+            |text, range| text.replace_range(range, ""),
+        ))
+    }
+    /// Remove the text in `range`, the value is the removed text
+    pub fn delete(
+        &mut self,
+        range: Range<usize>,
+    ) -> Result<SideEffect<String, (), String, impl FnOnce(&mut String, String)>, StrError> {
+        self.replace(range, "")
+    }
+    /// Replace the text in `range` with `new`, the value is the replaced text
+    pub fn replace(
+        &mut self,
+        range: Range<usize>,
+        new: &str,
+    ) -> Result<SideEffect<String, (), String, impl FnOnce(&mut String, String)>, StrError> {
+        self.check(range.clone())?;
+        let start = range.start;
+        let new_len = new.len();
+        Ok(self.0.peel_mut(
+            |text| {
+                let old = text[range.clone()].to_owned();
+                text.replace_range(range, new);
+                old
+            },
+            move |text, old| text.replace_range(start..start + new_len, &old),
+        ))
+    }
+    fn check(&self, range: Range<usize>) -> Result<(), StrError> {
+        let len = self.0.read(|s| s.len());
+        if range.start > range.end || range.end > len {
+            return Err(StrError::OutOfBounds);
+        }
+        if self
+            .0
+            .read(|s| s.is_char_boundary(range.start) && s.is_char_boundary(range.end))
+        {
+            Ok(())
+        } else {
+            Err(StrError::NotCharBoundary)
+        }
+    }
+}
+
+impl AtomStr {
+    /// Read access to the text, see [`Encased::borrow_state`]
+    pub fn borrow_state(&self) -> EncasedRef<'_, String> {
+        self.0.borrow_state()
+    }
+}
+
+/// A [`VecDeque`] whose pushes and pops at either end can be undone
+///
+/// Like [`AtomStr`], each operation returns a [`SideEffect`] on the shared deque. Undoing a push
+/// pops the value again, undoing a pop pushes the value back if there was one.
+///
+/// ```
+/// # use rewind::Atom;
+/// let mut queue = rewind::AtomDeque::new();
+/// let pushed = queue.push_back(1);
+/// let popped = queue.pop_front();
+/// assert_eq!(*popped, Some(1));
+/// popped.undo();
+/// assert_eq!(pushed.undo(), Some(1));
+/// assert!(queue.borrow_state().is_empty());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AtomDeque<T>(Encased<VecDeque<T>>);
+
+#[allow(clippy::type_complexity)]
+impl<T> AtomDeque<T> {
+    pub fn new() -> Self {
+        Self::from(VecDeque::new())
+    }
+    pub fn push_back(
+        &mut self,
+        value: T,
+    ) -> SideEffect<(), Option<T>, VecDeque<T>, impl FnOnce(&mut VecDeque<T>, ()) -> Option<T>>
+    {
+        self.0.peel_mut(|d| d.push_back(value), |d, _| d.pop_back())
+    }
+    pub fn push_front(
+        &mut self,
+        value: T,
+    ) -> SideEffect<(), Option<T>, VecDeque<T>, impl FnOnce(&mut VecDeque<T>, ()) -> Option<T>>
+    {
+        self.0
+            .peel_mut(|d| d.push_front(value), |d, _| d.pop_front())
+    }
+    pub fn pop_back(
+        &mut self,
+    ) -> SideEffect<Option<T>, (), VecDeque<T>, impl FnOnce(&mut VecDeque<T>, Option<T>)> {
+        self.0.peel_mut(VecDeque::pop_back, |d, v| d.extend(v))
+    }
+    pub fn pop_front(
+        &mut self,
+    ) -> SideEffect<Option<T>, (), VecDeque<T>, impl FnOnce(&mut VecDeque<T>, Option<T>)> {
+        self.0.peel_mut(VecDeque::pop_front, |d, v| {
+            if let Some(v) = v {
+                d.push_front(v);
+            }
+        })
+    }
+}
+
+impl<T> From<VecDeque<T>> for AtomDeque<T> {
+    fn from(d: VecDeque<T>) -> Self {
+        Self(Encased::new(d))
+    }
+}
+
+impl<T> AtomDeque<T> {
+    /// Read access to the contents, see [`Encased::borrow_state`]
+    pub fn borrow_state(&self) -> EncasedRef<'_, VecDeque<T>> {
+        self.0.borrow_state()
+    }
+}
+
+/// An [`Option`] whose changes can be undone
+///
+/// Each operation returns a [`SideEffect`] whose value is what the option held before, which
+/// undoing puts back.
+///
+/// ```
+/// # use rewind::Atom;
+/// let mut cached = rewind::AtomOption::new();
+/// let filled = cached.insert("page");
+/// let cleared = cached.take();
+/// assert_eq!(*cleared, Some("page"));
+/// cleared.undo();
+/// assert_eq!(*cached.borrow_state(), Some("page"));
+/// filled.undo();
+/// assert_eq!(*cached.borrow_state(), None);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AtomOption<T>(Encased<Option<T>>);
+
+#[allow(clippy::type_complexity)]
+impl<T> AtomOption<T> {
+    pub fn new() -> Self {
+        Self::from(None)
+    }
+    /// Take the value out, leaving `None`
+    pub fn take(
+        &mut self,
+    ) -> SideEffect<Option<T>, (), Option<T>, impl FnOnce(&mut Option<T>, Option<T>)> {
+        self.0.peel_mut(Option::take, Self::restore)
+    }
+    /// Put `value` in, the side effect has the value it replaced
+    pub fn replace(
+        &mut self,
+        value: T,
+    ) -> SideEffect<Option<T>, (), Option<T>, impl FnOnce(&mut Option<T>, Option<T>)> {
+        self.0.peel_mut(|o| o.replace(value), Self::restore)
+    }
+    /// [`replace`](Self::replace), named after [`Option::insert`]
+    ///
+    /// Undoing it puts back the value from before rather than always leaving `None`.
+    pub fn insert(
+        &mut self,
+        value: T,
+    ) -> SideEffect<Option<T>, (), Option<T>, impl FnOnce(&mut Option<T>, Option<T>)> {
+        self.replace(value)
+    }
+    fn restore(o: &mut Option<T>, previous: Option<T>) {
+        *o = previous;
+    }
+}
+
+impl<T> From<Option<T>> for AtomOption<T> {
+    fn from(value: Option<T>) -> Self {
+        Self(Encased::new(value))
+    }
+}
+
+impl<T> AtomOption<T> {
+    /// Read access to the contents, see [`Encased::borrow_state`]
+    pub fn borrow_state(&self) -> EncasedRef<'_, Option<T>> {
+        self.0.borrow_state()
+    }
+}
+
+/// A [`HashSet`] whose insertions and removals can be undone
+///
+/// Like [`AtomDeque`], each operation returns a [`SideEffect`] on the shared set. Undos only
+/// revert what the operation actually changed, so undoing the insert of an element which was
+/// already present leaves it in the set.
+///
+/// ```
+/// # use rewind::Atom;
+/// let mut tags = rewind::AtomHashSet::new();
+/// let first = tags.insert("new");
+/// let again = tags.insert("new");
+/// assert!(*first && !*again);
+/// again.undo();
+/// assert!(tags.borrow_state().contains("new"));
+/// first.undo();
+/// assert!(tags.borrow_state().is_empty());
+/// ```
+#[derive(Debug, Clone)]
+pub struct AtomHashSet<T>(Encased<HashSet<T>>);
+
+#[allow(clippy::type_complexity)]
+impl<T: Hash + Eq> AtomHashSet<T> {
+    pub fn new() -> Self {
+        Self::from(HashSet::new())
+    }
+    /// Insert `value`, the side effect holds whether it was newly inserted
+    pub fn insert(
+        &mut self,
+        value: T,
+    ) -> SideEffect<bool, (), HashSet<T>, impl FnOnce(&mut HashSet<T>, bool)>
+    where
+        T: Clone,
+    {
+        let inserted = value.clone();
+        self.0.peel_mut(
+            |s| s.insert(value),
+            move |s, was_new| {
+                if was_new {
+                    s.remove(&inserted);
+                }
+            },
+        )
+    }
+    /// Remove `value`, the side effect holds the removed element if it was present
+    pub fn remove<Q>(
+        &mut self,
+        value: &Q,
+    ) -> SideEffect<Option<T>, (), HashSet<T>, impl FnOnce(&mut HashSet<T>, Option<T>)>
+    where
+        T: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.0
+            .peel_mut(|s| s.take(value), |s, removed| s.extend(removed))
+    }
+    /// Remove every element, the side effect holds the removed elements
+    pub fn clear(
+        &mut self,
+    ) -> SideEffect<HashSet<T>, (), HashSet<T>, impl FnOnce(&mut HashSet<T>, HashSet<T>)> {
+        self.0
+            .peel_mut(std::mem::take, |s, removed| s.extend(removed))
+    }
+}
+
+impl<T: Hash + Eq> Default for AtomHashSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> From<HashSet<T>> for AtomHashSet<T> {
+    fn from(s: HashSet<T>) -> Self {
+        Self(Encased::new(s))
+    }
+}
+
+impl<T> AtomHashSet<T> {
+    /// Read access to the contents, see [`Encased::borrow_state`]
+    pub fn borrow_state(&self) -> EncasedRef<'_, HashSet<T>> {
+        self.0.borrow_state()
+    }
+}
+
+/// A root state with alternative branches derived from it, only one of which is active
+///
+/// Undoing the whole atom gives back the root, decaying it gives back the active branch's state.
+///
+/// ```
+/// # use rewind::Atom;
+/// use rewind::atom::BranchingAtom;
+/// let mut doc = BranchingAtom::new(String::from("draft"));
+/// let formal = doc.branch("formal", |s| format!("{s}, regards"));
+/// doc.branch("casual", |s| format!("{s} :)"));
+/// assert_eq!(doc.active_branch(), Some("casual"));
+/// assert!(doc.switch_branch("formal"));
+/// assert_eq!(doc.state(), "draft, regards");
+/// formal.undo(&mut doc);
+/// assert_eq!(doc.active_branch(), None);
+/// assert_eq!(doc.decay(), "draft");
+/// ```
+pub struct BranchingAtom<S> {
+    root: Option<S>,
+    /// Removed branches are left as `None` so handles to the others stay valid
+    branches: Vec<Option<(String, S)>>,
+    active: Option<usize>,
+}
+
+/// Refers to a branch of a [`BranchingAtom`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BranchHandle(usize);
+
+impl BranchHandle {
+    /// Remove the branch, switching back to the root if it was active
+    ///
+    /// Does nothing if the branch was already removed
+    pub fn undo<S>(self, tree: &mut BranchingAtom<S>) {
+        if let Some(b) = tree.branches.get_mut(self.0) {
+            b.take();
+        }
+        if tree.active == Some(self.0) {
+            tree.active = None;
+        }
+    }
+}
+
+impl<S> BranchingAtom<S> {
+    pub fn new(root: S) -> Self {
+        Self {
+            root: Some(root),
+            branches: Vec::new(),
+            active: None,
+        }
+    }
+    /// Create a branch from the result of `op` on the root state and make it active
+    ///
+    /// Any existing branch with the same label is replaced
+    pub fn branch(&mut self, label: &str, op: impl FnOnce(&S) -> S) -> BranchHandle {
+        let state = op(self.root.as_ref().unwrap());
+        if let Some(existing) = self.find(label) {
+            BranchHandle(existing).undo(self);
+        }
+        self.branches.push(Some((label.to_owned(), state)));
+        self.active = Some(self.branches.len() - 1);
+        BranchHandle(self.branches.len() - 1)
+    }
+    /// Label of the active branch, `None` if the root is active
+    pub fn active_branch(&self) -> Option<&str> {
+        self.active_el().map(|(l, _)| l.as_str())
+    }
+    /// Make the branch called `label` active, returns `false` if there is no such branch
+    pub fn switch_branch(&mut self, label: &str) -> bool {
+        match self.find(label) {
+            Some(i) => {
+                self.active = Some(i);
+                true
+            }
+            None => false,
+        }
+    }
+    /// Make the root state active without removing any branches
+    pub fn switch_to_root(&mut self) {
+        self.active = None;
+    }
+    /// The state of the active branch, or the root
+    pub fn state(&self) -> &S {
+        match self.active_el() {
+            Some((_, s)) => s,
+            None => self.root.as_ref().unwrap(),
+        }
+    }
+    pub fn root(&self) -> &S {
+        self.root.as_ref().unwrap()
+    }
+    fn find(&self, label: &str) -> Option<usize> {
+        self.branches
+            .iter()
+            .position(|b| b.as_ref().is_some_and(|(l, _)| l == label))
+    }
+    fn active_el(&self) -> Option<&(String, S)> {
+        self.branches.get(self.active?)?.as_ref()
+    }
+}
+
+impl<S> Drop for BranchingAtom<S> {
+    fn drop(&mut self) {}
+}
+
+impl<S: Debug> Debug for BranchingAtom<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BranchingAtom")
+            .field("root", &self.root)
+            .field("active", &self.active_branch())
+            .field("state", self.state())
+            .finish()
+    }
+}
+
+impl<S> Atom for BranchingAtom<S> {
+    type Undo = S;
+    type Decay = S;
+    /// Returns the root state
+    fn undo(mut self) -> Self::Undo {
+        self.root.take().unwrap()
+    }
+    /// Returns the state of the active branch
+    fn decay(mut self) -> Self::Decay {
+        match self.active.and_then(|i| self.branches[i].take()) {
+            Some((_, s)) => s,
+            None => self.root.take().unwrap(),
+        }
+    }
+}
+
+/// A vector that records each change, so it can be reverted to any earlier [`snapshot`]
+///
+/// ```
+/// use rewind::VecSnapshot;
+/// let mut items = VecSnapshot::new();
+/// items.push(1);
+/// let start = items.snapshot();
+/// items.push(2);
+/// items.pop();
+/// items.pop();
+/// items.revert_to(start);
+/// assert_eq!(*items, vec![1]);
+/// ```
+///
+/// [`snapshot`]: VecSnapshot::snapshot
+pub struct VecSnapshot<T> {
+    items: Vec<T>,
+    ops: Vec<VecOp<T>>,
+}
+
+/// A change recorded by a [`VecSnapshot`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VecOp<T> {
+    Push(T),
+    Pop(T),
+}
+
+impl<T: Clone> VecSnapshot<T> {
+    pub fn new() -> Self {
+        Self {
+            items: Vec::new(),
+            ops: Vec::new(),
+        }
+    }
+    pub fn push(&mut self, value: T) {
+        self.ops.push(VecOp::Push(value.clone()));
+        self.items.push(value);
+    }
+    pub fn pop(&mut self) -> Option<T> {
+        let value = self.items.pop()?;
+        self.ops.push(VecOp::Pop(value.clone()));
+        Some(value)
+    }
+    /// The number of changes so far, to revert back to with [`revert_to`](Self::revert_to)
+    pub fn snapshot(&self) -> usize {
+        self.ops.len()
+    }
+    /// Undo every change made after `snapshot`, returning them with the latest first
+    ///
+    /// Does nothing if `snapshot` is not before the current one
+    pub fn revert_to(&mut self, snapshot: usize) -> Vec<VecOp<T>> {
+        let reverted: Vec<_> = self
+            .ops
+            .drain(snapshot.min(self.ops.len())..)
+            .rev()
+            .collect();
+        for op in &reverted {
+            match op {
+                VecOp::Push(_) => {
+                    self.items.pop();
+                }
+                VecOp::Pop(v) => self.items.push(v.clone()),
+            }
+        }
+        reverted
+    }
+    pub fn into_vec(self) -> Vec<T> {
+        self.items
+    }
+}
+
+impl<T: Clone> Default for VecSnapshot<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Deref for VecSnapshot<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.items
+    }
+}
+
+impl<T: Debug> Debug for VecSnapshot<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VecSnapshot")
+            .field("items", &self.items)
+            .field("snapshot", &self.ops.len())
+            .finish()
+    }
+}
+
+/// An atom which isn't created until it is first used, so it can be set up in advance
+///
+/// Dereferencing, [`execute`](Self::execute), undoing or decaying it runs `forward` to create
+/// the atom, if it hasn't already. Undo on drop always applies: dropping it before then still
+/// runs `forward`, and then drops the result which undoes it.
+///
+/// ```
+/// # use rewind::Atom;
+/// use rewind::DelayedAtom;
+/// let mut items = rewind::encase(vec![1]);
+/// let mut target = items.clone();
+/// let push = DelayedAtom::new(move || target.peel_mut(|i| i.push(2), |i, _| drop(i.pop())));
+/// assert!(!push.is_executed());
+/// assert_eq!(*items.borrow_state(), vec![1]);
+/// let push = push.execute();
+/// assert_eq!(*items.borrow_state(), vec![1, 2]);
+/// push.undo();
+/// assert_eq!(*items.borrow_state(), vec![1]);
+/// ```
+pub struct DelayedAtom<A: Atom, F: FnOnce() -> A> {
+    value: OnceCell<A>,
+    forward: Cell<Option<F>>,
+}
+
+impl<A: Atom, F: FnOnce() -> A> DelayedAtom<A, F> {
+    pub fn new(forward: F) -> Self {
+        Self {
+            value: OnceCell::new(),
+            forward: Cell::new(Some(forward)),
+        }
+    }
+    /// Whether `forward` has been run yet
+    pub fn is_executed(&self) -> bool {
+        self.value.get().is_some()
+    }
+    /// Run `forward` now if it hasn't been, returning the created atom
+    pub fn execute(mut self) -> A {
+        self.force();
+        self.value.take().expect("just set")
+    }
+    fn force(&self) -> &A {
+        self.value.get_or_init(|| {
+            let forward = self.forward.take().expect("only taken when executed");
+            forward()
+        })
+    }
+}
+
+impl<A: Atom, F: FnOnce() -> A> Drop for DelayedAtom<A, F> {
+    fn drop(&mut self) {
+        // Dropping the created atom undoes it
+        if let Some(forward) = self.forward.take() {
+            drop(forward());
+        }
+    }
+}
+
+impl<A: Atom, F: FnOnce() -> A> Deref for DelayedAtom<A, F> {
+    type Target = A;
+
+    fn deref(&self) -> &Self::Target {
+        self.force()
+    }
+}
+
+impl<A: Atom + Debug, F: FnOnce() -> A> Debug for DelayedAtom<A, F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("DelayedAtom")
+            .field(&self.value.get())
+            .finish()
+    }
+}
+
+impl<A: Atom, F: FnOnce() -> A> Atom for DelayedAtom<A, F> {
+    type Undo = A::Undo;
+    type Decay = A::Decay;
+    fn undo(self) -> Self::Undo {
+        self.execute().undo()
+    }
+    fn decay(self) -> Self::Decay {
+        self.execute().decay()
+    }
+}
+
+/// An undo action with no data, see [`fn_atom`](rewind::fn_atom)
+pub struct FnAtom<Undo: FnOnce()> {
+    undo: Option<Undo>,
+}
+
+impl<Undo: FnOnce()> FnAtom<Undo> {
+    pub(crate) fn new(undo: Undo) -> Self {
+        Self { undo: Some(undo) }
+    }
+}
+
+impl<Undo: FnOnce()> Drop for FnAtom<Undo> {
+    fn drop(&mut self) {
+        if let Some(undo) = self.undo.take() {
+            undo();
+        }
+    }
+}
+
+impl<Undo: FnOnce()> Debug for FnAtom<Undo> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FnAtom").finish_non_exhaustive()
+    }
+}
+
+impl<Undo: FnOnce()> Atom for FnAtom<Undo> {
+    type Undo = ();
+    type Decay = ();
+    fn undo(self) -> Self::Undo {}
+    fn decay(mut self) -> Self::Decay {
+        self.undo.take();
+    }
+}
+
+/// An atom which does nothing, see [`noop`](rewind::noop)
+///
+/// This cannot be [`Copy`] since every atom implements [`Drop`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct NoopAtom;
+
+impl Drop for NoopAtom {
+    fn drop(&mut self) {}
+}
+
+impl Atom for NoopAtom {
+    type Undo = ();
+    type Decay = ();
+    fn undo(self) -> Self::Undo {}
+    fn decay(self) -> Self::Decay {}
+}
+
+/// Runs a cleanup on borrowed state unless disarmed, see [`guard`](rewind::guard)
+pub struct Guard<'a, S, Cleanup: FnOnce(&mut S)> {
+    state: &'a mut S,
+    cleanup: Option<Cleanup>,
+}
+
+impl<'a, S, Cleanup: FnOnce(&mut S)> Guard<'a, S, Cleanup> {
+    pub(crate) fn new(state: &'a mut S, cleanup: Cleanup) -> Self {
+        Self {
+            state,
+            cleanup: Some(cleanup),
+        }
+    }
+    /// Consume the guard without running the cleanup
+    pub fn disarm(self) {
+        self.decay()
+    }
+}
+
+impl<S, Cleanup: FnOnce(&mut S)> Drop for Guard<'_, S, Cleanup> {
+    fn drop(&mut self) {
+        if let Some(cleanup) = self.cleanup.take() {
+            cleanup(self.state);
+        }
+    }
+}
+
+impl<S, Cleanup: FnOnce(&mut S)> Deref for Guard<'_, S, Cleanup> {
+    type Target = S;
+
+    fn deref(&self) -> &Self::Target {
+        self.state
+    }
+}
+
+impl<S, Cleanup: FnOnce(&mut S)> DerefMut for Guard<'_, S, Cleanup> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.state
+    }
+}
+
+impl<S: Debug, Cleanup: FnOnce(&mut S)> Debug for Guard<'_, S, Cleanup> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Guard")
+            .field("state", &self.state)
+            .field("armed", &self.cleanup.is_some())
+            .finish()
+    }
+}
+
+impl<S, Cleanup: FnOnce(&mut S)> Atom for Guard<'_, S, Cleanup> {
+    type Undo = ();
+    type Decay = ();
+    /// Run the cleanup now
+    fn undo(self) -> Self::Undo {}
+    /// Same as [`disarm`](Guard::disarm)
+    fn decay(mut self) -> Self::Decay {
+        self.cleanup.take();
+    }
+}
+
+/// A value which can be snapshotted and later restored to it, usually from
+/// [`#[derive(Rewind)]`](macro@rewind::Rewind)
+///
+/// The derive also adds a `guard` method, which is a [`Guard`] restoring the snapshot taken when
+/// it was created.
+pub trait Rewind {
+    type Snapshot;
+    fn rewind_snapshot(&self) -> Self::Snapshot;
+    fn rewind_restore(&mut self, snapshot: Self::Snapshot);
+}
+
+/// Carries a value with an undo action which may fail and be retried
+///
+/// See [`retry`](rewind::retry) for examples
+pub struct RetryAtom<T, R, E, Undo: FnMut(&mut T) -> Result<R, E>> {
+    val: Option<T>,
+    undo: Option<Undo>,
+    max_retries: u32,
+    delay: Duration,
+}
+
+impl<T, R, E, Undo: FnMut(&mut T) -> Result<R, E>> RetryAtom<T, R, E, Undo> {
+    pub(crate) fn new(val: T, undo: Undo, max_retries: u32, delay: Duration) -> Self {
+        Self {
+            val: Some(val),
+            undo: Some(undo),
+            max_retries,
+            delay,
+        }
+    }
+    /// Run the undo once, giving up on it if it succeeded or ran out of retries
+    fn attempt(&mut self, attempt: u32) -> Option<Result<R, E>> {
+        let r = (self.undo.as_mut()?)(self.val.as_mut()?);
+        if r.is_ok() || attempt >= self.max_retries {
+            self.undo.take();
+            Some(r)
+        } else {
+            None
+        }
+    }
+    fn undo_mut(&mut self) -> Option<Result<R, E>> {
+        self.undo.as_ref()?;
+        let mut attempt = 0;
+        loop {
+            if let Some(r) = self.attempt(attempt) {
+                return Some(r);
+            }
+            attempt += 1;
+            std::thread::sleep(self.delay);
+        }
+    }
+    /// Undo without blocking the thread between retries
+    ///
+    /// `sleep` is used to wait the configured delay, which lets this work with any async
+    /// runtime. If the future is dropped before it completes then the remaining retries are done
+    /// synchronously when the atom is dropped.
+    pub async fn undo_async<F: Future<Output = ()>>(
+        mut self,
+        mut sleep: impl FnMut(Duration) -> F,
+    ) -> Result<R, E> {
+        let mut attempt = 0;
+        loop {
+            if let Some(r) = self.attempt(attempt) {
+                return r;
+            }
+            attempt += 1;
+            sleep(self.delay).await;
+        }
+    }
+}
+
+impl<T, R, E, Undo: FnMut(&mut T) -> Result<R, E>> Drop for RetryAtom<T, R, E, Undo> {
+    fn drop(&mut self) {
+        self.undo_mut();
+    }
+}
+
+impl<T: Debug, R, E, Undo: FnMut(&mut T) -> Result<R, E>> Debug for RetryAtom<T, R, E, Undo> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RetryAtom")
+            .field("value", &self.val)
+            .field("max_retries", &self.max_retries)
+            .field("delay", &self.delay)
+            .finish()
+    }
+}
+
+impl<T, R, E, Undo: FnMut(&mut T) -> Result<R, E>> Atom for RetryAtom<T, R, E, Undo> {
+    type Undo = Result<R, E>;
+    type Decay = T;
+    /// Returns the first success or the last error once the retries run out
+    fn undo(mut self) -> Self::Undo {
+        self.undo_mut().unwrap()
+    }
+    /// Returns the original value
+    fn decay(mut self) -> Self::Decay {
+        self.undo.take();
+        self.val.take().unwrap()
+    }
+}
+
+/// A foreign transaction which can be committed or rolled back, see [`adopt`](rewind::adopt)
+pub trait Commitable {
+    type Committed;
+    type Error: Debug;
+    fn commit(self) -> Result<Self::Committed, Self::Error>;
+    fn rollback(self);
+}
+
+/// Atom over a [`Commitable`], see [`adopt`](rewind::adopt)
+///
+/// Undoing or dropping this rolls back, decaying commits. By default a failed commit during
+/// decay panics, use [`store_errors_in`](Self::store_errors_in) to keep the error instead.
+pub struct Adopted<C: Commitable> {
+    inner: Option<C>,
+    errors: Option<Encased<Option<C::Error>>>,
+}
+
+impl<C: Commitable> Adopted<C> {
+    pub(crate) fn new(inner: C) -> Self {
+        Self {
+            inner: Some(inner),
+            errors: None,
+        }
+    }
+    /// Store the error from a failed commit in `slot` rather than panicking
+    ///
+    /// Decaying then returns `None` if the commit failed. Any error already in `slot` is
+    /// replaced.
+    pub fn store_errors_in(mut self, slot: &Encased<Option<C::Error>>) -> Self {
+        self.errors = Some(slot.clone());
+        self
+    }
+    /// Commit, returning the error directly whichever way errors are handled on decay
+    pub fn commit(mut self) -> Result<C::Committed, C::Error> {
+        self.inner
+            .take()
+            .expect("only taken when consumed")
+            .commit()
+    }
+}
+
+impl<C: Commitable> Drop for Adopted<C> {
+    fn drop(&mut self) {
+        if let Some(c) = self.inner.take() {
+            c.rollback();
+        }
+    }
+}
+
+impl<C: Commitable + Debug> Debug for Adopted<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Adopted")
+            .field("inner", &self.inner)
+            .field("stores_errors", &self.errors.is_some())
+            .finish()
+    }
+}
+
+impl<C: Commitable> Atom for Adopted<C> {
+    type Undo = ();
+    type Decay = Option<C::Committed>;
+    /// Roll back
+    fn undo(self) -> Self::Undo {}
+    /// Commit
+    ///
+    /// # Panics
+    /// If the commit fails and errors are not being stored
+    fn decay(mut self) -> Self::Decay {
+        let errors = self.errors.take();
+        match self.commit() {
+            Ok(c) => Some(c),
+            Err(e) => match errors {
+                Some(mut slot) => {
+                    slot.modify(|s| *s = Some(e));
+                    None
+                }
+                None => panic!("commit failed: {e:?}"),
+            },
+        }
+    }
+}
+
+///
+///
+/// See [`encase`](rewind::encase) for usage details
+///
+/// The state is shared through an [`Rc`], so neither this nor any [`SideEffect`] on it can be
+/// sent to another thread.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Default)]
+#[repr(transparent)]
+pub struct Encased<S>(Rc<RefCell<S>>);
+
+impl<S> Clone for Encased<S> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+/// An operation that has side effects on a shared state
+///
+/// The shared state `S` is managed via [`Encased<S>`]
+pub struct SideEffect<T, R, S, Undo: FnOnce(&mut S, T) -> R> {
+    undo: Option<Undo>,
+    value: Option<T>,
+    parent: Encased<S>,
+}
+impl<S> Encased<S> {
+    /// [`peel_mut`](Self::peel_mut) for an action which only needs to read the state
+    ///
+    /// ```
+    /// let log = rewind::encase(vec!["start"]);
+    /// let len = log.peel(|l| l.len(), |l, _| l.push("rolled back"));
+    /// assert_eq!(*len, 1);
+    /// drop(len);
+    /// assert_eq!(*log.borrow_state(), vec!["start", "rolled back"]);
+    /// ```
+    pub fn peel<R, Ru, U: FnOnce(&mut S, R) -> Ru>(
+        &self,
+        act: impl FnOnce(&S) -> R,
+        undo: U,
+    ) -> SideEffect<R, Ru, S, U> {
+        let stored = self.read(act);
+        SideEffect::with_parent(stored, undo, self.clone())
+    }
+    pub fn peel_mut<R, Ru, U: FnOnce(&mut S, R) -> Ru>(
+        &mut self,
+        act: impl FnOnce(&mut S) -> R,
+        undo: U,
+    ) -> SideEffect<R, Ru, S, U> {
+        let stored = act(&mut self.lock_state());
+        SideEffect::with_parent(stored, undo, Encased(self.0.clone()))
+    }
+    /// [`peel_mut`](Self::peel_mut) for an action which can fail, only giving a side effect if
+    /// it succeeds
+    ///
+    /// A failed action is assumed to have left the state as it was.
+    ///
+    /// ```
+    /// let mut items = rewind::encase(vec![1]);
+    /// let pop = |i: &mut Vec<i32>| i.pop().ok_or("empty");
+    /// let popped = items.peel_mut_fallible(pop, |i, v| i.push(v)).unwrap();
+    /// assert!(items.borrow_state().is_empty());
+    /// let failed = items.peel_mut_fallible(pop, |i, v| i.push(v));
+    /// assert_eq!(failed.err(), Some("empty"));
+    /// drop(popped);
+    /// assert_eq!(*items.borrow_state(), vec![1]);
+    /// ```
+    pub fn peel_mut_fallible<T, E, Ru, U: FnOnce(&mut S, T) -> Ru>(
+        &mut self,
+        act: impl FnOnce(&mut S) -> Result<T, E>,
+        undo: U,
+    ) -> Result<SideEffect<T, Ru, S, U>, E> {
+        let stored = act(&mut self.lock_state())?;
+        Ok(SideEffect::with_parent(
+            stored,
+            undo,
+            Encased(self.0.clone()),
+        ))
+    }
+    /// [`peel_mut`](Self::peel_mut) with an asynchronous action
+    ///
+    /// The state is borrowed until `act` completes, so other clones of this [`Encased`] must not
+    /// be used while it is pending. The undo is still synchronous since it may need to run on drop.
+    #[allow(clippy::await_holding_refcell_ref)]
+    pub async fn peel_mut_async<R, Ru, U: FnOnce(&mut S, R) -> Ru>(
+        &mut self,
+        act: impl AsyncFnOnce(&mut S) -> R,
+        undo: U,
+    ) -> SideEffect<R, Ru, S, U> {
+        let stored = {
+            let mut s = self.lock_state();
+            act(&mut s).await
+        };
+        SideEffect::with_parent(stored, undo, Encased(self.0.clone()))
+    }
+    /// Mutate the state without creating an undo
+    ///
+    /// This bypasses the undo system entirely, changes made here will not be reverted by any
+    /// [`SideEffect`]
+    ///
+    /// ```
+    /// let mut items = rewind::encase(vec![1, 2]);
+    /// items.modify(|i| i.push(3));
+    /// assert_eq!(items.read(|i| i.len()), 3);
+    /// ```
+    pub fn modify<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&mut S) -> R,
+    {
+        f(&mut self.lock_state())
+    }
+    /// Read the state
+    ///
+    /// Like [`modify`](Self::modify) this does not interact with the undo system
+    pub fn read<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&S) -> R,
+    {
+        f(&self.0.borrow())
+    }
+    /// Get an owned copy of the state, e.g. as a snapshot or for APIs which need ownership
+    ///
+    /// The copy is independent, later changes to the state don't show up in it.
+    ///
+    /// ```
+    /// let mut items = rewind::encase(vec![1]);
+    /// let snapshot = items.clone_state();
+    /// items.modify(|i| i.push(2));
+    /// assert_eq!(snapshot, vec![1]);
+    /// ```
+    pub fn clone_state(&self) -> S
+    where
+        S: Clone,
+    {
+        self.0.borrow().clone()
+    }
+    /// Hold exclusive access to the state until the returned guard is dropped
+    ///
+    /// This shares the borrow used by [`peel_mut`](Self::peel_mut), [`modify`](Self::modify) and
+    /// [`read`](Self::read), so calling any of them on this or any clone of it while the guard
+    /// is alive panics. The lock is not re-entrant, locking again while locked also panics.
+    ///
+    /// ```
+    /// let mut items = rewind::encase(vec![1, 2]);
+    /// {
+    ///     let mut items = items.lock();
+    ///     let total: i32 = items.iter().sum();
+    ///     items.push(total);
+    /// }
+    /// assert_eq!(items.read(|i| i.clone()), vec![1, 2, 3]);
+    /// ```
+    pub fn lock(&self) -> EncasedLock<'_, S> {
+        EncasedLock(self.lock_state())
+    }
+    /// Read access to the state until the returned guard is dropped
+    ///
+    /// Any number of these can be held at once, but locking, peeling or modifying through this
+    /// or any clone of it while one is alive panics, as does borrowing while the state is locked.
+    ///
+    /// ```
+    /// let items = rewind::encase(vec![1, 2]);
+    /// let first = items.borrow_state();
+    /// assert_eq!(first[0], 1);
+    /// assert_eq!(*items.borrow_state(), [1, 2]);
+    /// ```
+    pub fn borrow_state(&self) -> EncasedRef<'_, S> {
+        EncasedRef(
+            self.0
+                .try_borrow()
+                .expect("Encased state is already borrowed, is it locked?"),
+        )
+    }
+    fn lock_state(&self) -> RefMut<'_, S> {
+        self.0
+            .try_borrow_mut()
+            .expect("Encased state is already borrowed, is it locked?")
+    }
+    /// A reference to the state which does not keep it alive
+    ///
+    /// ```
+    /// let items = rewind::encase(vec![1]);
+    /// let weak = items.downgrade();
+    /// assert!(weak.upgrade().is_some());
+    /// drop(items);
+    /// assert!(weak.upgrade().is_none());
+    /// ```
+    pub fn downgrade(&self) -> WeakEncased<S> {
+        WeakEncased(Rc::downgrade(&self.0))
+    }
+    pub(crate) fn new(s: S) -> Self {
+        Self(Rc::new(RefCell::new(s)))
+    }
+    /// Start a [`Transaction`] of side effects which are rolled back unless it is committed
+    pub fn begin(&mut self) -> Transaction<'_, S> {
+        Transaction::new(self)
+    }
+    /// [`encase`](rewind::encase) `s`, expecting about `n` side effects to be taken from it
+    ///
+    /// Nothing is stored per side effect yet so this is the same as `encase`, the hint is for
+    /// storage added later on (e.g. observers) to allocate up front.
+    #[must_use]
+    pub fn with_capacity_hint(s: S, n: usize) -> Self {
+        // Reserved until there is storage to size
+        let _ = n;
+        Self::new(s)
+    }
+}
+
+/// A weak reference to the state of an [`Encased`], see [`Encased::downgrade`]
+///
+/// This can only get at the state by [`upgrade`](Self::upgrade)-ing, so storing it (e.g. in a
+/// callback) does not stop the state from being freed once every [`Encased`] and [`SideEffect`]
+/// on it is gone.
+pub struct WeakEncased<S>(Weak<RefCell<S>>);
+
+impl<S> WeakEncased<S> {
+    /// The [`Encased`] this was created from, if the state is still alive
+    pub fn upgrade(&self) -> Option<Encased<S>> {
+        self.0.upgrade().map(Encased)
+    }
+}
+impl<S> Clone for WeakEncased<S> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+impl<S> Debug for WeakEncased<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("WeakEncased")
+    }
+}
+
+/// Exclusive access to the state of an [`Encased`], see [`Encased::lock`]
+pub struct EncasedLock<'a, S>(RefMut<'a, S>);
+
+impl<S> Deref for EncasedLock<'_, S> {
+    type Target = S;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+impl<S> DerefMut for EncasedLock<'_, S> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+impl<S: Debug> Debug for EncasedLock<'_, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("EncasedLock").field(&*self.0).finish()
+    }
+}
+
+/// Shared access to the state of an [`Encased`], see [`Encased::borrow_state`]
+pub struct EncasedRef<'a, S>(Ref<'a, S>);
+
+impl<S> Deref for EncasedRef<'_, S> {
+    type Target = S;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+impl<S: Debug> Debug for EncasedRef<'_, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("EncasedRef").field(&*self.0).finish()
+    }
+}
+impl<T, R, S, Undo: FnOnce(&mut S, T) -> R> SideEffect<T, R, S, Undo> {
+    fn with_parent(value: T, undo: Undo, parent: Encased<S>) -> Self {
+        Self {
+            undo: Some(undo),
+            value: Some(value),
+            parent,
+        }
+    }
+    pub fn peel_mut<Rv, Ru, U: FnOnce(&mut S, Rv) -> Ru>(
+        &mut self,
+        act: impl FnOnce(&mut S) -> Rv,
+        undo: U,
+    ) -> SideEffect<Rv, Ru, S, U> {
+        self.parent.peel_mut(act, undo)
+    }
+    /// Split into the value, the state and the undo without running the undo
+    ///
+    /// The parts can be put back together with [`from_parts`](Self::from_parts)
+    ///
+    /// ```
+    /// let mut items = rewind::encase(vec![1, 2]);
+    /// let popped = items.peel_mut(|i| i.pop(), |i, v| i.extend(v));
+    /// let (value, mut state, undo) = popped.into_parts();
+    /// assert_eq!(value, Some(2));
+    /// undo(&mut state.lock(), value);
+    /// assert_eq!(*items.borrow_state(), vec![1, 2]);
+    /// ```
+    pub fn into_parts(mut self) -> (T, Encased<S>, Undo) {
+        let undo = self.undo.take().expect("only taken when consumed");
+        let value = self.value.take().expect("only taken when consumed");
+        (value, self.parent.clone(), undo)
+    }
+    /// Run the undo with the state borrowed through the [`RefCell`], so that it panics rather
+    /// than aliasing the state if it is locked elsewhere
+    fn undo_mut(&mut self) -> Option<R> {
+        let (undo, value) = (self.undo.take()?, self.value.take()?);
+        Some(self.parent.modify(|s| undo(s, value)))
+    }
+    /// Create a side effect on `parent` from its parts, see [`into_parts`](Self::into_parts)
+    pub fn from_parts(value: T, parent: Encased<S>, undo: Undo) -> Self {
+        Self::with_parent(value, undo, parent)
+    }
+}
+
+impl<T, E, R, S, Undo: FnOnce(&mut S, Result<T, E>) -> R> SideEffect<Result<T, E>, R, S, Undo> {
+    /// Only keep the side effect if the operation succeeded, with the undo given the `Ok` value
+    ///
+    /// A failed operation is taken to have changed nothing, so its side effect is decayed.
+    ///
+    /// ```
+    /// # use rewind::Atom;
+    /// let mut items = rewind::encase(vec![1]);
+    /// let popped = items
+    ///     .peel_mut(|i| i.pop().ok_or("empty"), |i, v| i.extend(v.ok()))
+    ///     .transpose()
+    ///     .unwrap();
+    /// assert_eq!(*popped, 1);
+    /// popped.undo();
+    /// assert_eq!(*items.borrow_state(), vec![1]);
+    /// ```
+    #[allow(clippy::type_complexity)]
+    pub fn transpose(self) -> Result<SideEffect<T, R, S, impl FnOnce(&mut S, T) -> R>, E> {
+        let (value, parent, undo) = self.into_parts();
+        match value {
+            Ok(value) => Ok(SideEffect::from_parts(value, parent, move |s, v| {
+                undo(s, Ok(v))
+            })),
+            Err(e) => Err(e),
+        }
+    }
+    /// Decay the side effect, giving back the result
+    ///
+    /// Like [`transpose`](Self::transpose) this takes a failure to have changed nothing, the
+    /// undo is not run either way. See [`undo_err`](Self::undo_err) to run it for failures.
+    pub fn decay_ok(self) -> Result<T, E> {
+        self.decay()
+    }
+    /// Undo the side effect if the operation failed, decaying it otherwise
+    ///
+    /// Returns the result of the undo if it was run.
+    pub fn undo_err(self) -> Option<R> {
+        if self.is_err() {
+            Some(self.undo())
+        } else {
+            let _ = self.decay();
+            None
+        }
+    }
+}
+impl<T, R, S, Undo: FnOnce(&mut S, T) -> R> Deref for SideEffect<T, R, S, Undo> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.value.as_ref().expect("only taken when consumed")
+    }
+}
+impl<T, R, S, Undo: FnOnce(&mut S, T) -> R> DerefMut for SideEffect<T, R, S, Undo> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.value.as_mut().expect("only taken when consumed")
+    }
+}
+impl<T, R, S, Undo: FnOnce(&mut S, T) -> R> Drop for SideEffect<T, R, S, Undo> {
+    fn drop(&mut self) {
+        self.undo_mut();
+    }
+}
+impl<T, S, R, Undo: FnOnce(&mut S, T) -> R> Atom for SideEffect<T, R, S, Undo> {
+    type Undo = R;
+    type Decay = T;
+
+    fn undo(mut self) -> Self::Undo {
+        self.undo_mut().expect("only taken when consumed")
+    }
+
+    fn decay(mut self) -> Self::Decay {
+        self.undo.take();
+        self.value.take().expect("only taken when consumed")
+    }
+}
+impl<T: Debug, S: Debug, R, Undo: FnOnce(&mut S, T) -> R> Debug for SideEffect<T, R, S, Undo> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SideEffect")
+            .field("value", &self.value)
+            .field("parent", &self.parent)
+            .finish()
+    }
+}
+
+/// [`Encased`] which can be shared between threads, see [`encase_sync`](rewind::encase_sync)
+///
+/// The state is behind an [`Arc`] and a [`Mutex`] rather than an [`Rc`] and a [`RefCell`], so it
+/// and any [`SyncSideEffect`] on it are [`Send`] and [`Sync`] whenever `S` is [`Send`]. Since
+/// undos need to run even while unwinding, a poisoned lock is used regardless.
+#[derive(Debug, Default)]
+pub struct SyncEncased<S>(Arc<Mutex<S>>);
+
+impl<S> Clone for SyncEncased<S> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<S> SyncEncased<S> {
+    pub fn new(s: S) -> Self {
+        Self(Arc::new(Mutex::new(s)))
+    }
+    /// Like [`Encased::peel_mut`], the lock is held while `act` runs
+    pub fn peel_mut<R, Ru, U: FnOnce(&mut S, R) -> Ru>(
+        &self,
+        act: impl FnOnce(&mut S) -> R,
+        undo: U,
+    ) -> SyncSideEffect<R, Ru, S, U> {
+        let stored = self.modify(act);
+        SyncSideEffect {
+            value: Some(stored),
+            undo: Some(undo),
+            parent: self.clone(),
+        }
+    }
+    /// Mutate the state without creating an undo
+    pub fn modify<R>(&self, f: impl FnOnce(&mut S) -> R) -> R {
+        f(&mut self.lock())
+    }
+    /// Read the state
+    pub fn read<R>(&self, f: impl FnOnce(&S) -> R) -> R {
+        f(&self.lock())
+    }
+    /// Hold the lock until the returned guard is dropped
+    ///
+    /// Like any [`Mutex`] this is not re-entrant, so peeling from or modifying the state on the
+    /// same thread while the guard is alive deadlocks.
+    pub fn lock(&self) -> MutexGuard<'_, S> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// [`SideEffect`] on a [`SyncEncased`], which can be moved to other threads
+///
+/// ```
+/// let counter = rewind::encase_sync(0);
+/// let bumped = counter.peel_mut(|c| *c += 1, |c, _| *c -= 1);
+/// std::thread::spawn(move || drop(bumped)).join().unwrap();
+/// assert_eq!(counter.read(|c| *c), 0);
+/// ```
+pub struct SyncSideEffect<T, R, S, Undo: FnOnce(&mut S, T) -> R> {
+    value: Option<T>,
+    undo: Option<Undo>,
+    parent: SyncEncased<S>,
+}
+
+impl<T, R, S, Undo: FnOnce(&mut S, T) -> R> Deref for SyncSideEffect<T, R, S, Undo> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.value.as_ref().expect("only taken when consumed")
+    }
+}
+impl<T, R, S, Undo: FnOnce(&mut S, T) -> R> DerefMut for SyncSideEffect<T, R, S, Undo> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.value.as_mut().expect("only taken when consumed")
+    }
+}
+impl<T, R, S, Undo: FnOnce(&mut S, T) -> R> Drop for SyncSideEffect<T, R, S, Undo> {
+    fn drop(&mut self) {
+        if let (Some(undo), Some(value)) = (self.undo.take(), self.value.take()) {
+            self.parent.modify(|s| undo(s, value));
+        }
+    }
+}
+impl<T, S, R, Undo: FnOnce(&mut S, T) -> R> Atom for SyncSideEffect<T, R, S, Undo> {
+    type Undo = R;
+    type Decay = T;
+
+    fn undo(mut self) -> Self::Undo {
+        let value = self.value.take().expect("only taken when consumed");
+        let undo = self.undo.take().expect("only taken when consumed");
+        self.parent.modify(|s| undo(s, value))
+    }
+
+    fn decay(mut self) -> Self::Decay {
+        self.undo.take();
+        self.value.take().expect("only taken when consumed")
+    }
+}
+impl<T: Debug, S: Debug, R, Undo: FnOnce(&mut S, T) -> R> Debug for SyncSideEffect<T, R, S, Undo> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SyncSideEffect")
+            .field("value", &self.value)
+            .field("parent", &self.parent)
+            .finish()
+    }
+}
+
+/// What undoing an atomic does if another thread changed it in the meantime
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Restore {
+    /// Put the old value back regardless, losing the other change
+    Force,
+    /// Only put the old value back if the atomic still holds what was written, otherwise leave
+    /// it alone
+    #[default]
+    OnlyIfUnchanged,
+}
+
+/// An atomic from [`std::sync::atomic`] which can be rolled back, see
+/// [`atomic_store`](rewind::atomic_store)
+///
+/// All accesses are [`SeqCst`](std::sync::atomic::Ordering::SeqCst).
+pub trait AtomicPrimitive: Sync {
+    type Value: Copy + PartialEq + Debug + Send + Sync;
+    fn swap(&self, value: Self::Value) -> Self::Value;
+    fn compare_exchange(
+        &self,
+        current: Self::Value,
+        new: Self::Value,
+    ) -> Result<Self::Value, Self::Value>;
+}
+
+/// An [`AtomicPrimitive`] which can be added to, see [`atomic_fetch_add`](rewind::atomic_fetch_add)
+///
+/// Additions wrap around, like [`AtomicUsize::fetch_add`](std::sync::atomic::AtomicUsize::fetch_add).
+pub trait AtomicCounter: AtomicPrimitive {
+    fn fetch_add(&self, delta: Self::Value) -> Self::Value;
+    fn fetch_sub(&self, delta: Self::Value) -> Self::Value;
+    fn wrapping_add(value: Self::Value, delta: Self::Value) -> Self::Value;
+}
+
+macro_rules! atomic_primitive {
+    ($atomic:ty, $value:ty) => {
+        impl AtomicPrimitive for $atomic {
+            type Value = $value;
+            fn swap(&self, value: $value) -> $value {
+                <$atomic>::swap(self, value, Ordering::SeqCst)
+            }
+            fn compare_exchange(&self, current: $value, new: $value) -> Result<$value, $value> {
+                <$atomic>::compare_exchange(self, current, new, Ordering::SeqCst, Ordering::SeqCst)
+            }
+        }
+    };
+    ($atomic:ty, $value:ty, counter) => {
+        atomic_primitive!($atomic, $value);
+        impl AtomicCounter for $atomic {
+            fn fetch_add(&self, delta: $value) -> $value {
+                <$atomic>::fetch_add(self, delta, Ordering::SeqCst)
+            }
+            fn fetch_sub(&self, delta: $value) -> $value {
+                <$atomic>::fetch_sub(self, delta, Ordering::SeqCst)
+            }
+            fn wrapping_add(value: $value, delta: $value) -> $value {
+                value.wrapping_add(delta)
+            }
+        }
+    };
+}
+
+atomic_primitive!(AtomicBool, bool);
+atomic_primitive!(AtomicUsize, usize, counter);
+atomic_primitive!(AtomicU64, u64, counter);
+
+/// A store to an atomic, undone by storing the previous value back according to a [`Restore`]
+/// policy, see [`atomic_store`](rewind::atomic_store)
+///
+/// Undoing gives `Ok` with the value that was replaced, or `Err` with the value that was found
+/// when [`Restore::OnlyIfUnchanged`] left it alone. Decaying gives the previous value.
+pub struct AtomicStore<'a, A: AtomicPrimitive> {
+    atomic: &'a A,
+    previous: A::Value,
+    written: A::Value,
+    policy: Restore,
+    resolved: bool,
+}
+
+impl<'a, A: AtomicPrimitive> AtomicStore<'a, A> {
+    pub(crate) fn new(atomic: &'a A, new: A::Value, policy: Restore) -> Self {
+        Self {
+            atomic,
+            previous: atomic.swap(new),
+            written: new,
+            policy,
+            resolved: false,
+        }
+    }
+    pub fn previous(&self) -> A::Value {
+        self.previous
+    }
+    fn restore(&mut self) -> Option<Result<A::Value, A::Value>> {
+        if std::mem::replace(&mut self.resolved, true) {
+            return None;
+        }
+        Some(restore(
+            self.atomic,
+            self.written,
+            self.previous,
+            self.policy,
+        ))
+    }
+}
+
+fn restore<A: AtomicPrimitive>(
+    atomic: &A,
+    expected: A::Value,
+    previous: A::Value,
+    policy: Restore,
+) -> Result<A::Value, A::Value> {
+    match policy {
+        Restore::Force => Ok(atomic.swap(previous)),
+        Restore::OnlyIfUnchanged => atomic.compare_exchange(expected, previous),
+    }
+}
+
+impl<A: AtomicPrimitive> Drop for AtomicStore<'_, A> {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+impl<A: AtomicPrimitive> Atom for AtomicStore<'_, A> {
+    type Undo = Result<A::Value, A::Value>;
+    type Decay = A::Value;
+    fn undo(mut self) -> Self::Undo {
+        self.restore().expect("only taken when consumed")
+    }
+    fn decay(mut self) -> Self::Decay {
+        self.resolved = true;
+        self.previous
+    }
+}
+
+impl<A: AtomicPrimitive> Debug for AtomicStore<'_, A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AtomicStore")
+            .field("previous", &self.previous)
+            .field("written", &self.written)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+/// An addition to an atomic counter, undone by subtracting it again, see
+/// [`atomic_fetch_add`](rewind::atomic_fetch_add)
+///
+/// With [`Restore::Force`] the delta is always subtracted, which keeps other threads' additions.
+/// With [`Restore::OnlyIfUnchanged`] the previous value is only put back if nothing changed the
+/// counter since. Undoing and decaying give the same values as [`AtomicStore`].
+pub struct AtomicFetchAdd<'a, A: AtomicCounter> {
+    atomic: &'a A,
+    previous: A::Value,
+    delta: A::Value,
+    policy: Restore,
+    resolved: bool,
+}
+
+impl<'a, A: AtomicCounter> AtomicFetchAdd<'a, A> {
+    pub(crate) fn new(atomic: &'a A, delta: A::Value, policy: Restore) -> Self {
+        Self {
+            atomic,
+            previous: atomic.fetch_add(delta),
+            delta,
+            policy,
+            resolved: false,
+        }
+    }
+    pub fn previous(&self) -> A::Value {
+        self.previous
+    }
+    fn restore(&mut self) -> Option<Result<A::Value, A::Value>> {
+        if std::mem::replace(&mut self.resolved, true) {
+            return None;
+        }
+        Some(match self.policy {
+            Restore::Force => Ok(self.atomic.fetch_sub(self.delta)),
+            Restore::OnlyIfUnchanged => {
+                let written = A::wrapping_add(self.previous, self.delta);
+                restore(self.atomic, written, self.previous, self.policy)
+            }
+        })
+    }
+}
+
+impl<A: AtomicCounter> Drop for AtomicFetchAdd<'_, A> {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+impl<A: AtomicCounter> Atom for AtomicFetchAdd<'_, A> {
+    type Undo = Result<A::Value, A::Value>;
+    type Decay = A::Value;
+    fn undo(mut self) -> Self::Undo {
+        self.restore().expect("only taken when consumed")
+    }
+    fn decay(mut self) -> Self::Decay {
+        self.resolved = true;
+        self.previous
+    }
+}
+
+impl<A: AtomicCounter> Debug for AtomicFetchAdd<'_, A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AtomicFetchAdd")
+            .field("previous", &self.previous)
+            .field("delta", &self.delta)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+/// [`Encased`] on a [`tokio::sync::Mutex`], for use in async code, see
+/// [`encase_tokio`](rewind::encase_tokio)
+///
+/// Waiting for the state never blocks the executor, except when undoing while it is locked
+/// outside of any runtime, see [`TokioSideEffect`].
+#[cfg(feature = "tokio")]
+#[derive(Debug, Default)]
+pub struct TokioEncased<S>(Arc<tokio::sync::Mutex<S>>);
+
+#[cfg(feature = "tokio")]
+impl<S> Clone for TokioEncased<S> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<S: Send + 'static> TokioEncased<S> {
+    pub fn new(s: S) -> Self {
+        Self(Arc::new(tokio::sync::Mutex::new(s)))
+    }
+    /// Like [`Encased::peel_mut`], waiting for the lock to run `act`
+    ///
+    /// The side effect remembers the runtime it was created on, if any, to undo on when dropped.
+    pub async fn peel_mut<R, Ru, U>(
+        &self,
+        act: impl FnOnce(&mut S) -> R,
+        undo: U,
+    ) -> TokioSideEffect<R, Ru, S, U>
+    where
+        R: Send + 'static,
+        Ru: 'static,
+        U: FnOnce(&mut S, R) -> Ru + Send + 'static,
+    {
+        let stored = self.modify(act).await;
+        TokioSideEffect {
+            value: Some(stored),
+            undo: Some(undo),
+            parent: self.clone(),
+            runtime: tokio::runtime::Handle::try_current().ok(),
+        }
+    }
+    /// Mutate the state without creating an undo
+    pub async fn modify<R>(&self, f: impl FnOnce(&mut S) -> R) -> R {
+        f(&mut *self.lock().await)
+    }
+    /// Read the state
+    pub async fn read<R>(&self, f: impl FnOnce(&S) -> R) -> R {
+        f(&*self.lock().await)
+    }
+    /// Hold the lock until the returned guard is dropped
+    pub async fn lock(&self) -> tokio::sync::MutexGuard<'_, S> {
+        self.0.lock().await
+    }
+}
+
+/// [`SideEffect`] on a [`TokioEncased`]
+///
+/// [`undo_async`](Self::undo_async) waits for the lock. Undoing through [`Atom::undo`] or by
+/// dropping can't wait, so it only undoes straight away if the state isn't locked. Otherwise
+/// the undo is spawned as a task on the runtime the side effect was created on, or the current
+/// one, and [`Atom::undo`] returns `None`. Outside of any runtime the thread is blocked until
+/// the lock is free instead.
+#[cfg(feature = "tokio")]
+pub struct TokioSideEffect<
+    T: Send + 'static,
+    R: 'static,
+    S: Send + 'static,
+    Undo: FnOnce(&mut S, T) -> R + Send + 'static,
+> {
+    value: Option<T>,
+    undo: Option<Undo>,
+    parent: TokioEncased<S>,
+    runtime: Option<tokio::runtime::Handle>,
+}
+
+#[cfg(feature = "tokio")]
+impl<T: Send, R, S: Send, Undo: FnOnce(&mut S, T) -> R + Send> TokioSideEffect<T, R, S, Undo> {
+    /// Undo once the lock is free
+    pub async fn undo_async(mut self) -> R {
+        let value = self.value.take().expect("only taken when consumed");
+        let undo = self.undo.take().expect("only taken when consumed");
+        self.parent.modify(|s| undo(s, value)).await
+    }
+    /// Undo now if the state isn't locked, see the type docs
+    fn undo_or_spawn(&mut self) -> Option<R> {
+        let (value, undo) = (self.value.take()?, self.undo.take()?);
+        if let Ok(mut s) = self.parent.0.try_lock() {
+            return Some(undo(&mut s, value));
+        }
+        let state = self.parent.0.clone();
+        match self
+            .runtime
+            .take()
+            .or_else(|| tokio::runtime::Handle::try_current().ok())
+        {
+            Some(runtime) => {
+                runtime.spawn(async move {
+                    undo(&mut *state.lock().await, value);
+                });
+                None
+            }
+            None => Some(undo(&mut state.blocking_lock(), value)),
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T: Send, R, S: Send, Undo: FnOnce(&mut S, T) -> R + Send> Deref
+    for TokioSideEffect<T, R, S, Undo>
+{
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.value.as_ref().expect("only taken when consumed")
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T: Send, R, S: Send, Undo: FnOnce(&mut S, T) -> R + Send> Drop
+    for TokioSideEffect<T, R, S, Undo>
+{
+    fn drop(&mut self) {
+        self.undo_or_spawn();
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T: Send, R, S: Send, Undo: FnOnce(&mut S, T) -> R + Send> Atom
+    for TokioSideEffect<T, R, S, Undo>
+{
+    type Undo = Option<R>;
+    type Decay = T;
+    /// `None` if the undo had to be spawned as a task
+    fn undo(mut self) -> Self::Undo {
+        self.undo_or_spawn()
+    }
+    fn decay(mut self) -> Self::Decay {
+        self.undo.take();
+        self.value.take().expect("only taken when consumed")
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T: Send + Debug, R, S: Send, Undo: FnOnce(&mut S, T) -> R + Send> Debug
+    for TokioSideEffect<T, R, S, Undo>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokioSideEffect")
+            .field("value", &self.value)
+            .finish()
+    }
+}
+
+impl<T, S, Undo: FnOnce(&mut S, T) + Clone> SideEffect<T, (), S, Undo> {
+    /// Make the side effect redoable, where `redo` runs the operation on the state again
+    ///
+    /// ```
+    /// # use rewind::Atom;
+    /// let mut items = rewind::encase(vec![1]);
+    /// let pushed = items
+    ///     .peel_mut(|i| i.push(2), |i, _| drop(i.pop()))
+    ///     .with_redo(|i| i.push(2));
+    /// let redo = pushed.undo();
+    /// assert_eq!(*items.borrow_state(), vec![1]);
+    /// let pushed = redo.redo();
+    /// assert_eq!(*items.borrow_state(), vec![1, 2]);
+    /// pushed.decay();
+    /// ```
+    pub fn with_redo<Re: FnOnce(&mut S) -> T + Clone>(self, redo: Re) -> Redoable<T, S, Undo, Re> {
+        Redoable {
+            effect: Some(self),
+            redo: Some(redo),
+        }
+    }
+}
+
+/// A [`SideEffect`] whose undo gives back a [`Redo`] to apply it again, see
+/// [`SideEffect::with_redo`]
+///
+/// The undo and redo are cloned for each round, so both need to be [`Clone`].
+pub struct Redoable<T, S, Undo: FnOnce(&mut S, T) + Clone, Re: FnOnce(&mut S) -> T + Clone> {
+    effect: Option<SideEffect<T, (), S, Undo>>,
+    redo: Option<Re>,
+}
+
+impl<T, S, Undo: FnOnce(&mut S, T) + Clone, Re: FnOnce(&mut S) -> T + Clone> Deref
+    for Redoable<T, S, Undo, Re>
+{
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.effect.as_ref().expect("only taken when consumed")
+    }
+}
+
+impl<T, S, Undo: FnOnce(&mut S, T) + Clone, Re: FnOnce(&mut S) -> T + Clone> Drop
+    for Redoable<T, S, Undo, Re>
+{
+    // Dropping `effect` runs the undo
+    fn drop(&mut self) {}
+}
+
+impl<T, S, Undo: FnOnce(&mut S, T) + Clone, Re: FnOnce(&mut S) -> T + Clone> Atom
+    for Redoable<T, S, Undo, Re>
+{
+    type Undo = Redo<T, S, Undo, Re>;
+    type Decay = T;
+    fn undo(mut self) -> Self::Undo {
+        let effect = self.effect.take().expect("only taken when consumed");
+        let undo = effect
+            .undo
+            .as_ref()
+            .expect("only taken when consumed")
+            .clone();
+        let parent = effect.parent.clone();
+        effect.undo();
+        Redo {
+            parent,
+            fns: Some((undo, self.redo.take().expect("only taken when consumed"))),
+            value: PhantomData,
+        }
+    }
+    fn decay(mut self) -> Self::Decay {
+        self.effect
+            .take()
+            .expect("only taken when consumed")
+            .decay()
+    }
+}
+
+impl<T: Debug, S: Debug, Undo: FnOnce(&mut S, T) + Clone, Re: FnOnce(&mut S) -> T + Clone> Debug
+    for Redoable<T, S, Undo, Re>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Redoable").field(&self.effect).finish()
+    }
+}
+
+/// Applies an undone [`Redoable`] again, with [`redo`](Self::redo)
+///
+/// Unlike other atoms, dropping or decaying this does nothing, the redo is just discarded.
+/// Undoing it is the same as [`redo`](Self::redo).
+pub struct Redo<T, S, Undo: FnOnce(&mut S, T) + Clone, Re: FnOnce(&mut S) -> T + Clone> {
+    parent: Encased<S>,
+    fns: Option<(Undo, Re)>,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T, S, Undo: FnOnce(&mut S, T) + Clone, Re: FnOnce(&mut S) -> T + Clone> Redo<T, S, Undo, Re> {
+    /// Run the operation again, giving back a [`Redoable`] to undo it
+    pub fn redo(mut self) -> Redoable<T, S, Undo, Re> {
+        let (undo, redo) = self.fns.take().expect("only taken when consumed");
+        self.parent.peel_mut(redo.clone(), undo).with_redo(redo)
+    }
+}
+
+impl<T, S, Undo: FnOnce(&mut S, T) + Clone, Re: FnOnce(&mut S) -> T + Clone> Drop
+    for Redo<T, S, Undo, Re>
+{
+    fn drop(&mut self) {}
+}
+
+impl<T, S, Undo: FnOnce(&mut S, T) + Clone, Re: FnOnce(&mut S) -> T + Clone> Atom
+    for Redo<T, S, Undo, Re>
+{
+    type Undo = Redoable<T, S, Undo, Re>;
+    type Decay = ();
+    fn undo(self) -> Self::Undo {
+        self.redo()
+    }
+    fn decay(self) -> Self::Decay {}
+}
+
+impl<T, S, Undo: FnOnce(&mut S, T) + Clone, Re: FnOnce(&mut S) -> T + Clone> Debug
+    for Redo<T, S, Undo, Re>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Redo")
+    }
+}
+
+type ChainedOps<S> = Vec<(Box<dyn Any>, Box<dyn FnOnce(&mut S, Box<dyn Any>)>)>;
+
+/// Undo `ops` newest first
+fn undo_chained<S>(parent: &mut Encased<S>, ops: ChainedOps<S>) {
+    if !ops.is_empty() {
+        parent.modify(|s| ops.into_iter().rev().for_each(|(v, undo)| undo(s, v)));
+    }
+}
+
+/// Collects several operations on an [`Encased`] into a single [`ChainedSideEffect`]
+///
+/// Each operation is applied as soon as it is added. If the chain is dropped before
+/// [`into_atom`](Self::into_atom) is called, the operations added so far are undone.
+///
+/// ```
+/// # use rewind::Atom;
+/// let items = rewind::encase(vec![1]);
+/// let mut chain = rewind::SideEffectChain::new(&items);
+/// chain
+///     .add(|i| i.push(2), |i, _| { i.pop(); })
+///     .add(|i| i.remove(0), |i, v| i.insert(0, v));
+/// let moved = chain.into_atom();
+/// assert_eq!(*items.borrow_state(), vec![2]);
+/// moved.undo();
+/// assert_eq!(*items.borrow_state(), vec![1]);
+/// ```
+pub struct SideEffectChain<S> {
+    parent: Encased<S>,
+    ops: ChainedOps<S>,
+}
+
+impl<S> SideEffectChain<S> {
+    pub fn new(parent: &Encased<S>) -> Self {
+        Self {
+            parent: parent.clone(),
+            ops: Vec::new(),
+        }
+    }
+    /// Apply `act` to the state, registering `undo` to be run with its result
+    pub fn add<T: 'static>(
+        &mut self,
+        act: impl FnOnce(&mut S) -> T,
+        undo: impl FnOnce(&mut S, T) + 'static,
+    ) -> &mut Self {
+        let v = self.parent.modify(act);
+        self.ops.push((
+            Box::new(v),
+            Box::new(move |s, v| undo(s, *v.downcast().expect("stored with this undo"))),
+        ));
+        self
+    }
+    /// Number of operations added so far
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+    /// Finish the chain, all the operations are undone or decayed together from now on
+    pub fn into_atom(mut self) -> ChainedSideEffect<S> {
+        ChainedSideEffect {
+            parent: self.parent.clone(),
+            ops: std::mem::take(&mut self.ops),
+        }
+    }
+}
+
+impl<S> Drop for SideEffectChain<S> {
+    fn drop(&mut self) {
+        undo_chained(&mut self.parent, std::mem::take(&mut self.ops));
+    }
+}
+
+impl<S> Debug for SideEffectChain<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SideEffectChain")
+            .field("operations", &self.ops.len())
+            .finish()
+    }
+}
+
+/// Several operations on an [`Encased`] undone as one, see [`SideEffectChain`]
+///
+/// The operations are undone newest first, and decaying gives back the results of the operations
+/// in the order they were added.
+pub struct ChainedSideEffect<S> {
+    parent: Encased<S>,
+    ops: ChainedOps<S>,
+}
+
+impl<S> Drop for ChainedSideEffect<S> {
+    fn drop(&mut self) {
+        undo_chained(&mut self.parent, std::mem::take(&mut self.ops));
+    }
+}
+
+impl<S> Debug for ChainedSideEffect<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChainedSideEffect")
+            .field("operations", &self.ops.len())
+            .finish()
+    }
+}
+
+impl<S> Atom for ChainedSideEffect<S> {
+    type Undo = ();
+    type Decay = Vec<Box<dyn Any>>;
+    fn undo(self) -> Self::Undo {}
+    fn decay(mut self) -> Self::Decay {
+        std::mem::take(&mut self.ops)
+            .into_iter()
+            .map(|(v, _)| v)
+            .collect()
+    }
+}
+
+/// Turns each item of an iterator into an atom as it is yielded, see
+/// [`atom_iter`](rewind::atom_iter)
+///
+/// The atoms are given to the caller, use [`with_stack`](Self::with_stack) to push them onto a
+/// [`Stack`] instead.
+#[derive(Debug, Clone)]
+pub struct AtomIter<I, F> {
+    iter: I,
+    factory: F,
+}
+
+impl<I, F> AtomIter<I, F> {
+    pub(crate) fn new(iter: I, factory: F) -> Self {
+        Self { iter, factory }
+    }
+    /// Push each atom onto `stack` as it is made, yielding its handle
+    ///
+    /// ```
+    /// use rewind::{stack::Stack, AtomIterExt};
+    /// let mut items = rewind::encase(vec![]);
+    /// let mut stack = Stack::new();
+    /// let mut target = items.clone();
+    /// let pushed = [1, 2, 3]
+    ///     .atom_iter(move |v| target.peel_mut(|i| i.push(v), |i, _| drop(i.pop())))
+    ///     .with_stack(&mut stack)
+    ///     .count();
+    /// assert_eq!((pushed, items.clone_state()), (3, vec![1, 2, 3]));
+    /// stack.undo_all();
+    /// assert!(items.clone_state().is_empty());
+    /// ```
+    pub fn with_stack(self, stack: &mut Stack) -> StackedAtomIter<'_, I, F> {
+        StackedAtomIter { inner: self, stack }
+    }
+}
+
+impl<I: Iterator, A: Atom, F: FnMut(I::Item) -> A> Iterator for AtomIter<I, F> {
+    type Item = A;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(&mut self.factory)
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+/// An [`AtomIter`] pushing its atoms onto a [`Stack`], see [`AtomIter::with_stack`]
+#[derive(Debug)]
+pub struct StackedAtomIter<'a, I, F> {
+    inner: AtomIter<I, F>,
+    stack: &'a mut Stack,
+}
+
+impl<I: Iterator, A: Atom + 'static, F: FnMut(I::Item) -> A> Iterator for StackedAtomIter<'_, I, F>
+where
+    A::Undo: 'static,
+    A::Decay: 'static,
+{
+    type Item = AtomHandle;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let atom = self.inner.next()?;
+        Some(self.stack.push(atom))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// Adds [`atom_iter`](Self::atom_iter) to anything which can be iterated
+pub trait AtomIterExt: IntoIterator + Sized {
+    /// See [`atom_iter`](rewind::atom_iter)
+    fn atom_iter<A: Atom, F: FnMut(Self::Item) -> A>(
+        self,
+        factory: F,
+    ) -> AtomIter<Self::IntoIter, F> {
+        AtomIter::new(self.into_iter(), factory)
+    }
+}
+
+impl<I: IntoIterator> AtomIterExt for I {}
+
+/// An undo action that can be cancelled
+///
+/// Implementors should implement [`Drop`] as `self.undo();`, although unfortunately since [`Drop`] cannot
+/// be implemented on a generic this cannot be enforced
+#[allow(drop_bounds)]
+pub trait Atom: Drop {
+    type Undo;
+    type Decay;
+    /// Undo the operation
+    ///
+    /// ```
+    /// # use rewind::Atom;
+    /// let mut items = rewind::own_id(vec!["hello", "world"]);
+    /// items.push("wow");
+    /// let items = items.undo();
+    /// assert_eq!(items.len(), 2);
+    /// ```
+    fn undo(self) -> Self::Undo;
+    /// Forget about how to undo
+    ///
+    /// ```
+    /// # use rewind::Atom;
+    /// let mut items = rewind::own_id(vec!["hello", "world"]);
+    /// items.push("wow");
+    /// let items = items.decay();
+    /// assert_eq!(items.len(), 3);
+    /// ```
+    fn decay(self) -> Self::Decay;
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    #[test]
+    fn simple_and_owned_can_move_between_threads() {
+        let simple = Simple::new(vec![1], |mut v: Vec<i32>| {
+            v.push(2);
+            v
+        });
+        let mut owned = Owning::new(vec![1], |v| v);
+        owned.push(2);
+        assert_send_sync(&simple);
+        assert_send_sync(&owned);
+        let (undone, owned) = std::thread::spawn(move || (simple.undo(), owned.undo()))
+            .join()
+            .unwrap();
+        assert_eq!(undone, vec![1, 2]);
+        assert_eq!(owned, vec![1]);
+    }
+
+    #[test]
+    fn guards_roll_back_when_dropped_on_other_threads() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let rolled_back = Arc::new(AtomicBool::new(false));
+        let flag = rolled_back.clone();
+        let guard = Simple::new((), move |_| flag.store(true, Ordering::SeqCst));
+        std::thread::spawn(move || drop(guard)).join().unwrap();
+        assert!(rolled_back.load(Ordering::SeqCst));
+
+        let items = SyncEncased::new(vec![1]);
+        let kept = items.peel_mut(|i| i.push(2), |i, _| i.pop());
+        assert_send_sync(&kept);
+        let dropped = items.peel_mut(|i| i.push(3), |i, _| i.pop());
+        std::thread::spawn(move || {
+            drop(dropped);
+            kept.decay();
+        })
+        .join()
+        .unwrap();
+        assert_eq!(items.read(|i| i.clone()), vec![1, 2]);
+    }
+
+    #[test]
+    fn observers_see_every_change_and_the_undo() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut items = ObservableOwning::new(vec![1], |v| v);
+        let log = seen.clone();
+        items.observe(move |v: &Vec<i32>| log.borrow_mut().push(v.clone()));
+        for i in 2..=4 {
+            items.get_mut().push(i);
+        }
+        assert_eq!(*items, [1, 2, 3, 4]);
+        {
+            let mut items = items.get_mut();
+            items.pop();
+            items.pop();
+        }
+        assert_eq!(items.undo(), [1]);
+        assert_eq!(
+            *seen.borrow(),
+            [
+                vec![1, 2],
+                vec![1, 2, 3],
+                vec![1, 2, 3, 4],
+                vec![1, 2],
+                vec![1]
+            ]
+        );
+
+        let mut items = ObservableOwning::new(vec![1], |v| v);
+        let log = seen.clone();
+        items.observe(move |v: &Vec<i32>| log.borrow_mut().push(v.clone()));
+        items.get_mut().push(5);
+        assert_eq!(items.decay(), [1, 5]);
+        assert_eq!(seen.borrow().last(), Some(&vec![1, 5]));
+    }
+
+    #[test]
+    fn atoms_are_ordered_by_value_alone() {
+        let undone = Rc::new(RefCell::new(Vec::new()));
+        let mut heap = std::collections::BinaryHeap::new();
+        for v in [5u32, 12, 1, 7] {
+            let undone = undone.clone();
+            heap.push(Simple::new(v, move |v| undone.borrow_mut().push(v)));
+        }
+        let max = heap.pop().unwrap();
+        assert_eq!(max.value(), &12);
+        max.undo();
+        assert_eq!(heap.peek().map(|a| *a.value()), Some(7));
+        assert_eq!(*undone.borrow(), [12]);
+        drop(heap);
+        undone.borrow_mut().sort();
+        assert_eq!(*undone.borrow(), [1, 5, 7, 12]);
+
+        let different: [fn(i32) -> i32; 2] = [|v| v, |v| v + 1];
+        assert_eq!(Simple::new(3, different[0]), Simple::new(3, different[1]));
+        let id = |v| v;
+        let mut a = Owning::new(1, id);
+        let b = Owning::new(2, id);
+        assert!(a < b);
+        *a = 3;
+        assert!(a > b);
+        assert_eq!(a.undo(), 1);
+    }
+
+    #[test]
+    fn cloned_state_is_independent_of_the_encased() {
+        let mut items = Encased::new(vec![1, 2]);
+        let mut snapshot = items.clone_state();
+        let pushed = items.peel_mut(|i| i.push(3), |i, _| i.pop());
+        items.modify(|i| i[0] = 10);
+        assert_eq!(snapshot, [1, 2]);
+        snapshot.clear();
+        assert_eq!(items.clone_state(), [10, 2, 3]);
+        drop(pushed);
+        assert_eq!(items.clone_state(), [10, 2]);
+        assert!(snapshot.is_empty());
+    }
+
+    #[test]
+    fn paired_atoms_resolve_together_or_apart() {
+        let undone = Rc::new(RefCell::new(Vec::new()));
+        let atom = |v: i32| {
+            let undone = undone.clone();
+            Simple::new(v, move |v| {
+                undone.borrow_mut().push(v);
+                v * 10
+            })
+        };
+        assert_eq!(PairedAtom::new(atom(1), atom(2)).undo(), (10, 20));
+        assert_eq!(*undone.borrow(), [2, 1]);
+
+        let (first, second) = PairedAtom::new(atom(3), atom(4)).undo_first();
+        assert_eq!(first, 30);
+        assert_eq!(*undone.borrow(), [2, 1, 3]);
+        assert_eq!(second.decay(), 4);
+
+        let (first, second) = PairedAtom::new(atom(5), atom(6)).undo_second();
+        assert_eq!((second, undone.borrow().len()), (60, 4));
+        assert_eq!(first.undo(), 50);
+
+        let (first, second) = PairedAtom::new(atom(7), atom(8)).split();
+        assert_eq!(undone.borrow().len(), 5);
+        drop(first);
+        drop(second);
+        drop(PairedAtom::new(atom(9), atom(10)));
+        assert_eq!(*undone.borrow(), [2, 1, 3, 6, 5, 7, 8, 10, 9]);
+        assert_eq!(PairedAtom::new(atom(11), atom(12)).decay(), (11, 12));
+        assert_eq!(undone.borrow().len(), 9);
+    }
+
+    #[test]
+    fn atom_iters_roll_back_everything_they_pushed() {
+        let items = Encased::new(vec![0]);
+        let mut target = items.clone();
+        let mut push = move |v: i32| {
+            target.peel_mut(
+                |i| {
+                    i.push(v);
+                    v
+                },
+                |i, v| i.retain(|x| *x != v),
+            )
+        };
+        let mut atoms = vec![1, 2, 3].atom_iter(&mut push).collect::<Vec<_>>();
+        assert_eq!(items.clone_state(), [0, 1, 2, 3]);
+        atoms.pop().unwrap().decay();
+        drop(atoms);
+        assert_eq!(items.clone_state(), [0, 3]);
+
+        let mut stack = Stack::new();
+        let handles: Vec<_> = (4..8).atom_iter(push).with_stack(&mut stack).collect();
+        assert_eq!(handles.len(), 4);
+        assert_eq!(items.clone_state(), [0, 3, 4, 5, 6, 7]);
+        stack.undo_all();
+        assert_eq!(items.clone_state(), [0, 3]);
+    }
+
+    #[test]
+    fn atomic_undos_follow_their_restore_policy_after_a_race() {
+        let flag = AtomicU64::new(1);
+        let race = |atomic: &AtomicU64| {
+            std::thread::scope(|s| {
+                s.spawn(|| atomic.store(7, Ordering::SeqCst));
+            })
+        };
+        let stored = AtomicStore::new(&flag, 2, Restore::OnlyIfUnchanged);
+        assert_send_sync(&stored);
+        race(&flag);
+        assert_eq!(stored.undo(), Err(7));
+        assert_eq!(flag.load(Ordering::SeqCst), 7);
+        let stored = AtomicStore::new(&flag, 2, Restore::Force);
+        race(&flag);
+        assert_eq!(stored.undo(), Ok(7));
+        assert_eq!(flag.load(Ordering::SeqCst), 7);
+        drop(AtomicStore::new(&flag, 3, Restore::OnlyIfUnchanged));
+        assert_eq!(flag.load(Ordering::SeqCst), 7);
+
+        let hits = AtomicUsize::new(10);
+        let counted = AtomicFetchAdd::new(&hits, 5, Restore::OnlyIfUnchanged);
+        std::thread::scope(|s| {
+            s.spawn(|| hits.fetch_add(1, Ordering::SeqCst));
+        });
+        assert_eq!(counted.undo(), Err(16));
+        let counted = AtomicFetchAdd::new(&hits, 5, Restore::Force);
+        std::thread::scope(|s| {
+            s.spawn(|| hits.fetch_add(1, Ordering::SeqCst));
+        });
+        assert_eq!(counted.undo(), Ok(22));
+        assert_eq!(hits.load(Ordering::SeqCst), 17);
+        assert_eq!(AtomicFetchAdd::new(&hits, 3, Restore::Force).decay(), 17);
+        assert_eq!(hits.load(Ordering::SeqCst), 20);
+
+        let ready = AtomicBool::new(false);
+        drop(AtomicStore::new(&ready, true, Restore::OnlyIfUnchanged));
+        assert!(!ready.load(Ordering::SeqCst));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn tokio_side_effects_undo_once_the_lock_is_free() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let items = TokioEncased::new(vec![1]);
+            let pushed = items.peel_mut(|i| i.push(2), |i, _| i.pop()).await;
+            let dropped = items.peel_mut(|i| i.push(3), |i, _| i.pop()).await;
+            let (locked, holding) = tokio::sync::oneshot::channel();
+            let held = items.clone();
+            let holder = tokio::spawn(async move {
+                let _guard = held.lock().await;
+                locked.send(()).unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            });
+            holding.await.unwrap();
+            drop(dropped);
+            assert_eq!(items.0.try_lock().map(|_| ()).ok(), None);
+            holder.await.unwrap();
+            assert_eq!(items.read(|i| i.clone()).await, vec![1, 2]);
+
+            let held = items.clone();
+            let guard = held.lock().await;
+            let undo = tokio::spawn(pushed.undo_async());
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            assert_eq!(*guard, vec![1, 2]);
+            drop(guard);
+            assert_eq!(undo.await.unwrap(), Some(2));
+            assert_eq!(items.read(|i| i.clone()).await, vec![1]);
+        });
+    }
+
+    fn flaky(fails: u32) -> impl FnMut(&mut Vec<u32>) -> Result<usize, u32> {
+        move |attempts| {
+            attempts.push(attempts.len() as u32);
+            if attempts.len() as u32 > fails {
+                Ok(attempts.len())
+            } else {
+                Err(attempts.len() as u32)
+            }
+        }
+    }
+
+    #[test]
+    fn retry_undoes_after_transient_failures() {
+        let ok = RetryAtom::new(Vec::new(), flaky(2), 2, Duration::ZERO);
+        assert_eq!(ok.undo(), Ok(3));
+        let failed = RetryAtom::new(Vec::new(), flaky(2), 1, Duration::ZERO);
+        assert_eq!(failed.undo(), Err(2));
+    }
+
+    #[test]
+    fn retry_keeps_trying_on_drop() {
+        let calls = Rc::new(RefCell::new(0));
+        let c = calls.clone();
+        drop(RetryAtom::new(
+            (),
+            move |_| {
+                *c.borrow_mut() += 1;
+                if *c.borrow() < 3 {
+                    Err(())
+                } else {
+                    Ok(())
+                }
+            },
+            5,
+            Duration::ZERO,
+        ));
+        assert_eq!(*calls.borrow(), 3);
+    }
+
+    #[test]
+    fn encased_with_capacity_hint_acts_like_encase() {
+        let mut items = Encased::with_capacity_hint(vec![1], 16);
+        let pushed = items.peel_mut(
+            |i| i.push(2),
+            |i, _| {
+                i.pop();
+            },
+        );
+        assert_eq!(items.read(|i| i.len()), 2);
+        let len = items.peel(|i| i.len(), |i, _| i.push(3));
+        items.modify(|i| i.push(4));
+        drop(len);
+        assert_eq!(*items.lock(), vec![1, 2, 4, 3]);
+        drop(pushed);
+        assert_eq!(*items.borrow_state(), vec![1, 2, 4]);
+        let weak = items.downgrade();
+        drop(items);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn weak_encased_upgrades_while_state_is_alive() {
+        let mut items = crate::encase(vec![1]);
+        let weak = items.downgrade();
+        let pushed = items.peel_mut(|i| i.push(2), |i, _| i.pop());
+        drop(items);
+        let mut upgraded = weak.upgrade().expect("side effect keeps the state alive");
+        assert_eq!(*upgraded.borrow_state(), vec![1, 2]);
+        drop(pushed);
+        assert_eq!(*upgraded.borrow_state(), vec![1]);
+        upgraded.modify(|i| i.clear());
+        drop(upgraded);
+        assert!(weak.upgrade().is_none());
+        assert!(weak.clone().upgrade().is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Encased state is already borrowed")]
+    fn peeling_while_locked_panics() {
+        let mut items = Encased::new(vec![1]);
+        let other = items.clone();
+        let _lock = other.lock();
+        items.peel_mut(|i| i.push(2), |i, _| i.pop());
+    }
+
+    #[test]
+    #[should_panic(expected = "Encased state is already borrowed")]
+    fn lock_is_not_reentrant() {
+        let items = Encased::new(vec![1]);
+        let _lock = items.lock();
+        let _again = items.lock();
+    }
+
+    #[test]
+    fn unlocking_allows_peeling_again() {
+        let mut items = Encased::new(vec![1]);
+        items.lock().push(2);
+        let e = items.peel_mut(|i| i.push(3), |i, _| i.pop());
+        drop(e);
+        assert_eq!(items.read(|i| i.clone()), vec![1, 2]);
+    }
+
+    #[test]
+    fn guard_cleans_up_unless_disarmed() {
+        let mut items = vec![1];
+        {
+            let mut g = Guard::new(&mut items, |i| i.clear());
+            g.push(2);
+        }
+        assert!(items.is_empty());
+        let mut g = Guard::new(&mut items, |i| i.clear());
+        g.push(3);
+        g.disarm();
+        assert_eq!(items, vec![3]);
+        Guard::new(&mut items, |i| i.push(4)).undo();
+        assert_eq!(items, vec![3, 4]);
+    }
+
+    #[test]
+    fn side_effect_parts_can_be_reassembled() {
+        let mut items = Encased::new(vec![1, 2]);
+        let (value, parent, undo) = items.peel_mut(|i| i.pop(), |i, v| i.extend(v)).into_parts();
+        assert_eq!(*items.borrow_state(), vec![1]);
+        let rebuilt = SideEffect::from_parts(value.map(|v| v * 10), parent, undo);
+        drop(rebuilt);
+        assert_eq!(*items.borrow_state(), vec![1, 20]);
+    }
+
+    #[test]
+    fn string_edits_reverse_fully() {
+        let mut text = AtomStr::new("héllo");
+        let mut edits = crate::stack::Stack::new();
+        let end = text.borrow_state().len();
+        edits.push(text.insert(end, " wörld").unwrap());
+        edits.push(text.delete(0..3).unwrap());
+        edits.push(text.replace(0..3, "HELLO").unwrap());
+        assert_eq!(&*text.borrow_state(), "HELLO wörld");
+        assert_eq!(text.delete(8..9).unwrap_err(), StrError::NotCharBoundary);
+        assert_eq!(text.insert(100, "!").unwrap_err(), StrError::OutOfBounds);
+        edits.undo_all();
+        assert_eq!(&*text.borrow_state(), "héllo");
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn debug_atoms_log_their_value() {
+        use std::sync::Mutex;
+        static LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        struct Capture;
+        impl log::Log for Capture {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+            fn log(&self, record: &log::Record) {
+                if record.target().starts_with("rewind") {
+                    let line = format!("{} {} {}", record.level(), record.target(), record.args());
+                    LOGS.lock().unwrap().push(line);
+                }
+            }
+            fn flush(&self) {}
+        }
+        static LOGGER: Capture = Capture;
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        assert_eq!(crate::debug_atom(vec![1], |v| v.len()).undo(), 1);
+        assert_eq!(crate::debug_atom("kept", |_| ()).decay(), "kept");
+        drop(
+            crate::debug_atom(2, |_| ())
+                .with_prefix("seats")
+                .with_target("rewind::seats")
+                .with_level(log::Level::Info),
+        );
+        assert_eq!(
+            *LOGS.lock().unwrap(),
+            vec![
+                "DEBUG rewind undo: [1]",
+                "DEBUG rewind decay: \"kept\"",
+                "INFO rewind::seats seats: undo: 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn transposed_results_leave_the_stack_intact() {
+        #[derive(Default)]
+        struct Stack<T> {
+            els: Vec<T>,
+        }
+        impl<T> Stack<T> {
+            pub fn pop(&mut self) -> Result<T, ()> {
+                self.els.pop().ok_or(())
+            }
+        }
+        fn may_fail() -> Result<(), ()> {
+            Err(())
+        }
+        let undo = |s: &mut Stack<i32>, v: Result<i32, ()>| s.els.extend(v);
+
+        let mut s = crate::encase(Stack { els: vec![4, 5] });
+        let result = (|| {
+            let value = s.peel_mut(|s| s.pop(), undo).transpose()?;
+            let v2 = s.peel_mut(|s| s.pop(), undo).transpose()?;
+            may_fail()?;
+            println!("{}, {}", value.decay(), v2.decay());
+            Ok::<(), ()>(())
+        })();
+        assert!(result.is_err());
+        assert_eq!(s.borrow_state().els, vec![4, 5]);
+
+        let mut s = crate::encase(Stack { els: vec![4] });
+        assert_eq!(s.peel_mut(|s| s.pop(), undo).decay_ok(), Ok(4));
+        assert!(s.peel_mut(|s| s.pop(), undo).transpose().is_err());
+        assert_eq!(
+            s.peel_mut(|s| s.pop(), |_, _| "undone").undo_err(),
+            Some("undone")
+        );
+        s.lock().els.push(6);
+        assert_eq!(s.peel_mut(|s| s.pop(), undo).undo_err(), None);
+        assert!(s.borrow_state().els.is_empty());
+    }
+
+    #[test]
+    fn atom_option_undoes_every_transition() {
+        let mut opt = AtomOption::new();
+        // None -> Some
+        let inserted = opt.insert(1);
+        assert_eq!((*inserted, *opt.borrow_state()), (None, Some(1)));
+        // Some -> Some
+        let replaced = opt.replace(2);
+        assert_eq!((*replaced, *opt.borrow_state()), (Some(1), Some(2)));
+        let reinserted = opt.insert(3);
+        // Some -> None
+        let taken = opt.take();
+        assert_eq!((*taken, *opt.borrow_state()), (Some(3), None));
+        // None -> None
+        let taken_again = opt.take();
+        assert_eq!(*taken_again, None);
+        taken_again.undo();
+        assert_eq!(*opt.borrow_state(), None);
+        taken.undo();
+        assert_eq!(*opt.borrow_state(), Some(3));
+        reinserted.undo();
+        assert_eq!(*opt.borrow_state(), Some(2));
+        replaced.undo();
+        assert_eq!(*opt.borrow_state(), Some(1));
+        inserted.undo();
+        assert_eq!(*opt.borrow_state(), None);
+        assert_eq!(*AtomOption::from(Some(4)).take(), Some(4));
+    }
+
+    #[test]
+    fn deque_operations_reverse_in_order() {
+        let mut queue = AtomDeque::from(VecDeque::from([2]));
+        let mut ops = crate::stack::Stack::new();
+        ops.push(queue.push_front(1));
+        ops.push(queue.push_back(3));
+        ops.push(queue.pop_front());
+        ops.push(queue.push_front(0));
+        ops.push(queue.pop_back());
+        ops.push(queue.pop_back());
+        ops.push(queue.pop_back());
+        ops.push(queue.pop_back());
+        ops.push(queue.pop_front());
+        assert!(queue.borrow_state().is_empty());
+        ops.undo_all();
+        assert_eq!(*queue.borrow_state(), VecDeque::from([2]));
+    }
+
+    #[test]
+    fn set_undos_only_revert_actual_changes() {
+        let mut set = AtomHashSet::from(HashSet::from([1, 2]));
+        let mut ops = crate::stack::Stack::new();
+        let existing = set.insert(1);
+        assert!(!*existing);
+        ops.push(existing);
+        ops.push(set.insert(3));
+        ops.push(set.remove(&4));
+        ops.push(set.remove(&2));
+        assert_eq!(*set.borrow_state(), HashSet::from([1, 3]));
+        let cleared = set.clear();
+        assert_eq!(*cleared, HashSet::from([1, 3]));
+        assert!(set.borrow_state().is_empty());
+        cleared.undo();
+        assert_eq!(*set.borrow_state(), HashSet::from([1, 3]));
+        ops.undo_all();
+        assert_eq!(*set.borrow_state(), HashSet::from([1, 2]));
+    }
+
+    #[test]
+    fn mapped_simple_maps_only_the_decayed_value() {
+        let mapped = Rc::new(std::cell::Cell::new(false));
+        let m = mapped.clone();
+        let atom = crate::simple(vec![1, 2], |v| v.len()).map_value(move |v| {
+            m.set(true);
+            v.into_iter().sum::<i32>()
+        });
+        assert_eq!(atom.original(), &vec![1, 2]);
+        assert_eq!(atom.undo(), 2);
+        assert!(!mapped.get());
+
+        let undone = Rc::new(std::cell::Cell::new(false));
+        let u = undone.clone();
+        let atom = crate::simple(vec![1, 2], move |_| u.set(true)).map_value(|v| v.len());
+        assert_eq!(atom.decay(), 2);
+        assert!(!undone.get());
+        drop(crate::simple(1, |_| undone.set(true)).map_value(|v| v + 1));
+        assert!(undone.get());
+    }
+
+    #[test]
+    fn branches_diverge_from_the_root() {
+        let mut tree = BranchingAtom::new(vec![1]);
+        let a = tree.branch("a", |s| [s.as_slice(), &[2]].concat());
+        let b = tree.branch("b", |s| [s.as_slice(), &[3, 4]].concat());
+        assert_eq!(tree.state(), &vec![1, 3, 4]);
+        assert!(tree.switch_branch("a"));
+        assert_eq!(tree.state(), &vec![1, 2]);
+        assert!(!tree.switch_branch("c"));
+        b.undo(&mut tree);
+        assert!(!tree.switch_branch("b"));
+        assert_eq!(tree.active_branch(), Some("a"));
+        a.undo(&mut tree);
+        assert_eq!(tree.state(), &vec![1]);
+        tree.branch("b", |s| s.iter().map(|v| v * 10).collect());
+        assert_eq!(tree.decay(), vec![10]);
+    }
+
+    #[test]
+    fn vec_snapshot_reverts_to_earlier_snapshots() {
+        let mut items = VecSnapshot::new();
+        let empty = items.snapshot();
+        items.push(1);
+        items.push(2);
+        assert_eq!(items.pop(), Some(2));
+        let one = items.snapshot();
+        items.push(3);
+        items.push(4);
+        assert_eq!(items.pop(), Some(4));
+        assert_eq!(items.pop(), Some(3));
+        assert_eq!(items.pop(), Some(1));
+        assert_eq!(items.pop(), None);
+        items.push(5);
+        let five = items.snapshot();
+        assert_eq!(*items, vec![5]);
+
+        assert!(items.revert_to(five + 1).is_empty());
+        assert_eq!(
+            items.revert_to(one),
+            vec![
+                VecOp::Push(5),
+                VecOp::Pop(1),
+                VecOp::Pop(3),
+                VecOp::Pop(4),
+                VecOp::Push(4),
+                VecOp::Push(3),
+            ]
+        );
+        assert_eq!(*items, vec![1]);
+        items.push(6);
+        items.revert_to(empty);
+        assert!(items.is_empty());
+        assert_eq!(items.snapshot(), 0);
+    }
+
+    #[test]
+    fn delayed_atoms_run_on_first_use() {
+        let runs = Rc::new(Cell::new(0));
+        let undos = Rc::new(Cell::new(0));
+        let delayed = || {
+            let (runs, undos) = (runs.clone(), undos.clone());
+            DelayedAtom::new(move || {
+                runs.set(runs.get() + 1);
+                crate::own(runs.get(), move |v| {
+                    undos.set(undos.get() + 1);
+                    v
+                })
+            })
+        };
+        let first = delayed();
+        let second = delayed();
+        assert_eq!(runs.get(), 0);
+        assert!(!second.is_executed());
+        assert_eq!(**second, 1);
+        assert_eq!(**second, 1);
+        assert!(second.is_executed());
+        assert_eq!(first.decay(), 2);
+        assert_eq!(runs.get(), 2);
+        assert_eq!(undos.get(), 0);
+
+        second.undo();
+        assert_eq!(undos.get(), 1);
+        drop(delayed());
+        assert_eq!((runs.get(), undos.get()), (3, 2));
+        let executed = delayed().execute();
+        assert_eq!(runs.get(), 4);
+        drop(executed);
+        assert_eq!(undos.get(), 3);
+    }
+
+    #[test]
+    fn simple_undo_does_not_cause_ub_on_drop() {
+        Simple::new(vec!["test"], |c| c).undo();
+    }
+    #[test]
+    fn owned_undo_does_not_cause_ub_on_drop() {
+        Owning::new(vec!["test"], |c| c).undo();
+    }
+
+    #[test]
+    fn owned_iterators_undo_to_before_iterating() {
+        let mut it = crate::own_id(vec![1, 2, 3, 4].into_iter());
+        assert_eq!(it.len(), 4);
+        assert_eq!(it.next(), Some(1));
+        assert_eq!(it.len(), 3);
+        assert_eq!(
+            it.by_ref().map(|v| v * 10).take(2).collect::<Vec<_>>(),
+            vec![20, 30]
+        );
+        assert_eq!(it.len(), 1);
+        assert_eq!(it.undo().collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+
+        let mut it = crate::own_id(vec![1, 2].into_iter());
+        it.next();
+        assert_eq!(it.decay().collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn owned_replace_keeps_undo_snapshot() {
+        let mut items = Owning::new(vec![1], |c| c);
+        items.push(2);
+        assert_eq!(items.replace(vec![3]), vec![1, 2]);
+        assert_eq!(items.replace(vec![4]), vec![3]);
+        assert_eq!(*items, vec![4]);
+        assert_eq!(items.undo(), vec![1]);
+    }
+
+    #[test]
+    fn owning_with_only_stores_the_snapshot() {
+        let mut items = OwningWith::new(
+            (0..10_000).collect::<Vec<_>>(),
+            |v| v.len(),
+            |mut v, len| {
+                v.truncate(len);
+                v
+            },
+        );
+        items.extend(0..5);
+        assert_eq!(items.len(), 10_005);
+        assert_eq!(items.undo.as_ref().map(|(len, _)| *len), Some(10_000));
+        let items = items.undo();
+        assert_eq!(items.len(), 10_000);
+        assert_eq!(items.last(), Some(&9_999));
+    }
+
+    #[test]
+    fn atom_runs_on_drop_if_uncancelled() {
+        let mut scoped = 12;
+        {
+            let atom = Simple::new(&mut scoped, |v| {
+                *v = 0;
+            });
+            drop(atom);
+        }
+        assert_eq!(scoped, 0);
+    }
+
+    #[test]
+    fn cancelling_atom_stops_it_running_on_drop() {
+        let mut scoped = 12;
+        {
+            let atom = Simple::new(&mut scoped, |s| {
+                *s = 13;
+            });
+            atom.decay();
         }
         assert_eq!(scoped, 12);
     }
+
+    #[test]
+    fn modifying_encased_is_not_undone_by_side_effects() {
+        let mut items = Encased::new(vec![1, 2]);
+        let pushed = items.peel_mut(|i| i.push(3), |i, _| i.pop());
+        items.modify(|i| i.insert(0, 0));
+        assert_eq!(items.read(|i| i.clone()), vec![0, 1, 2, 3]);
+        pushed.undo();
+        assert_eq!(items.read(|i| i.clone()), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn chained_side_effects_undo_in_reverse() {
+        let log = Encased::new(vec!["start".to_owned()]);
+        let mut chain = SideEffectChain::new(&log);
+        chain
+            .add(
+                |l| l.push("a".to_owned()),
+                |l, _| l.push("undo a".to_owned()),
+            )
+            .add(
+                |l| l.push("b".to_owned()),
+                |l, _| l.push("undo b".to_owned()),
+            )
+            .add(|l| l.len(), |l, len| l.push(format!("undo len {len}")));
+        assert_eq!(chain.len(), 3);
+        chain.into_atom().undo();
+        assert_eq!(
+            *log.borrow_state(),
+            vec!["start", "a", "b", "undo len 3", "undo b", "undo a"]
+        );
+
+        let mut chain = SideEffectChain::new(&log);
+        chain.add(|l| l.drain(..).count(), |_, _| {});
+        let counts = chain.into_atom().decay();
+        assert_eq!(counts[0].downcast_ref::<usize>(), Some(&6));
+        assert!(log.borrow_state().is_empty());
+
+        let mut chain = SideEffectChain::new(&log);
+        chain.add(
+            |l| l.push("dropped".to_owned()),
+            |l, _| {
+                l.pop();
+            },
+        );
+        drop(chain);
+        assert!(log.borrow_state().is_empty());
+    }
+
+    #[test]
+    fn adopted_transactions_resolve_with_the_stack() {
+        struct FakeTx {
+            name: &'static str,
+            fail_commit: bool,
+            log: Rc<RefCell<Vec<String>>>,
+        }
+        impl Commitable for FakeTx {
+            type Committed = &'static str;
+            type Error = String;
+            fn commit(self) -> Result<&'static str, String> {
+                if self.fail_commit {
+                    return Err(format!("{} conflicted", self.name));
+                }
+                self.log.borrow_mut().push(format!("commit {}", self.name));
+                Ok(self.name)
+            }
+            fn rollback(self) {
+                self.log
+                    .borrow_mut()
+                    .push(format!("rollback {}", self.name));
+            }
+        }
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let tx = |name, fail_commit| FakeTx {
+            name,
+            fail_commit,
+            log: log.clone(),
+        };
+        let mut stack = crate::stack::Stack::new();
+        let native = log.clone();
+        stack.push(crate::simple("write", move |w| {
+            native.borrow_mut().push(format!("un{w}"))
+        }));
+        stack.push(crate::adopt(tx("a", false)));
+        drop(stack);
+        assert_eq!(*log.borrow(), vec!["rollback a", "unwrite"]);
+        log.borrow_mut().clear();
+
+        let mut stack = crate::stack::Stack::new();
+        stack.push(crate::adopt(tx("b", false)));
+        stack.decay_all();
+        assert_eq!(*log.borrow(), vec!["commit b"]);
+
+        let errors = Encased::new(None);
+        let failed = crate::adopt(tx("c", true)).store_errors_in(&errors);
+        assert_eq!(failed.decay(), None);
+        assert_eq!(*errors.borrow_state(), Some("c conflicted".to_owned()));
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            crate::adopt(tx("d", true)).decay()
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(
+            crate::adopt(tx("e", true)).commit(),
+            Err("e conflicted".to_owned())
+        );
+    }
+
+    #[test]
+    fn atom_builders_only_undo_once_built() {
+        let undone = Rc::new(RefCell::new(Vec::new()));
+        let undo = |undone: &Rc<RefCell<Vec<i32>>>| {
+            let undone = undone.clone();
+            move |v| undone.borrow_mut().push(v)
+        };
+        drop(AtomBuilder::new(1).with_undo(undo(&undone)));
+        assert_eq!(*undone.borrow(), vec![1]);
+
+        let builder = AtomBuilder::new(2);
+        let checked = if *builder.value() > 1 {
+            Err("too big")
+        } else {
+            Ok(undo(&undone))
+        };
+        assert_eq!(builder.try_build(checked).err(), Some("too big"));
+        {
+            let _abandoned = AtomBuilder::new(3);
+        }
+        assert_eq!(*undone.borrow(), vec![1]);
+
+        let built = AtomBuilder::new(4).try_build(Ok::<_, ()>(undo(&undone)));
+        built.unwrap().undo();
+        assert_eq!(*undone.borrow(), vec![1, 4]);
+    }
+
+    #[test]
+    fn contextual_atoms_pass_context_and_value_to_the_undo() {
+        let logger = Rc::new(RefCell::new(Vec::<String>::new()));
+        let undo = |log: Rc<RefCell<Vec<String>>>, (id, name): (u32, &str)| {
+            log.borrow_mut().push(format!("removed {id} ({name})"));
+            id
+        };
+        let removed = ContextualAtom::new(logger.clone(), (3, "alice"), undo);
+        let kept = removed.context();
+        assert_eq!(removed.undo(), 3);
+        assert_eq!(*kept.borrow(), ["removed 3 (alice)"]);
+
+        drop(ContextualAtom::new(logger.clone(), (4, "bob"), undo));
+        let decayed = ContextualAtom::new(logger.clone(), (5, "carol"), undo);
+        assert_eq!(decayed.decay(), (5, "carol"));
+        assert_eq!(*logger.borrow(), ["removed 3 (alice)", "removed 4 (bob)"]);
+        assert_eq!(Rc::strong_count(&logger), 2);
+    }
 }
@@ -0,0 +1,562 @@
+use std::{collections::HashMap, hash::Hash, ops::Deref};
+
+use crate::atom::{Encased, SideEffect};
+
+/// How to reverse one change to an [`UndoVec`]
+#[derive(Debug, Clone)]
+enum VecOp<T> {
+    Pushed,
+    Popped(T),
+    Inserted(usize),
+    Removed(usize, T),
+    SwapRemoved(usize, T),
+    Set(usize, T),
+    Swapped(usize, usize),
+    Truncated(Vec<T>),
+}
+
+/// A point in the journal of an [`UndoVec`], see [`UndoVec::checkpoint`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VecCheckpoint(usize);
+
+/// A [`Vec`] which journals the inverse of every change, so that it can be rolled back
+///
+/// Unlike the atoms in [`atom`](crate::atom), changes don't each give a separate undo. They are
+/// journaled in order instead, so rolling back always reverses them newest first and indices
+/// shifted by inserts and removes stay correct. [`commit`](Self::commit) forgets the journal.
+///
+/// ```
+/// let mut items = rewind::UndoVec::from(vec![1, 2, 3]);
+/// let start = items.checkpoint();
+/// items.insert(0, 0);
+/// items.swap_remove(1);
+/// items.truncate(1);
+/// assert_eq!(*items, [0]);
+/// items.rollback(start);
+/// assert_eq!(*items, [1, 2, 3]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct UndoVec<T> {
+    items: Vec<T>,
+    journal: Vec<VecOp<T>>,
+}
+
+impl<T> UndoVec<T> {
+    pub fn new() -> Self {
+        Self::from(Vec::new())
+    }
+    pub fn push(&mut self, value: T) {
+        self.items.push(value);
+        self.journal.push(VecOp::Pushed);
+    }
+    pub fn pop(&mut self) -> Option<&T> {
+        let value = self.items.pop()?;
+        self.journal.push(VecOp::Popped(value));
+        self.last_removed()
+    }
+    /// # Panics
+    ///
+    /// If `index > len`, like [`Vec::insert`]
+    pub fn insert(&mut self, index: usize, value: T) {
+        self.items.insert(index, value);
+        self.journal.push(VecOp::Inserted(index));
+    }
+    /// Remove the element at `index`, the removed element is kept in the journal
+    ///
+    /// # Panics
+    ///
+    /// If `index` is out of bounds, like [`Vec::remove`]
+    pub fn remove(&mut self, index: usize) -> &T {
+        let value = self.items.remove(index);
+        self.journal.push(VecOp::Removed(index, value));
+        self.last_removed().expect("just journaled")
+    }
+    /// # Panics
+    ///
+    /// If `index` is out of bounds, like [`Vec::swap_remove`]
+    pub fn swap_remove(&mut self, index: usize) -> &T {
+        let value = self.items.swap_remove(index);
+        self.journal.push(VecOp::SwapRemoved(index, value));
+        self.last_removed().expect("just journaled")
+    }
+    /// Replace the element at `index`, the previous element is kept in the journal
+    ///
+    /// # Panics
+    ///
+    /// If `index` is out of bounds
+    pub fn set(&mut self, index: usize, value: T) -> &T {
+        let old = std::mem::replace(&mut self.items[index], value);
+        self.journal.push(VecOp::Set(index, old));
+        self.last_removed().expect("just journaled")
+    }
+    /// # Panics
+    ///
+    /// If either index is out of bounds, like [`slice::swap`]
+    pub fn swap(&mut self, a: usize, b: usize) {
+        self.items.swap(a, b);
+        self.journal.push(VecOp::Swapped(a, b));
+    }
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.items.len() {
+            let removed = self.items.split_off(len);
+            self.journal.push(VecOp::Truncated(removed));
+        }
+    }
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+    /// Mark the current state to [`rollback`](Self::rollback) to later
+    pub fn checkpoint(&self) -> VecCheckpoint {
+        VecCheckpoint(self.journal.len())
+    }
+    /// Reverse every change made since `checkpoint`, newest first
+    ///
+    /// # Panics
+    ///
+    /// If the journal is already shorter than at `checkpoint`, because of an earlier rollback
+    /// to before it or a [`commit`](Self::commit)
+    pub fn rollback(&mut self, checkpoint: VecCheckpoint) {
+        assert!(
+            checkpoint.0 <= self.journal.len(),
+            "checkpoint at {} is past the end of the journal ({})",
+            checkpoint.0,
+            self.journal.len()
+        );
+        while self.journal.len() > checkpoint.0 {
+            self.undo_last();
+        }
+    }
+    /// Reverse every journaled change
+    pub fn rollback_all(&mut self) {
+        self.rollback(VecCheckpoint(0));
+    }
+    /// Keep the changes, emptying the journal
+    ///
+    /// Checkpoints taken before this can no longer be rolled back to.
+    pub fn commit(&mut self) {
+        self.journal.clear();
+    }
+    /// Number of changes which can be rolled back
+    pub fn journal_len(&self) -> usize {
+        self.journal.len()
+    }
+    pub fn into_inner(self) -> Vec<T> {
+        self.items
+    }
+    fn last_removed(&self) -> Option<&T> {
+        match self.journal.last()? {
+            VecOp::Popped(v)
+            | VecOp::Removed(_, v)
+            | VecOp::SwapRemoved(_, v)
+            | VecOp::Set(_, v) => Some(v),
+            _ => None,
+        }
+    }
+    fn undo_last(&mut self) {
+        let Some(op) = self.journal.pop() else {
+            return;
+        };
+        let items = &mut self.items;
+        match op {
+            VecOp::Pushed => {
+                items.pop();
+            }
+            VecOp::Popped(v) => items.push(v),
+            VecOp::Inserted(i) => {
+                items.remove(i);
+            }
+            VecOp::Removed(i, v) => items.insert(i, v),
+            VecOp::SwapRemoved(i, v) => {
+                // The last element was moved into `i`, unless `i` was the last element
+                if i == items.len() {
+                    items.push(v);
+                } else {
+                    let moved = std::mem::replace(&mut items[i], v);
+                    items.push(moved);
+                }
+            }
+            VecOp::Set(i, v) => items[i] = v,
+            VecOp::Swapped(a, b) => items.swap(a, b),
+            VecOp::Truncated(removed) => items.extend(removed),
+        }
+    }
+}
+
+impl<T> Default for UndoVec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> From<Vec<T>> for UndoVec<T> {
+    fn from(items: Vec<T>) -> Self {
+        Self {
+            items,
+            journal: Vec::new(),
+        }
+    }
+}
+
+impl<T> Deref for UndoVec<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.items
+    }
+}
+
+/// A point in the journal of an [`UndoHashMap`], see [`UndoHashMap::checkpoint`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MapCheckpoint(usize);
+
+/// A [`HashMap`] which journals what each change replaced, so that it can be rolled back
+///
+/// Every change is journaled as the key and the value it had before, or `None` if it had none,
+/// so that rolling back puts back replaced values rather than just removing keys. Like
+/// [`UndoVec`], rollbacks reverse the changes newest first. An [`encase`](crate::encase)-d map
+/// also has mutators which return atoms instead of journaling.
+///
+/// ```
+/// let mut prices = rewind::UndoHashMap::new();
+/// prices.insert("tea", 3);
+/// let start = prices.checkpoint();
+/// prices.insert("tea", 4);
+/// prices.remove(&"coffee");
+/// prices.update("cake", |old| Some(old.map_or(5, |p| p + 1)));
+/// prices.rollback(start);
+/// assert_eq!(prices.get("tea"), Some(&3));
+/// assert_eq!(prices.len(), 1);
+/// ```
+#[derive(Debug, Clone)]
+pub struct UndoHashMap<K, V> {
+    map: HashMap<K, V>,
+    journal: Vec<(K, Option<V>)>,
+}
+
+impl<K: Clone + Eq + Hash, V> UndoHashMap<K, V> {
+    pub fn new() -> Self {
+        Self::from(HashMap::new())
+    }
+    /// Insert `value`, the value it replaced is kept in the journal
+    pub fn insert(&mut self, key: K, value: V) -> Option<&V> {
+        let old = self.map.insert(key.clone(), value);
+        self.journaled(key, old)
+    }
+    /// Remove `key`, the removed value is kept in the journal
+    ///
+    /// Nothing is journaled if `key` wasn't in the map.
+    pub fn remove(&mut self, key: &K) -> Option<&V> {
+        let old = self.map.remove(key)?;
+        self.journaled(key.clone(), Some(old))
+    }
+    /// Replace the value for `key` with what `f` makes of the current one, `None` removing it
+    ///
+    /// Since `f` only borrows the current value, it can be moved into the journal as is.
+    pub fn update(&mut self, key: K, f: impl FnOnce(Option<&V>) -> Option<V>) {
+        let new = f(self.map.get(&key));
+        let old = match new {
+            Some(new) => self.map.insert(key.clone(), new),
+            None => self.map.remove(&key),
+        };
+        self.journal.push((key, old));
+    }
+    /// Change the value for `key` in place, returning whether it was there
+    ///
+    /// The value is cloned to keep the original in the journal.
+    pub fn modify(&mut self, key: &K, f: impl FnOnce(&mut V)) -> bool
+    where
+        V: Clone,
+    {
+        let Some(value) = self.map.get_mut(key) else {
+            return false;
+        };
+        let old = value.clone();
+        f(value);
+        self.journal.push((key.clone(), Some(old)));
+        true
+    }
+    /// Mark the current state to [`rollback`](Self::rollback) to later
+    pub fn checkpoint(&self) -> MapCheckpoint {
+        MapCheckpoint(self.journal.len())
+    }
+    /// Reverse every change made since `checkpoint`, newest first
+    ///
+    /// # Panics
+    ///
+    /// If the journal is already shorter than at `checkpoint`, because of an earlier rollback
+    /// to before it or a [`commit`](Self::commit)
+    pub fn rollback(&mut self, checkpoint: MapCheckpoint) {
+        assert!(
+            checkpoint.0 <= self.journal.len(),
+            "checkpoint at {} is past the end of the journal ({})",
+            checkpoint.0,
+            self.journal.len()
+        );
+        let undone = self.journal.split_off(checkpoint.0);
+        for (key, old) in undone.into_iter().rev() {
+            self.restore(key, old);
+        }
+    }
+    /// Reverse every journaled change
+    pub fn rollback_all(&mut self) {
+        self.rollback(MapCheckpoint(0));
+    }
+    /// Keep the changes, emptying the journal
+    ///
+    /// Checkpoints taken before this can no longer be rolled back to.
+    pub fn commit(&mut self) {
+        self.journal.clear();
+    }
+    /// Number of changes which can be rolled back
+    pub fn journal_len(&self) -> usize {
+        self.journal.len()
+    }
+    pub fn into_inner(self) -> HashMap<K, V> {
+        self.map
+    }
+    fn journaled(&mut self, key: K, old: Option<V>) -> Option<&V> {
+        self.journal.push((key, old));
+        self.journal.last().and_then(|(_, old)| old.as_ref())
+    }
+    /// Put back the value `key` had before a change, without journaling it
+    fn restore(&mut self, key: K, old: Option<V>) {
+        match old {
+            Some(old) => self.map.insert(key, old),
+            None => self.map.remove(&key),
+        };
+    }
+}
+
+/// Changes to an [`encase`](crate::encase)-d [`UndoHashMap`] as atoms
+///
+/// These are not journaled, each returns a [`SideEffect`] on the shared map instead which holds
+/// the value the key had before and puts it back when undone.
+///
+/// ```
+/// # use rewind::Atom;
+/// let mut stock = rewind::encase(rewind::UndoHashMap::new());
+/// let added = stock.insert("pens", 10);
+/// let sold = stock.update("pens", |n| n.map(|n| n - 1));
+/// let missing = stock.remove(&"ink");
+/// assert_eq!(*missing, None);
+/// missing.undo();
+/// sold.undo();
+/// assert_eq!(stock.borrow_state().get("pens"), Some(&10));
+/// added.undo();
+/// assert!(stock.borrow_state().is_empty());
+/// ```
+#[allow(clippy::type_complexity)]
+impl<K: Clone + Eq + Hash, V> Encased<UndoHashMap<K, V>> {
+    /// [`UndoHashMap::insert`], the side effect holds the replaced value
+    pub fn insert(
+        &mut self,
+        key: K,
+        value: V,
+    ) -> SideEffect<Option<V>, (), UndoHashMap<K, V>, impl FnOnce(&mut UndoHashMap<K, V>, Option<V>)>
+    {
+        let restored = key.clone();
+        self.peel_mut(
+            |m| m.map.insert(key, value),
+            move |m, old| m.restore(restored, old),
+        )
+    }
+    /// [`UndoHashMap::remove`], the side effect holds the removed value
+    ///
+    /// Undoing the removal of a missing key does nothing.
+    pub fn remove(
+        &mut self,
+        key: &K,
+    ) -> SideEffect<Option<V>, (), UndoHashMap<K, V>, impl FnOnce(&mut UndoHashMap<K, V>, Option<V>)>
+    {
+        let restored = key.clone();
+        self.peel_mut(
+            |m| m.map.remove(key),
+            move |m, old| {
+                if old.is_some() {
+                    m.restore(restored, old);
+                }
+            },
+        )
+    }
+    /// [`UndoHashMap::update`], the side effect holds the value before `f` was applied
+    pub fn update(
+        &mut self,
+        key: K,
+        f: impl FnOnce(Option<&V>) -> Option<V>,
+    ) -> SideEffect<Option<V>, (), UndoHashMap<K, V>, impl FnOnce(&mut UndoHashMap<K, V>, Option<V>)>
+    {
+        let restored = key.clone();
+        self.peel_mut(
+            |m| match f(m.map.get(&key)) {
+                Some(new) => m.map.insert(key, new),
+                None => m.map.remove(&key),
+            },
+            move |m, old| m.restore(restored, old),
+        )
+    }
+}
+
+impl<K: Clone + Eq + Hash, V> Default for UndoHashMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> From<HashMap<K, V>> for UndoHashMap<K, V> {
+    fn from(map: HashMap<K, V>) -> Self {
+        Self {
+            map,
+            journal: Vec::new(),
+        }
+    }
+}
+
+impl<K, V> Deref for UndoHashMap<K, V> {
+    type Target = HashMap<K, V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// xorshift, so that failures can be reproduced from the seed
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    fn random_op(rng: &mut Rng, items: &mut UndoVec<u32>, next: &mut u32) {
+        let len = items.len();
+        *next += 1;
+        match rng.below(9) {
+            0 | 1 => items.push(*next),
+            2 => {
+                items.pop();
+            }
+            3 => items.insert(rng.below(len + 1), *next),
+            4 if len > 0 => {
+                items.remove(rng.below(len));
+            }
+            5 if len > 0 => {
+                items.swap_remove(rng.below(len));
+            }
+            6 if len > 0 => {
+                items.set(rng.below(len), *next);
+            }
+            7 if len > 0 => items.swap(rng.below(len), rng.below(len)),
+            8 => items.truncate(rng.below(len + 2)),
+            _ => items.clear(),
+        }
+    }
+
+    #[test]
+    fn random_changes_roll_back_to_each_checkpoint() {
+        for seed in 1..200 {
+            let mut rng = Rng(seed);
+            let mut items = UndoVec::from((0..rng.below(6) as u32).collect::<Vec<_>>());
+            let mut next = 100;
+            let mut snapshots = Vec::new();
+            for _ in 0..rng.below(40) {
+                if rng.below(4) == 0 {
+                    snapshots.push((items.checkpoint(), items.to_vec()));
+                }
+                random_op(&mut rng, &mut items, &mut next);
+            }
+            while let Some((checkpoint, snapshot)) = snapshots.pop() {
+                items.rollback(checkpoint);
+                assert_eq!(*items, snapshot, "seed {seed}");
+                for _ in 0..rng.below(5) {
+                    random_op(&mut rng, &mut items, &mut next);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn committed_changes_stay_after_rollback() {
+        let mut items = UndoVec::new();
+        items.push("a");
+        items.push("b");
+        items.commit();
+        let start = items.checkpoint();
+        assert_eq!(items.swap_remove(0), &"a");
+        assert_eq!(items.pop(), Some(&"b"));
+        assert_eq!(items.journal_len(), 2);
+        items.rollback(start);
+        assert_eq!(*items, ["a", "b"]);
+        items.rollback_all();
+        assert_eq!(items.into_inner(), ["a", "b"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "past the end of the journal")]
+    fn rolling_back_past_a_commit_panics() {
+        let mut items = UndoVec::new();
+        items.push(1);
+        let pushed = items.checkpoint();
+        items.commit();
+        items.rollback(pushed);
+    }
+
+    #[test]
+    fn map_rollbacks_restore_replaced_values() {
+        let mut map = UndoHashMap::from(HashMap::from([("a", 1)]));
+        let start = map.checkpoint();
+        assert_eq!(map.insert("a", 2), Some(&1));
+        assert_eq!(map.insert("b", 3), None);
+        map.rollback(start);
+        assert_eq!(*map, HashMap::from([("a", 1)]));
+
+        assert_eq!(map.remove(&"missing"), None);
+        assert!(!map.modify(&"missing", |v| *v += 1));
+        assert_eq!(map.journal_len(), 0);
+
+        map.insert("a", 10);
+        assert!(map.modify(&"a", |v| *v += 1));
+        map.update("a", |_| None);
+        map.update("a", |old| Some(old.map_or(20, |v| v + 1)));
+        assert_eq!(map.get("a"), Some(&20));
+        let mut undone = vec![];
+        while map.journal_len() > 0 {
+            map.rollback(MapCheckpoint(map.journal_len() - 1));
+            undone.push(map.get("a").copied());
+        }
+        assert_eq!(undone, [None, Some(11), Some(10), Some(1)]);
+    }
+
+    #[test]
+    fn map_atoms_put_back_what_they_changed() {
+        use crate::{stack::Stack, Atom};
+        let mut map = crate::encase(UndoHashMap::from(HashMap::from([("a", 1)])));
+        let missing = map.remove(&"missing");
+        assert_eq!(*missing, None);
+        missing.undo();
+        assert_eq!(**map.borrow_state(), HashMap::from([("a", 1)]));
+
+        let mut stack = Stack::new();
+        let replaced = map.insert("a", 2);
+        assert_eq!(*replaced, Some(1));
+        stack.push(replaced);
+        stack.push(map.update("a", |v| v.map(|v| v * 10)));
+        stack.push(map.remove(&"a"));
+        stack.push(map.insert("b", 3));
+        assert_eq!(**map.borrow_state(), HashMap::from([("b", 3)]));
+        assert_eq!(map.borrow_state().journal_len(), 0);
+        let mut undone = vec![];
+        while stack.pop_undo().is_some() {
+            undone.push(map.borrow_state().get("a").copied());
+        }
+        assert_eq!(undone, [None, Some(20), Some(2), Some(1)]);
+        assert_eq!(**map.borrow_state(), HashMap::from([("a", 1)]));
+    }
+}
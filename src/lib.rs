@@ -3,8 +3,21 @@
 extern crate self as rewind;
 
 pub mod atom;
+pub mod collections;
+pub mod stack;
+#[cfg(feature = "testing")]
+pub mod testing;
 
-pub use atom::Atom;
+pub use atom::{
+    Atom, AtomBuilder, AtomDeque, AtomHashSet, AtomIter, AtomIterExt, AtomOption, AtomStr,
+    Commitable, DelayedAtom, Rewind, SideEffectChain, VecSnapshot,
+};
+pub use collections::{UndoHashMap, UndoVec};
+#[cfg(feature = "macros")]
+pub use rewind_macros::{
+    atomic, isomorphic, isomorphic_impl, isomorphic_pair, transaction, Encase, Rewind,
+};
+pub use stack::{Checkpoint, Histogram};
 
 /// Create an undo operation with stored data
 ///
@@ -12,6 +25,50 @@ pub fn simple<T, R, Undo: FnOnce(T) -> R>(value: T, undo: Undo) -> atom::Simple<
     atom::Simple::new(value, undo)
 }
 
+/// Like [`simple`], but `undo` also gets `context`
+///
+/// ```
+/// # use rewind::Atom;
+/// # use std::cell::RefCell;
+/// let log = RefCell::new(vec![]);
+/// let created = rewind::effect_with_context(&log, "report.txt", |log, name| {
+///     log.borrow_mut().push(format!("deleted {name}"))
+/// });
+/// created.undo();
+/// assert_eq!(log.into_inner(), ["deleted report.txt"]);
+/// ```
+pub fn effect_with_context<C: Clone, T, R, Undo: FnOnce(C, T) -> R>(
+    context: C,
+    value: T,
+    undo: Undo,
+) -> atom::ContextualAtom<C, T, R, Undo> {
+    atom::ContextualAtom::new(context, value, undo)
+}
+
+/// Run `forward` now and `undo` when the returned atom is undone or dropped
+///
+/// ```
+/// # use rewind::Atom;
+/// # use std::cell::Cell;
+/// let volume = Cell::new(5);
+/// let muted = rewind::fn_atom(|| volume.set(0), || volume.set(5));
+/// assert_eq!(volume.get(), 0);
+/// muted.undo();
+/// assert_eq!(volume.get(), 5);
+/// ```
+pub fn fn_atom<Undo: FnOnce()>(forward: impl FnOnce(), undo: Undo) -> atom::FnAtom<Undo> {
+    forward();
+    atom::FnAtom::new(undo)
+}
+
+/// [`fn_atom`] where the result of `forward` is passed to `undo`
+pub fn fn_atom_returning<T, R, Undo: FnOnce(T) -> R>(
+    forward: impl FnOnce() -> T,
+    undo: Undo,
+) -> atom::Simple<T, R, Undo> {
+    simple(forward(), undo)
+}
+
 /// Provides a way around rust's ownership requirements.
 ///
 /// E.g. the following code does not compile:
@@ -36,11 +93,225 @@ pub fn own<T: Clone, Undo: FnOnce(T) -> T>(value: T, undo: Undo) -> atom::Owning
     atom::Owning::new(value, undo)
 }
 
+/// [`own`] a value and tell observers about every change and the undo
+///
+/// See [`ObservableOwning`](atom::ObservableOwning) for examples
+pub fn observable_own<T: Clone, Undo: FnOnce(T) -> T>(
+    value: T,
+    undo: Undo,
+) -> atom::ObservableOwning<T, Undo> {
+    atom::ObservableOwning::new(value, undo)
+}
+
 /// [`own`](rewind::own) with identity
 pub fn own_id<T: Clone>(value: T) -> atom::Owning<T, impl FnOnce(T) -> T> {
     atom::Owning::new(value, |c| c)
 }
 
+/// [`own_id`] with the default value
+///
+/// ```
+/// # use rewind::Atom;
+/// #[derive(Default, Clone, Debug, PartialEq)]
+/// struct Config {
+///     retries: u32,
+/// }
+/// let mut config = rewind::own_default::<Config>();
+/// config.retries = 3;
+/// assert_eq!(config.undo(), Config::default());
+/// ```
+pub fn own_default<T: Default + Clone>() -> atom::Owning<T, impl FnOnce(T) -> T> {
+    own_id(T::default())
+}
+
+/// [`own_id`] with a clone of `value`, for values which are only borrowed
+///
+/// ```
+/// # use rewind::Atom;
+/// let hosts = vec!["a", "b"];
+/// let mut edited = rewind::own_ref(&hosts);
+/// edited.push("c");
+/// assert_eq!(edited.undo(), hosts);
+/// ```
+pub fn own_ref<T: Clone>(value: &T) -> atom::Owning<T, impl FnOnce(T) -> T> {
+    own_id(value.clone())
+}
+
+/// [`own_with`] with a clone of `value`, see [`own_ref`]
+pub fn own_ref_with<T: Clone, U, Undo: FnOnce(T, U) -> T>(
+    value: &T,
+    extract: impl FnOnce(&T) -> U,
+    undo: Undo,
+) -> atom::OwningWith<T, U, Undo> {
+    own_with(value.clone(), extract, undo)
+}
+
+/// [`own`](rewind::own) which only keeps the part of the original value needed for the undo
+///
+/// `extract` takes the snapshot before any modifications are made, `undo` is given the modified
+/// value and the snapshot and should return the restored value. This avoids cloning the whole
+/// value when only a small part of it is needed to undo:
+///
+/// ```
+/// # use rewind::Atom;
+/// let mut items = rewind::own_with(vec![1, 2, 3], |v| v.len(), |mut v, len| {
+///     v.truncate(len);
+///     v
+/// });
+/// items.push(4);
+/// assert_eq!(items.undo(), vec![1, 2, 3]);
+/// ```
+pub fn own_with<T, U, Undo: FnOnce(T, U) -> T>(
+    value: T,
+    extract: impl FnOnce(&T) -> U,
+    undo: Undo,
+) -> atom::OwningWith<T, U, Undo> {
+    atom::OwningWith::new(value, extract, undo)
+}
+
+/// Run `cleanup` on `state` when the returned guard is dropped, unless it is disarmed
+///
+/// A lighter alternative to [`encase`] when a single scope needs cleaning up. The guard
+/// dereferences to `state` so it can still be modified in the meantime:
+///
+/// ```
+/// let mut buf = vec![1, 2];
+/// {
+///     let mut buf = rewind::guard(&mut buf, |b| b.truncate(2));
+///     buf.push(3);
+///     // bail out early, the push is reverted
+/// }
+/// assert_eq!(buf, vec![1, 2]);
+/// let mut guarded = rewind::guard(&mut buf, |b| b.truncate(2));
+/// guarded.push(3);
+/// guarded.disarm();
+/// assert_eq!(buf, vec![1, 2, 3]);
+/// ```
+pub fn guard<S, Cleanup: FnOnce(&mut S)>(
+    state: &mut S,
+    cleanup: Cleanup,
+) -> atom::Guard<'_, S, Cleanup> {
+    atom::Guard::new(state, cleanup)
+}
+
+/// An atom which does nothing when undone, decayed or dropped
+///
+/// Useful as a placeholder where an atom is required but there is nothing to undo:
+///
+/// ```
+/// # use rewind::Atom;
+/// let maybe: Option<rewind::atom::NoopAtom> = None;
+/// maybe.unwrap_or_else(rewind::noop).undo();
+/// ```
+pub fn noop() -> atom::NoopAtom {
+    atom::NoopAtom
+}
+
+/// Create an undo operation which is retried if it fails
+///
+/// The undo is tried once and then up to `max_retries` more times, waiting `delay` between each
+/// attempt. Undoing explicitly gives back the first success or the last error, while dropping the
+/// atom retries in the same way and ignores the outcome. Use
+/// [`undo_async`](atom::RetryAtom::undo_async) to avoid blocking the thread while waiting.
+///
+/// ```
+/// # use rewind::Atom;
+/// # use std::time::Duration;
+/// let mut failures = 2;
+/// let record = rewind::retry(
+///     "record-1",
+///     move |id| {
+///         if failures > 0 {
+///             failures -= 1;
+///             Err("connection reset")
+///         } else {
+///             Ok(format!("deleted {id}"))
+///         }
+///     },
+///     3,
+///     Duration::from_millis(1),
+/// );
+/// assert_eq!(record.undo(), Ok("deleted record-1".to_owned()));
+/// ```
+pub fn retry<T, R, E, Undo: FnMut(&mut T) -> Result<R, E>>(
+    value: T,
+    undo: Undo,
+    max_retries: u32,
+    delay: std::time::Duration,
+) -> atom::RetryAtom<T, R, E, Undo> {
+    atom::RetryAtom::new(value, undo, max_retries, delay)
+}
+
+/// Let a foreign transaction, e.g. a database handle, be resolved like an atom
+///
+/// Undoing or dropping the atom rolls the transaction back and decaying commits it, so it can be
+/// pushed onto a [`Stack`](stack::Stack) next to other atoms. See [`Adopted`](atom::Adopted) for
+/// how failed commits are handled.
+///
+/// ```
+/// # use rewind::{Atom, Commitable};
+/// struct Staged(&'static str);
+/// impl Commitable for Staged {
+///     type Committed = &'static str;
+///     type Error = std::io::Error;
+///     fn commit(self) -> Result<Self::Committed, Self::Error> {
+///         Ok(self.0)
+///     }
+///     fn rollback(self) {}
+/// }
+/// let mut stack = rewind::stack::Stack::new();
+/// stack.push(rewind::adopt(Staged("config.toml")));
+/// let committed = stack.decay_all();
+/// assert_eq!(
+///     committed[0].downcast_ref::<Option<&str>>(),
+///     Some(&Some("config.toml"))
+/// );
+/// ```
+pub fn adopt<C: Commitable>(c: C) -> atom::Adopted<C> {
+    atom::Adopted::new(c)
+}
+
+/// Record how long undoing `atom` takes in `histogram` under `name`
+///
+/// ```
+/// use std::{cell::RefCell, rc::Rc};
+/// let histogram = Rc::new(RefCell::new(rewind::Histogram::new()));
+/// drop(rewind::timed("noop", rewind::noop(), &histogram));
+/// assert_eq!(histogram.borrow().summary()[0].1, 1);
+/// ```
+pub fn timed<A: Atom>(
+    name: &'static str,
+    atom: A,
+    histogram: &std::rc::Rc<std::cell::RefCell<Histogram>>,
+) -> atom::Timed<A> {
+    atom::Timed::new(name, atom, histogram)
+}
+
+/// Make an atom from each item of `iter` as it is yielded
+///
+/// ```
+/// # use rewind::Atom;
+/// let mut undone = vec![];
+/// let atoms: Vec<_> = rewind::atom_iter(1..=3, |v| rewind::simple(v, |v| v * 2)).collect();
+/// for atom in atoms.into_iter().rev() {
+///     undone.push(atom.undo());
+/// }
+/// assert_eq!(undone, [6, 4, 2]);
+/// ```
+pub fn atom_iter<I: IntoIterator, A: Atom, F: FnMut(I::Item) -> A>(
+    iter: I,
+    factory: F,
+) -> atom::AtomIter<I::IntoIter, F> {
+    atom::AtomIter::new(iter.into_iter(), factory)
+}
+
+/// Keep two atoms together, while still being able to resolve them separately
+///
+/// See [`PairedAtom`](atom::PairedAtom)
+pub fn pair<A: Atom, B: Atom>(a: A, b: B) -> atom::PairedAtom<A, B> {
+    atom::PairedAtom::new(a, b)
+}
+
 /// Lift a value to a source for operations
 ///
 /// This function puts `S` on the heap and has additional runtime overhead on top of that. The
@@ -55,6 +326,291 @@ pub fn encase<S>(s: S) -> atom::Encased<S> {
     atom::Encased::new(s)
 }
 
+/// Compose two reversible transformations into one
+///
+/// Returns a forward transformation which applies `f` then `g`, and its inverse which applies
+/// `g_inv` then `f_inv`. Since the result is itself a transformation and its inverse, it can be
+/// passed to `compose` again. Use [`peel_mut`](atom::Encased::peel_mut) to apply it with the
+/// inverse as the undo:
+///
+/// ```
+/// # use rewind::Atom;
+/// let (f, inv) = rewind::compose(|v: &mut i32| *v += 1, |v| *v -= 1, |v| *v *= 2, |v| *v /= 2);
+/// let mut value = rewind::encase(3);
+/// let effect = value.peel_mut(f, |v, _| inv(v));
+/// assert_eq!(*value.borrow_state(), 8);
+/// effect.undo();
+/// assert_eq!(*value.borrow_state(), 3);
+/// ```
+pub fn compose<S, F, FInv, G, GInv>(
+    f: F,
+    f_inv: FInv,
+    g: G,
+    g_inv: GInv,
+) -> (impl FnOnce(&mut S), impl FnOnce(&mut S))
+where
+    F: FnOnce(&mut S),
+    FInv: FnOnce(&mut S),
+    G: FnOnce(&mut S),
+    GInv: FnOnce(&mut S),
+{
+    (
+        move |s: &mut S| {
+            f(s);
+            g(s);
+        },
+        move |s: &mut S| {
+            g_inv(s);
+            f_inv(s);
+        },
+    )
+}
+
+/// [`encase`] a value which can be shared between threads
+///
+/// See [`SyncEncased`](atom::SyncEncased), which uses a [`Mutex`](std::sync::Mutex) instead of a
+/// [`RefCell`](std::cell::RefCell).
+pub fn encase_sync<S>(s: S) -> atom::SyncEncased<S> {
+    atom::SyncEncased::new(s)
+}
+
+/// Store `new` in an atomic, putting the previous value back on undo
+///
+/// Works with [`AtomicBool`](std::sync::atomic::AtomicBool),
+/// [`AtomicUsize`](std::sync::atomic::AtomicUsize) and [`AtomicU64`](std::sync::atomic::AtomicU64).
+/// `policy` decides what happens if another thread stored something else in the meantime.
+///
+/// ```
+/// # use rewind::{atom::Restore, Atom};
+/// # use std::sync::atomic::{AtomicBool, Ordering};
+/// let ready = AtomicBool::new(false);
+/// let set = rewind::atomic_store(&ready, true, Restore::OnlyIfUnchanged);
+/// assert!(ready.load(Ordering::SeqCst));
+/// assert_eq!(set.undo(), Ok(true));
+/// assert!(!ready.load(Ordering::SeqCst));
+/// ```
+pub fn atomic_store<A: atom::AtomicPrimitive>(
+    atomic: &A,
+    new: A::Value,
+    policy: atom::Restore,
+) -> atom::AtomicStore<'_, A> {
+    atom::AtomicStore::new(atomic, new, policy)
+}
+
+/// Add `delta` to an atomic counter, subtracting it again on undo
+///
+/// ```
+/// # use rewind::{atom::Restore, Atom};
+/// # use std::sync::atomic::{AtomicUsize, Ordering};
+/// let hits = AtomicUsize::new(3);
+/// let hit = rewind::atomic_fetch_add(&hits, 2, Restore::Force);
+/// hits.fetch_add(10, Ordering::SeqCst);
+/// hit.undo().unwrap();
+/// assert_eq!(hits.load(Ordering::SeqCst), 13);
+/// ```
+pub fn atomic_fetch_add<A: atom::AtomicCounter>(
+    atomic: &A,
+    delta: A::Value,
+    policy: atom::Restore,
+) -> atom::AtomicFetchAdd<'_, A> {
+    atom::AtomicFetchAdd::new(atomic, delta, policy)
+}
+
+/// [`encase`] a value for use in async code with tokio
+///
+/// See [`TokioEncased`](atom::TokioEncased), which uses a [`tokio::sync::Mutex`] so that waiting
+/// for the state doesn't block the executor.
+#[cfg(feature = "tokio")]
+pub fn encase_tokio<S: Send + 'static>(s: S) -> atom::TokioEncased<S> {
+    atom::TokioEncased::new(s)
+}
+
+/// [`encase`] the default value
+pub fn encase_default<S: Default>() -> atom::Encased<S> {
+    encase(S::default())
+}
+
+/// Run `further_work` with the value held by `atom`, then decay the atom if it succeeds or undo it
+/// if it fails
+///
+/// The result of the undo is discarded.
+///
+/// ```
+/// let mut rows = rewind::encase(vec!["alice"]);
+/// let inserted = rows.peel_mut(
+///     |r| {
+///         r.push("bob");
+///         r.len() - 1
+///     },
+///     |r, _| {
+///         r.pop();
+///     },
+/// );
+/// let checked = rewind::apply_or_undo(inserted, |id| if *id < 1 { Ok(*id) } else { Err("table full") });
+/// assert_eq!(checked, Err("table full"));
+/// assert_eq!(*rows.borrow_state(), vec!["alice"]);
+/// ```
+pub fn apply_or_undo<T, E, A: Atom + std::ops::Deref>(
+    atom: A,
+    further_work: impl FnOnce(&A::Target) -> Result<T, E>,
+) -> Result<T, E> {
+    match further_work(&atom) {
+        Ok(v) => {
+            atom.decay();
+            Ok(v)
+        }
+        Err(e) => {
+            atom.undo();
+            Err(e)
+        }
+    }
+}
+
+/// [`simple`] which logs its value when undone or decayed, for tracing what gets rolled back
+///
+/// ```
+/// # use rewind::Atom;
+/// let reserved = rewind::debug_atom(3, |seat| println!("freed {seat}")).with_prefix("seats");
+/// reserved.undo(); // logs "seats: undo: 3"
+/// ```
+#[cfg(feature = "log")]
+pub fn debug_atom<T: std::fmt::Debug, R, Undo: FnOnce(T) -> R>(
+    value: T,
+    undo: Undo,
+) -> atom::DebugAtom<T, R, Undo> {
+    atom::DebugAtom::new(value, undo)
+}
+
+/// Await `fut`, decaying `atom` if it completes and undoing it if the future is dropped first
+///
+/// Cancelling async work, e.g. the branch of a `select!` which lost or a task being aborted,
+/// drops it at whichever `.await` it was stopped at. The returned future holds `atom` in an
+/// [`AbortRollback`](atom::AbortRollback), so it is undone then too.
+///
+/// `atom` and `fut` may borrow, the returned future just can't outlive them. Like most `async`
+/// futures it isn't [`Unpin`], so it needs pinning with [`pin!`](std::pin::pin) or
+/// [`Box::pin`] to be polled by reference, as `select!` does in a loop.
+///
+/// ```
+/// # use std::{future::Future, task::{Context, Waker}};
+/// let mut items = rewind::encase(vec![1]);
+/// let pushed = items.peel_mut(|i| i.push(2), |i, _| i.pop());
+/// let mut work = Box::pin(rewind::with_rollback(pushed, std::future::pending::<()>()));
+/// let mut cx = Context::from_waker(Waker::noop());
+/// assert!(work.as_mut().poll(&mut cx).is_pending());
+/// assert_eq!(*items.borrow_state(), vec![1, 2]);
+/// drop(work);
+/// assert_eq!(*items.borrow_state(), vec![1]);
+/// ```
+pub async fn with_rollback<A: Atom, T>(atom: A, fut: impl std::future::Future<Output = T>) -> T {
+    let guard = atom::AbortRollback::new(atom);
+    let out = fut.await;
+    guard.complete();
+    out
+}
+
+/// Run `f` with `stack`, then decay the atoms it pushed if it succeeds or undo them if it fails
+/// or panics
+///
+/// Atoms already on the stack are left as they are, so a failing nested `attempt` only undoes
+/// its own. A succeeding one decays its own though, so they are no longer undone if an outer one
+/// fails. Use [`Stack::transaction`](stack::Stack::transaction) to leave them to the outer one
+/// instead.
+///
+/// ```
+/// let mut names = rewind::encase(vec!["alice"]);
+/// let mut stack = rewind::stack::Stack::new();
+/// let added = rewind::attempt(&mut stack, |s| {
+///     s.push(names.peel_mut(|n| n.push("bob"), |n, _| {
+///         n.pop();
+///     }));
+///     Err::<(), _>("name taken")
+/// });
+/// assert_eq!(added, Err("name taken"));
+/// assert_eq!(*names.borrow_state(), vec!["alice"]);
+/// ```
+pub fn attempt<T, E>(
+    stack: &mut stack::Stack,
+    f: impl FnOnce(&mut stack::Stack) -> Result<T, E>,
+) -> Result<T, E> {
+    let mark = stack.mark();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(stack)));
+    let policy = match &result {
+        Ok(Ok(_)) => stack::Resolve::Decay,
+        _ => stack::Resolve::Undo,
+    };
+    stack.resolve_since(mark, policy);
+    result.unwrap_or_else(|p| std::panic::resume_unwind(p))
+}
+
+/// Run `f`, undoing `atom` right away if it panics, or giving it back still pending if not
+///
+/// The undo runs before the panic carries on, so the state is already restored for destructors
+/// run while unwinding, e.g. of guards in the callers which read it. `atom` can be a
+/// [`Stack`](stack::Stack) to protect everything on it.
+///
+/// `f` is treated as [`UnwindSafe`](std::panic::UnwindSafe) even when it captures an
+/// [`Encased`](atom::Encased) or other `RefCell`. A panic may leave such state half changed, and
+/// it is up to the undo to put it back, like it would be for an error.
+///
+/// ```
+/// # use rewind::Atom;
+/// let mut seats = rewind::encase(vec![1, 2]);
+/// let taken = seats.peel_mut(|s| s.pop(), |s, v| s.extend(v));
+/// let (taken, booked) = rewind::protect(taken, || "booked");
+/// assert_eq!(booked, "booked");
+/// taken.undo();
+/// assert_eq!(*seats.borrow_state(), vec![1, 2]);
+/// ```
+pub fn protect<A: Atom, R>(atom: A, f: impl FnOnce() -> R) -> (A, R) {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(r) => (atom, r),
+        Err(panic) => {
+            atom.undo();
+            std::panic::resume_unwind(panic)
+        }
+    }
+}
+
+/// Create a [`SchemaStack`](stack::SchemaStack) starting from the default state
+///
+/// Operations are values of `Op` rather than closures, each storing its inverse when pushed.
+///
+/// ```
+/// use rewind::stack::SchemaOp;
+/// struct Add(i32);
+/// impl SchemaOp<i32> for Add {
+///     fn apply(&self, total: &mut i32) {
+///         *total += self.0;
+///     }
+///     fn invert(&self, _: &i32) -> Self {
+///         Add(-self.0)
+///     }
+/// }
+/// let mut total = rewind::schema::<i32, Add>();
+/// total.push(Add(3));
+/// total.push(Add(4));
+/// total.undo_one();
+/// assert_eq!(*total.state(), 3);
+/// ```
+pub fn schema<S: Default, Op: stack::SchemaOp<S>>() -> stack::SchemaStack<S, Op> {
+    stack::SchemaStack::new(S::default())
+}
+
+/// Start building a [`Stack`](stack::Stack)
+///
+/// ```
+/// let stack = rewind::undo_stack()
+///     .with_capacity(10)
+///     .named("edits")
+///     .with_on_undo(|results| println!("undid {} atoms", results.len()))
+///     .build();
+/// assert_eq!(stack.to_string(), "edits (0 pending)");
+/// ```
+pub fn undo_stack() -> stack::StackBuilder {
+    stack::StackBuilder::default()
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -81,8 +637,8 @@ mod tests {
 
         let mut s = rewind::encase(Stack::<i32>::default());
         let result = (|| {
-            s.push(4);
-            s.push(5);
+            s.lock().push(4);
+            s.lock().push(5);
             let value = s.peel_mut(
                 |s| s.pop(),
                 |s, v| {
@@ -104,7 +660,1249 @@ mod tests {
             Ok::<(), ()>(())
         })();
         assert!(result.is_err());
-        assert_eq!(s.els, vec![4, 5]); // uh oh
+        assert_eq!(s.borrow_state().els, vec![4, 5]); // uh oh
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_skip_on_err_only_arms_successes() {
+        #[derive(Default)]
+        struct Stack<T> {
+            els: Vec<T>,
+        }
+        impl<T> Stack<T> {
+            #[isomorphic(to = push, skip_on_err)]
+            pub fn pop(&mut self) -> Result<T, ()> {
+                self.els.pop().ok_or(())
+            }
+
+            pub fn push(&mut self, el: T) {
+                self.els.push(el);
+            }
+        }
+        fn may_fail() -> Result<(), ()> {
+            Err(())
+        }
+
+        let mut s = rewind::encase(Stack::<i32> { els: vec![4, 5] });
+        let result = (|| {
+            let value = Stack::pop(&mut s)?;
+            let v2 = Stack::pop(&mut s)?;
+            assert!(Stack::pop(&mut s).is_err());
+            may_fail()?;
+            println!("{}, {}", value.decay(), v2.decay());
+            Ok::<(), ()>(())
+        })();
+        assert!(result.is_err());
+        assert_eq!(s.borrow_state().els, vec![4, 5]);
+    }
+    fn block_on<F: std::future::Future>(f: F) -> F::Output {
+        let mut f = std::pin::pin!(f);
+        let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+        loop {
+            if let std::task::Poll::Ready(v) = f.as_mut().poll(&mut cx) {
+                return v;
+            }
+        }
+    }
+
+    #[test]
+    fn with_rollback_undoes_only_when_cancelled() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let mut items = encase(vec![1]);
+        let pushed = items.peel_mut(|i| i.push(2), |i, _| i.retain(|v| *v != 2));
+        let cancelled = rt.block_on(async {
+            let slow = tokio::time::sleep(std::time::Duration::from_secs(5));
+            tokio::time::timeout(
+                std::time::Duration::from_millis(1),
+                with_rollback(pushed, slow),
+            )
+            .await
+        });
+        assert!(cancelled.is_err());
+        assert_eq!(*items.borrow_state(), vec![1]);
+
+        let mut checked = items.clone();
+        let pushed = items.peel_mut(|i| i.push(3), |i, _| i.retain(|v| *v != 3));
+        let finished = rt.block_on(with_rollback(pushed, async {
+            tokio::task::yield_now().await;
+            checked.modify(|i| i.len())
+        }));
+        assert_eq!(finished, 2);
+        assert_eq!(*items.borrow_state(), vec![1, 3]);
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_async_methods_await_the_body() {
+        #[derive(Default)]
+        struct Counter {
+            n: i32,
+        }
+        impl Counter {
+            fn dec(&mut self) {
+                self.n -= 1;
+            }
+            #[isomorphic(to = dec)]
+            async fn inc(&mut self) {
+                std::future::ready(()).await;
+                self.n += 1;
+            }
+        }
+        let mut c = encase(Counter::default());
+        let effect = block_on(Counter::inc(&mut c));
+        assert_eq!(c.borrow_state().n, 1);
+        effect.undo();
+        assert_eq!(c.borrow_state().n, 0);
+        drop(block_on(Counter::inc(&mut c)));
+        assert_eq!(c.borrow_state().n, 0);
+    }
+    #[test]
+    fn retry_async_sleeps_between_attempts() {
+        let mut fails = 2;
+        let atom = retry(
+            (),
+            move |_| {
+                if fails > 0 {
+                    fails -= 1;
+                    Err(fails)
+                } else {
+                    Ok(())
+                }
+            },
+            2,
+            std::time::Duration::from_secs(60),
+        );
+        let mut sleeps = 0;
+        let r = block_on(atom.undo_async(|_| {
+            sleeps += 1;
+            std::future::ready(())
+        }));
+        assert_eq!(r, Ok(()));
+        assert_eq!(sleeps, 2);
+    }
+    #[test]
+    fn composed_undo_runs_in_reverse() {
+        let (fg, fg_inv) = compose(
+            |s: &mut Vec<&str>| s.push("f"),
+            |s| s.push("f_inv"),
+            |s| s.push("g"),
+            |s| s.push("g_inv"),
+        );
+        let (fgh, fgh_inv) = compose(fg, fg_inv, |s| s.push("h"), |s| s.push("h_inv"));
+        let mut log = encase(Vec::new());
+        let effect = log.peel_mut(fgh, |s, _| fgh_inv(s));
+        assert_eq!(*log.borrow_state(), vec!["f", "g", "h"]);
+        drop(effect);
+        assert_eq!(
+            *log.borrow_state(),
+            vec!["f", "g", "h", "h_inv", "g_inv", "f_inv"]
+        );
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_passes_the_value_to_the_undo() {
+        #[derive(Default)]
+        struct Stack {
+            els: Vec<i32>,
+            cleared: usize,
+        }
+        impl Stack {
+            fn push(&mut self, el: Option<i32>) {
+                self.els.extend(el);
+            }
+            fn uncount(&mut self) {
+                self.cleared -= 1;
+            }
+            #[isomorphic(to = push)]
+            fn pop(&mut self) -> Option<i32> {
+                self.els.pop()
+            }
+            #[isomorphic(to = uncount, no_args)]
+            fn count(&mut self) -> usize {
+                self.cleared += 1;
+                self.cleared
+            }
+        }
+        let mut s = encase(Stack {
+            els: vec![4, 5],
+            cleared: 0,
+        });
+        let popped = Stack::pop(&mut s);
+        assert_eq!(*popped, Some(5));
+        assert_eq!(s.borrow_state().els, vec![4]);
+        drop(popped);
+        assert_eq!(s.borrow_state().els, vec![4, 5]);
+        assert_eq!(Stack::pop(&mut s).decay(), Some(5));
+        assert_eq!(s.borrow_state().els, vec![4]);
+        let counted = Stack::count(&mut s);
+        assert_eq!(*counted, 1);
+        drop(counted);
+        assert_eq!(s.borrow_state().cleared, 0);
+    }
+    #[test]
+    fn noop_has_no_side_effects() {
+        let mut s = stack::Stack::new();
+        s.push(noop());
+        s.push(noop().clone());
+        assert_eq!(s.undo_all().len(), 2);
+        drop(noop());
+        noop().decay();
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_undo_args_are_captured_before_the_body() {
+        struct Items(Vec<i32>);
+        impl Items {
+            fn truncate(&mut self, len: usize) {
+                self.0.truncate(len);
+            }
+            #[isomorphic(to = truncate(self.0.len()))]
+            fn extend(&mut self, with: Vec<i32>) {
+                self.0.extend(with);
+            }
+        }
+        let mut items = encase(Items(vec![1]));
+        let extended = Items::extend(&mut items, vec![2, 3]);
+        assert_eq!(items.borrow_state().0, vec![1, 2, 3]);
+        drop(extended);
+        assert_eq!(items.borrow_state().0, vec![1]);
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_closure_undo_gets_the_value() {
+        struct Stack(Vec<i32>);
+        impl Stack {
+            #[isomorphic(to = |s, v| {
+                if let Some(v) = v {
+                    s.0.push(v)
+                }
+            })]
+            fn pop(&mut self) -> Option<i32> {
+                self.0.pop()
+            }
+        }
+        let mut s = encase(Stack(vec![1, 2]));
+        drop(Stack::pop(&mut s));
+        assert_eq!(s.borrow_state().0, vec![1, 2]);
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_free_functions_take_the_encased_state() {
+        #[derive(Debug, PartialEq)]
+        struct Account {
+            balance: u64,
+        }
+        struct Receipt(u64);
+        fn deposit(account: &mut Account, receipt: Receipt) {
+            account.balance += receipt.0;
+        }
+        #[isomorphic(to = deposit)]
+        fn withdraw(account: &mut Account, amount: u64) -> Receipt {
+            account.balance -= amount;
+            Receipt(amount)
+        }
+        fn reset(account: &mut Account, balance: u64) {
+            account.balance = balance;
+        }
+        #[isomorphic(to = reset(account.balance))]
+        fn double(account: &mut Account) {
+            account.balance *= 2;
+        }
+        let mut account = encase(Account { balance: 10 });
+        let receipt = withdraw(&mut account, 4);
+        assert_eq!(account.borrow_state().balance, 6);
+        drop(receipt);
+        assert_eq!(account.borrow_state().balance, 10);
+        drop(double(&mut account));
+        assert_eq!(account.borrow_state().balance, 10);
+        assert_eq!(withdraw(&mut account, 3).decay().0, 3);
+        assert_eq!(account.borrow_state().balance, 7);
+    }
+    #[test]
+    fn default_constructors_start_empty() {
+        let mut items = own_default::<Vec<i32>>();
+        assert!(items.is_empty());
+        items.push(1);
+        assert!(items.undo().is_empty());
+        let mut items = encase_default::<Vec<i32>>();
+        drop(items.peel_mut(|i| i.push(1), |i, _| i.pop()));
+        assert!(items.borrow_state().is_empty());
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_keeps_generics_and_lifetimes() {
+        use std::fmt::Debug;
+        struct Bag<T> {
+            items: Vec<T>,
+        }
+        impl<T: Clone> Bag<T> {
+            fn unapply(&mut self) {
+                self.items.pop();
+            }
+            #[isomorphic(to = unapply)]
+            fn apply<U>(&mut self, item: U)
+            where
+                U: Into<T> + Debug,
+            {
+                self.items.push(item.into());
+            }
+            #[isomorphic(to = |b, _| { b.items.pop(); })]
+            fn push_ref<'a>(&mut self, item: &'a T) -> &'a T {
+                self.items.push(item.clone());
+                item
+            }
+        }
+        fn remove_first<T>(bag: &mut Bag<T>, _: &T) {
+            bag.items.remove(0);
+        }
+        #[isomorphic(to = remove_first)]
+        fn push_first<'a, T>(bag: &mut Bag<T>, item: &'a T) -> &'a T
+        where
+            T: Clone + 'a,
+        {
+            bag.items.insert(0, item.clone());
+            item
+        }
+        let mut bag = encase(Bag::<String> { items: vec![] });
+        let applied = Bag::apply(&mut bag, "a");
+        assert_eq!(bag.borrow_state().items, vec!["a"]);
+        drop(applied);
+        assert!(bag.borrow_state().items.is_empty());
+        let item = "b".to_owned();
+        let pushed = Bag::push_ref(&mut bag, &item);
+        assert_eq!(*pushed, "b");
+        drop(pushed);
+        assert!(bag.borrow_state().items.is_empty());
+        let pushed = push_first(&mut bag, &item);
+        assert_eq!(bag.borrow_state().items, vec!["b"]);
+        drop(pushed);
+        assert!(bag.borrow_state().items.is_empty());
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_async_free_functions_pass_the_value() {
+        struct Service {
+            jobs: Vec<u32>,
+        }
+        fn requeue(service: &mut Service, job: Option<u32>) {
+            service.jobs.extend(job);
+        }
+        #[isomorphic(to = requeue)]
+        async fn take_job(service: &mut Service) -> Option<u32> {
+            std::future::ready(()).await;
+            service.jobs.pop()
+        }
+        let mut service = encase(Service { jobs: vec![1, 2] });
+        let job = block_on(take_job(&mut service));
+        assert_eq!(*job, Some(2));
+        assert_eq!(service.borrow_state().jobs, vec![1]);
+        drop(job);
+        assert_eq!(service.borrow_state().jobs, vec![1, 2]);
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_keeps_the_return_value_readable() {
+        struct Log(Vec<&'static str>);
+        impl Log {
+            fn forget(&mut self) {
+                self.0.pop();
+            }
+            #[isomorphic(to = forget)]
+            fn note(&mut self, msg: &'static str) -> () {
+                self.0.push(msg)
+            }
+            #[isomorphic(to = forget, no_args)]
+            fn note_len(&mut self, msg: &'static str) -> usize {
+                self.0.push(msg);
+                self.0.len()
+            }
+        }
+        let mut log = encase(Log(Vec::new()));
+        let noted = Log::note(&mut log, "a");
+        assert_eq!(*noted, ());
+        let len = Log::note_len(&mut log, "b");
+        assert_eq!(*len, 2);
+        drop(len);
+        drop(noted);
+        assert!(log.borrow_state().0.is_empty());
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_shared_receivers_register_an_undo() {
+        use std::cell::Cell;
+        struct Pool {
+            next: Cell<u32>,
+            released: Vec<u32>,
+        }
+        impl Pool {
+            fn release(&mut self, token: u32) {
+                self.released.push(token);
+            }
+            #[isomorphic(to = release)]
+            fn reserve(&self) -> u32 {
+                let id = self.next.get();
+                self.next.set(id + 1);
+                id
+            }
+        }
+        let pool = encase(Pool {
+            next: Cell::new(1),
+            released: Vec::new(),
+        });
+        let a = Pool::reserve(&pool);
+        let b = Pool::reserve(&pool);
+        assert_eq!((*a, *b), (1, 2));
+        drop(b);
+        a.decay();
+        assert_eq!(pool.borrow_state().released, vec![2]);
+    }
+    #[test]
+    fn apply_or_undo_resolves_by_the_result() {
+        #[derive(Default)]
+        struct Db {
+            rows: Vec<(u32, &'static str)>,
+        }
+        fn insert(
+            db: &mut atom::Encased<Db>,
+            id: u32,
+            name: &'static str,
+        ) -> impl Atom<Undo = ()> + std::ops::Deref<Target = u32> {
+            db.peel_mut(
+                move |db| {
+                    db.rows.push((id, name));
+                    id
+                },
+                |db, _| {
+                    db.rows.pop();
+                },
+            )
+        }
+        let unique = |db: &atom::Encased<Db>, id: u32| {
+            if db.read(|db| db.rows.iter().filter(|(i, _)| *i == id).count()) > 1 {
+                Err("duplicate id")
+            } else {
+                Ok(id)
+            }
+        };
+        let mut db = encase_default::<Db>();
+        let check = db.clone();
+        assert_eq!(
+            apply_or_undo(insert(&mut db, 1, "a"), |id| unique(&check, *id)),
+            Ok(1)
+        );
+        assert_eq!(
+            apply_or_undo(insert(&mut db, 1, "b"), |id| unique(&check, *id)),
+            Err("duplicate id")
+        );
+        assert_eq!(db.borrow_state().rows, vec![(1, "a")]);
+    }
+    #[cfg(feature = "macros")]
+    mod attrs {
+        use super::*;
+
+        pub struct Counter(pub i32);
+        impl Counter {
+            fn dec(&mut self) {
+                self.0 -= 1;
+            }
+            /// Increments, only in tests
+            #[cfg(test)]
+            #[isomorphic(to = dec)]
+            #[must_use]
+            #[allow(unused_variables)]
+            pub(crate) fn inc(&mut self, unused: u8) {
+                self.0 += 1;
+            }
+            #[cfg(not(test))]
+            #[isomorphic(to = dec)]
+            pub(crate) fn inc(&mut self, _unused: u8) {
+                self.0 += 2;
+            }
+            #[isomorphic(to = dec)]
+            fn inc_when(&mut self, #[cfg(test)] by: i32, #[cfg(not(test))] by: u8) {
+                self.0 += by;
+            }
+        }
+        #[isomorphic(to = reset)]
+        pub(super) fn bump(#[allow(unused_mut)] counter: &mut Counter) {
+            counter.0 += 10;
+        }
+        fn reset(counter: &mut Counter) {
+            counter.0 -= 10;
+        }
+
+        #[test]
+        fn isomorphic_keeps_attributes_and_visibility() {
+            let mut c = encase(Counter(0));
+            let inc = Counter::inc(&mut c, 0);
+            assert_eq!(c.borrow_state().0, 1);
+            drop(inc);
+            drop(Counter::inc_when(&mut c, 1));
+            assert_eq!(c.borrow_state().0, 0);
+            bump(&mut c).decay();
+            assert_eq!(c.borrow_state().0, 10);
+        }
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_forwards_every_argument() {
+        struct Grid(Vec<Vec<u8>>);
+        impl Grid {
+            fn clear(&mut self, row: usize, col: usize) {
+                self.0[row][col] = 0;
+            }
+            #[isomorphic(to = clear(row, col))]
+            fn set(&mut self, row: usize, col: usize, value: u8) {
+                self.0[row][col] = value;
+            }
+            #[isomorphic(to = clear(pos.0, pos.1))]
+            fn set_at(&mut self, pos: (usize, usize), (base, offset): (u8, u8)) {
+                self.0[pos.0][pos.1] = base + offset;
+            }
+            fn shrink(&mut self, len: usize) {
+                self.0[0].truncate(len);
+            }
+            #[isomorphic(to = shrink(self.0[0].len()))]
+            fn extend(&mut self, mut values: Vec<u8>, more: impl IntoIterator<Item = u8>) {
+                values.extend(more);
+                self.0[0].extend(values);
+            }
+        }
+        let mut grid = encase(Grid(vec![vec![0; 2]; 2]));
+        let set = Grid::set(&mut grid, 1, 0, 7);
+        assert_eq!(grid.borrow_state().0, vec![vec![0, 0], vec![7, 0]]);
+        drop(set);
+        let set = Grid::set_at(&mut grid, (0, 1), (4, 1));
+        assert_eq!(grid.borrow_state().0[0][1], 5);
+        drop(set);
+        drop(Grid::extend(&mut grid, vec![1], [2, 3]));
+        assert_eq!(grid.borrow_state().0, vec![vec![0; 2]; 2]);
+    }
+    #[test]
+    fn fn_atoms_run_forward_now_and_undo_later() {
+        use std::cell::Cell;
+        let value = Cell::new(1);
+        let doubled = fn_atom(|| value.set(value.get() * 2), || value.set(value.get() / 2));
+        assert_eq!(value.get(), 2);
+        drop(doubled);
+        assert_eq!(value.get(), 1);
+        fn_atom(|| value.set(3), || value.set(1)).decay();
+        assert_eq!(value.get(), 3);
+        let old = fn_atom_returning(|| value.replace(10), |old| value.replace(old));
+        assert_eq!(value.get(), 10);
+        assert_eq!(old.undo(), 10);
+        assert_eq!(value.get(), 3);
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_can_keep_the_original() {
+        struct Stack(Vec<i32>);
+        impl Stack {
+            fn push(&mut self, v: Option<i32>) {
+                self.0.extend(v);
+            }
+            #[isomorphic(to = push, keep_original)]
+            fn pop(&mut self) -> Option<i32> {
+                self.0.pop()
+            }
+            #[isomorphic(to = push, suffix = "_undoable")]
+            fn pop_front(&mut self) -> Option<i32> {
+                (!self.0.is_empty()).then(|| self.0.remove(0))
+            }
+        }
+        let mut plain = Stack(vec![1, 2, 3]);
+        assert_eq!(plain.pop(), Some(3));
+        assert_eq!(plain.pop_front(), Some(1));
+        let mut s = encase(plain);
+        drop(Stack::pop_iso(&mut s));
+        assert_eq!(s.borrow_state().0, vec![2]);
+        assert_eq!(Stack::pop_front_undoable(&mut s).decay(), Some(2));
+        assert!(s.borrow_state().0.is_empty());
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_impl_wraps_trait_impl_methods() {
+        trait Editable {
+            fn apply(&mut self, op: i32);
+            fn revert(&mut self, op: i32);
+        }
+        #[derive(Default)]
+        struct Total(i32);
+        #[isomorphic_impl(vis = pub(crate))]
+        impl Editable for Total {
+            #[undo(revert(op))]
+            fn apply(&mut self, op: i32) {
+                self.0 += op;
+            }
+            fn revert(&mut self, op: i32) {
+                self.0 -= op;
+            }
+        }
+        let mut plain = Total::default();
+        plain.apply(1);
+        assert_eq!(plain.0, 1);
+        let mut total = encase(plain);
+        let applied = total.apply(5);
+        assert_eq!(total.borrow_state().0, 6);
+        applied.undo();
+        assert_eq!(total.borrow_state().0, 1);
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn encase_newtype_delegates_to_the_state() {
+        trait Labelled {
+            fn label(&self) -> String;
+            fn relabel(&mut self, label: &str) -> String;
+        }
+        #[derive(Default, Encase)]
+        struct Log<T> {
+            entries: Vec<T>,
+            label: String,
+        }
+        #[isomorphic_impl(newtype)]
+        impl<T: Clone> Log<T> {
+            #[undo(pop, no_args)]
+            pub fn push(&mut self, entry: T) {
+                self.entries.push(entry);
+            }
+            pub fn pop(&mut self) -> Option<T> {
+                self.entries.pop()
+            }
+            pub fn last(&self) -> Option<T> {
+                self.entries.last().cloned()
+            }
+            pub fn first(&self) -> Option<&T> {
+                self.entries.first()
+            }
+            pub fn len(&self) -> usize {
+                self.entries.len()
+            }
+            pub fn with_label(&self, (prefix, n): (&str, usize)) -> Self {
+                Log {
+                    entries: self.entries.iter().take(n).cloned().collect(),
+                    label: format!("{prefix}{}", self.label),
+                }
+            }
+        }
+        #[isomorphic_impl(newtype, vis = pub(crate))]
+        impl<T> Labelled for Log<T> {
+            fn label(&self) -> String {
+                self.label.clone()
+            }
+            #[undo(|s, old| s.label = old)]
+            fn relabel(&mut self, label: &str) -> String {
+                std::mem::replace(&mut self.label, label.to_owned())
+            }
+        }
+        let mut log = EncasedLog::from(Log::<i32>::default());
+        let pushed = log.push(1);
+        drop(log.push(2));
+        assert_eq!((log.len(), log.last()), (1, Some(1)));
+        assert_eq!(log.read(|l| l.first().copied()), Some(1));
+        assert_eq!(log.with_label(("copy of ", 0)).label, "copy of ");
+        let relabelled = log.relabel("draft");
+        assert_eq!(log.label(), "draft");
+        let shared = log.clone();
+        pushed.undo();
+        relabelled.undo();
+        assert_eq!(shared.len(), 0);
+        assert_eq!(shared.label(), "");
+        assert_eq!(log.modify(|l| l.pop()), None);
+        assert!(log.into_encased().borrow_state().entries.is_empty());
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn atomic_blocks_undo_when_they_fail() {
+        fn parse(raw: &str) -> Result<i32, String> {
+            raw.parse().map_err(|_| format!("bad number: {raw}"))
+        }
+        let items = encase(vec![1]);
+        let log = encase(Vec::<String>::new());
+        let imported: Result<usize, String> = atomic!(items, log => {
+            for raw in ["2", "3", "x", "4"] {
+                let n = parse(raw)?;
+                items.peel_mut(|i| i.push(n), |i, _| {
+                    i.pop();
+                });
+                log.peel_mut(|l| l.push(format!("added {n}")), |l, _| drop(l.pop()));
+            }
+            Ok(items.read(|i| i.len()))
+        });
+        assert_eq!(imported, Err("bad number: x".to_owned()));
+        assert_eq!(*items.borrow_state(), vec![1]);
+        assert!(log.borrow_state().is_empty());
+
+        let popped: Result<Option<i32>, ()> = atomic!(items => {
+            let popped = items.peel_mut(|i| i.pop(), |i, v| i.extend(v));
+            if popped.is_none() {
+                return Err(());
+            }
+            Ok(popped)
+        });
+        assert_eq!(popped, Ok(Some(1)));
+        assert!(items.borrow_state().is_empty());
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _: Result<(), ()> = atomic!(items => {
+                items.peel_mut(|i| i.push(5), |i, _| {
+                    i.pop();
+                });
+                panic!("boom")
+            });
+        }));
+        assert!(panicked.is_err());
+        assert!(items.borrow_state().is_empty());
+    }
+    #[test]
+    fn nested_attempts_only_undo_their_own_atoms() {
+        let mut names = encase(vec!["alice"]);
+        let mut stack = stack::Stack::new();
+        let push = |names: &mut atom::Encased<Vec<&'static str>>, name| {
+            names.peel_mut(
+                move |n| n.push(name),
+                |n, _| {
+                    n.pop();
+                },
+            )
+        };
+        stack.push(push(&mut names, "bob"));
+        let outer = attempt(&mut stack, |s| {
+            s.push(push(&mut names, "carol"));
+            let inner = attempt(s, |s| {
+                s.push(push(&mut names, "dave"));
+                s.push(push(&mut names, "erin"));
+                Err::<(), _>("inner")
+            });
+            assert_eq!(inner, Err("inner"));
+            assert_eq!(*names.borrow_state(), vec!["alice", "bob", "carol"]);
+            attempt(s, |s| {
+                s.push(push(&mut names, "frank"));
+                Ok::<_, &str>(())
+            })?;
+            Ok::<_, &str>(s.len())
+        });
+        assert_eq!(outer, Ok(2));
+        assert_eq!(stack.len(), 1);
+        assert_eq!(
+            *names.borrow_state(),
+            vec!["alice", "bob", "carol", "frank"]
+        );
+
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            attempt(&mut stack, |s| {
+                s.push(push(&mut names, "grace"));
+                panic!("boom");
+                #[allow(unreachable_code)]
+                Ok::<(), ()>(())
+            })
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(stack.len(), 1);
+        assert_eq!(names.borrow_state().len(), 4);
+        // Only the atom from before the attempts is undone, which pops the last name
+        stack.undo_all();
+        assert_eq!(*names.borrow_state(), vec!["alice", "bob", "carol"]);
+    }
+    #[test]
+    fn protect_undoes_before_unwinding_further() {
+        use std::{cell::Cell, rc::Rc};
+        struct CheckOnDrop {
+            items: atom::Encased<Vec<i32>>,
+            restored: Rc<Cell<Option<bool>>>,
+        }
+        impl Drop for CheckOnDrop {
+            fn drop(&mut self) {
+                self.restored.set(Some(self.items.read(|i| *i == vec![1])));
+            }
+        }
+        let items = encase(vec![1]);
+        let restored = Rc::new(Cell::new(None));
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _check = CheckOnDrop {
+                items: items.clone(),
+                restored: restored.clone(),
+            };
+            let mut target = items.clone();
+            let pushed = target.peel_mut(
+                |i| i.push(2),
+                |i, _| {
+                    i.pop();
+                },
+            );
+            protect(pushed, || panic!("risky work failed"))
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(restored.get(), Some(true));
+
+        let mut stack = stack::Stack::new();
+        let mut target = items.clone();
+        stack.push(target.peel_mut(|i| i.clear(), |i, _| i.push(1)));
+        let (stack, n) = protect(stack, || 5);
+        assert_eq!((stack.len(), n), (1, 5));
+        drop(stack);
+        assert_eq!(*items.borrow_state(), vec![1]);
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_undo_expressions_see_state_and_value() {
+        #[derive(Default)]
+        struct Doc {
+            text: String,
+            journal: Vec<String>,
+        }
+        impl Doc {
+            #[isomorphic(undo = {
+                self.text.truncate(self.text.len() - value);
+                state.journal.push(format!("reverted {value}"));
+            })]
+            fn append(&mut self, s: &str) -> usize {
+                self.text.push_str(s);
+                s.len()
+            }
+        }
+        #[isomorphic(undo = state.clear())]
+        fn fill(doc: &mut Doc) {
+            doc.text = "filled".to_owned();
+        }
+        #[isomorphic(undo = LOG.with(|l| l.set(l.get() + 1)))]
+        fn touch(_doc: &mut Doc) {}
+        thread_local! {
+            static LOG: std::cell::Cell<i32> = const { std::cell::Cell::new(0) };
+        }
+        impl Doc {
+            fn clear(&mut self) {
+                self.text.clear();
+            }
+        }
+
+        let mut doc = encase_default::<Doc>();
+        let appended = Doc::append(&mut doc, "hello");
+        assert_eq!(*appended, 5);
+        drop(appended);
+        assert_eq!(doc.borrow_state().text, "");
+        assert_eq!(doc.borrow_state().journal, vec!["reverted 5"]);
+        fill(&mut doc).undo();
+        assert!(doc.borrow_state().text.is_empty());
+        touch(&mut doc).undo();
+        assert_eq!(LOG.with(|l| l.get()), 1);
+    }
+    #[cfg(feature = "macros")]
+    fn label_of(n: i32) -> String {
+        format!("#{n}")
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_renames_self_only_where_it_is_the_state() {
+        struct Tags {
+            names: Vec<String>,
+            next: i32,
+        }
+        impl Tags {
+            const PREFIX: &'static str = "tag";
+            fn prefixed(n: i32) -> String {
+                format!("{}{n}", Self::PREFIX)
+            }
+            fn untag(&mut self) {
+                self.names.pop();
+                self.next -= 1;
+            }
+            #[isomorphic(to = untag)]
+            fn tag(&mut self) {
+                let name = format!(
+                    "{}:{}",
+                    Self::prefixed(self.next),
+                    self::label_of(self.next)
+                );
+                assert!(!matches!(self.names.last(), Some(last) if *last == name));
+                let mut push = |n: String| self.names.push(n);
+                push(name);
+                struct Counter(i32);
+                impl Counter {
+                    fn bump(&mut self) -> i32 {
+                        self.0 += 1;
+                        self.0
+                    }
+                }
+                self.next = Counter(self.next).bump();
+            }
+        }
+        let mut tags = encase(Tags {
+            names: vec![],
+            next: 0,
+        });
+        let tagged = Tags::tag(&mut tags);
+        assert_eq!(tags.borrow_state().names, vec!["tag0:#0"]);
+        assert_eq!(tags.borrow_state().next, 1);
+        drop(tagged);
+        assert!(tags.borrow_state().names.is_empty());
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_fail_type_only_undoes_successes() {
+        #[derive(Default)]
+        struct Ledger {
+            entries: Vec<i32>,
+            failures: Vec<String>,
+        }
+        fn record_failure(ledger: &mut Ledger, err: &str) {
+            ledger.entries.pop();
+            ledger.failures.push(err.to_owned());
+        }
+        impl Ledger {
+            fn unrecord(&mut self, _: usize) {
+                self.entries.pop();
+            }
+            #[isomorphic(to = unrecord, fail_type = Result<_, String>, on_fail = record_failure)]
+            fn record(&mut self, amount: i32) -> Result<usize, String> {
+                self.entries.push(amount);
+                if amount < 0 {
+                    return Err(format!("negative: {amount}"));
+                }
+                Ok(self.entries.len())
+            }
+            #[isomorphic(to = unrecord, fail_type = Result<usize, String>)]
+            fn check(&self) -> Result<usize, String> {
+                self.entries.len().checked_sub(1).ok_or_else(String::new)
+            }
+        }
+        let mut ledger = encase_default::<Ledger>();
+        let recorded = Ledger::record(&mut ledger, 5).unwrap();
+        assert_eq!(*recorded, 1);
+        let failed = Ledger::record(&mut ledger, -1).map(|_| ());
+        assert_eq!(failed, Err("negative: -1".to_owned()));
+        assert_eq!(ledger.borrow_state().entries, vec![5]);
+        assert_eq!(ledger.borrow_state().failures, vec!["negative: -1"]);
+        drop(recorded);
+        assert!(ledger.borrow_state().entries.is_empty());
+
+        assert!(Ledger::check(&ledger).is_err());
+        ledger.modify(|l| l.entries.push(1));
+        drop(Ledger::check(&ledger).unwrap());
+        assert!(ledger.borrow_state().entries.is_empty());
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_redo_runs_the_operation_again() {
+        #[derive(Default)]
+        struct Tags {
+            names: Vec<String>,
+        }
+        impl Tags {
+            #[isomorphic(to = pop, no_args, keep_original, redo = add)]
+            fn add(&mut self, name: String) {
+                self.names.push(name);
+            }
+            fn pop(&mut self) {
+                self.names.pop();
+            }
+        }
+        let mut tags = encase_default::<Tags>();
+        let added = Tags::add_iso(&mut tags, "a".to_owned());
+        let redo = added.undo();
+        assert!(tags.borrow_state().names.is_empty());
+        let added = redo.redo();
+        assert_eq!(tags.borrow_state().names, vec!["a"]);
+        // Undoing a redo is a redo, so a stack can go back and forth
+        let redo = added.undo().undo().undo();
+        assert!(tags.borrow_state().names.is_empty());
+        drop(redo);
+        assert!(tags.borrow_state().names.is_empty());
+
+        let mut count = encase(0);
+        #[isomorphic(to = |s, _| *s -= 1, redo = |s| *s += 1)]
+        fn bump(s: &mut i32) {
+            *s += 1;
+        }
+        let bumped = [bump(&mut count), bump(&mut count)];
+        let redos = bumped.map(Atom::undo);
+        assert_eq!(*count.borrow_state(), 0);
+        let bumped = redos.map(|r| r.redo());
+        assert_eq!(*count.borrow_state(), 2);
+        drop(bumped);
+        assert_eq!(*count.borrow_state(), 0);
+    }
+    #[test]
+    fn owning_a_reference_undoes_to_the_snapshot() {
+        let original = vec![1, 2, 3];
+        let mut copy = own_ref(&original);
+        copy.clear();
+        copy.push(4);
+        assert_eq!(*copy, vec![4]);
+        assert_eq!(copy.undo(), original);
+
+        let mut copy = own_ref_with(
+            &original,
+            |v| v.len(),
+            |mut v, len| {
+                v.truncate(len);
+                v
+            },
+        );
+        copy.push(5);
+        assert_eq!(copy.undo(), original);
+        assert_eq!(own_ref(&original).decay(), original);
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_impl_wraps_marked_methods_in_a_trait() {
+        struct Stack<T> {
+            els: Vec<T>,
+            peeked: usize,
+        }
+        #[isomorphic_impl]
+        impl<T: Clone> Stack<T> {
+            fn unpush(&mut self) {
+                self.els.pop();
+            }
+            fn unpeek(&mut self) {
+                self.peeked -= 1;
+            }
+            #[undo(unpush)]
+            fn push(&mut self, v: T) {
+                self.els.push(v);
+            }
+            /// Remove the newest element
+            #[undo(to = |s, v| s.els.extend(v))]
+            fn pop(&mut self) -> Option<T> {
+                self.els.pop()
+            }
+            #[undo(truncate(self.els.len()))]
+            fn extend(&mut self, mut items: Vec<Self>) {
+                for s in &mut items {
+                    self.els.append(&mut s.els);
+                }
+            }
+            fn truncate(&mut self, len: usize) {
+                self.els.truncate(len);
+            }
+            #[undo(unpeek, no_args)]
+            fn peek(&self) -> Option<T> {
+                self.els.last().cloned()
+            }
+            #[undo(unpush)]
+            async fn push_later(&mut self, v: T) {
+                std::future::ready(()).await;
+                self.els.push(v);
+            }
+        }
+        impl<T> Stack<T> {
+            fn of(els: Vec<T>) -> Self {
+                Self { els, peeked: 0 }
+            }
+        }
+        let mut s = encase(Stack::of(vec![1]));
+        let pushed = s.push(2);
+        let popped = s.pop();
+        assert_eq!(popped.decay(), Some(2));
+        pushed.undo();
+        assert_eq!(s.borrow_state().els, Vec::<i32>::new());
+        let popped = s.pop();
+        assert_eq!(*popped, None);
+        drop(popped);
+
+        let extended = s.extend(vec![Stack::of(vec![3, 4]), Stack::of(vec![5])]);
+        assert_eq!(s.borrow_state().els, vec![3, 4, 5]);
+        drop(extended);
+        assert!(s.borrow_state().els.is_empty());
+
+        s.modify(|s| s.els.push(6));
+        let peeked = s.peek();
+        assert_eq!(*peeked, Some(6));
+        s.modify(|s| s.peeked += 1);
+        drop(peeked);
+        assert_eq!(s.borrow_state().peeked, 0);
+
+        let later = block_on(s.push_later(7));
+        assert_eq!(s.borrow_state().els, vec![6, 7]);
+        drop(later);
+        assert_eq!(s.borrow_state().els, vec![6]);
+
+        let mut plain = Stack::of(vec![8]);
+        assert_eq!(plain.pop(), Some(8));
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_pair_methods_undo_each_other() {
+        struct Stack {
+            els: Vec<i32>,
+            cleared: usize,
+        }
+        #[isomorphic_pair(push, pop, name = UndoableStack)]
+        impl Stack {
+            fn push(&mut self, v: i32) {
+                self.els.push(v);
+            }
+            fn pop(&mut self) -> i32 {
+                self.els.pop().unwrap()
+            }
+            #[undo(unclear)]
+            fn clear(&mut self) {
+                self.cleared += 1;
+            }
+            fn unclear(&mut self) {
+                self.cleared -= 1;
+            }
+        }
+        let mut s = encase(Stack {
+            els: vec![1],
+            cleared: 0,
+        });
+        let pushed = s.push(2);
+        assert_eq!(s.borrow_state().els, vec![1, 2]);
+        pushed.undo();
+        assert_eq!(s.borrow_state().els, vec![1]);
+        let popped = s.pop();
+        assert_eq!(*popped, 1);
+        assert!(s.borrow_state().els.is_empty());
+        popped.undo();
+        assert_eq!(s.borrow_state().els, vec![1]);
+        drop(s.clear());
+        assert_eq!(s.borrow_state().cleared, 0);
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn isomorphic_impl_trait_can_be_named() {
+        #[derive(Default)]
+        struct Counter(i32);
+        #[isomorphic_impl(name = Undoable)]
+        impl Counter {
+            fn dec(&mut self) {
+                self.0 -= 1;
+            }
+            #[undo(dec)]
+            fn inc(&mut self) {
+                self.0 += 1;
+            }
+        }
+        fn inc_twice(c: &mut impl Undoable) {
+            c.inc().decay();
+            c.inc().decay();
+        }
+        let mut c = encase_default::<Counter>();
+        inc_twice(&mut c);
+        c.inc().undo();
+        assert_eq!(c.borrow_state().0, 2);
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn derived_rewind_restores_all_but_skipped_fields() {
+        #[derive(Rewind, Debug, PartialEq)]
+        struct Config {
+            retries: u8,
+            hosts: Vec<String>,
+            #[rewind(skip)]
+            lookups: usize,
+        }
+        let mut config = Config {
+            retries: 1,
+            hosts: vec!["a".to_owned()],
+            lookups: 0,
+        };
+        {
+            let mut config = config.guard();
+            config.retries = 3;
+            config.hosts.clear();
+            config.lookups += 1;
+        }
+        assert_eq!(
+            (config.retries, config.hosts.len(), config.lookups),
+            (1, 1, 1)
+        );
+        let mut guard = config.guard();
+        guard.retries = 5;
+        guard.disarm();
+        assert_eq!(config.retries, 5);
+
+        let snapshot = config.rewind_snapshot();
+        config.hosts.push("b".to_owned());
+        config.rewind_restore(snapshot);
+        assert_eq!(config.hosts, vec!["a"]);
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn derived_rewind_handles_generics_and_nesting() {
+        #[derive(Rewind)]
+        struct Limits<T> {
+            max: T,
+            #[rewind(skip)]
+            hits: Vec<T>,
+        }
+        #[derive(Rewind)]
+        struct Server<'a, T: Copy> {
+            name: &'a str,
+            #[rewind(nested)]
+            limits: Limits<T>,
+        }
+        #[derive(Rewind)]
+        struct Pair<T>(#[rewind(skip)] T, u8);
+
+        let mut server = Server {
+            name: "a",
+            limits: Limits {
+                max: 10,
+                hits: vec![],
+            },
+        };
+        let guard = {
+            let mut guard = server.guard();
+            guard.name = "b";
+            guard.limits.max = 20;
+            guard.limits.hits.push(1);
+            guard
+        };
+        guard.undo();
+        assert_eq!((server.name, server.limits.max), ("a", 10));
+        assert_eq!(server.limits.hits, vec![1]);
+
+        let mut pair = Pair(String::from("kept"), 1);
+        let mut guard = pair.guard();
+        guard.0.push('!');
+        guard.1 = 2;
+        drop(guard);
+        assert_eq!((pair.0.as_str(), pair.1), ("kept!", 1));
+    }
+    #[cfg(feature = "macros")]
+    #[transaction]
+    fn push_parsed(
+        items: &mut atom::Encased<Vec<i32>>,
+        raw: &[&str],
+    ) -> Result<usize, std::num::ParseIntError> {
+        for r in raw {
+            let v = r.parse()?;
+            tx.push(items.peel_mut(|i| i.push(v), |i, _| i.pop()));
+            if v < 0 {
+                panic!("negative");
+            }
+        }
+        Ok(items.borrow_state().len())
+    }
+    #[cfg(feature = "macros")]
+    #[transaction(steps)]
+    async fn push_later(items: &mut atom::Encased<Vec<i32>>, v: i32) -> Result<(), ()> {
+        steps.push(items.peel_mut(|i| i.push(v), |i, _| i.pop()));
+        std::future::ready(()).await;
+        if v == 0 {
+            return Err(());
+        }
+        Ok(())
+    }
+    #[cfg(feature = "macros")]
+    #[test]
+    fn transactions_undo_everything_on_failure() {
+        let mut items = encase(vec![1]);
+        assert_eq!(push_parsed(&mut items, &["2", "3"]), Ok(3));
+        assert!(push_parsed(&mut items, &["4", "x", "5"]).is_err());
+        assert_eq!(*items.borrow_state(), vec![1, 2, 3]);
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            push_parsed(&mut items, &["6", "-7"])
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(*items.borrow_state(), vec![1, 2, 3]);
+
+        assert_eq!(block_on(push_later(&mut items, 0)), Err(()));
+        assert_eq!(block_on(push_later(&mut items, 4)), Ok(()));
+        assert_eq!(*items.borrow_state(), vec![1, 2, 3, 4]);
     }
     #[test]
     fn encasing_cannot_leak_abstraction_and_cause_panic_due_to_multiple_borrows() {
@@ -124,8 +1922,8 @@ mod tests {
             },
         );
         assert_eq!(*v, Some(3));
-        assert_eq!(items.len(), 2);
+        assert_eq!(items.borrow_state().len(), 2);
         v.undo();
-        assert_eq!(items.len(), 3);
+        assert_eq!(items.borrow_state().len(), 3);
     }
 }
@@ -0,0 +1,3384 @@
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Display,
+    hash::Hash,
+    ops::{Add, AddAssign, Bound, RangeBounds},
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+    rc::{Rc, Weak},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc,
+    },
+    thread::{JoinHandle, ThreadId},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Serialize, Serializer};
+
+use crate::{
+    atom::{Encased, SideEffect},
+    Atom,
+};
+
+/// An atom whose undo can be described as plain data
+///
+/// Closures cannot be serialized, so to persist a rollback plan (e.g. for crash recovery) the atom
+/// instead describes what its undo would do. See [`Stack::serialize_intents`] and
+/// [`Stack::rehydrate`].
+#[cfg(feature = "serde")]
+pub trait ReplayableAtom: Atom {
+    type Intent: Serialize + DeserializeOwned;
+    fn intent(&self) -> Self::Intent;
+}
+
+/// An atom whose undo can fail without being lost, so that it can be tried again
+///
+/// Push these with [`Stack::push_fallible`] and resolve them with [`Stack::try_undo`]. A plain
+/// [`undo`](Atom::undo) is still used everywhere else, e.g. when the stack is dropped.
+pub trait FallibleAtom: Atom + Sized {
+    type Error: std::error::Error + Send + Sync + 'static;
+    /// Undo, or give the atom back along with the reason it could not be undone yet
+    fn try_undo(self) -> Result<Self::Undo, (Self, Self::Error)>;
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Type erased [`Atom`] so that atoms of different types can live in the same [`Stack`]
+trait ErasedAtom {
+    fn undo_boxed(self: Box<Self>) -> Box<dyn Any>;
+    fn decay_boxed(self: Box<Self>) -> Box<dyn Any>;
+    /// Atoms which cannot fail are simply undone
+    #[allow(clippy::type_complexity)]
+    fn try_undo_boxed(self: Box<Self>) -> Result<Box<dyn Any>, (Box<dyn ErasedAtom>, BoxError)> {
+        Ok(self.undo_boxed())
+    }
+    /// Take the atom out to undo on another thread, if it was pushed with
+    /// [`Stack::push_independent`]
+    #[cfg(feature = "rayon")]
+    fn take_independent(&mut self) -> Option<Box<dyn IndependentAtom>> {
+        None
+    }
+}
+
+/// Type erased atom pushed with [`Stack::push_independent`], which can be undone on any thread
+#[cfg(feature = "rayon")]
+trait IndependentAtom: Send {
+    fn undo_send(self: Box<Self>) -> Box<dyn Any + Send>;
+}
+
+/// Marks an atom pushed with [`Stack::push_independent`], `None` once taken out to be undone
+#[cfg(feature = "rayon")]
+struct Independent<A>(Option<A>);
+
+#[cfg(feature = "rayon")]
+impl<A: Atom + Send + 'static> IndependentAtom for Independent<A>
+where
+    A::Undo: Send + 'static,
+{
+    fn undo_send(self: Box<Self>) -> Box<dyn Any + Send> {
+        Box::new(self.0.expect("only taken when consumed").undo())
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<A: Atom + Send + 'static> ErasedAtom for Independent<A>
+where
+    A::Undo: Send + 'static,
+    A::Decay: 'static,
+{
+    fn undo_boxed(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(self.0.expect("only taken when consumed").undo())
+    }
+    fn decay_boxed(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(self.0.expect("only taken when consumed").decay())
+    }
+    fn take_independent(&mut self) -> Option<Box<dyn IndependentAtom>> {
+        Some(Box::new(Independent(Some(self.0.take()?))))
+    }
+}
+
+impl<A: Atom + 'static> ErasedAtom for A
+where
+    A::Undo: 'static,
+    A::Decay: 'static,
+{
+    fn undo_boxed(self: Box<Self>) -> Box<dyn Any> {
+        Box::new((*self).undo())
+    }
+    fn decay_boxed(self: Box<Self>) -> Box<dyn Any> {
+        Box::new((*self).decay())
+    }
+}
+
+/// Marks an atom pushed with [`Stack::push_fallible`]
+struct Fallible<A>(A);
+
+impl<A: FallibleAtom + 'static> ErasedAtom for Fallible<A>
+where
+    A::Undo: 'static,
+    A::Decay: 'static,
+{
+    fn undo_boxed(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(self.0.undo())
+    }
+    fn decay_boxed(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(self.0.decay())
+    }
+    fn try_undo_boxed(self: Box<Self>) -> Result<Box<dyn Any>, (Box<dyn ErasedAtom>, BoxError)> {
+        match self.0.try_undo() {
+            Ok(r) => Ok(Box::new(r)),
+            Err((atom, e)) => Err((Box::new(Fallible(atom)), Box::new(e))),
+        }
+    }
+}
+
+/// Type erased [`Atom`] which may borrow, used by [`ScopedStack`]
+///
+/// Since results may borrow as well they cannot be boxed as [`Any`] and are dropped instead
+trait DynAtom {
+    fn undo_dyn(self: Box<Self>);
+    fn decay_dyn(self: Box<Self>);
+}
+
+impl<A: Atom> DynAtom for A {
+    fn undo_dyn(self: Box<Self>) {
+        (*self).undo();
+    }
+    fn decay_dyn(self: Box<Self>) {
+        (*self).decay();
+    }
+}
+
+struct StackEl {
+    generation: u64,
+    /// `None` once the atom has been resolved through its handle
+    atom: Option<Box<dyn ErasedAtom>>,
+    label: Option<&'static str>,
+    /// Whether the last [`Stack::try_undo`] of the atom failed
+    failed: bool,
+    #[cfg(feature = "serde")]
+    intent: Option<Box<dyn Any>>,
+}
+
+/// An atom which has been removed from the stack but not resolved yet
+struct Popped {
+    atom: Box<dyn ErasedAtom>,
+    label: Option<&'static str>,
+    /// Position among the pending atoms when it was removed
+    position: usize,
+}
+
+/// How to resolve atoms removed in bulk from a [`Stack`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Resolve {
+    /// Undo the atoms, newest first
+    Undo,
+    /// Decay the atoms, oldest first
+    #[default]
+    Decay,
+}
+
+/// Information about a pending atom on a [`Stack`], see [`Stack::retain`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct StackEntryMeta {
+    /// Label given to [`Stack::push_labeled`]
+    pub label: Option<&'static str>,
+    /// Position among the pending atoms, the oldest is at 0
+    pub position: usize,
+}
+
+/// Changes to a [`Stack`], see [`Stack::observe`]
+///
+/// `label` is the label given to [`Stack::push_labeled`], if any. `index` is the position the
+/// atom had among the pending atoms when it was resolved, the oldest being at 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackEvent {
+    Pushed {
+        label: Option<&'static str>,
+    },
+    Undone {
+        label: Option<&'static str>,
+        index: usize,
+    },
+    Decayed {
+        label: Option<&'static str>,
+        index: usize,
+    },
+    /// Every atom on the stack was resolved at once, e.g. by [`Stack::undo_all`] or the stack
+    /// being dropped. Sent after the events for the individual atoms
+    Cleared,
+}
+
+/// Refers to a single atom pushed onto a [`Stack`]
+///
+/// Handles stay valid while other atoms are resolved, but become stale once the atom they refer
+/// to has been undone or decayed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AtomHandle {
+    index: usize,
+    generation: u64,
+}
+
+/// Errors from operations on a [`Stack`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    /// The handle refers to an atom which has already been resolved
+    StaleHandle,
+    /// [`end_group`](Stack::end_group) was called without a matching [`begin_group`](Stack::begin_group)
+    NoOpenGroup,
+}
+
+impl Display for StackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StackError::StaleHandle => f.write_str("handle refers to an already resolved atom"),
+            StackError::NoOpenGroup => f.write_str("no group is open"),
+        }
+    }
+}
+impl std::error::Error for StackError {}
+
+/// Errors from [`Stack::rollback_to_len`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RollbackError {
+    /// The stack already has fewer pending atoms than the target
+    AlreadyShorter { len: usize, target: usize },
+    /// The [`CheckpointId`] was marked by a different [`Checkpoint`]
+    OtherCheckpoint,
+}
+
+impl Display for RollbackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RollbackError::AlreadyShorter { len, target } => {
+                write!(
+                    f,
+                    "cannot roll back to {target} atoms, only {len} are pending"
+                )
+            }
+            RollbackError::OtherCheckpoint => {
+                f.write_str("checkpoint id was marked by a different checkpoint")
+            }
+        }
+    }
+}
+impl std::error::Error for RollbackError {}
+
+/// Undos which failed during [`Stack::try_undo`] or [`Stack::retry_failed`]
+///
+/// Each failure has the position of the atom among the pending atoms, the oldest being at 0, its
+/// label and the error, in the order the undos were attempted. The atoms stay on the stack.
+#[derive(Debug)]
+pub struct UndoError {
+    pub failures: Vec<(usize, Option<&'static str>, BoxError)>,
+}
+
+impl Display for UndoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} undo(s) failed", self.failures.len())?;
+        for (i, (index, label, e)) in self.failures.iter().enumerate() {
+            f.write_str(if i == 0 { ": " } else { "; " })?;
+            match label {
+                Some(label) => write!(f, "atom {index} ({label}): {e}")?,
+                None => write!(f, "atom {index}: {e}")?,
+            }
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for UndoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        let (_, _, e) = self.failures.first()?;
+        Some(&**e)
+    }
+}
+
+/// How long undos took, by operation name
+///
+/// Filled in by [`Timed`](crate::atom::Timed) atoms, or by a [`Stack`] given one with
+/// [`StackBuilder::with_histogram`], which records each undo under the label of the atom.
+///
+/// ```
+/// use std::time::Duration;
+/// let mut histogram = rewind::Histogram::new();
+/// histogram.record("save", Duration::from_millis(2));
+/// histogram.record("save", Duration::from_millis(4));
+/// assert_eq!(histogram.summary(), vec![("save", 2, 3_000_000, 4_000_000)]);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    durations: HashMap<&'static str, Vec<Duration>>,
+}
+
+/// Name undos of atoms without a label are recorded under by [`Stack`]
+pub const UNLABELED: &str = "<unlabeled>";
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Add a single undo of `name` which took `duration`
+    pub fn record(&mut self, name: &'static str, duration: Duration) {
+        self.durations.entry(name).or_default().push(duration);
+    }
+    /// The name, number of undos, mean and maximum duration in nanoseconds of each operation,
+    /// sorted by name
+    pub fn summary(&self) -> Vec<(&'static str, usize, u128, u128)> {
+        let mut summary: Vec<_> = self
+            .durations
+            .iter()
+            .map(|(name, ds)| {
+                let total: u128 = ds.iter().map(Duration::as_nanos).sum();
+                let max = ds.iter().max().map_or(0, Duration::as_nanos);
+                (*name, ds.len(), total / ds.len() as u128, max)
+            })
+            .collect();
+        summary.sort_unstable_by_key(|(name, ..)| *name);
+        summary
+    }
+    /// Print the [`summary`](Self::summary) to stderr
+    pub fn print_report(&self) {
+        eprint!("{self}");
+    }
+}
+
+impl Display for Histogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, count, mean, max) in self.summary() {
+            let (mean, max) = (
+                Duration::from_nanos(mean as u64),
+                Duration::from_nanos(max as u64),
+            );
+            writeln!(f, "{name}: {count} undos, mean {mean:?}, max {max:?}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A collection of atoms of different types, undone newest first
+///
+/// Any atoms still on the stack when it is dropped are undone.
+///
+/// ```
+/// let mut stack = rewind::stack::Stack::new();
+/// let first = stack.push(rewind::simple(1, |v| v));
+/// stack.push(rewind::simple(2, |v| v));
+/// stack.decay_one(first).unwrap();
+/// let undone = stack.undo_all();
+/// assert_eq!(undone.len(), 1);
+/// assert_eq!(undone[0].downcast_ref::<i32>(), Some(&2));
+/// ```
+#[derive(Default)]
+pub struct Stack {
+    els: Vec<StackEl>,
+    next_generation: u64,
+    /// Label and start index of groups which have not been closed yet, innermost last
+    open_groups: Vec<(String, usize)>,
+    groups: Vec<Group>,
+    name: Option<String>,
+    on_undo: Option<Box<UndoHook>>,
+    observers: Vec<ObserverSlot>,
+    /// Events which have happened but not been sent to the observers yet
+    events: Vec<StackEvent>,
+    stats: StackStats,
+    /// Number of atoms pending, kept alongside the stats for the peak
+    pending: usize,
+    /// Number of resolved elements dropped from the bottom of `els`, which handles are offset by
+    base: usize,
+    histogram: Option<Rc<RefCell<Histogram>>>,
+    max_len: Option<usize>,
+    evict_policy: Resolve,
+}
+
+/// Counters for everything that has happened on a [`Stack`], see [`Stack::stats`]
+///
+/// Atoms moved onto a stack by [`Stack::move_between`] or [`Stack::split_off`] count as pushed
+/// there, atoms moved off are not counted as resolved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct StackStats {
+    pub pushed: usize,
+    pub undone: usize,
+    pub decayed: usize,
+    /// Most atoms that were pending at once
+    pub peak_pending: usize,
+}
+
+type UndoHook = dyn FnMut(&[Box<dyn Any>]);
+type Panic = Box<dyn Any + Send>;
+/// Results of the undos that completed, and the panics with their index in the undo order
+type UndoResults = (Vec<Box<dyn Any>>, Vec<(usize, Panic)>);
+
+/// Resume a panic caught while resolving atoms, unless we are already unwinding
+fn resume(panic: Option<Panic>) {
+    if let Some(p) = panic {
+        if !std::thread::panicking() {
+            resume_unwind(p);
+        }
+    }
+}
+
+/// Undo `batch` on the rayon thread pool, giving the results in the same order
+#[cfg(feature = "rayon")]
+fn undo_batch(batch: Vec<Box<dyn IndependentAtom>>) -> Vec<Result<Box<dyn Any + Send>, Panic>> {
+    let mut results: Vec<_> = batch.iter().map(|_| None).collect();
+    rayon::scope(|scope| {
+        for (atom, slot) in batch.into_iter().zip(&mut results) {
+            scope.spawn(move |_| *slot = Some(catch_unwind(AssertUnwindSafe(|| atom.undo_send()))));
+        }
+    });
+    results
+        .into_iter()
+        .map(|r| r.expect("every undo finishes within the scope"))
+        .collect()
+}
+
+/// Result of a single undo from [`Stack::undo_all_catching`]
+pub enum UndoOutcome {
+    Undone(Box<dyn Any>),
+    /// The undo panicked, this is the panic payload
+    Panicked(Box<dyn Any + Send>),
+}
+
+impl std::fmt::Debug for UndoOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UndoOutcome::Undone(_) => f.write_str("Undone"),
+            UndoOutcome::Panicked(_) => f.write_str("Panicked"),
+        }
+    }
+}
+type Observer = dyn FnMut(StackEvent);
+
+struct ObserverSlot {
+    /// Dead once the [`ObserverHandle`] is dropped, `None` for observers which are never removed
+    alive: Option<Weak<()>>,
+    f: Box<Observer>,
+}
+
+/// Keeps an observer registered with [`Stack::observe`], dropping it unregisters the observer
+#[must_use = "the observer is removed when the handle is dropped"]
+#[derive(Debug)]
+pub struct ObserverHandle(#[allow(dead_code)] Rc<()>);
+
+/// Builder for a [`Stack`], see [`undo_stack`](rewind::undo_stack)
+#[derive(Default)]
+pub struct StackBuilder {
+    capacity: usize,
+    name: Option<String>,
+    on_undo: Option<Box<UndoHook>>,
+    histogram: Option<Rc<RefCell<Histogram>>>,
+}
+
+impl StackBuilder {
+    /// Pre-allocate space for `n` atoms
+    pub fn with_capacity(mut self, n: usize) -> Self {
+        self.capacity = n;
+        self
+    }
+    /// Name shown when the stack is displayed
+    pub fn named(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+    /// Called with the results whenever atoms on the stack are undone
+    ///
+    /// Single undos (e.g. [`Stack::undo_one`]) pass one result, bulk undos (including the
+    /// stack being dropped) pass all of them at once.
+    pub fn with_on_undo(mut self, f: impl FnMut(&[Box<dyn Any>]) + 'static) -> Self {
+        self.on_undo = Some(Box::new(f));
+        self
+    }
+    /// Record how long each undo takes in `histogram`, under the label of the atom or
+    /// [`UNLABELED`]
+    pub fn with_histogram(mut self, histogram: &Rc<RefCell<Histogram>>) -> Self {
+        self.histogram = Some(histogram.clone());
+        self
+    }
+    pub fn build(self) -> Stack {
+        let mut s = Stack::new();
+        s.els.reserve(self.capacity);
+        s.name = self.name;
+        s.on_undo = self.on_undo;
+        s.histogram = self.histogram;
+        s
+    }
+}
+
+/// A closed group covering `els[start..end]`
+struct Group {
+    label: String,
+    start: usize,
+    end: usize,
+}
+
+impl Popped {
+    /// The pending atoms in `els`, oldest first, with positions starting from `first_position`
+    fn pending(els: impl IntoIterator<Item = StackEl>, first_position: usize) -> Vec<Self> {
+        els.into_iter()
+            .filter_map(|el| Some((el.atom?, el.label)))
+            .enumerate()
+            .map(|(i, (atom, label))| Self {
+                atom,
+                label,
+                position: first_position + i,
+            })
+            .collect()
+    }
+}
+
+/// The newest undo step on a stack
+enum Step {
+    Single(Popped),
+    /// Newest first
+    Group(Vec<Popped>),
+}
+
+impl Stack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// A stack holding just `atom`
+    ///
+    /// This stands in for `From<A>`, which would overlap with `From<Stack> for Stack` since a
+    /// [`Stack`] is an [`Atom`] itself. Several atoms can be collected or converted from a [`Vec`].
+    ///
+    /// ```
+    /// # use std::{cell::Cell, rc::Rc};
+    /// let undone = Rc::new(Cell::new(false));
+    /// let flag = undone.clone();
+    /// let stack = rewind::stack::Stack::from_atom(rewind::simple((), move |_| flag.set(true)));
+    /// drop(stack);
+    /// assert!(undone.get());
+    /// ```
+    pub fn from_atom<A: Atom + 'static>(atom: A) -> Self
+    where
+        A::Undo: 'static,
+        A::Decay: 'static,
+    {
+        let mut s = Self::new();
+        s.push(atom);
+        s
+    }
+    /// Add an atom to the top of the stack
+    pub fn push<A: Atom + 'static>(&mut self, atom: A) -> AtomHandle
+    where
+        A::Undo: 'static,
+        A::Decay: 'static,
+    {
+        self.push_el(Box::new(atom), None)
+    }
+    /// [`push`](Self::push) with a label that is passed to observers
+    pub fn push_labeled<A: Atom + 'static>(&mut self, label: &'static str, atom: A) -> AtomHandle
+    where
+        A::Undo: 'static,
+        A::Decay: 'static,
+    {
+        self.push_el(Box::new(atom), Some(label))
+    }
+    /// Add an atom to the top of the stack, recording its intent for [`serialize_intents`](Stack::serialize_intents)
+    #[cfg(feature = "serde")]
+    pub fn push_replayable<A: ReplayableAtom + 'static>(&mut self, atom: A) -> AtomHandle
+    where
+        A::Undo: 'static,
+        A::Decay: 'static,
+        A::Intent: 'static,
+    {
+        let intent = Box::new(atom.intent());
+        self.push_existing(StackEl {
+            generation: 0,
+            atom: Some(Box::new(atom)),
+            label: None,
+            failed: false,
+            intent: Some(intent),
+        })
+    }
+    /// Add an atom which doesn't depend on the atoms around it, see
+    /// [`undo_parallel`](Self::undo_parallel)
+    ///
+    /// It is otherwise treated like any other atom, undone in order by everything else.
+    #[cfg(feature = "rayon")]
+    pub fn push_independent<A: Atom + Send + 'static>(&mut self, atom: A) -> AtomHandle
+    where
+        A::Undo: Send + 'static,
+        A::Decay: 'static,
+    {
+        self.push_el(Box::new(Independent(Some(atom))), None)
+    }
+    /// Add an atom whose undo can fail, see [`try_undo`](Self::try_undo)
+    pub fn push_fallible<A: FallibleAtom + 'static>(&mut self, atom: A) -> AtomHandle
+    where
+        A::Undo: 'static,
+        A::Decay: 'static,
+    {
+        self.push_el(Box::new(Fallible(atom)), None)
+    }
+    /// Number of atoms which are yet to be resolved
+    pub fn len(&self) -> usize {
+        self.pending
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Number of times [`pop_undo`](Self::pop_undo) needs to be called to empty the stack
+    ///
+    /// This is the same as [`len`](Self::len) unless atoms have been grouped, in which case each
+    /// outermost group only counts once
+    pub fn steps(&self) -> usize {
+        let mut groups: Vec<_> = self.groups.iter().map(|g| (g.start, g.end)).collect();
+        groups.sort_by_key(|(start, end)| (*start, std::cmp::Reverse(*end)));
+        let mut groups = groups.into_iter().peekable();
+        let mut steps = 0;
+        let mut i = 0;
+        while i < self.els.len() {
+            while groups.peek().is_some_and(|(start, _)| *start < i) {
+                groups.next();
+            }
+            let end = match groups.peek() {
+                Some((start, end)) if *start == i => *end,
+                _ => i + 1,
+            };
+            if self.els[i..end].iter().any(|el| el.atom.is_some()) {
+                steps += 1;
+            }
+            i = end;
+        }
+        steps
+    }
+    /// Label of the group the next [`pop_undo`](Self::pop_undo) would resolve
+    ///
+    /// `None` if the next step is a single atom or there is nothing to undo
+    pub fn next_group_label(&self) -> Option<&str> {
+        self.top_group().flatten().map(|g| g.label.as_str())
+    }
+    /// Start a group of atoms which are undone together by [`pop_undo`](Self::pop_undo)
+    ///
+    /// Groups can be nested. While a group is open, popping only considers the steps inside it.
+    pub fn begin_group(&mut self, label: &str) {
+        self.open_groups.push((label.to_owned(), self.els.len()));
+    }
+    /// Close the group started by the last [`begin_group`](Self::begin_group)
+    pub fn end_group(&mut self) -> Result<(), StackError> {
+        let (label, start) = self.open_groups.pop().ok_or(StackError::NoOpenGroup)?;
+        let end = self.els.len();
+        if start < end {
+            self.groups.push(Group { label, start, end });
+        }
+        Ok(())
+    }
+    /// Run `f` with every atom it pushes put in a single group
+    ///
+    /// ```
+    /// # use std::{cell::RefCell, rc::Rc};
+    /// let items = Rc::new(RefCell::new(vec![]));
+    /// let mut stack = rewind::stack::Stack::new();
+    /// stack.group("fill", |s| {
+    ///     for i in 0..3 {
+    ///         items.borrow_mut().push(i);
+    ///         let items = items.clone();
+    ///         s.push(rewind::simple((), move |_| items.borrow_mut().pop()));
+    ///     }
+    /// });
+    /// assert_eq!(stack.len(), 3);
+    /// assert_eq!(stack.steps(), 1);
+    /// stack.pop_undo();
+    /// assert!(items.borrow().is_empty());
+    /// ```
+    pub fn group<R>(&mut self, label: &str, f: impl FnOnce(&mut Stack) -> R) -> R {
+        self.begin_group(label);
+        let r = f(self);
+        self.end_group()
+            .expect("group was closed by the closure it was opened for");
+        r
+    }
+    /// Register a function to be called whenever the stack changes
+    ///
+    /// Observers are called in the order they were registered, after the operation that caused
+    /// the event has finished. If an observer panics the remaining observers are still called and
+    /// the stack is left in a consistent state before the panic is resumed.
+    ///
+    /// ```
+    /// # use std::{cell::Cell, rc::Rc};
+    /// use rewind::stack::{Stack, StackEvent};
+    /// let can_undo = Rc::new(Cell::new(false));
+    /// let mut stack = Stack::new();
+    /// let button = can_undo.clone();
+    /// stack.on_change(move |e| match e {
+    ///     StackEvent::Pushed { .. } => button.set(true),
+    ///     StackEvent::Cleared => button.set(false),
+    ///     _ => {}
+    /// });
+    /// stack.push_labeled("type 'a'", rewind::simple((), |_| ()));
+    /// assert!(can_undo.get());
+    /// stack.undo_all();
+    /// assert!(!can_undo.get());
+    /// ```
+    pub fn on_change(&mut self, f: impl FnMut(StackEvent) + 'static) {
+        self.observers.push(ObserverSlot {
+            alive: None,
+            f: Box::new(f),
+        });
+    }
+    /// [`on_change`](Self::on_change) for an observer which is removed when the returned handle
+    /// is dropped
+    ///
+    /// ```
+    /// # use std::{cell::Cell, rc::Rc};
+    /// let seen = Rc::new(Cell::new(0));
+    /// let mut stack = rewind::stack::Stack::new();
+    /// let counter = seen.clone();
+    /// let handle = stack.observe(move |_| counter.set(counter.get() + 1));
+    /// stack.push(rewind::simple((), |_| ()));
+    /// drop(handle);
+    /// stack.push(rewind::simple((), |_| ()));
+    /// assert_eq!(seen.get(), 1);
+    /// ```
+    pub fn observe(&mut self, f: impl FnMut(StackEvent) + 'static) -> ObserverHandle {
+        let alive = Rc::new(());
+        self.observers.push(ObserverSlot {
+            alive: Some(Rc::downgrade(&alive)),
+            f: Box::new(f),
+        });
+        ObserverHandle(alive)
+    }
+    /// Decay a single atom, leaving the rest of the stack untouched
+    pub fn decay_one(&mut self, h: AtomHandle) -> Result<(), StackError> {
+        let popped = self.take_handle(h)?;
+        self.decay_popped(popped);
+        self.flush_events();
+        Ok(())
+    }
+    /// Undo a single atom, leaving the rest of the stack untouched
+    pub fn undo_one(&mut self, h: AtomHandle) -> Result<Box<dyn Any>, StackError> {
+        let popped = self.take_handle(h)?;
+        let r = self.undo_popped(popped);
+        self.notify_undo(std::slice::from_ref(&r));
+        self.flush_events();
+        Ok(r)
+    }
+    /// Undo the newest atom, or the newest group of atoms
+    ///
+    /// Groups are undone newest first and their result is a `Vec<Box<dyn Any>>` of every undo in
+    /// the group.
+    ///
+    /// This is the intended way to drive interactive undo:
+    ///
+    /// ```
+    /// let mut stack = rewind::stack::Stack::new();
+    /// stack.push(rewind::simple(1, |v| v * 10));
+    /// stack.push(rewind::simple(2, |v| v * 10));
+    /// while let Some(r) = stack.pop_undo_as::<i32>() {
+    ///     println!("undid: {}", r.unwrap());
+    /// }
+    /// assert!(stack.is_empty());
+    /// ```
+    pub fn pop_undo(&mut self) -> Option<Box<dyn Any>> {
+        let r = self.pop_step().map(|step| match step {
+            Step::Single(p) => {
+                let r = self.undo_popped(p);
+                self.notify_undo(std::slice::from_ref(&r));
+                r
+            }
+            Step::Group(ps) => {
+                let (rs, panics) = self.undo_each(ps);
+                self.notify_undo(&rs);
+                self.flush_events();
+                resume(panics.into_iter().next().map(|(_, p)| p));
+                Box::new(rs)
+            }
+        });
+        self.flush_events();
+        r
+    }
+    /// Decay the newest atom, or the newest group of atoms
+    pub fn pop_decay(&mut self) -> Option<()> {
+        let r = self.pop_step().map(|step| match step {
+            Step::Single(p) => {
+                self.decay_popped(p);
+            }
+            Step::Group(ps) => ps.into_iter().rev().for_each(|p| {
+                self.decay_popped(p);
+            }),
+        });
+        self.flush_events();
+        r
+    }
+    /// [`pop_undo`](Self::pop_undo) but with the result downcast to `T`
+    ///
+    /// The atom is undone even if the downcast fails, in which case the result is returned as-is
+    pub fn pop_undo_as<T: 'static>(&mut self) -> Option<Result<T, Box<dyn Any>>> {
+        self.pop_undo().map(|r| r.downcast::<T>().map(|r| *r))
+    }
+    /// Undo every atom on the stack, newest first
+    ///
+    /// The results are in the order the undos were run. If any of the undos panic the rest are
+    /// still run, and then the first panic is resumed. See
+    /// [`undo_all_catching`](Self::undo_all_catching) to get every panic instead.
+    pub fn undo_all(&mut self) -> Vec<Box<dyn Any>> {
+        let (results, panics) = self.undo_all_inner();
+        resume(panics.into_iter().next().map(|(_, p)| p));
+        results
+    }
+    /// [`undo_all`](Self::undo_all) but returning panics from the undos instead of resuming them
+    ///
+    /// Any state touched by an undo which panicked may have been left partially modified.
+    pub fn undo_all_catching(&mut self) -> Vec<UndoOutcome> {
+        let (results, panics) = self.undo_all_inner();
+        let mut results = results.into_iter();
+        let mut panics = panics.into_iter().peekable();
+        let mut outcomes = Vec::new();
+        loop {
+            let outcome = match panics.next_if(|(i, _)| *i == outcomes.len()) {
+                Some((_, p)) => UndoOutcome::Panicked(p),
+                None => match results.next() {
+                    Some(r) => UndoOutcome::Undone(r),
+                    None => break,
+                },
+            };
+            outcomes.push(outcome);
+        }
+        outcomes
+    }
+    /// [`undo_all`](Self::undo_all) but keeping the atoms whose undo fails on the stack
+    ///
+    /// Every atom is attempted, newest first, even after a failure. Only atoms pushed with
+    /// [`push_fallible`](Self::push_fallible) can fail, and those that did are left pending in
+    /// their original order so that [`retry_failed`](Self::retry_failed) can try them again.
+    /// Handles to them stay valid. The results of the undos which succeeded are only returned if
+    /// none failed.
+    ///
+    /// Panics are handled like in [`undo_all`](Self::undo_all).
+    pub fn try_undo(&mut self) -> Result<Vec<Box<dyn Any>>, UndoError> {
+        self.try_undo_where(true)
+    }
+    /// [`try_undo`](Self::try_undo) for only the atoms which failed to undo last time
+    ///
+    /// Atoms pushed since are left alone.
+    pub fn retry_failed(&mut self) -> Result<Vec<Box<dyn Any>>, UndoError> {
+        self.try_undo_where(false)
+    }
+    /// [`undo_all`](Self::undo_all) but undoing runs of
+    /// [`push_independent`](Self::push_independent) atoms in parallel
+    ///
+    /// Each run of independent atoms is undone on the rayon thread pool, and finished before the
+    /// next atom down is undone, so other atoms are still undone newest first around them. The
+    /// results are in the order [`undo_all`](Self::undo_all) would give them, and panics are
+    /// handled the same way. Undos run on other threads aren't recorded in the [`Histogram`].
+    ///
+    /// ```
+    /// let mut stack = rewind::stack::Stack::new();
+    /// for i in 0..100 {
+    ///     stack.push_independent(rewind::simple(i, |i| i * 2));
+    /// }
+    /// let undone = stack.undo_parallel();
+    /// assert_eq!(undone[0].downcast_ref::<i32>(), Some(&198));
+    /// ```
+    #[cfg(feature = "rayon")]
+    pub fn undo_parallel(&mut self) -> Vec<Box<dyn Any>> {
+        let mut popped = Popped::pending(self.els.drain(..), 0);
+        popped.reverse();
+        self.trim_resolved();
+        let any = !popped.is_empty();
+        let mut results = Vec::new();
+        let mut panic = None;
+        let mut popped = popped.into_iter().peekable();
+        while let Some(mut p) = popped.next() {
+            let Some(first) = p.atom.take_independent() else {
+                let (rs, panics) = self.undo_each([p]);
+                results.extend(rs);
+                panic = panic.or(panics.into_iter().next().map(|(_, p)| p));
+                continue;
+            };
+            let mut batch = vec![first];
+            let mut resolved = vec![(p.label, p.position)];
+            while let Some(atom) = popped.peek_mut().and_then(|p| p.atom.take_independent()) {
+                let p = popped.next().expect("just peeked");
+                batch.push(atom);
+                resolved.push((p.label, p.position));
+            }
+            for (r, (label, index)) in undo_batch(batch).into_iter().zip(resolved) {
+                match r {
+                    Ok(r) => results.push(r as Box<dyn Any>),
+                    Err(p) => {
+                        panic.get_or_insert(p);
+                    }
+                }
+                self.record(StackEvent::Undone { label, index });
+            }
+        }
+        self.notify_undo(&results);
+        self.cleared(any);
+        resume(panic);
+        results
+    }
+    /// Decay every atom on the stack, oldest first
+    pub fn decay_all(&mut self) -> Vec<Box<dyn Any>> {
+        let popped = Popped::pending(self.els.drain(..), 0);
+        self.trim_resolved();
+        let results: Vec<_> = popped.into_iter().map(|p| self.decay_popped(p)).collect();
+        self.cleared(!results.is_empty());
+        results
+    }
+    /// Move up to `n` of the newest atoms from `from` onto `to`
+    ///
+    /// The moved atoms keep their relative order, so they are undone in the same order on `to`
+    /// as they would have been on `from`. Nothing is resolved, but handles to the moved atoms
+    /// become stale and observers of `to` see them as pushed. Returns the number of atoms moved.
+    ///
+    /// ```
+    /// use rewind::stack::Stack;
+    /// let mut doc_a = Stack::new();
+    /// let mut doc_b = Stack::new();
+    /// doc_a.push(rewind::simple(1, |v| v));
+    /// doc_a.push(rewind::simple(2, |v| v));
+    /// assert_eq!(Stack::move_between(&mut doc_a, &mut doc_b, 5), 2);
+    /// assert_eq!(doc_b.pop_undo_as::<i32>().unwrap().unwrap(), 2);
+    /// ```
+    pub fn move_between(from: &mut Stack, to: &mut Stack, n: usize) -> usize {
+        let moved = from.split_els(from.len().saturating_sub(n));
+        let count = moved.len();
+        for el in moved {
+            to.push_existing(el);
+        }
+        count
+    }
+    /// Split the stack in two at the pending position `at`, mirroring [`Vec::split_off`]
+    ///
+    /// The returned stack owns the atoms from `at` onwards (the newer ones) and `self` keeps the
+    /// older ones, each still undoing newest first. The new stack starts without a name, hook,
+    /// observers or groups, and handles to the moved atoms become stale.
+    ///
+    /// # Panics
+    ///
+    /// If `at > self.len()`
+    ///
+    /// ```
+    /// use rewind::stack::Stack;
+    /// let mut stack = Stack::new();
+    /// stack.push(rewind::simple(1, |v| v));
+    /// stack.push(rewind::simple(2, |v| v));
+    /// let mut newer = stack.split_off(1);
+    /// assert_eq!(newer.pop_undo_as::<i32>().unwrap().unwrap(), 2);
+    /// assert_eq!(stack.len(), 1);
+    /// ```
+    pub fn split_off(&mut self, at: usize) -> Stack {
+        let len = self.len();
+        assert!(
+            at <= len,
+            "split index {at} is out of bounds for a stack of {len}"
+        );
+        let mut split = Stack::new();
+        for el in self.split_els(at) {
+            split.push_existing(el);
+        }
+        split
+    }
+    /// Take every atom, leaving `self` empty but keeping its configuration
+    ///
+    /// Equivalent to `split_off(0)`
+    pub fn take(&mut self) -> Stack {
+        self.split_off(0)
+    }
+    /// Keep only the atoms for which `pred` returns `true`, resolving the rest according to `policy`
+    ///
+    /// The remaining atoms keep their order and handles to them stay valid.
+    ///
+    /// ```
+    /// use rewind::stack::{Resolve, Stack};
+    /// let mut stack = Stack::new();
+    /// stack.push_labeled("cache:a", rewind::simple(1, |v| v));
+    /// stack.push_labeled("db:b", rewind::simple(2, |v| v));
+    /// stack.retain(|m| !m.label.is_some_and(|l| l.starts_with("cache:")), Resolve::Decay);
+    /// assert_eq!(stack.len(), 1);
+    /// ```
+    pub fn retain(&mut self, mut pred: impl FnMut(&StackEntryMeta) -> bool, policy: Resolve) {
+        let popped = self.take_where(|meta| !pred(meta));
+        self.resolve_many(popped, policy);
+    }
+    /// Remove the pending atoms at the positions in `range` and resolve them according to `policy`
+    ///
+    /// Positions are counted from the oldest pending atom. The results are in the order the atoms
+    /// were resolved in.
+    ///
+    /// # Panics
+    ///
+    /// If the range is out of bounds for the number of pending atoms
+    pub fn drain_resolve(
+        &mut self,
+        range: impl RangeBounds<usize>,
+        policy: Resolve,
+    ) -> Vec<Box<dyn Any>> {
+        let len = self.len();
+        let start = match range.start_bound() {
+            Bound::Included(s) => *s,
+            Bound::Excluded(s) => s + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(e) => e + 1,
+            Bound::Excluded(e) => *e,
+            Bound::Unbounded => len,
+        };
+        assert!(
+            start <= end && end <= len,
+            "range {start}..{end} out of bounds for stack of length {len}"
+        );
+        let popped = self.take_where(|meta| (start..end).contains(&meta.position));
+        self.resolve_many(popped, policy)
+    }
+    /// Evict the oldest atoms until at most `max_len` are pending
+    ///
+    /// Evicted atoms are decayed, or resolved according to
+    /// [`auto_evict_policy`](Self::auto_evict_policy) if it was set. The results are in the order
+    /// the atoms were resolved in.
+    ///
+    /// ```
+    /// let mut stack = rewind::stack::Stack::new();
+    /// for i in 0..4 {
+    ///     stack.push(rewind::simple(i, |v| v));
+    /// }
+    /// let evicted = stack.truncate_from_bottom(1);
+    /// assert_eq!(evicted[0].downcast_ref::<i32>(), Some(&0));
+    /// assert_eq!(stack.len(), 1);
+    /// ```
+    pub fn truncate_from_bottom(&mut self, max_len: usize) -> Vec<Box<dyn Any>> {
+        let excess = self.len().saturating_sub(max_len);
+        self.drain_resolve(..excess, self.evict_policy)
+    }
+    /// Keep at most `max_len` atoms pending, evicting the oldest on every push
+    ///
+    /// Atoms already on the stack are evicted straight away, and handles to evicted atoms become
+    /// stale.
+    pub fn set_max_len(&mut self, max_len: usize) {
+        self.max_len = Some(max_len);
+        self.truncate_from_bottom(max_len);
+    }
+    pub fn max_len(&self) -> Option<usize> {
+        self.max_len
+    }
+    /// How atoms evicted by [`set_max_len`](Self::set_max_len) and
+    /// [`truncate_from_bottom`](Self::truncate_from_bottom) are resolved, decayed by default
+    ///
+    /// Undoing them is unusual, since it rolls back the oldest changes while keeping newer ones
+    /// which may depend on them.
+    pub fn auto_evict_policy(&mut self, policy: Resolve) {
+        self.evict_policy = policy;
+    }
+    /// Undo the newest atoms until only `target_len` are pending
+    ///
+    /// Groups are ignored, so this may undo only part of a group.
+    ///
+    /// ```
+    /// let mut stack = rewind::stack::Stack::new();
+    /// stack.push(rewind::simple(1, |v| v));
+    /// let save = stack.len();
+    /// stack.push(rewind::simple(2, |v| v));
+    /// stack.push(rewind::simple(3, |v| v));
+    /// assert_eq!(stack.rollback_to_len(save).unwrap().len(), 2);
+    /// assert_eq!(stack.len(), 1);
+    /// ```
+    pub fn rollback_to_len(
+        &mut self,
+        target_len: usize,
+    ) -> Result<Vec<Box<dyn Any>>, RollbackError> {
+        let len = self.len();
+        if len < target_len {
+            return Err(RollbackError::AlreadyShorter {
+                len,
+                target: target_len,
+            });
+        }
+        Ok(self.drain_resolve(target_len.., Resolve::Undo))
+    }
+
+    /// Run `f`, undoing the atoms it pushed if it returns `Err`
+    ///
+    /// Atoms pushed before are left pending either way, so a nested transaction which fails only
+    /// rolls back to where it started, even if `f` resolved some of those earlier atoms itself.
+    ///
+    /// ```
+    /// let mut stack = rewind::stack::Stack::new();
+    /// stack.push(rewind::simple(1, |v| v));
+    /// let failed = stack.transaction(|s| {
+    ///     s.push(rewind::simple(2, |v| v));
+    ///     Err::<(), _>("nope")
+    /// });
+    /// assert_eq!(failed, Err("nope"));
+    /// assert_eq!(stack.len(), 1);
+    /// ```
+    pub fn transaction<T, E>(
+        &mut self,
+        f: impl FnOnce(&mut Stack) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let save = self.mark();
+        let result = f(self);
+        if result.is_err() {
+            self.resolve_since(save, Resolve::Undo);
+        }
+        result
+    }
+    /// Marks the atoms pushed from now on, for [`resolve_since`](Self::resolve_since)
+    pub(crate) fn mark(&self) -> u64 {
+        self.next_generation
+    }
+    /// Resolve the pending atoms pushed since `mark`
+    pub(crate) fn resolve_since(&mut self, mark: u64, policy: Resolve) -> Vec<Box<dyn Any>> {
+        let start = self
+            .els
+            .iter()
+            .filter(|el| el.atom.is_some())
+            .position(|el| el.generation >= mark)
+            .unwrap_or(self.len());
+        self.drain_resolve(start.., policy)
+    }
+
+    /// Serialize the intents of all pending atoms pushed with [`push_replayable`](Stack::push_replayable), oldest first
+    ///
+    /// Atoms without an intent of type `I` are skipped. The output can be turned back into a
+    /// stack with [`rehydrate`](Stack::rehydrate).
+    #[cfg(feature = "serde")]
+    pub fn serialize_intents<I: Serialize + 'static, Ser: Serializer>(
+        &self,
+        serializer: Ser,
+    ) -> Result<Ser::Ok, Ser::Error> {
+        serializer.collect_seq(
+            self.els
+                .iter()
+                .filter(|el| el.atom.is_some())
+                .filter_map(|el| el.intent.as_ref()?.downcast_ref::<I>()),
+        )
+    }
+    /// Rebuild a stack from previously serialized intents
+    ///
+    /// `registry` maps each intent to its undo action and is called when the corresponding atom
+    /// is undone. Dispatching on the intent (e.g. matching on an enum) is left up to the registry.
+    #[cfg(feature = "serde")]
+    pub fn rehydrate<I: 'static, R: 'static>(
+        intents: impl IntoIterator<Item = I>,
+        registry: impl Fn(I) -> R + 'static,
+    ) -> Stack {
+        let registry = std::rc::Rc::new(registry);
+        intents
+            .into_iter()
+            .map(|intent| {
+                let registry = registry.clone();
+                rewind::simple(intent, move |i| registry(i))
+            })
+            .collect()
+    }
+
+    /// Counts of the atoms pushed and resolved over the lifetime of the stack
+    ///
+    /// ```
+    /// use rewind::stack::Stack;
+    /// let mut stack = Stack::new();
+    /// stack.push(rewind::simple(1, |v| v));
+    /// stack.push(rewind::simple(2, |v| v));
+    /// stack.pop_undo();
+    /// stack.pop_decay();
+    /// let stats = stack.stats();
+    /// assert_eq!((stats.pushed, stats.undone, stats.decayed), (2, 1, 1));
+    /// assert_eq!(stats.peak_pending, 2);
+    /// ```
+    pub fn stats(&self) -> StackStats {
+        self.stats
+    }
+    /// Name given by [`StackBuilder::named`]
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    fn notify_undo(&mut self, results: &[Box<dyn Any>]) {
+        if let Some(f) = &mut self.on_undo {
+            if !results.is_empty() {
+                f(results);
+            }
+        }
+    }
+    /// Every atom leaving the stack is resolved through either this or [`decay_popped`](Self::decay_popped)
+    ///
+    /// The atom is recorded as resolved even if it panics, since it has left the stack either way
+    fn undo_popped(&mut self, p: Popped) -> Box<dyn Any> {
+        let (label, atom) = (p.label, p.atom);
+        let r = catch_unwind(AssertUnwindSafe(|| self.timed(label, || atom.undo_boxed())));
+        self.record(StackEvent::Undone {
+            label,
+            index: p.position,
+        });
+        r.unwrap_or_else(|panic| resume_unwind(panic))
+    }
+    fn decay_popped(&mut self, p: Popped) -> Box<dyn Any> {
+        let r = catch_unwind(AssertUnwindSafe(|| p.atom.decay_boxed()));
+        self.record(StackEvent::Decayed {
+            label: p.label,
+            index: p.position,
+        });
+        r.unwrap_or_else(|panic| resume_unwind(panic))
+    }
+    /// Run an undo, recording how long it took if the stack has a histogram
+    fn timed<R>(&self, label: Option<&'static str>, undo: impl FnOnce() -> R) -> R {
+        let Some(histogram) = &self.histogram else {
+            return undo();
+        };
+        let start = Instant::now();
+        let r = undo();
+        histogram
+            .borrow_mut()
+            .record(label.unwrap_or(UNLABELED), start.elapsed());
+        r
+    }
+    /// Undo each atom in turn, carrying on past any that panic
+    ///
+    /// Returns the results of the undos which completed and the panics along with their index
+    /// in `popped`
+    fn undo_each(&mut self, popped: impl IntoIterator<Item = Popped>) -> UndoResults {
+        let mut results = Vec::new();
+        let mut panics = Vec::new();
+        for (i, p) in popped.into_iter().enumerate() {
+            let (label, index) = (p.label, p.position);
+            match catch_unwind(AssertUnwindSafe(|| {
+                self.timed(label, || p.atom.undo_boxed())
+            })) {
+                Ok(r) => results.push(r),
+                Err(panic) => panics.push((i, panic)),
+            }
+            self.record(StackEvent::Undone { label, index });
+        }
+        (results, panics)
+    }
+    fn undo_all_inner(&mut self) -> UndoResults {
+        let mut popped = Popped::pending(self.els.drain(..), 0);
+        popped.reverse();
+        self.trim_resolved();
+        let any = !popped.is_empty();
+        let (results, panics) = self.undo_each(popped);
+        self.notify_undo(&results);
+        self.cleared(any);
+        (results, panics)
+    }
+    /// Attempt every pending atom newest first, or only those which failed before unless `all`
+    fn try_undo_where(&mut self, all: bool) -> Result<Vec<Box<dyn Any>>, UndoError> {
+        let mut results = Vec::new();
+        let mut failures = Vec::new();
+        let mut panic = None;
+        for i in (0..self.els.len()).rev() {
+            let el = &mut self.els[i];
+            if !(all || el.failed) {
+                continue;
+            }
+            let Some(atom) = el.atom.take() else {
+                continue;
+            };
+            let (label, index) = (el.label, self.pending_before(i));
+            match catch_unwind(AssertUnwindSafe(|| {
+                self.timed(label, || atom.try_undo_boxed())
+            })) {
+                Ok(Err((atom, e))) => {
+                    let el = &mut self.els[i];
+                    el.atom = Some(atom);
+                    el.failed = true;
+                    failures.push((index, label, e));
+                    continue;
+                }
+                Ok(Ok(r)) => results.push(r),
+                Err(p) => {
+                    panic.get_or_insert(p);
+                }
+            }
+            self.record(StackEvent::Undone { label, index });
+        }
+        self.trim_resolved();
+        self.notify_undo(&results);
+        if all && failures.is_empty() {
+            self.cleared(!results.is_empty() || panic.is_some());
+        } else {
+            self.flush_events();
+        }
+        resume(panic);
+        if failures.is_empty() {
+            Ok(results)
+        } else {
+            Err(UndoError { failures })
+        }
+    }
+    /// Remove every pending atom matching `f`, oldest first
+    fn take_where(&mut self, mut f: impl FnMut(&StackEntryMeta) -> bool) -> Vec<Popped> {
+        let mut popped = Vec::new();
+        let pending = self.els.iter_mut().filter(|el| el.atom.is_some());
+        for (position, el) in pending.enumerate() {
+            let meta = StackEntryMeta {
+                label: el.label,
+                position,
+            };
+            if f(&meta) {
+                popped.push(Popped {
+                    atom: el.atom.take().unwrap(),
+                    label: el.label,
+                    position,
+                });
+            }
+        }
+        self.trim_resolved();
+        popped
+    }
+    /// Resolve atoms which were taken off the stack, `popped` is oldest first
+    fn resolve_many(&mut self, popped: Vec<Popped>, policy: Resolve) -> Vec<Box<dyn Any>> {
+        let (results, panics) = match policy {
+            Resolve::Undo => self.undo_each(popped.into_iter().rev()),
+            Resolve::Decay => (
+                popped.into_iter().map(|p| self.decay_popped(p)).collect(),
+                Vec::new(),
+            ),
+        };
+        if policy == Resolve::Undo {
+            self.notify_undo(&results);
+        }
+        self.flush_events();
+        resume(panics.into_iter().next().map(|(_, p)| p));
+        results
+    }
+    fn cleared(&mut self, any: bool) {
+        if any {
+            self.record(StackEvent::Cleared);
+        }
+        self.flush_events();
+    }
+    /// Number of pending atoms below `els[index]`
+    fn pending_before(&self, index: usize) -> usize {
+        self.els[..index.min(self.els.len())]
+            .iter()
+            .filter(|el| el.atom.is_some())
+            .count()
+    }
+    /// Queue an event for the observers and count it towards the stats
+    fn record(&mut self, event: StackEvent) {
+        match event {
+            StackEvent::Pushed { .. } => {
+                self.stats.pushed += 1;
+                self.pending += 1;
+                self.stats.peak_pending = self.stats.peak_pending.max(self.pending);
+            }
+            StackEvent::Undone { .. } => {
+                self.stats.undone += 1;
+                self.pending -= 1;
+            }
+            StackEvent::Decayed { .. } => {
+                self.stats.decayed += 1;
+                self.pending -= 1;
+            }
+            StackEvent::Cleared => {}
+        }
+        self.events.push(event);
+    }
+    /// Send pending events to the observers
+    ///
+    /// This must only be called once the stack is in a consistent state, since observers may panic
+    fn flush_events(&mut self) {
+        let events = std::mem::take(&mut self.events);
+        self.observers
+            .retain(|o| o.alive.as_ref().is_none_or(|a| a.strong_count() > 0));
+        let mut panic = None;
+        for e in events {
+            for observer in &mut self.observers {
+                if let Err(p) = catch_unwind(AssertUnwindSafe(|| (observer.f)(e))) {
+                    panic.get_or_insert(p);
+                }
+            }
+        }
+        resume(panic);
+    }
+    fn push_el(&mut self, atom: Box<dyn ErasedAtom>, label: Option<&'static str>) -> AtomHandle {
+        self.push_existing(StackEl {
+            generation: 0,
+            atom: Some(atom),
+            label,
+            failed: false,
+            #[cfg(feature = "serde")]
+            intent: None,
+        })
+    }
+    /// Push an element, giving it a new generation
+    fn push_existing(&mut self, mut el: StackEl) -> AtomHandle {
+        let generation = self.next_generation;
+        self.next_generation += 1;
+        el.generation = generation;
+        self.record(StackEvent::Pushed { label: el.label });
+        self.els.push(el);
+        self.flush_events();
+        let handle = AtomHandle {
+            index: self.base + self.els.len() - 1,
+            generation,
+        };
+        if let Some(max_len) = self.max_len {
+            self.truncate_from_bottom(max_len);
+        }
+        handle
+    }
+    fn take_handle(&mut self, h: AtomHandle) -> Result<Popped, StackError> {
+        let index = h
+            .index
+            .checked_sub(self.base)
+            .ok_or(StackError::StaleHandle)?;
+        let position = self.pending_before(index);
+        let popped = self
+            .els
+            .get_mut(index)
+            .filter(|el| el.generation == h.generation)
+            .and_then(|el| {
+                Some(Popped {
+                    atom: el.atom.take()?,
+                    label: el.label,
+                    position,
+                })
+            })
+            .ok_or(StackError::StaleHandle)?;
+        self.trim_resolved();
+        Ok(popped)
+    }
+    /// The outermost group containing the newest atom, if the newest step is a group
+    ///
+    /// Returns `None` if there are no steps left
+    fn top_group(&self) -> Option<Option<&Group>> {
+        let floor = self.open_groups.last().map_or(0, |(_, start)| *start);
+        let top = self.els.len().checked_sub(1).filter(|top| *top >= floor)?;
+        Some(
+            self.groups
+                .iter()
+                .filter(|g| g.start >= floor && g.start <= top && top < g.end)
+                .min_by_key(|g| g.start),
+        )
+    }
+    fn pop_step(&mut self) -> Option<Step> {
+        let group_start = self.top_group()?.map(|g| g.start);
+        let start = group_start.unwrap_or(self.els.len().checked_sub(1)?);
+        let position = self.pending_before(start);
+        let mut popped = Popped::pending(self.els.drain(start..), position);
+        let step = match group_start {
+            Some(_) => {
+                popped.reverse();
+                Step::Group(popped)
+            }
+            None => Step::Single(popped.pop()?),
+        };
+        self.trim_resolved();
+        Some(step)
+    }
+    /// Remove the pending atoms from position `at` onwards without resolving them
+    fn split_els(&mut self, at: usize) -> Vec<StackEl> {
+        let split = self
+            .els
+            .iter()
+            .enumerate()
+            .filter(|(_, el)| el.atom.is_some())
+            .nth(at)
+            .map_or(self.els.len(), |(i, _)| i);
+        let moved = self
+            .els
+            .split_off(split)
+            .into_iter()
+            .filter(|el| el.atom.is_some())
+            .collect::<Vec<_>>();
+        self.pending -= moved.len();
+        self.trim_resolved();
+        moved
+    }
+    /// Keeps the invariant that the top and bottom of the stack are always pending atoms and that
+    /// groups only cover elements which exist
+    fn trim_resolved(&mut self) {
+        while self.els.last().is_some_and(|el| el.atom.is_none()) {
+            self.els.pop();
+        }
+        let dropped = self.els.iter().take_while(|el| el.atom.is_none()).count();
+        self.els.drain(..dropped);
+        self.base += dropped;
+        let len = self.els.len();
+        self.groups.retain_mut(|g| {
+            g.start = g.start.saturating_sub(dropped);
+            g.end = g.end.saturating_sub(dropped).min(len);
+            g.start < g.end
+        });
+        for (_, start) in &mut self.open_groups {
+            *start = start.saturating_sub(dropped).min(len);
+        }
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        self.undo_all();
+    }
+}
+
+impl Atom for Stack {
+    type Undo = Vec<Box<dyn Any>>;
+    type Decay = Vec<Box<dyn Any>>;
+    fn undo(mut self) -> Self::Undo {
+        self.undo_all()
+    }
+    fn decay(mut self) -> Self::Decay {
+        self.decay_all()
+    }
+}
+
+impl<A: Atom + 'static> From<Vec<A>> for Stack
+where
+    A::Undo: 'static,
+    A::Decay: 'static,
+{
+    /// Pushes the atoms in order, so the last element is undone first
+    fn from(atoms: Vec<A>) -> Self {
+        atoms.into_iter().collect()
+    }
+}
+
+impl<A: Atom + 'static> FromIterator<A> for Stack
+where
+    A::Undo: 'static,
+    A::Decay: 'static,
+{
+    fn from_iter<I: IntoIterator<Item = A>>(iter: I) -> Self {
+        let mut s = Stack::new();
+        for atom in iter {
+            s.push(atom);
+        }
+        s
+    }
+}
+
+impl AddAssign for Stack {
+    /// Moves every pending atom of `rhs` on top of `self`, so they are undone before the atoms
+    /// already in `self`
+    ///
+    /// Only the atoms are moved, `rhs`'s name, hook, observers and groups are dropped with it.
+    fn add_assign(&mut self, mut rhs: Stack) {
+        for el in rhs.split_els(0) {
+            self.push_existing(el);
+        }
+    }
+}
+
+impl Add for Stack {
+    type Output = Stack;
+    /// The atoms of `self` followed by the atoms of `rhs`, see [`AddAssign`]
+    ///
+    /// ```
+    /// use rewind::stack::Stack;
+    /// let a: Stack = vec![rewind::simple(1, |v| v)].into();
+    /// let b: Stack = vec![rewind::simple(2, |v| v)].into();
+    /// let mut combined = a + b;
+    /// assert_eq!(combined.pop_undo_as::<i32>().unwrap().unwrap(), 2);
+    /// ```
+    fn add(mut self, rhs: Stack) -> Stack {
+        self += rhs;
+        self
+    }
+}
+
+impl std::fmt::Debug for Stack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Stack")
+            .field("name", &self.name)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl Display for Stack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({} pending)",
+            self.name.as_deref().unwrap_or("stack"),
+            self.len()
+        )
+    }
+}
+
+/// A [`Stack`] for atoms which borrow from their surroundings
+///
+/// Results of undo and decay are discarded since they may borrow too. Atoms are undone newest
+/// first, and any left when the stack is dropped are undone, as with [`Stack`].
+///
+/// ```
+/// # use rewind::stack::ScopedStack;
+/// let mut count = 0;
+/// let mut total = 10;
+/// {
+///     let mut stack = ScopedStack::new();
+///     stack.push(rewind::simple(&mut count, |c| *c += 1));
+///     stack.push(rewind::simple(&mut total, |t| *t -= 1));
+/// }
+/// assert_eq!((count, total), (1, 9));
+/// ```
+#[derive(Default)]
+pub struct ScopedStack<'a> {
+    els: Vec<Box<dyn DynAtom + 'a>>,
+}
+
+impl<'a> ScopedStack<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn push<A: Atom + 'a>(&mut self, atom: A) {
+        self.els.push(Box::new(atom));
+    }
+    pub fn len(&self) -> usize {
+        self.els.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.els.is_empty()
+    }
+    /// Undo the newest atom, returns `false` if there was nothing to undo
+    pub fn pop_undo(&mut self) -> bool {
+        self.els.pop().map(|a| a.undo_dyn()).is_some()
+    }
+    /// Decay the newest atom, returns `false` if there was nothing to decay
+    pub fn pop_decay(&mut self) -> bool {
+        self.els.pop().map(|a| a.decay_dyn()).is_some()
+    }
+    /// Undo every atom, newest first
+    ///
+    /// As with [`Stack::undo_all`], a panicking undo does not stop the rest from running
+    pub fn undo_all(&mut self) {
+        let mut panic = None;
+        while let Some(a) = self.els.pop() {
+            if let Err(p) = catch_unwind(AssertUnwindSafe(|| a.undo_dyn())) {
+                panic.get_or_insert(p);
+            }
+        }
+        resume(panic);
+    }
+    /// Decay every atom, oldest first
+    pub fn decay_all(&mut self) {
+        self.els.drain(..).for_each(|a| a.decay_dyn());
+    }
+}
+
+impl Drop for ScopedStack<'_> {
+    fn drop(&mut self) {
+        self.undo_all();
+    }
+}
+
+impl std::fmt::Debug for ScopedStack<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScopedStack")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// An [`Encased`] state whose side effects are pushed onto a shared [`Stack`] as they are made,
+/// used by [`atomic!`](crate::atomic)
+///
+/// The side effects never escape, so their values are cloned to give back: one copy is kept for
+/// the undo.
+pub struct EncasedScope<'a, S> {
+    state: Encased<S>,
+    stack: &'a RefCell<Stack>,
+}
+
+impl<'a, S: 'static> EncasedScope<'a, S> {
+    pub fn new(state: &Encased<S>, stack: &'a RefCell<Stack>) -> Self {
+        Self {
+            state: state.clone(),
+            stack,
+        }
+    }
+    /// [`Encased::peel_mut`], pushing the side effect onto the stack
+    pub fn peel_mut<R: Clone + 'static, Ru: 'static>(
+        &mut self,
+        act: impl FnOnce(&mut S) -> R,
+        undo: impl FnOnce(&mut S, R) -> Ru + 'static,
+    ) -> R {
+        self.keep(self.state.clone().peel_mut(act, undo))
+    }
+    /// [`Encased::peel`], pushing the side effect onto the stack
+    pub fn peel<R: Clone + 'static, Ru: 'static>(
+        &mut self,
+        act: impl FnOnce(&S) -> R,
+        undo: impl FnOnce(&mut S, R) -> Ru + 'static,
+    ) -> R {
+        self.keep(self.state.peel(act, undo))
+    }
+    /// Push any other atom onto the stack
+    pub fn push<A: Atom + 'static>(&mut self, atom: A) {
+        self.stack.borrow_mut().push(atom);
+    }
+    /// [`Encased::read`]
+    pub fn read<R>(&self, f: impl FnOnce(&S) -> R) -> R {
+        self.state.read(f)
+    }
+    /// [`Encased::modify`], which is not undone
+    pub fn modify<R>(&mut self, f: impl FnOnce(&mut S) -> R) -> R {
+        self.state.modify(f)
+    }
+    fn keep<R: Clone + 'static, Ru: 'static>(
+        &mut self,
+        effect: SideEffect<R, Ru, S, impl FnOnce(&mut S, R) -> Ru + 'static>,
+    ) -> R {
+        let value = (*effect).clone();
+        self.push(effect);
+        value
+    }
+}
+
+impl<S> std::fmt::Debug for EncasedScope<'_, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EncasedScope")
+    }
+}
+
+/// Side effects on an [`Encased`] state which are undone together unless committed, see
+/// [`Encased::begin`]
+///
+/// The side effects are kept by the transaction rather than given out, so they are always undone
+/// newest first. Dropping it without committing rolls back.
+///
+/// ```
+/// # #[derive(Debug, PartialEq)]
+/// # struct Accounts { checking: i64, savings: i64 }
+/// let mut accounts = rewind::encase(Accounts { checking: 10, savings: 0 });
+/// let mut tx = accounts.begin();
+/// tx.run_labeled("debit", |a| a.checking -= 5, |a, _| a.checking += 5);
+/// tx.run_labeled("credit", |a| a.savings += 5, |a, _| a.savings -= 5);
+/// let summary = tx.commit();
+/// assert_eq!(summary.labels, vec!["debit", "credit"]);
+/// assert_eq!(*accounts.borrow_state(), Accounts { checking: 5, savings: 5 });
+/// ```
+pub struct Transaction<'a, S> {
+    state: &'a mut Encased<S>,
+    effects: ScopedStack<'a>,
+    labels: Vec<Option<&'static str>>,
+}
+
+/// What a [`Transaction`] did, from [`Transaction::commit`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransactionSummary {
+    pub operations: usize,
+    /// Labels of the operations run with [`Transaction::run_labeled`], oldest first
+    pub labels: Vec<&'static str>,
+}
+
+/// A point in a [`Transaction`] to roll back to, see [`Transaction::savepoint`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Savepoint(usize);
+
+impl<'a, S> Transaction<'a, S> {
+    pub(crate) fn new(state: &'a mut Encased<S>) -> Self {
+        Self {
+            state,
+            effects: ScopedStack::new(),
+            labels: Vec::new(),
+        }
+    }
+    /// Run `act` on the state, keeping `undo` to roll it back
+    ///
+    /// Like [`EncasedScope`], the value is cloned to give back since one is kept for the undo.
+    pub fn run<R: Clone + 'a, Ru: 'a>(
+        &mut self,
+        act: impl FnOnce(&mut S) -> R,
+        undo: impl FnOnce(&mut S, R) -> Ru + 'a,
+    ) -> R {
+        let effect = self.state.peel_mut(act, undo);
+        let value = (*effect).clone();
+        self.effects.push(effect);
+        self.labels.push(None);
+        value
+    }
+    /// [`run`](Self::run) with a label for the [`TransactionSummary`]
+    pub fn run_labeled<R: Clone + 'a, Ru: 'a>(
+        &mut self,
+        label: &'static str,
+        act: impl FnOnce(&mut S) -> R,
+        undo: impl FnOnce(&mut S, R) -> Ru + 'a,
+    ) -> R {
+        let value = self.run(act, undo);
+        *self.labels.last_mut().expect("just pushed") = Some(label);
+        value
+    }
+    /// Read the state
+    pub fn read<R>(&self, f: impl FnOnce(&S) -> R) -> R {
+        self.state.read(f)
+    }
+    /// The number of operations run so far
+    pub fn len(&self) -> usize {
+        self.effects.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+    /// The current point, to go back to with [`rollback_to`](Self::rollback_to)
+    pub fn savepoint(&self) -> Savepoint {
+        Savepoint(self.effects.len())
+    }
+    /// Undo the operations run since `savepoint`, newest first
+    ///
+    /// Does nothing if they were already rolled back.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) {
+        while self.effects.len() > savepoint.0 {
+            self.effects.pop_undo();
+        }
+        self.labels.truncate(savepoint.0);
+    }
+    /// Keep every operation, decaying them
+    pub fn commit(mut self) -> TransactionSummary {
+        self.effects.decay_all();
+        let summary = TransactionSummary {
+            operations: self.labels.len(),
+            labels: self.labels.iter().flatten().copied().collect(),
+        };
+        self.labels.clear();
+        summary
+    }
+    /// Undo every operation, newest first, the same as dropping the transaction
+    pub fn rollback(self) {}
+}
+
+impl<S> std::fmt::Debug for Transaction<'_, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Transaction")
+            .field("operations", &self.len())
+            .finish()
+    }
+}
+
+/// Marks points on a [`Stack`] to roll back to later, without being tied to the stack
+///
+/// Any number of marks can be taken and rolled back to independently, each only remembering how
+/// many atoms were pending at the time, like [`Stack::rollback_to_len`].
+///
+/// ```
+/// use rewind::{stack::Stack, Checkpoint};
+/// let checkpoint = Checkpoint::new();
+/// let mut stack = Stack::new();
+/// stack.push(rewind::simple(1, |v| v));
+/// let id = checkpoint.mark(&stack);
+/// stack.push(rewind::simple(2, |v| v));
+/// assert_eq!(checkpoint.rollback(&mut stack, id).unwrap().len(), 1);
+/// assert_eq!(stack.len(), 1);
+/// ```
+#[derive(Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    id: u64,
+}
+
+/// A point marked by [`Checkpoint::mark`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CheckpointId {
+    checkpoint: u64,
+    len: usize,
+}
+
+impl CheckpointId {
+    /// Number of atoms which were pending when this was marked
+    pub fn stack_len(&self) -> usize {
+        self.len
+    }
+}
+
+impl Checkpoint {
+    pub fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Self {
+            id: NEXT.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+    /// Remember how many atoms are pending on `stack`
+    pub fn mark(&self, stack: &Stack) -> CheckpointId {
+        CheckpointId {
+            checkpoint: self.id,
+            len: stack.len(),
+        }
+    }
+    /// Undo the atoms pushed onto `stack` since `id` was marked, newest first
+    pub fn rollback(
+        &self,
+        stack: &mut Stack,
+        id: CheckpointId,
+    ) -> Result<Vec<Box<dyn Any>>, RollbackError> {
+        if id.checkpoint != self.id {
+            return Err(RollbackError::OtherCheckpoint);
+        }
+        stack.rollback_to_len(id.len)
+    }
+}
+
+impl Default for Checkpoint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Errors from a [`RemoteStack`] whose executor thread is gone
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RemoteError {
+    /// An undo or decay panicked on the executor, with the panic message if it had one
+    Panicked(Option<String>),
+    /// The executor stopped earlier, nothing was sent to it
+    Stopped,
+}
+
+impl Display for RemoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RemoteError::Panicked(Some(message)) => {
+                write!(f, "undo executor panicked: {message}")
+            }
+            RemoteError::Panicked(None) => f.write_str("undo executor panicked"),
+            RemoteError::Stopped => f.write_str("undo executor has stopped"),
+        }
+    }
+}
+impl std::error::Error for RemoteError {}
+
+trait RemoteAtom: Send {
+    fn push_onto(self: Box<Self>, stack: &mut Stack);
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<A: Atom + Send + 'static> RemoteAtom for A
+where
+    A::Undo: 'static,
+    A::Decay: 'static,
+{
+    fn push_onto(self: Box<Self>, stack: &mut Stack) {
+        stack.push(*self);
+    }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+enum RemoteCommand {
+    Push(Box<dyn RemoteAtom>),
+    Resolve(fn(&mut Stack) -> usize, mpsc::Sender<usize>),
+}
+
+/// A [`Stack`] living on a thread of its own, so that every undo runs there
+///
+/// Useful when rollbacks have to happen on a specific thread, e.g. for thread-affine libraries.
+/// Atoms are sent to the executor thread as they are pushed, and [`undo_all`](Self::undo_all) and
+/// [`decay_all`](Self::decay_all) block until it is done. Dropping the `RemoteStack` undoes
+/// whatever is left on the executor and waits for it to finish.
+///
+/// If an undo panics the executor thread stops, the error is reported by the resolution that
+/// was running and later calls give [`RemoteError::Stopped`].
+///
+/// ```
+/// let mut stack = rewind::stack::RemoteStack::spawn();
+/// let caller = std::thread::current().id();
+/// let checked = rewind::simple(caller, |caller| assert_ne!(std::thread::current().id(), caller));
+/// stack.push(checked).unwrap();
+/// assert_eq!(stack.undo_all(), Ok(1));
+/// ```
+#[derive(Debug)]
+pub struct RemoteStack {
+    commands: Option<mpsc::Sender<RemoteCommand>>,
+    executor: Option<JoinHandle<()>>,
+}
+
+impl RemoteStack {
+    /// Start the executor thread
+    pub fn spawn() -> Self {
+        let (commands, received) = mpsc::channel();
+        let executor = std::thread::Builder::new()
+            .name("rewind-executor".to_owned())
+            .spawn(move || {
+                let mut stack = Stack::new();
+                for command in received {
+                    match command {
+                        RemoteCommand::Push(atom) => atom.push_onto(&mut stack),
+                        RemoteCommand::Resolve(resolve, done) => {
+                            // The caller may have given up waiting
+                            let _ = done.send(resolve(&mut stack));
+                        }
+                    }
+                }
+            })
+            .expect("failed to spawn the undo executor");
+        Self {
+            commands: Some(commands),
+            executor: Some(executor),
+        }
+    }
+    /// The id of the executor thread, `None` once it has stopped
+    pub fn executor_id(&self) -> Option<ThreadId> {
+        self.executor.as_ref().map(|e| e.thread().id())
+    }
+    /// Send `atom` to the top of the executor's stack
+    ///
+    /// If the executor has stopped the atom is given back, rather than being undone on this
+    /// thread by dropping it.
+    pub fn push<A: Atom + Send + 'static>(&mut self, atom: A) -> Result<(), (RemoteError, A)>
+    where
+        A::Undo: 'static,
+        A::Decay: 'static,
+    {
+        let Err(RemoteCommand::Push(atom)) = self.send(RemoteCommand::Push(Box::new(atom))) else {
+            return Ok(());
+        };
+        let atom = atom.into_any().downcast().expect("the atom which was sent");
+        Err((self.stopped(), *atom))
+    }
+    /// Undo every atom on the executor, newest first, and wait for it to finish
+    ///
+    /// Returns how many atoms were undone.
+    pub fn undo_all(&mut self) -> Result<usize, RemoteError> {
+        self.resolve(|s| s.undo_all().len())
+    }
+    /// Decay every atom on the executor, oldest first, and wait for it to finish
+    ///
+    /// Returns how many atoms were decayed.
+    pub fn decay_all(&mut self) -> Result<usize, RemoteError> {
+        self.resolve(|s| s.decay_all().len())
+    }
+    fn send(&self, command: RemoteCommand) -> Result<(), RemoteCommand> {
+        match &self.commands {
+            Some(commands) => commands.send(command).map_err(|e| e.0),
+            None => Err(command),
+        }
+    }
+    fn resolve(&mut self, resolve: fn(&mut Stack) -> usize) -> Result<usize, RemoteError> {
+        let (done, confirmed) = mpsc::channel();
+        if self.send(RemoteCommand::Resolve(resolve, done)).is_ok() {
+            if let Ok(n) = confirmed.recv() {
+                return Ok(n);
+            }
+        }
+        Err(self.stopped())
+    }
+    /// Find out why the executor is gone, reporting a panic only once
+    fn stopped(&mut self) -> RemoteError {
+        self.commands = None;
+        match self.executor.take().map(JoinHandle::join) {
+            Some(Err(panic)) => RemoteError::Panicked(
+                panic
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned()),
+            ),
+            _ => RemoteError::Stopped,
+        }
+    }
+}
+
+impl Drop for RemoteStack {
+    fn drop(&mut self) {
+        // Closing the channel makes the executor drop its stack, undoing what is left
+        self.commands = None;
+        if let Some(executor) = self.executor.take() {
+            let _ = executor.join();
+        }
+    }
+}
+
+/// An operation on `S` described as data, so that its inverse can be stored instead of a closure
+///
+/// See [`SchemaStack`]
+pub trait SchemaOp<S> {
+    /// Perform the operation
+    fn apply(&self, state: &mut S);
+    /// The operation which reverts this one, given the state before it is applied
+    fn invert(&self, state: &S) -> Self;
+}
+
+/// A state along with the inverses of the operations applied to it, see
+/// [`schema`](rewind::schema)
+///
+/// Unlike [`Stack`] no closures are involved, so with the `serde` feature the whole thing,
+/// state and undo history, can be serialized as a `(state, inverses)` pair if `S` and `Op` can.
+/// Nothing is undone on drop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaStack<S, Op> {
+    state: S,
+    /// Oldest first
+    inverses: Vec<Op>,
+}
+
+impl<S, Op: SchemaOp<S>> SchemaStack<S, Op> {
+    pub fn new(state: S) -> Self {
+        Self {
+            state,
+            inverses: Vec::new(),
+        }
+    }
+    /// Apply `op` to the state, storing its inverse
+    pub fn push(&mut self, op: Op) {
+        let inverse = op.invert(&self.state);
+        op.apply(&mut self.state);
+        self.inverses.push(inverse);
+    }
+    /// Apply the inverse of the newest operation, returns whether there was one
+    pub fn undo_one(&mut self) -> bool {
+        match self.inverses.pop() {
+            Some(inverse) => {
+                inverse.apply(&mut self.state);
+                true
+            }
+            None => false,
+        }
+    }
+    /// Undo every operation, newest first
+    pub fn undo_all(&mut self) {
+        while self.undo_one() {}
+    }
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+    /// Inverses of the operations which can still be undone, oldest first
+    pub fn inverses(&self) -> &[Op] {
+        &self.inverses
+    }
+    pub fn len(&self) -> usize {
+        self.inverses.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.inverses.is_empty()
+    }
+    /// Keep the state as it is, dropping the undo history
+    pub fn into_state(self) -> S {
+        self.state
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<S: Serialize, Op: Serialize> Serialize for SchemaStack<S, Op> {
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        (&self.state, &self.inverses).serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, S: serde::Deserialize<'de>, Op: serde::Deserialize<'de>> serde::Deserialize<'de>
+    for SchemaStack<S, Op>
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (state, inverses) = serde::Deserialize::deserialize(deserializer)?;
+        Ok(Self { state, inverses })
+    }
+}
+
+/// A separate [`Stack`] for each key, e.g. one rollback chain per in-flight request
+///
+/// Atoms under the same key are undone newest first. When resolving every key at once, the keys
+/// are resolved in the order they were first pushed to. On drop every key is resolved according
+/// to the drop policy, which is [`Resolve::Undo`] unless set with
+/// [`with_drop_policy`](UndoRegistry::with_drop_policy).
+///
+/// ```
+/// use rewind::stack::UndoRegistry;
+/// let mut registry = UndoRegistry::new();
+/// registry.push("req-1", rewind::simple(1, |v| v));
+/// registry.push("req-2", rewind::simple(2, |v| v));
+/// registry.push("req-1", rewind::simple(3, |v| v));
+/// assert_eq!(registry.undo_key(&"req-1").len(), 2);
+/// assert_eq!(registry.keys().collect::<Vec<_>>(), vec![&"req-2"]);
+/// ```
+pub struct UndoRegistry<K: Hash + Eq> {
+    /// Stack for each key along with when the key was first pushed to
+    stacks: HashMap<K, (u64, Stack)>,
+    next_order: u64,
+    drop_policy: Resolve,
+}
+
+impl<K: Hash + Eq> UndoRegistry<K> {
+    pub fn new() -> Self {
+        Self::with_drop_policy(Resolve::Undo)
+    }
+    /// Create a registry which resolves any remaining atoms with `policy` when dropped
+    pub fn with_drop_policy(policy: Resolve) -> Self {
+        Self {
+            stacks: HashMap::new(),
+            next_order: 0,
+            drop_policy: policy,
+        }
+    }
+    pub fn push<A: Atom + 'static>(&mut self, key: K, atom: A) -> AtomHandle
+    where
+        A::Undo: 'static,
+        A::Decay: 'static,
+    {
+        let next_order = &mut self.next_order;
+        let (_, stack) = self.stacks.entry(key).or_insert_with(|| {
+            *next_order += 1;
+            (*next_order, Stack::new())
+        });
+        stack.push(atom)
+    }
+    /// Undo every atom under `key`, newest first
+    ///
+    /// Returns nothing if there are no atoms for the key
+    pub fn undo_key(&mut self, key: &K) -> Vec<Box<dyn Any>> {
+        self.stacks
+            .remove(key)
+            .map(|(_, mut s)| s.undo_all())
+            .unwrap_or_default()
+    }
+    /// Decay every atom under `key`, oldest first
+    pub fn decay_key(&mut self, key: &K) -> Vec<Box<dyn Any>> {
+        self.stacks
+            .remove(key)
+            .map(|(_, mut s)| s.decay_all())
+            .unwrap_or_default()
+    }
+    /// Undo every key, in the order the keys were first pushed to
+    ///
+    /// The results are the results of each key's undos in turn
+    pub fn undo_all(&mut self) -> Vec<Box<dyn Any>> {
+        self.resolve_all(Resolve::Undo)
+    }
+    /// Decay every key, in the order the keys were first pushed to
+    pub fn decay_all(&mut self) -> Vec<Box<dyn Any>> {
+        self.resolve_all(Resolve::Decay)
+    }
+    /// Keys which have atoms pending, in no particular order
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.stacks.keys()
+    }
+    /// Number of atoms pending under `key`
+    pub fn len_of(&self, key: &K) -> usize {
+        self.stacks.get(key).map_or(0, |(_, s)| s.len())
+    }
+    /// Number of keys with atoms pending
+    pub fn len(&self) -> usize {
+        self.stacks.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.stacks.is_empty()
+    }
+    fn resolve_all(&mut self, policy: Resolve) -> Vec<Box<dyn Any>> {
+        let mut stacks: Vec<_> = self.stacks.drain().map(|(_, s)| s).collect();
+        stacks.sort_by_key(|(order, _)| *order);
+        stacks
+            .into_iter()
+            .flat_map(|(_, mut s)| match policy {
+                Resolve::Undo => s.undo_all(),
+                Resolve::Decay => s.decay_all(),
+            })
+            .collect()
+    }
+}
+
+impl<K: Hash + Eq> Default for UndoRegistry<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq> Drop for UndoRegistry<K> {
+    fn drop(&mut self) {
+        self.resolve_all(self.drop_policy);
+    }
+}
+
+impl<K: Hash + Eq + std::fmt::Debug> std::fmt::Debug for UndoRegistry<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map()
+            .entries(self.stacks.iter().map(|(k, (_, s))| (k, s.len())))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    fn logging(log: &Rc<RefCell<Vec<i32>>>, v: i32) -> impl Atom<Undo = (), Decay = i32> + 'static {
+        let log = log.clone();
+        rewind::simple(v, move |v| log.borrow_mut().push(v))
+    }
+
+    #[test]
+    fn stack_undoes_newest_first_on_drop() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        {
+            let mut s = Stack::new();
+            s.push(logging(&log, 1));
+            s.push(logging(&log, 2));
+            s.push(logging(&log, 3));
+        }
+        assert_eq!(*log.borrow(), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn resolving_one_atom_keeps_others_in_order() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut s = Stack::new();
+        s.push(logging(&log, 1));
+        let h2 = s.push(logging(&log, 2));
+        let h3 = s.push(logging(&log, 3));
+        s.push(logging(&log, 4));
+        s.decay_one(h2).unwrap();
+        s.undo_one(h3).unwrap();
+        assert_eq!(*log.borrow(), vec![3]);
+        assert_eq!(s.len(), 2);
+        s.undo_all();
+        assert_eq!(*log.borrow(), vec![3, 4, 1]);
+    }
+
+    #[test]
+    fn stale_handles_are_an_error() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut s = Stack::new();
+        let h = s.push(logging(&log, 1));
+        s.undo_one(h).unwrap();
+        assert_eq!(s.decay_one(h), Err(StackError::StaleHandle));
+        assert!(s.undo_one(h).is_err());
+
+        s.push(logging(&log, 2));
+        assert_eq!(s.decay_one(h), Err(StackError::StaleHandle));
+        assert_eq!(s.len(), 1);
+    }
+
+    #[test]
+    fn popping_resolves_only_the_newest_atom() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut s = Stack::new();
+        s.push(logging(&log, 1));
+        s.push(logging(&log, 2));
+        let h = s.push(logging(&log, 3));
+        s.undo_one(h).unwrap();
+        assert!(s.pop_undo().is_some());
+        assert_eq!(*log.borrow(), vec![3, 2]);
+        assert_eq!(s.pop_decay(), Some(()));
+        assert_eq!(*log.borrow(), vec![3, 2]);
+        assert!(s.pop_undo().is_none());
+        assert_eq!(s.pop_decay(), None);
+    }
+
+    #[test]
+    fn pop_undo_as_downcasts_the_result() {
+        let mut s = Stack::new();
+        s.push(rewind::simple(2, |v| v * 2));
+        s.push(rewind::simple(2, |v| v * 3));
+        assert!(matches!(s.pop_undo_as::<String>(), Some(Err(_))));
+        assert_eq!(s.pop_undo_as::<i32>().map(Result::unwrap), Some(4));
+        assert!(s.pop_undo_as::<i32>().is_none());
+    }
+
+    #[test]
+    fn pop_undo_reverts_the_newest_group_only() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut s = Stack::new();
+        s.group("first", |s| {
+            s.push(logging(&log, 1));
+            s.push(logging(&log, 2));
+        });
+        s.begin_group("second");
+        s.push(logging(&log, 3));
+        s.push(logging(&log, 4));
+        s.end_group().unwrap();
+        assert_eq!(s.len(), 4);
+        assert_eq!(s.steps(), 2);
+        assert_eq!(s.next_group_label(), Some("second"));
+
+        let undone = s.pop_undo().unwrap();
+        assert_eq!(undone.downcast_ref::<Vec<Box<dyn Any>>>().unwrap().len(), 2);
+        assert_eq!(*log.borrow(), vec![4, 3]);
+        assert_eq!(s.len(), 2);
+        assert_eq!(s.steps(), 1);
+        s.push(logging(&log, 5));
+        assert_eq!(s.steps(), 2);
+        assert_eq!(s.next_group_label(), None);
+        s.pop_undo();
+        assert_eq!(*log.borrow(), vec![4, 3, 5]);
+        assert_eq!(s.next_group_label(), Some("first"));
+    }
+
+    #[test]
+    fn groups_nest() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut s = Stack::new();
+        s.begin_group("outer");
+        s.push(logging(&log, 1));
+        s.group("inner", |s| {
+            s.push(logging(&log, 2));
+            s.push(logging(&log, 3));
+        });
+        assert_eq!(s.steps(), 2);
+        s.pop_undo();
+        assert_eq!(*log.borrow(), vec![3, 2]);
+        s.push(logging(&log, 4));
+        s.end_group().unwrap();
+        assert_eq!(s.end_group(), Err(StackError::NoOpenGroup));
+        s.push(logging(&log, 5));
+        assert_eq!(s.steps(), 2);
+        s.pop_undo();
+        s.pop_undo();
+        assert_eq!(*log.borrow(), vec![3, 2, 5, 4, 1]);
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn undo_hook_fires_for_single_and_bulk_undos() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let hook_calls = calls.clone();
+        let mut s = rewind::undo_stack()
+            .with_capacity(4)
+            .named("my_stack")
+            .with_on_undo(move |rs| {
+                hook_calls.borrow_mut().push(
+                    rs.iter()
+                        .map(|r| *r.downcast_ref::<i32>().unwrap())
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .build();
+        let h = s.push(rewind::simple(1, |v| v));
+        s.push(rewind::simple(2, |v| v));
+        s.push(rewind::simple(3, |v| v));
+        s.push(rewind::simple(4, |v| v));
+        assert_eq!(s.to_string(), "my_stack (4 pending)");
+        s.undo_one(h).unwrap();
+        s.pop_undo();
+        s.pop_decay();
+        assert_eq!(*calls.borrow(), vec![vec![1], vec![4]]);
+        s.push(rewind::simple(5, |v| v));
+        drop(s);
+        assert_eq!(*calls.borrow(), vec![vec![1], vec![4], vec![5, 2]]);
+    }
+
+    #[test]
+    fn observers_see_every_change() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut s = Stack::new();
+        for _ in 0..2 {
+            let events = events.clone();
+            s.on_change(move |e| events.borrow_mut().push(e));
+        }
+        let h = s.push_labeled("a", rewind::simple((), |_| ()));
+        s.push(rewind::simple((), |_| ()));
+        s.push_labeled("c", rewind::simple((), |_| ()));
+        s.undo_one(h).unwrap();
+        s.pop_decay();
+        drop(s);
+        let label = Some("a");
+        let expected = [
+            StackEvent::Pushed { label },
+            StackEvent::Pushed { label: None },
+            StackEvent::Pushed { label: Some("c") },
+            StackEvent::Undone { label, index: 0 },
+            StackEvent::Decayed {
+                label: Some("c"),
+                index: 1,
+            },
+            StackEvent::Undone {
+                label: None,
+                index: 0,
+            },
+            StackEvent::Cleared,
+        ];
+        let doubled: Vec<_> = expected.iter().flat_map(|e| [*e, *e]).collect();
+        assert_eq!(*events.borrow(), doubled);
+    }
+
+    #[test]
+    fn observer_handles_unregister_on_drop() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut s = Stack::new();
+        let seen = events.clone();
+        let first = s.observe(move |e| seen.borrow_mut().push((1, e)));
+        let seen = events.clone();
+        let _second = s.observe(move |e| seen.borrow_mut().push((2, e)));
+        s.push_labeled("a", rewind::simple((), |_| ()));
+        s.push_labeled("b", rewind::simple((), |_| ()));
+        s.push_labeled("c", rewind::simple((), |_| ()));
+        drop(first);
+        s.drain_resolve(1..2, Resolve::Decay);
+        s.pop_undo();
+        let pushed = StackEvent::Pushed { label: Some("a") };
+        assert_eq!(events.borrow()[..2], [(1, pushed), (2, pushed)]);
+        assert_eq!(
+            events.borrow()[6..],
+            [
+                (
+                    2,
+                    StackEvent::Decayed {
+                        label: Some("b"),
+                        index: 1
+                    }
+                ),
+                (
+                    2,
+                    StackEvent::Undone {
+                        label: Some("c"),
+                        index: 1
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn panicking_observer_does_not_corrupt_the_stack() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::new(RefCell::new(0));
+        let mut s = Stack::new();
+        s.on_change(|e| {
+            if matches!(e, StackEvent::Undone { .. }) {
+                panic!("observer failed");
+            }
+        });
+        let counter = seen.clone();
+        s.on_change(move |_| *counter.borrow_mut() += 1);
+        s.push(logging(&log, 1));
+        s.push(logging(&log, 2));
+        let r = std::panic::catch_unwind(AssertUnwindSafe(|| s.pop_undo()));
+        assert!(r.is_err());
+        assert_eq!(*log.borrow(), vec![2]);
+        assert_eq!(*seen.borrow(), 3);
+        assert_eq!(s.len(), 1);
+        s.pop_decay();
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn scoped_stack_undoes_borrowing_atoms() {
+        let (mut a, mut b, mut c) = (1, 2, 3);
+        let order = RefCell::new(Vec::new());
+        let mut s = ScopedStack::new();
+        s.push(rewind::simple(&mut a, |v| {
+            *v = 0;
+            order.borrow_mut().push('a');
+        }));
+        s.push(rewind::simple(&mut b, |v| {
+            *v = 0;
+            order.borrow_mut().push('b');
+        }));
+        s.push(rewind::simple(&mut c, |v| {
+            *v = 0;
+            order.borrow_mut().push('c');
+        }));
+        assert_eq!(s.len(), 3);
+        s.undo_all();
+        assert!(s.is_empty());
+        drop(s);
+        assert_eq!((a, b, c), (0, 0, 0));
+        assert_eq!(*order.borrow(), vec!['c', 'b', 'a']);
+    }
+
+    #[test]
+    fn stacked_atoms_roll_back_exactly_once() {
+        struct Counted(Rc<RefCell<u32>>, bool);
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                if self.1 {
+                    *self.0.borrow_mut() += 1;
+                }
+            }
+        }
+        impl Atom for Counted {
+            type Undo = ();
+            type Decay = ();
+            fn undo(self) {}
+            fn decay(mut self) {
+                self.1 = false;
+            }
+        }
+        let count = Rc::new(RefCell::new(0));
+        let mut s = Stack::new();
+        let h = s.push(Counted(count.clone(), true));
+        s.push(Counted(count.clone(), true));
+        s.push(Counted(count.clone(), true));
+        s.undo_one(h).unwrap();
+        assert_eq!(*count.borrow(), 1);
+        s.pop_undo();
+        assert_eq!(*count.borrow(), 2);
+        s.push(Counted(count.clone(), true));
+        s.pop_decay();
+        drop(s);
+        assert_eq!(*count.borrow(), 3);
+    }
+
+    #[test]
+    fn retain_prunes_only_matching_atoms() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut s = Stack::new();
+        s.push_labeled("cache:a", logging(&log, 1));
+        let db = s.push_labeled("db:a", logging(&log, 2));
+        s.push_labeled("cache:b", logging(&log, 3));
+        s.push_labeled("db:b", logging(&log, 4));
+        s.push_labeled("cache:c", logging(&log, 5));
+        s.retain(|m| !m.label.unwrap().starts_with("cache:"), Resolve::Undo);
+        assert_eq!(*log.borrow(), vec![5, 3, 1]);
+        assert_eq!(s.len(), 2);
+        s.decay_one(db).unwrap();
+        s.undo_all();
+        assert_eq!(*log.borrow(), vec![5, 3, 1, 4]);
+    }
+
+    #[test]
+    fn drain_resolve_uses_pending_positions() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut s = Stack::new();
+        for v in 0..5 {
+            s.push(logging(&log, v));
+        }
+        s.drain_resolve(1..2, Resolve::Decay);
+        let undone = s.drain_resolve(1..=2, Resolve::Undo);
+        assert_eq!(undone.len(), 2);
+        assert_eq!(*log.borrow(), vec![3, 2]);
+        s.drain_resolve(.., Resolve::Decay);
+        assert!(s.is_empty());
+        assert_eq!(*log.borrow(), vec![3, 2]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn drain_resolve_out_of_bounds_panics() {
+        let mut s = Stack::new();
+        s.push(rewind::simple((), |_| ()));
+        s.drain_resolve(0..2, Resolve::Undo);
+    }
+
+    #[test]
+    fn moved_atoms_undo_in_their_new_stack() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut from = Stack::new();
+        let mut to = Stack::new();
+        to.push(logging(&log, 10));
+        let h = from.push(logging(&log, 1));
+        from.push(logging(&log, 2));
+        from.push(logging(&log, 3));
+        assert_eq!(Stack::move_between(&mut from, &mut to, 2), 2);
+        assert_eq!((from.len(), to.len()), (1, 3));
+        to.undo_all();
+        assert_eq!(*log.borrow(), vec![3, 2, 10]);
+        assert_eq!(Stack::move_between(&mut from, &mut to, 5), 1);
+        assert!(from.decay_one(h).is_err());
+        assert_eq!(Stack::move_between(&mut from, &mut to, 5), 0);
+        drop(to);
+        assert_eq!(*log.borrow(), vec![3, 2, 10, 1]);
+    }
+
+    #[test]
+    fn split_halves_resolve_like_the_whole() {
+        let whole_log = Rc::new(RefCell::new(Vec::new()));
+        let mut whole = Stack::new();
+        let split_log = Rc::new(RefCell::new(Vec::new()));
+        let mut older = Stack::new();
+        for v in 1..=4 {
+            whole.push(logging(&whole_log, v));
+            older.push(logging(&split_log, v));
+        }
+        let newer = older.split_off(2);
+        assert_eq!((older.len(), newer.len()), (2, 2));
+        drop(whole);
+        drop(newer);
+        drop(older);
+        assert_eq!(*whole_log.borrow(), *split_log.borrow());
+
+        let mut s = Stack::new();
+        s.push(logging(&split_log, 5));
+        let taken = s.take();
+        assert!(s.is_empty());
+        assert_eq!(taken.len(), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn split_off_out_of_bounds_panics() {
+        Stack::new().split_off(1);
+    }
+
+    #[test]
+    fn added_stacks_undo_rhs_first() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let a: Stack = (1..=2).map(|v| logging(&log, v)).collect();
+        let b: Stack = (3..=4).map(|v| logging(&log, v)).collect();
+        let mut combined = a + b;
+        assert_eq!(combined.len(), 4);
+        combined.undo_all();
+        assert_eq!(*log.borrow(), vec![4, 3, 2, 1]);
+
+        let mut a: Stack = (1..=2).map(|v| logging(&log, v)).collect();
+        a += (3..=4).map(|v| logging(&log, v)).collect();
+        assert_eq!(a.stats().pushed, 4);
+        log.borrow_mut().clear();
+        drop(a);
+        assert_eq!(*log.borrow(), vec![4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn registry_keys_resolve_independently() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut registry = UndoRegistry::new();
+        registry.push('a', logging(&log, 1));
+        registry.push('b', logging(&log, 10));
+        registry.push('a', logging(&log, 2));
+        registry.push('b', logging(&log, 20));
+        registry.push('a', logging(&log, 3));
+        assert_eq!((registry.len_of(&'a'), registry.len_of(&'b')), (3, 2));
+        registry.undo_key(&'a');
+        assert_eq!(*log.borrow(), vec![3, 2, 1]);
+        assert_eq!(registry.decay_key(&'b').len(), 2);
+        assert_eq!(*log.borrow(), vec![3, 2, 1]);
+        assert!(registry.is_empty());
+        assert!(registry.undo_key(&'a').is_empty());
+    }
+
+    #[test]
+    fn registry_drop_resolves_keys_in_first_push_order() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut registry = UndoRegistry::new();
+        registry.push("second", logging(&log, 1));
+        registry.push("first", logging(&log, 10));
+        registry.push("second", logging(&log, 2));
+        drop(registry);
+        assert_eq!(*log.borrow(), vec![2, 1, 10]);
+
+        let mut registry = UndoRegistry::with_drop_policy(Resolve::Decay);
+        registry.push(0, logging(&log, 3));
+        drop(registry);
+        assert_eq!(*log.borrow(), vec![2, 1, 10]);
+    }
+
+    #[test]
+    fn stats_count_every_resolution_path() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut s = Stack::new();
+        let h = s.push(logging(&log, 1));
+        s.push(logging(&log, 2));
+        s.group("g", |s| {
+            s.push(logging(&log, 3));
+            s.push(logging(&log, 4));
+        });
+        s.pop_undo();
+        s.decay_one(h).unwrap();
+        s.push(logging(&log, 5));
+        s.push(logging(&log, 6));
+        let moved = s.split_off(2);
+        s.retain(|_| false, Resolve::Decay);
+        let stats = s.stats();
+        assert_eq!(
+            (
+                stats.pushed,
+                stats.undone,
+                stats.decayed,
+                stats.peak_pending
+            ),
+            (6, 2, 3, 4)
+        );
+        s.push(logging(&log, 7));
+        s.undo_all();
+        assert_eq!(s.stats().undone, 3);
+        assert_eq!(moved.stats().pushed, 1);
+    }
+
+    #[test]
+    fn rollback_to_len_stops_at_the_target() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut s = Stack::new();
+        s.push(logging(&log, 1));
+        s.push(logging(&log, 2));
+        s.push(logging(&log, 3));
+        assert!(s.rollback_to_len(3).unwrap().is_empty());
+        assert_eq!(s.rollback_to_len(1).unwrap().len(), 2);
+        assert_eq!(*log.borrow(), vec![3, 2]);
+        assert_eq!(
+            s.rollback_to_len(2).unwrap_err(),
+            RollbackError::AlreadyShorter { len: 1, target: 2 }
+        );
+        assert_eq!(s.len(), 1);
+    }
+
+    #[test]
+    fn nested_transactions_roll_back_to_their_own_start() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut s = Stack::new();
+        s.push(logging(&log, 1));
+        let outer = s.transaction(|s| {
+            s.push(logging(&log, 2));
+            let inner = s.transaction(|s| {
+                s.push(logging(&log, 3));
+                s.push(logging(&log, 4));
+                Err::<(), _>("inner")
+            });
+            assert_eq!(inner, Err("inner"));
+            assert_eq!(*log.borrow(), vec![4, 3]);
+            assert_eq!(s.len(), 2);
+            s.transaction(|s| {
+                s.push(logging(&log, 5));
+                Ok::<_, &str>(())
+            })?;
+            assert_eq!(s.len(), 3);
+            Err::<(), _>("outer")
+        });
+        assert_eq!(outer, Err("outer"));
+        assert_eq!(*log.borrow(), vec![4, 3, 5, 2]);
+        assert_eq!(s.len(), 1);
+
+        let shrunk = s.transaction(|s| {
+            s.pop_undo();
+            s.push(logging(&log, 6));
+            Err::<(), _>(())
+        });
+        assert!(shrunk.is_err());
+        assert_eq!(*log.borrow(), vec![4, 3, 5, 2, 1, 6]);
+        assert!(s.is_empty());
+        assert_eq!(s.transaction(|_| Ok::<_, ()>(7)), Ok(7));
+    }
+
+    #[test]
+    fn failed_transfer_rolls_back_the_debit() {
+        #[derive(Debug, PartialEq)]
+        struct Accounts {
+            checking: i64,
+            savings: i64,
+        }
+        fn transfer(accounts: &mut Encased<Accounts>, amount: i64, fail: bool) -> Result<(), &str> {
+            let mut tx = accounts.begin();
+            let left = tx.run_labeled(
+                "debit",
+                |a| {
+                    a.checking -= amount;
+                    a.checking
+                },
+                move |a, _| a.checking += amount,
+            );
+            if left < 0 || fail {
+                return Err("transfer failed");
+            }
+            tx.run_labeled(
+                "credit",
+                |a| a.savings += amount,
+                move |a, _| a.savings -= amount,
+            );
+            assert_eq!(tx.commit().labels, vec!["debit", "credit"]);
+            Ok(())
+        }
+        let mut accounts = crate::encase(Accounts {
+            checking: 10,
+            savings: 0,
+        });
+        assert!(transfer(&mut accounts, 4, true).is_err());
+        assert!(transfer(&mut accounts, 11, false).is_err());
+        assert_eq!(accounts.read(|a| (a.checking, a.savings)), (10, 0));
+        transfer(&mut accounts, 4, false).unwrap();
+        assert_eq!(accounts.read(|a| (a.checking, a.savings)), (6, 4));
+
+        let mut tx = accounts.begin();
+        tx.run(|a| a.savings += 1, |a, _| a.savings -= 1);
+        let save = tx.savepoint();
+        tx.run_labeled("bonus", |a| a.savings += 10, |a, _| a.savings -= 10);
+        tx.run(|a| a.checking = 0, |a, _| a.checking = 6);
+        assert_eq!(tx.read(|a| (a.checking, a.savings)), (0, 15));
+        tx.rollback_to(save);
+        tx.rollback_to(save);
+        assert_eq!(tx.read(|a| a.savings), 5);
+        assert_eq!(
+            tx.commit(),
+            TransactionSummary {
+                operations: 1,
+                labels: vec![],
+            }
+        );
+        let mut tx = accounts.begin();
+        tx.run(|a| a.checking += 100, |a, _| a.checking -= 100);
+        tx.rollback();
+        assert_eq!(accounts.read(|a| (a.checking, a.savings)), (6, 5));
+    }
+
+    fn panicking(log: &Rc<RefCell<Vec<i32>>>, v: i32) -> impl Atom<Undo = (), Decay = i32> {
+        let log = log.clone();
+        rewind::simple(v, move |v| {
+            log.borrow_mut().push(v);
+            panic!("undo {v} failed");
+        })
+    }
+
+    #[test]
+    fn panicking_undo_does_not_stop_the_rest() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut s = Stack::new();
+        s.push(logging(&log, 1));
+        s.push(panicking(&log, 2));
+        s.push(logging(&log, 3));
+        let r = std::panic::catch_unwind(AssertUnwindSafe(|| drop(s)));
+        assert!(r.is_err());
+        assert_eq!(*log.borrow(), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn undo_all_catching_reports_each_outcome() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut s = Stack::new();
+        s.push(logging(&log, 1));
+        s.push(panicking(&log, 2));
+        s.push(logging(&log, 3));
+        let outcomes = s.undo_all_catching();
+        assert!(matches!(
+            outcomes[..],
+            [
+                UndoOutcome::Undone(_),
+                UndoOutcome::Panicked(_),
+                UndoOutcome::Undone(_)
+            ]
+        ));
+        assert_eq!(*log.borrow(), vec![3, 2, 1]);
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn scoped_stack_carries_on_past_panics() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut s = ScopedStack::new();
+        s.push(logging(&log, 1));
+        s.push(panicking(&log, 2));
+        s.push(logging(&log, 3));
+        let r = std::panic::catch_unwind(AssertUnwindSafe(|| s.undo_all()));
+        assert!(r.is_err());
+        assert_eq!(*log.borrow(), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn stack_from_single_atom_undoes_it_on_drop() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let s = Stack::from_atom(logging(&log, 1));
+        assert_eq!(s.len(), 1);
+        drop(s);
+        assert_eq!(*log.borrow(), vec![1]);
+    }
+
+    #[test]
+    fn stack_from_vec_keeps_push_order() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        drop(Stack::from(vec![logging(&log, 1), logging(&log, 2)]));
+        drop((3..5).map(|v| logging(&log, v)).collect::<Stack>());
+        assert_eq!(*log.borrow(), vec![2, 1, 4, 3]);
+    }
+
+    #[test]
+    fn nested_stacks_are_undone_with_their_parent() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        {
+            let mut outer = Stack::new();
+            outer.push(logging(&log, 1));
+            outer.push(Stack::from(vec![logging(&log, 2), logging(&log, 3)]));
+            outer.push(logging(&log, 4));
+        }
+        assert_eq!(*log.borrow(), vec![4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn stack_undo_returns_every_result() {
+        let mut outer = Stack::new();
+        outer.push(rewind::simple(1, |v| v));
+        outer.push(Stack::from(vec![rewind::simple(2, |v| v)]));
+        let results = outer.undo();
+        assert_eq!(results.len(), 2);
+        let inner = results[0].downcast_ref::<Vec<Box<dyn Any>>>().unwrap();
+        assert_eq!(inner[0].downcast_ref::<i32>(), Some(&2));
+        assert_eq!(results[1].downcast_ref::<i32>(), Some(&1));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn schema_history_round_trips_through_serde() {
+        #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+        enum FileOp {
+            Insert(usize, String),
+            Delete(usize, usize),
+        }
+        impl SchemaOp<String> for FileOp {
+            fn apply(&self, file: &mut String) {
+                match self {
+                    FileOp::Insert(at, text) => file.insert_str(*at, text),
+                    FileOp::Delete(at, len) => drop(file.drain(*at..at + len)),
+                }
+            }
+            fn invert(&self, file: &String) -> Self {
+                match self {
+                    FileOp::Insert(at, text) => FileOp::Delete(*at, text.len()),
+                    FileOp::Delete(at, len) => FileOp::Insert(*at, file[*at..at + len].to_owned()),
+                }
+            }
+        }
+        let mut file = rewind::schema::<String, FileOp>();
+        file.push(FileOp::Insert(0, "hello".to_owned()));
+        file.push(FileOp::Insert(5, " world".to_owned()));
+        file.push(FileOp::Delete(0, 6));
+        assert_eq!(file.state(), "world");
+
+        let json = serde_json::to_string(&file).unwrap();
+        let mut file: SchemaStack<String, FileOp> = serde_json::from_str(&json).unwrap();
+        assert_eq!(file.inverses()[2], FileOp::Insert(0, "hello ".to_owned()));
+        assert!(file.undo_one());
+        assert_eq!(file.state(), "hello world");
+        file.undo_all();
+        assert_eq!(file.state(), "");
+        assert!(!file.undo_one());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn stack_can_be_rehydrated_from_serialized_intents() {
+        #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+        enum Cleanup {
+            Remove(i32),
+        }
+        struct Insert {
+            items: Rc<RefCell<Vec<i32>>>,
+            value: i32,
+            armed: bool,
+        }
+        impl Insert {
+            fn new(items: &Rc<RefCell<Vec<i32>>>, value: i32) -> Self {
+                items.borrow_mut().push(value);
+                Self {
+                    items: items.clone(),
+                    value,
+                    armed: true,
+                }
+            }
+        }
+        impl Drop for Insert {
+            fn drop(&mut self) {
+                if self.armed {
+                    self.items.borrow_mut().retain(|v| *v != self.value);
+                }
+            }
+        }
+        impl Atom for Insert {
+            type Undo = ();
+            type Decay = ();
+            fn undo(self) {}
+            fn decay(mut self) {
+                self.armed = false;
+            }
+        }
+        impl ReplayableAtom for Insert {
+            type Intent = Cleanup;
+            fn intent(&self) -> Cleanup {
+                Cleanup::Remove(self.value)
+            }
+        }
+
+        let items = Rc::new(RefCell::new(Vec::new()));
+        let mut s = Stack::new();
+        s.push_replayable(Insert::new(&items, 1));
+        s.push(logging(&Rc::default(), 5));
+        s.push_replayable(Insert::new(&items, 2));
+        let mut out = Vec::new();
+        s.serialize_intents::<Cleanup, _>(&mut serde_json::Serializer::new(&mut out))
+            .unwrap();
+        s.decay_all();
+        drop(s);
+        assert_eq!(*items.borrow(), vec![1, 2]);
+
+        let intents: Vec<Cleanup> = serde_json::from_slice(&out).unwrap();
+        assert_eq!(intents, vec![Cleanup::Remove(1), Cleanup::Remove(2)]);
+        let replay_items = items.clone();
+        let mut s = Stack::rehydrate(intents, move |i| match i {
+            Cleanup::Remove(v) => replay_items.borrow_mut().retain(|i| *i != v),
+        });
+        assert_eq!(s.len(), 2);
+        s.undo_all();
+        assert!(items.borrow().is_empty());
+    }
+
+    #[test]
+    fn failed_undos_stay_on_the_stack_until_retried() {
+        use std::cell::Cell;
+        struct Flaky {
+            v: Option<i32>,
+            broken: Rc<Cell<bool>>,
+            log: Rc<RefCell<Vec<i32>>>,
+        }
+        impl Drop for Flaky {
+            fn drop(&mut self) {
+                if let Some(v) = self.v.take() {
+                    self.log.borrow_mut().push(v);
+                }
+            }
+        }
+        impl Atom for Flaky {
+            type Undo = i32;
+            type Decay = i32;
+            fn undo(mut self) -> i32 {
+                let v = self.v.take().unwrap();
+                self.log.borrow_mut().push(v);
+                v
+            }
+            fn decay(mut self) -> i32 {
+                self.v.take().unwrap()
+            }
+        }
+        impl FallibleAtom for Flaky {
+            type Error = std::io::Error;
+            fn try_undo(self) -> Result<i32, (Self, Self::Error)> {
+                if self.broken.get() {
+                    Err((self, std::io::Error::other("disk full")))
+                } else {
+                    Ok(self.undo())
+                }
+            }
+        }
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let broken = Rc::new(Cell::new(false));
+        let flaky = |v, broken: &Rc<Cell<bool>>| Flaky {
+            v: Some(v),
+            broken: broken.clone(),
+            log: log.clone(),
+        };
+        let mut s = Stack::new();
+        s.push_fallible(flaky(1, &Rc::new(Cell::new(false))));
+        let h = s.push_fallible(flaky(2, &broken));
+        s.push_fallible(flaky(3, &Rc::new(Cell::new(false))));
+        broken.set(true);
+
+        let err = s.try_undo().unwrap_err();
+        assert_eq!(err.failures.len(), 1);
+        assert_eq!((err.failures[0].0, err.failures[0].1), (1, None));
+        assert_eq!(err.to_string(), "1 undo(s) failed: atom 1: disk full");
+        assert_eq!(*log.borrow(), vec![3, 1]);
+        assert_eq!(s.len(), 1);
+
+        s.push(logging(&log, 4));
+        assert!(s.retry_failed().is_err());
+        broken.set(false);
+        let undone = s.retry_failed().unwrap();
+        assert_eq!(undone[0].downcast_ref::<i32>(), Some(&2));
+        assert_eq!(*log.borrow(), vec![3, 1, 2]);
+        assert_eq!(s.len(), 1);
+        assert!(matches!(s.undo_one(h), Err(StackError::StaleHandle)));
+    }
+
+    #[test]
+    fn checkpoint_marks_roll_back_independently() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let checkpoint = Checkpoint::new();
+        let mut s = Stack::new();
+        s.push(logging(&log, 1));
+        let first = checkpoint.mark(&s);
+        s.push(logging(&log, 2));
+        s.push(logging(&log, 3));
+        let second = checkpoint.mark(&s);
+        s.push(logging(&log, 4));
+
+        assert_eq!(checkpoint.rollback(&mut s, second).unwrap().len(), 1);
+        assert_eq!(*log.borrow(), vec![4]);
+        s.push(logging(&log, 5));
+        assert_eq!(checkpoint.rollback(&mut s, first).unwrap().len(), 3);
+        assert_eq!(*log.borrow(), vec![4, 5, 3, 2]);
+        assert_eq!(
+            checkpoint.rollback(&mut s, second).unwrap_err(),
+            RollbackError::AlreadyShorter { len: 1, target: 3 }
+        );
+        assert_eq!(
+            Checkpoint::new().rollback(&mut s, first).unwrap_err(),
+            RollbackError::OtherCheckpoint
+        );
+    }
+
+    #[test]
+    fn histograms_summarise_undo_durations() {
+        let mut histogram = Histogram::new();
+        for i in 1..=50 {
+            histogram.record("fast", Duration::from_nanos(i * 10));
+            histogram.record("slow", Duration::from_micros(1));
+        }
+        assert_eq!(
+            histogram.summary(),
+            vec![("fast", 50, 255, 500), ("slow", 50, 1000, 1000)]
+        );
+        assert_eq!(
+            histogram.to_string(),
+            "fast: 50 undos, mean 255ns, max 500ns\nslow: 50 undos, mean 1µs, max 1µs\n"
+        );
+
+        let histogram = Rc::new(RefCell::new(Histogram::new()));
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut s = rewind::undo_stack().with_histogram(&histogram).build();
+        s.push_labeled("save", logging(&log, 1));
+        s.push_labeled("save", logging(&log, 2));
+        s.push(logging(&log, 3));
+        s.pop_undo();
+        s.push(rewind::atom::Timed::new(
+            "timed",
+            logging(&log, 4),
+            &histogram,
+        ));
+        drop(s);
+        let counts: Vec<_> = histogram
+            .borrow()
+            .summary()
+            .into_iter()
+            .map(|(name, count, ..)| (name, count))
+            .collect();
+        assert_eq!(counts, vec![(UNLABELED, 2), ("save", 2), ("timed", 1)]);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn independent_atoms_undo_in_parallel_between_ordered_ones() {
+        use std::sync::{Arc, Mutex};
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let atom = |v: i32| {
+            let log = log.clone();
+            rewind::simple(v, move |v| log.lock().unwrap().push(v))
+        };
+        let mut s = Stack::new();
+        for v in 1..=4 {
+            s.push_independent(atom(v));
+        }
+        s.push(atom(5));
+        for v in 6..=9 {
+            s.push_independent(atom(v));
+        }
+        let observed = Rc::new(RefCell::new(Vec::new()));
+        let events = observed.clone();
+        s.on_change(move |e| events.borrow_mut().push(e));
+        assert_eq!(s.undo_parallel().len(), 9);
+
+        let mut log = log.lock().unwrap().clone();
+        assert_eq!(log[4], 5);
+        log[..4].sort_unstable();
+        log[5..].sort_unstable();
+        assert_eq!(log, vec![6, 7, 8, 9, 5, 1, 2, 3, 4]);
+        let indices: Vec<_> = observed
+            .borrow()
+            .iter()
+            .filter_map(|e| match e {
+                StackEvent::Undone { index, .. } => Some(*index),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(indices, (0..9).rev().collect::<Vec<_>>());
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn remote_stacks_undo_on_the_executor_thread() {
+        let mut stack = RemoteStack::spawn();
+        let executor = stack.executor_id().unwrap();
+        assert_ne!(executor, std::thread::current().id());
+        let (seen, ran_on) = std::sync::mpsc::channel();
+        for i in 0..3 {
+            let seen = seen.clone();
+            let atom = rewind::simple(i, move |i| {
+                seen.send((i, std::thread::current().id())).unwrap()
+            });
+            stack.push(atom).unwrap();
+        }
+        assert_eq!(stack.undo_all(), Ok(3));
+        let undone: Vec<_> = ran_on.try_iter().collect();
+        assert_eq!(undone, [(2, executor), (1, executor), (0, executor)]);
+
+        stack
+            .push(rewind::simple(3, move |i| {
+                seen.send((i, std::thread::current().id())).unwrap()
+            }))
+            .unwrap();
+        drop(stack);
+        assert_eq!(ran_on.try_iter().collect::<Vec<_>>(), [(3, executor)]);
+
+        let mut stack = RemoteStack::spawn();
+        stack.push(rewind::simple((), |_| panic!("boom"))).unwrap();
+        assert_eq!(
+            stack.undo_all(),
+            Err(RemoteError::Panicked(Some("boom".into())))
+        );
+        assert_eq!(stack.decay_all(), Err(RemoteError::Stopped));
+        let (e, returned) = stack.push(rewind::simple(4, |i| i)).unwrap_err();
+        assert_eq!((e, returned.decay()), (RemoteError::Stopped, 4));
+        assert_eq!(stack.executor_id(), None);
+    }
+
+    #[test]
+    fn max_len_evicts_the_oldest_atoms_on_push() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut stack = Stack::new();
+        stack.set_max_len(3);
+        let handles: Vec<_> = (0..5).map(|i| stack.push(logging(&log, i))).collect();
+        assert_eq!(stack.len(), 3);
+        assert!(log.borrow().is_empty());
+        assert_eq!(stack.stats().decayed, 2);
+        assert_eq!(
+            stack.undo_one(handles[0]).err(),
+            Some(StackError::StaleHandle)
+        );
+        stack.undo_one(handles[3]).unwrap();
+        stack.undo_all();
+        assert_eq!(*log.borrow(), [3, 4, 2]);
+
+        log.borrow_mut().clear();
+        let mut stack = Stack::new();
+        stack.auto_evict_policy(Resolve::Undo);
+        for i in 0..4 {
+            stack.push(logging(&log, i));
+        }
+        stack.set_max_len(2);
+        assert_eq!(*log.borrow(), [1, 0]);
+        stack.push(logging(&log, 4));
+        assert_eq!(*log.borrow(), [1, 0, 2]);
+        assert_eq!(stack.max_len(), Some(2));
+    }
+
+    #[test]
+    fn evicted_atoms_free_their_storage() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut stack = Stack::new();
+        stack.set_max_len(3);
+        let first = stack.push(logging(&log, 0));
+        for i in 1..10_000 {
+            stack.push(logging(&log, i));
+        }
+        assert_eq!(stack.els.len(), 3);
+        assert_eq!(stack.decay_one(first), Err(StackError::StaleHandle));
+
+        stack.begin_group("tail");
+        let middle = stack.push(logging(&log, 10_000));
+        stack.push(logging(&log, 10_001));
+        stack.end_group().unwrap();
+        stack.push(logging(&log, 10_002));
+        assert_eq!(stack.els.len(), 3);
+        stack.undo_one(middle).unwrap();
+        stack.pop_undo();
+        assert_eq!(stack.next_group_label(), Some("tail"));
+        stack.pop_undo();
+        assert!(stack.is_empty());
+        assert_eq!(*log.borrow(), [10_000, 10_002, 10_001]);
+    }
+
+    #[test]
+    fn panicking_resolutions_still_leave_the_stack() {
+        struct Failing;
+        impl Drop for Failing {
+            fn drop(&mut self) {}
+        }
+        impl Atom for Failing {
+            type Undo = ();
+            type Decay = ();
+            fn undo(self) {
+                panic!("undo failed");
+            }
+            fn decay(self) {
+                panic!("decay failed");
+            }
+        }
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut stack = Stack::new();
+        stack.push(logging(&log, 0));
+        stack.push(logging(&log, 1));
+        let failing = stack.push(Failing);
+        stack.push(Failing);
+        assert!(catch_unwind(AssertUnwindSafe(|| stack.pop_undo())).is_err());
+        assert!(catch_unwind(AssertUnwindSafe(|| stack.decay_one(failing))).is_err());
+        assert_eq!(stack.len(), 2);
+        assert_eq!(stack.stats().undone + stack.stats().decayed, 2);
+
+        stack.set_max_len(1);
+        assert_eq!(stack.len(), 1);
+        assert_eq!(stack.undo_all().len(), 1);
+        assert!(stack.is_empty());
+        assert_eq!(*log.borrow(), [1]);
+    }
+}
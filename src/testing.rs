@@ -0,0 +1,94 @@
+//! Assertions for testing atoms, enabled by the `testing` feature
+//!
+//! ```
+//! use rewind::testing::{assert_decay, assert_undo};
+//! assert_undo(rewind::simple(2, |v| v * 10), 20);
+//! assert_decay(rewind::simple(2, |v| v * 10), 2);
+//! ```
+
+use std::{any::type_name, cell::Cell, fmt::Debug, rc::Rc};
+
+use crate::Atom;
+
+/// Undo `atom` and check the result, naming the atom's type if it doesn't match
+#[track_caller]
+pub fn assert_undo<A: Atom>(atom: A, expected: A::Undo)
+where
+    A::Undo: PartialEq + Debug,
+{
+    let got = atom.undo();
+    assert!(
+        got == expected,
+        "undo of {} produced {got:?}, expected {expected:?}",
+        type_name::<A>()
+    );
+}
+
+/// Decay `atom` and check the result, naming the atom's type if it doesn't match
+#[track_caller]
+pub fn assert_decay<A: Atom>(atom: A, expected: A::Decay)
+where
+    A::Decay: PartialEq + Debug,
+{
+    let got = atom.decay();
+    assert!(
+        got == expected,
+        "decay of {} produced {got:?}, expected {expected:?}",
+        type_name::<A>()
+    );
+}
+
+/// An atom which records whether it was undone, for checking that whatever it is given to decays
+/// it instead
+///
+/// Dropping the atom counts as undoing it, since that runs the undo of any other atom.
+///
+/// ```
+/// use rewind::testing::{assert_no_undo, probe};
+/// let (atom, probe) = probe();
+/// let mut stack = rewind::stack::Stack::new();
+/// stack.push(atom);
+/// stack.decay_all();
+/// assert_no_undo(&probe);
+/// ```
+#[derive(Debug)]
+pub struct ProbeAtom(Option<Rc<Cell<bool>>>);
+
+/// The flag set by a [`ProbeAtom`], see [`probe`]
+#[derive(Debug, Clone)]
+pub struct Probe(Rc<Cell<bool>>);
+
+impl Probe {
+    pub fn undone(&self) -> bool {
+        self.0.get()
+    }
+}
+
+/// A [`ProbeAtom`] and its [`Probe`]
+pub fn probe() -> (ProbeAtom, Probe) {
+    let flag = Rc::new(Cell::new(false));
+    (ProbeAtom(Some(flag.clone())), Probe(flag))
+}
+
+impl Drop for ProbeAtom {
+    fn drop(&mut self) {
+        if let Some(flag) = self.0.take() {
+            flag.set(true);
+        }
+    }
+}
+
+impl Atom for ProbeAtom {
+    type Undo = ();
+    type Decay = ();
+    fn undo(self) -> Self::Undo {}
+    fn decay(mut self) -> Self::Decay {
+        self.0.take();
+    }
+}
+
+/// Check that the [`ProbeAtom`] of `probe` was decayed, or not resolved yet, rather than undone
+#[track_caller]
+pub fn assert_no_undo(probe: &Probe) {
+    assert!(!probe.undone(), "atom was undone, expected it to decay");
+}
@@ -0,0 +1,175 @@
+//! Ownership edge cases for the atoms, meant to be run under `cargo miri test` as well
+//!
+//! Values are [`Tracked`] so that leaks and double drops show up as a wrong live count even
+//! without Miri.
+
+use std::{
+    cell::{Cell, RefCell},
+    panic::{catch_unwind, AssertUnwindSafe},
+    rc::Rc,
+};
+
+use rewind::{stack::Stack, Atom};
+
+#[derive(Debug)]
+struct Tracked {
+    live: Rc<Cell<i32>>,
+    id: i32,
+}
+
+impl Tracked {
+    fn new(live: &Rc<Cell<i32>>, id: i32) -> Self {
+        live.set(live.get() + 1);
+        Self {
+            live: live.clone(),
+            id,
+        }
+    }
+}
+
+impl Clone for Tracked {
+    fn clone(&self) -> Self {
+        Self::new(&self.live, self.id)
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.live.set(self.live.get() - 1);
+    }
+}
+
+#[test]
+fn double_peels_keep_the_state_consistent() {
+    let mut items = rewind::encase(vec![1]);
+    let first = items.peel_mut(|i| i.push(2), |i, _| i.pop());
+    let mut other = items.clone();
+    let second = other.peel_mut(|i| i.push(3), |i, _| i.pop());
+    assert_eq!(items.clone_state(), [1, 2, 3]);
+    drop(second);
+    let third = items.peel_mut(|i| i.len(), |i, len| i.truncate(len - 1));
+    assert_eq!(*third, 2);
+    drop(third);
+    assert_eq!(items.clone_state(), [1]);
+    first.decay();
+    assert_eq!(other.clone_state(), [1]);
+}
+
+#[test]
+fn panicking_undos_drop_everything_once() {
+    let live = Rc::new(Cell::new(0));
+    let atom = rewind::simple(Tracked::new(&live, 1), |_| panic!("undo failed"));
+    assert!(catch_unwind(AssertUnwindSafe(|| atom.undo())).is_err());
+    assert_eq!(live.get(), 0);
+
+    let mut stack = Stack::new();
+    stack.push(rewind::own_id(Tracked::new(&live, 2)));
+    stack.push(rewind::simple(Tracked::new(&live, 3), |_| {
+        panic!("undo failed")
+    }));
+    stack.push(rewind::simple(Tracked::new(&live, 4), |t| t.id));
+    assert!(catch_unwind(AssertUnwindSafe(|| drop(stack))).is_err());
+    assert_eq!(live.get(), 0);
+
+    let mut state = rewind::encase(Vec::new());
+    let effect = state.peel_mut(
+        |s| s.push(Tracked::new(&live, 5)),
+        |_, _| panic!("undo failed"),
+    );
+    assert!(catch_unwind(AssertUnwindSafe(|| drop(effect))).is_err());
+    drop(state);
+    assert_eq!(live.get(), 0);
+}
+
+#[test]
+fn decay_after_partial_moves() {
+    let live = Rc::new(Cell::new(0));
+    let mut owned = rewind::own_id(Tracked::new(&live, 1));
+    let replaced = owned.replace(Tracked::new(&live, 2));
+    assert_eq!((replaced.id, owned.decay().id), (1, 2));
+    drop(replaced);
+    assert_eq!(live.get(), 0);
+
+    let mapped = rewind::simple(Tracked::new(&live, 3), |t| t).map_value(|t| t.id);
+    assert_eq!(mapped.decay(), 3);
+    assert_eq!(live.get(), 0);
+
+    let mut state = rewind::encase(Vec::new());
+    let pushed = state.peel_mut(
+        |s| {
+            s.push(Tracked::new(&live, 4));
+            Tracked::new(&live, 5)
+        },
+        |s, _| s.pop(),
+    );
+    let (value, parent, undo) = pushed.into_parts();
+    let rebuilt = rewind::atom::SideEffect::from_parts(value, parent, undo);
+    assert_eq!(rebuilt.decay().id, 5);
+    assert_eq!(live.get(), 1);
+    drop(state);
+    assert_eq!(live.get(), 0);
+}
+
+#[test]
+fn stack_drop_undoes_in_order_for_order_dependent_atoms() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    {
+        let mut stack = Stack::new();
+        for i in 0..5 {
+            log.borrow_mut().push(i);
+            let log = log.clone();
+            stack.push(rewind::simple(i, move |i| {
+                assert_eq!(log.borrow_mut().pop(), Some(i), "undone out of order");
+            }));
+        }
+    }
+    assert!(log.borrow().is_empty());
+}
+
+#[test]
+fn owning_into_parts_leaks_nothing() {
+    let live = Rc::new(Cell::new(0));
+    let mut owned = rewind::own(Tracked::new(&live, 1), |t| t);
+    owned.id = 2;
+    let (modified, original, undo) = owned.into_parts();
+    assert_eq!((modified.id, undo(original).id), (2, 1));
+    drop(modified);
+    assert_eq!(live.get(), 0);
+
+    let mut owned = rewind::own_id(Tracked::new(&live, 3));
+    owned.id = 4;
+    assert_eq!(owned.undo().id, 3);
+    assert_eq!(live.get(), 0);
+    drop(rewind::own_id(Tracked::new(&live, 5)));
+    assert_eq!(live.get(), 0);
+}
+
+#[test]
+fn touching_a_locked_state_panics_instead_of_aliasing() {
+    let mut items = rewind::encase(vec![1]);
+    let pushed = items.peel_mut(|i| i.push(2), |i, _| i.pop());
+    let reader = items.clone();
+    {
+        let _lock = items.lock();
+        assert!(catch_unwind(AssertUnwindSafe(|| reader.borrow_state().len())).is_err());
+    }
+    drop(pushed);
+    assert_eq!(*reader.borrow_state(), [1]);
+}
+
+#[test]
+fn modifying_a_borrowed_state_panics_instead_of_aliasing() {
+    let items = rewind::encase(vec![1]);
+    let r = items.borrow_state();
+    let mut other = items.clone();
+    assert!(catch_unwind(AssertUnwindSafe(|| other.modify(|v| v.clear()))).is_err());
+    assert!(catch_unwind(AssertUnwindSafe(|| drop(
+        other.peel_mut(|v| v.pop(), |_, _| ())
+    )))
+    .is_err());
+    assert!(catch_unwind(AssertUnwindSafe(|| drop(other.lock()))).is_err());
+    assert_eq!(r[0], 1);
+    drop(r);
+    other.modify(|v| v.clear());
+    assert!(items.borrow_state().is_empty());
+}
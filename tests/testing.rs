@@ -0,0 +1,39 @@
+#![cfg(feature = "testing")]
+
+use rewind::{
+    stack::Stack,
+    testing::{assert_decay, assert_no_undo, assert_undo, probe},
+};
+
+#[test]
+fn assertions_pass_on_the_expected_result() {
+    assert_undo(rewind::own_id(vec![1]), vec![1]);
+    assert_decay(rewind::simple("kept", |_| ()), "kept");
+
+    let (atom, probe) = probe();
+    let mut stack = Stack::new();
+    stack.push(atom);
+    assert_no_undo(&probe);
+    stack.decay_all();
+    assert_no_undo(&probe);
+}
+
+#[test]
+#[should_panic(expected = "undo of rewind::atom::Simple")]
+fn undo_mismatch_names_the_atom() {
+    assert_undo(rewind::simple(1, |v| v + 1), 1);
+}
+
+#[test]
+#[should_panic(expected = "decay of rewind::atom::Simple")]
+fn decay_mismatch_names_the_atom() {
+    assert_decay(rewind::simple(1, |v| v + 1), 2);
+}
+
+#[test]
+#[should_panic(expected = "atom was undone")]
+fn undone_probe_fails() {
+    let (atom, probe) = probe();
+    drop(Stack::from(vec![atom]));
+    assert_no_undo(&probe);
+}
@@ -0,0 +1,7 @@
+#[test]
+// trybuild runs rustc, which Miri can't spawn
+#[cfg_attr(miri, ignore)]
+fn ui() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
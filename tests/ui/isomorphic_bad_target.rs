@@ -0,0 +1,9 @@
+struct Counter(i32);
+impl Counter {
+    #[rewind::isomorphic(to = 1 + 2)]
+    fn inc(&mut self) {
+        self.0 += 1;
+    }
+}
+
+fn main() {}
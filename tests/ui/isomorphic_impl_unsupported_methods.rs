@@ -0,0 +1,18 @@
+struct Counter(i32);
+#[rewind::isomorphic_impl]
+impl Counter {
+    fn dec(&mut self) {
+        self.0 -= 1;
+    }
+    #[undo(dec)]
+    fn inc(mut self) -> Self {
+        self.0 += 1;
+        self
+    }
+    #[undo(dec)]
+    fn add(&mut self, (a, b): (i32, i32)) {
+        self.0 += a + b;
+    }
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+fn reset() {}
+
+#[rewind::isomorphic(to = reset)]
+fn start() {}
+
+fn main() {}
@@ -0,0 +1,4 @@
+#[rewind::isomorphic(to = dec)]
+struct Counter(i32);
+
+fn main() {}
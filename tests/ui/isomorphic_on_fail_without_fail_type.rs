@@ -0,0 +1,16 @@
+struct Counter(i32);
+fn reset(c: &mut Counter, _: &()) {
+    c.0 = 0;
+}
+impl Counter {
+    fn dec(&mut self) {
+        self.0 -= 1;
+    }
+    #[rewind::isomorphic(to = dec, on_fail = reset)]
+    fn inc(&mut self) -> Result<(), ()> {
+        self.0 += 1;
+        Ok(())
+    }
+}
+
+fn main() {}
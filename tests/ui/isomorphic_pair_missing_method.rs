@@ -0,0 +1,12 @@
+struct Stack(Vec<i32>);
+#[rewind::isomorphic_pair(push, pop)]
+impl Stack {
+    fn push(&mut self, v: i32) {
+        self.0.push(v);
+    }
+    fn remove(&mut self) -> i32 {
+        self.0.pop().unwrap()
+    }
+}
+
+fn main() {}
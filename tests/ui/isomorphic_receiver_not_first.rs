@@ -0,0 +1,12 @@
+struct Counter(i32);
+impl Counter {
+    fn dec(&mut self) {
+        self.0 -= 1;
+    }
+    #[rewind::isomorphic(to = dec)]
+    fn inc(by: i32, &mut self) {
+        self.0 += by;
+    }
+}
+
+fn main() {}
@@ -0,0 +1,13 @@
+struct Token;
+
+#[rewind::isomorphic(to = unuse, no_args, keep_original, redo = consume)]
+fn consume(count: &mut i32, token: Token) {
+    drop(token);
+    *count += 1;
+}
+
+fn unuse(count: &mut i32) {
+    *count -= 1;
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+struct Counter(i32);
+impl Counter {
+    fn dec(&mut self) {
+        self.0 -= 1;
+    }
+    #[rewind::isomorphic(undo = dec)]
+    fn inc(&mut self) {
+        self.0 += 1;
+    }
+}
+
+fn main() {}
@@ -0,0 +1,13 @@
+struct Counter(i32);
+impl Counter {
+    fn dec(&mut self) {
+        self.0 -= 1;
+    }
+    #[rewind::isomorphic(to = dec, keep_original)]
+    fn inc(&mut self) {
+        self.0 += 1;
+    }
+    fn inc_iso(&mut self) {}
+}
+
+fn main() {}
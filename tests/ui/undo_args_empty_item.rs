@@ -0,0 +1,12 @@
+struct Items(Vec<i32>);
+impl Items {
+    fn truncate(&mut self, len: usize) {
+        self.0.truncate(len);
+    }
+    #[rewind::isomorphic(to = truncate(, self.0.len()))]
+    fn extend(&mut self, with: Vec<i32>) {
+        self.0.extend(with);
+    }
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+struct Stack(Vec<i32>);
+impl Stack {
+    #[rewind::isomorphic(to = |s| s.0.clear())]
+    fn pop(&mut self) -> Option<i32> {
+        self.0.pop()
+    }
+}
+
+fn main() {}